@@ -5,47 +5,234 @@ use anyhow::Context;
 use crate::{
     build_id::BuildId,
     cache::{CachableFetcher, FetcherCacheKey},
-    utils::Presence,
+    utils::{percent_encode_to_filename, Presence},
     vfs::AsFile,
 };
 
 use std::fmt::Debug;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Default limit of [ArchiveUnpacker::new]
+pub const DEFAULT_MAX_SOURCE_UNPACK_SIZE: u64 = 8 << 30;
 
 /// An archive (tarball, zip, etc) to be unpacked
 pub struct SourceArchive {
     /// path of the file
     file: Box<dyn AsFile + Send + Sync>,
+    /// original name of the file, if known. Used to guess the compression.
+    file_name: Option<String>,
     /// BuildId of which this file is the source
-    ///
-    /// it is assumed that there is at most one source archive per build id
     build_id: BuildId,
+    /// cache key: the build id, followed by the archive name when the sources of this build id
+    /// are several archives
+    key: String,
 }
 
 impl Debug for SourceArchive {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("SourceArchive")
             .field("build_id", &self.build_id)
+            .field("file_name", &self.file_name)
             .finish()
     }
 }
 
 impl SourceArchive {
+    /// The source archive of `build_id`.
+    ///
     /// two source archives from the same build_id will be considered the same
-    pub fn new<F: AsFile + Send + Sync + 'static>(file: F, build_id: BuildId) -> Self {
+    ///
+    /// `file_name` is the original name of the archive, like `foo-1.0.tar.zst`.
+    pub fn new<F: AsFile + Send + Sync + 'static>(
+        file: F,
+        file_name: Option<String>,
+        build_id: BuildId,
+    ) -> Self {
         Self {
             file: Box::new(file),
+            file_name,
+            key: build_id.to_string(),
             build_id,
         }
     }
+
+    /// One of several archives named `file_name` in the source directory of `build_id`, for
+    /// example the main source and vendored dependencies.
+    ///
+    /// two source archives from the same build_id and with the same name will be considered the
+    /// same
+    pub fn in_directory<F: AsFile + Send + Sync + 'static>(
+        file: F,
+        file_name: String,
+        build_id: BuildId,
+    ) -> Self {
+        Self {
+            file: Box::new(file),
+            key: format!("{}-{}", &*build_id, percent_encode_to_filename(&file_name)),
+            file_name: Some(file_name),
+            build_id,
+        }
+    }
+}
+
+/// Extensions of the archives that are unpacked when found in a source directory
+const ARCHIVE_EXTENSIONS: &[&str] = &[
+    ".tar",
+    ".tar.gz",
+    ".tgz",
+    ".tar.bz2",
+    ".tbz2",
+    ".tar.xz",
+    ".txz",
+    ".tar.lzma",
+    ".tar.zst",
+    ".tar.zstd",
+    ".tar.lz",
+    ".zip",
+];
+
+/// Whether this file name looks like a source archive
+pub fn is_archive_name(file_name: &str) -> bool {
+    ARCHIVE_EXTENSIONS
+        .iter()
+        .any(|extension| file_name.ends_with(extension))
+}
+
+/// Compressions that libarchive does not always detect by itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    Zstd,
+    Lzip,
+}
+
+impl Compression {
+    /// Guesses the compression from the extension of `file_name`.
+    ///
+    /// None means that libarchive should figure it out.
+    fn from_file_name(file_name: &str) -> Option<Self> {
+        if file_name.ends_with(".zst") || file_name.ends_with(".zstd") {
+            Some(Compression::Zstd)
+        } else if file_name.ends_with(".lz") {
+            Some(Compression::Lzip)
+        } else {
+            None
+        }
+    }
+}
+
+/// Decompresses `file` with `compression` into an anonymous file next to `into`.
+///
+/// Fails as soon as the tarball exceeds `max_size` bytes, so that a compression bomb cannot fill
+/// the disk before [check_unpacked_size] gets to look at it.
+///
+/// Blocking.
+fn decompress_tarball(
+    file: std::fs::File,
+    compression: Compression,
+    into: &Path,
+    max_size: u64,
+) -> anyhow::Result<std::fs::File> {
+    let file = BufReader::new(file);
+    let decoder: Box<dyn Read> = match compression {
+        Compression::Zstd => {
+            Box::new(zstd::stream::read::Decoder::with_buffer(file).context("initializing zstd")?)
+        }
+        Compression::Lzip => {
+            let stream =
+                liblzma::stream::Stream::new_lzip_decoder(u64::MAX, liblzma::stream::CONCATENATED)
+                    .context("initializing lzip")?;
+            Box::new(liblzma::bufread::XzDecoder::new_stream(file, stream))
+        }
+    };
+    // libarchive needs to seek in its input, so store the tarball in an anonymous file next to
+    // `into`.
+    let parent = into.parent().context("unpack destination has no parent")?;
+    let mut tarball = tempfile::tempfile_in(parent)
+        .with_context(|| format!("creating temporary file in {}", parent.display()))?;
+    let written = std::io::copy(&mut decoder.take(max_size.saturating_add(1)), &mut tarball)
+        .with_context(|| format!("decompressing {compression:?} archive"))?;
+    anyhow::ensure!(
+        written <= max_size,
+        "{compression:?} archive decompresses to more than {max_size} bytes, see --max-source-unpack-size"
+    );
+    tarball.seek(SeekFrom::Start(0))?;
+    Ok(tarball)
+}
+
+/// Fails if the files in `archive` add up to more than `max_size` bytes.
+///
+/// Only reads the headers of the entries, but the whole archive is still decompressed.
+///
+/// Blocking.
+fn check_unpacked_size(archive: &mut std::fs::File, max_size: u64) -> anyhow::Result<()> {
+    let total = Arc::new(AtomicU64::new(0));
+    let counter = total.clone();
+    // rejecting every entry skips its data
+    let entries = compress_tools::ArchiveIteratorBuilder::new(&mut *archive)
+        .filter(move |_, stat| {
+            counter.fetch_add(stat.st_size.max(0) as u64, Ordering::Relaxed);
+            false
+        })
+        .build()
+        .context("reading archive")?;
+    for content in entries {
+        if let compress_tools::ArchiveContents::Err(e) = content {
+            return Err(e).context("reading archive");
+        }
+        if total.load(Ordering::Relaxed) > max_size {
+            break;
+        }
+    }
+    let total = total.load(Ordering::Relaxed);
+    anyhow::ensure!(
+        total <= max_size,
+        "archive contains more than {max_size} bytes of files ({total}), see --max-source-unpack-size"
+    );
+    archive.seek(SeekFrom::Start(0))?;
+    Ok(())
+}
+
+/// Unpacks the archive `file` to `into`, after decompressing it with `compression` if specified.
+///
+/// Nothing is unpacked if the files in the archive add up to more than `max_size` bytes.
+///
+/// Blocking.
+fn unpack_archive(
+    file: std::fs::File,
+    compression: Option<Compression>,
+    into: &Path,
+    max_size: u64,
+) -> anyhow::Result<()> {
+    let mut archive = match compression {
+        None => file,
+        Some(compression) => decompress_tarball(file, compression, into, max_size)?,
+    };
+    check_unpacked_size(&mut archive, max_size)?;
+    compress_tools::uncompress_archive(archive, into, compress_tools::Ownership::Ignore)?;
+    Ok(())
 }
 
 #[derive(Debug, Clone, Copy)]
 /// A helper to unpack archives and cache the unpacking.
-pub struct ArchiveUnpacker;
+pub struct ArchiveUnpacker {
+    /// see [ArchiveUnpacker::new]
+    max_size: u64,
+}
+
+impl ArchiveUnpacker {
+    /// Refuses to unpack archives whose files add up to more than `max_size` bytes, so that a
+    /// giant source archive cannot fill the disk.
+    pub fn new(max_size: u64) -> Self {
+        Self { max_size }
+    }
+}
 
 impl FetcherCacheKey for SourceArchive {
     fn as_key(&self) -> &str {
-        &self.build_id
+        &self.key
     }
 }
 
@@ -55,18 +242,123 @@ impl CachableFetcher<SourceArchive> for ArchiveUnpacker {
         key: &'a SourceArchive,
         into: &'a std::path::Path,
     ) -> anyhow::Result<crate::utils::Presence> {
-        let mut file = key
+        let file = key
             .file
             .open()
             .await
-            .with_context(|| format!("opening {key:?} for unpacking"))?;
-        compress_tools::tokio_support::uncompress_archive(
-            &mut file,
-            into,
-            compress_tools::Ownership::Ignore,
-        )
-        .await
-        .with_context(|| format!("unpacking {key:?}"))?;
+            .with_context(|| format!("opening {key:?} for unpacking"))?
+            .into_std()
+            .await;
+        let compression = key
+            .file_name
+            .as_deref()
+            .and_then(Compression::from_file_name);
+        let max_size = self.max_size;
+        let into = into.to_owned();
+        tokio::task::spawn_blocking(move || unpack_archive(file, compression, &into, max_size))
+            .await?
+            .with_context(|| format!("unpacking {key:?}"))?;
         Ok(Presence::Found)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{file_sha256, fixture};
+
+    #[test]
+    fn compression_from_file_name() {
+        assert_eq!(
+            Compression::from_file_name("hello-1.0.tar.zst"),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(
+            Compression::from_file_name("hello-1.0.tar.lz"),
+            Some(Compression::Lzip)
+        );
+        assert_eq!(Compression::from_file_name("hello-1.0.tar.gz"), None);
+    }
+
+    #[test]
+    fn archive_names() {
+        assert!(is_archive_name("hello-1.0.tar.zst"));
+        assert!(is_archive_name("extra-0.1.tgz"));
+        assert!(!is_archive_name("README"));
+        assert!(!is_archive_name("hello.c"));
+    }
+
+    #[test]
+    fn keys() {
+        let build_id = BuildId::new("7a5f0e8d2c1b3a4f5e6d7c8b9a0f1e2d3c4b5a69").unwrap();
+        let single = SourceArchive::new(
+            fixture("hello-1.0.tar.lz"),
+            Some("hello-1.0.tar.lz".into()),
+            build_id.clone(),
+        );
+        let first = SourceArchive::in_directory(
+            fixture("hello-1.0.tar.lz"),
+            "a.tar".into(),
+            build_id.clone(),
+        );
+        let second =
+            SourceArchive::in_directory(fixture("hello-1.0.tar.lz"), "b.tar".into(), build_id);
+        assert_eq!(single.as_key(), "7a5f0e8d2c1b3a4f5e6d7c8b9a0f1e2d3c4b5a69");
+        assert_ne!(first.as_key(), second.as_key());
+        assert_ne!(first.as_key(), single.as_key());
+    }
+
+    #[tokio::test]
+    async fn unpack_lzip() {
+        let t = tempfile::tempdir().unwrap();
+        let into = t.path().join("out");
+        let archive = SourceArchive::new(
+            fixture("hello-1.0.tar.lz"),
+            Some("hello-1.0.tar.lz".into()),
+            BuildId::new("7a5f0e8d2c1b3a4f5e6d7c8b9a0f1e2d3c4b5a69").unwrap(),
+        );
+        let presence = ArchiveUnpacker::new(DEFAULT_MAX_SOURCE_UNPACK_SIZE)
+            .fetch(&archive, &into)
+            .await
+            .unwrap();
+        assert_eq!(presence, Presence::Found);
+        assert_eq!(
+            file_sha256(into.join("hello-1.0/src/hello.c")).await,
+            "3d3ba8a9ae40b8994cb00925b3a357074f8940ab36973a921c6764f9248eab1d"
+        );
+    }
+
+    #[test]
+    fn unpack_size_limit() {
+        let t = tempfile::tempdir().unwrap();
+        let into = t.path().join("out");
+        let open = || std::fs::File::open(fixture("hello-1.0.tar.lz")).unwrap();
+        let err = unpack_archive(open(), Some(Compression::Lzip), &into, 10).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("--max-source-unpack-size"),
+            "{err:#}"
+        );
+        // nothing was unpacked
+        assert!(!into.exists());
+        unpack_archive(open(), Some(Compression::Lzip), &into, 1 << 20).unwrap();
+        assert!(into.join("hello-1.0/src/hello.c").is_file());
+    }
+
+    #[test]
+    fn unpack_compression_bomb() {
+        let t = tempfile::tempdir().unwrap();
+        let into = t.path().join("out");
+        // 64 MiB of zeros is a valid, empty tarball, and compresses to a few kilobytes
+        let bomb = zstd::encode_all(std::io::repeat(0).take(64 << 20), 19).unwrap();
+        assert!(bomb.len() < 1 << 20);
+        let path = t.path().join("bomb.tar.zst");
+        std::fs::write(&path, bomb).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let err = unpack_archive(file, Some(Compression::Zstd), &into, 1 << 20).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("--max-source-unpack-size"),
+            "{err:#}"
+        );
+        assert!(!into.exists());
+    }
+}
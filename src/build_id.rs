@@ -1,6 +1,8 @@
 //! Parsing and utils about Build Ids
 
-use std::{fmt::Display, ops::Deref};
+use std::{fmt::Display, ops::Deref, path::Component, path::Path};
+
+use anyhow::Context;
 
 /// A unique identifier for an elf executable or shared object.
 ///
@@ -10,7 +12,7 @@ use std::{fmt::Display, ops::Deref};
 /// file -L /bin/sh
 /// /bin/sh: ELF 64-bit LSB executable, x86-64, version 1 (SYSV), dynamically linked, interpreter /nix/store/maxa3xhmxggrc5v2vc0c3pjb79hjlkp9-glibc-2.40-66/lib/ld-linux-x86-64.so.2, BuildID[sha1]=094b9da7911246c32c8962fe4573d52165304991, for GNU/Linux 3.10.0, not stripped
 /// ```
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct BuildId(String);
 
 impl BuildId {
@@ -43,6 +45,169 @@ impl BuildId {
             extension
         )
     }
+
+    /// Whether this build id starts with `prefix`, ignoring case.
+    pub fn has_prefix(&self, prefix: &str) -> bool {
+        self.0
+            .get(..prefix.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+    }
+}
+
+/// Build id prefixes shorter than this are not completed, see [is_build_id_prefix].
+pub const MIN_BUILD_ID_PREFIX_LEN: usize = 8;
+
+/// Whether `raw` looks like a truncated build id: at least [MIN_BUILD_ID_PREFIX_LEN] but less than
+/// 40 hexadecimal characters.
+pub fn is_build_id_prefix(raw: &str) -> bool {
+    (MIN_BUILD_ID_PREFIX_LEN..40).contains(&raw.len()) && raw.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[test]
+fn test_build_id_prefix() {
+    assert!(is_build_id_prefix("0e204818"));
+    assert!(is_build_id_prefix(
+        "0E20481820d3b92468102b35a5e4a29a8695c1a"
+    ));
+    assert!(!is_build_id_prefix("0e20481"));
+    assert!(!is_build_id_prefix(
+        "0e20481820d3b92468102b35a5e4a29a8695c1af"
+    ));
+    assert!(!is_build_id_prefix("0e20481g"));
+    let build_id = BuildId::new("0e20481820d3b92468102b35a5e4a29a8695c1af").unwrap();
+    assert!(build_id.has_prefix("0E204818"));
+    assert!(!build_id.has_prefix("0e204819"));
+}
+
+/// Default [PathTemplate] of debuginfo in debug outputs, the layout of the debug outputs of
+/// nixpkgs
+pub const DEFAULT_DEBUG_PATH_TEMPLATE: &str = "lib/debug/.build-id/{id_prefix}/{id_rest}.debug";
+
+/// A relative path where `{id_prefix}` and `{id_rest}` stand for the first two characters of a
+/// build id and the rest of it, like [DEFAULT_DEBUG_PATH_TEMPLATE].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathTemplate(String);
+
+impl PathTemplate {
+    /// Parses a template.
+    ///
+    /// Fails if it is not a relative path staying in the directory it is relative to, if it
+    /// contains other placeholders, or if it lacks one of them, as several build ids would then
+    /// share a path.
+    pub fn new(template: &str) -> anyhow::Result<Self> {
+        let path = Path::new(template);
+        anyhow::ensure!(
+            path.components()
+                .all(|component| matches!(component, Component::Normal(_))),
+            "{template:?} is not a relative path without .."
+        );
+        let mut placeholders = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find(['{', '}']) {
+            let Some(after) = rest[start..].strip_prefix('{') else {
+                anyhow::bail!("unmatched }} in {template:?}");
+            };
+            let Some(end) = after.find('}') else {
+                anyhow::bail!("unmatched {{ in {template:?}");
+            };
+            let name = &after[..end];
+            anyhow::ensure!(
+                matches!(name, "id_prefix" | "id_rest"),
+                "unknown placeholder {{{name}}} in {template:?}, expected {{id_prefix}} or {{id_rest}}"
+            );
+            placeholders.push(name);
+            rest = &after[end + 1..];
+        }
+        for name in ["id_prefix", "id_rest"] {
+            anyhow::ensure!(
+                placeholders.contains(&name),
+                "{template:?} does not contain {{{name}}}"
+            );
+        }
+        Ok(Self(template.to_owned()))
+    }
+
+    /// The path for `build_id`
+    pub fn expand(&self, build_id: &BuildId) -> String {
+        self.0
+            .replace("{id_prefix}", &build_id[..2])
+            .replace("{id_rest}", &build_id[2..])
+    }
+}
+
+impl Default for PathTemplate {
+    /// [DEFAULT_DEBUG_PATH_TEMPLATE]
+    fn default() -> Self {
+        Self(DEFAULT_DEBUG_PATH_TEMPLATE.to_owned())
+    }
+}
+
+#[test]
+fn test_path_template() {
+    let build_id = BuildId::new("483bd7f7229bdb06462222e1e353e4f37e15c293").unwrap();
+    let default = PathTemplate::new(DEFAULT_DEBUG_PATH_TEMPLATE).unwrap();
+    assert_eq!(default.expand(&build_id), build_id.in_debug_output("debug"));
+    assert_eq!(PathTemplate::default(), default);
+    let flat = PathTemplate::new("lib/debug/{id_prefix}{id_rest}.dbg").unwrap();
+    assert_eq!(
+        flat.expand(&build_id),
+        "lib/debug/483bd7f7229bdb06462222e1e353e4f37e15c293.dbg"
+    );
+    for bad in [
+        "/lib/debug/{id_prefix}/{id_rest}.debug",
+        "../{id_prefix}/{id_rest}.debug",
+        "lib/debug/{id_rest}.debug",
+        "lib/debug/{id}/{id_prefix}{id_rest}.debug",
+        "lib/debug/{id_prefix}{id_rest",
+        "lib/debug/{id_prefix}}{id_rest}",
+    ] {
+        PathTemplate::new(bad).unwrap_err();
+    }
+}
+
+/// Parses the content of a file listing build ids, one per line.
+///
+/// Blank lines and lines starting with `#` are ignored. Leading and trailing whitespace is
+/// stripped.
+///
+/// Errors mention the line number of the offending line.
+pub fn parse_build_id_list(content: &str) -> anyhow::Result<Vec<BuildId>> {
+    let mut result = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let build_id = BuildId::new(line)
+            .with_context(|| format!("line {}: invalid build id {line:?}", i + 1))?;
+        result.push(build_id);
+    }
+    Ok(result)
+}
+
+#[test]
+fn test_parse_build_id_list() {
+    let content = "# internal tools
+0e20481820d3b92468102b35a5e4a29a8695c1af
+
+  483bd7f7229bdb06462222e1e353e4f37e15c293  
+";
+    let build_ids = parse_build_id_list(content).unwrap();
+    assert_eq!(
+        build_ids,
+        [
+            BuildId::new("0e20481820d3b92468102b35a5e4a29a8695c1af").unwrap(),
+            BuildId::new("483bd7f7229bdb06462222e1e353e4f37e15c293").unwrap()
+        ]
+    );
+    let err = parse_build_id_list(
+        "# ok
+0e20481820d3b92468102b35a5e4a29a8695c1af
+nope
+",
+    )
+    .unwrap_err();
+    assert!(format!("{err:#}").contains("line 3"), "{err:#}");
 }
 
 #[test]
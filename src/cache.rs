@@ -8,7 +8,7 @@ use std::{
     marker::PhantomData,
     path::{Path, PathBuf},
     sync::{Arc, Weak},
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::Context;
@@ -17,6 +17,7 @@ use tracing::{instrument, Instrument, Level};
 use weak_table::WeakValueHashMap;
 
 use crate::{
+    nar::collect_unpack_timings,
     utils::{remove_recursively_if_exists, touch, Presence},
     vfs::RestrictedPath,
 };
@@ -27,6 +28,145 @@ use crate::{
 const PARTIAL: &str = "partial";
 /// Directory where finished outputs are stored.
 const CACHE: &str = "cache";
+/// Directory where failed fetches are moved from [`PARTIAL`], see
+/// [CacheSettings::keep_failed_fetches]
+const FAILED: &str = "failed";
+/// Directory of the [DecompressedCopies] of compressed debug files
+const DECOMPRESSED: &str = "decompressed";
+
+/// Settings shared by all [`FetcherCache`]s of a process, see [`FetcherCache::new`]
+#[derive(Debug, Clone)]
+pub struct CacheSettings {
+    /// The cache directory containing the root directories of the [`FetcherCache`]s
+    pub cache_dir: PathBuf,
+    /// Read-only directories with the same layout as `cache_dir`, where entries are looked for,
+    /// in order, when they are not in `cache_dir`.
+    ///
+    /// Fetched entries are written to `cache_dir`, and cleanup only removes entries from
+    /// `cache_dir`. This allows sharing a cache directory populated by other hosts, for example on
+    /// NFS.
+    pub read_only_tiers: Vec<PathBuf>,
+    /// Command run after each successful fetch, with the key and the location of the fetched
+    /// entry as arguments.
+    ///
+    /// If the command fails, so does the fetch, and the entry is removed from the cache.
+    pub post_fetch_command: Option<PathBuf>,
+    /// Whether what a failed fetch wrote is moved to `failed/<key>-<timestamp>` for inspection,
+    /// instead of being removed.
+    ///
+    /// These directories are only removed by the cleanup at startup, once they expire.
+    pub keep_failed_fetches: bool,
+    /// How many entries cleanup examines before pausing, so that cleaning up a large cache does
+    /// not starve requests of IO
+    pub cleanup_batch_size: usize,
+    /// Whether the debug files (`*.debug`) fetched are stored compressed with zstd, as
+    /// `*.debug.zst`, to save disk space.
+    ///
+    /// Requests for such a file resolve to its compressed version, see
+    /// [crate::vfs::AsFile::open_decompressing]. Reading it at random offsets needs a
+    /// decompressed copy, see [DecompressedCopies].
+    ///
+    /// Entries written with `compress` off are never looked up compressed, and conversely.
+    pub compress: bool,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            cache_dir: PathBuf::new(),
+            read_only_tiers: Vec::new(),
+            post_fetch_command: None,
+            keep_failed_fetches: false,
+            cleanup_batch_size: 1000,
+            compress: false,
+        }
+    }
+}
+
+impl CacheSettings {
+    /// The read-only counterparts of `root_dir` in [CacheSettings::read_only_tiers]
+    fn read_only_tiers_of(&self, root_dir: &Path) -> Vec<PathBuf> {
+        let Ok(relative) = root_dir.strip_prefix(&self.cache_dir) else {
+            return Vec::new();
+        };
+        self.read_only_tiers
+            .iter()
+            .map(|tier| tier.join(relative))
+            .collect()
+    }
+}
+
+/// How long cleanup pauses between two batches of entries
+const CLEANUP_BATCH_PAUSE: Duration = Duration::from_millis(10);
+
+/// Appended to the name of files compressed by [compress_debug_files]
+pub const COMPRESSED_SUFFIX: &str = ".zst";
+
+/// Debug files are written once and rarely read, so compressing them well is worth some time.
+const COMPRESSION_LEVEL: i32 = 9;
+
+/// Whether this file is compressed by [compress_debug_files]
+pub fn is_compressible(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "debug")
+}
+
+/// Replaces the debug files in `dir` by a zstd compressed version, see [CacheSettings::compress].
+///
+/// The decompressed size is recorded in the zstd frame header. Nothing happens if `dir` is a
+/// file.
+pub fn compress_debug_files(dir: &Path) -> anyhow::Result<()> {
+    for entry in walkdir::WalkDir::new(dir).min_depth(1).follow_links(false) {
+        let entry = entry.with_context(|| format!("walking {}", dir.display()))?;
+        if !entry.file_type().is_file() || !is_compressible(entry.path()) {
+            continue;
+        }
+        let path = entry.path();
+        let mut compressed_path = path.as_os_str().to_owned();
+        compressed_path.push(COMPRESSED_SUFFIX);
+        let mut source =
+            std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        let metadata = source.metadata()?;
+        let target = std::fs::File::create(&compressed_path)
+            .with_context(|| format!("creating {compressed_path:?}"))?;
+        let mut encoder = zstd::stream::write::Encoder::new(target, COMPRESSION_LEVEL)?;
+        encoder.set_pledged_src_size(Some(metadata.len()))?;
+        std::io::copy(&mut source, &mut encoder)
+            .with_context(|| format!("compressing {}", path.display()))?;
+        let target = encoder.finish()?;
+        target.set_permissions(metadata.permissions())?;
+        target.set_modified(metadata.modified()?)?;
+        std::fs::remove_file(path).with_context(|| format!("removing {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Waiting longer than this for another task to fetch the same key is logged as a warning.
+const SLOW_WAIT_WARNING: Duration = Duration::from_secs(10);
+/// Fetches longer than this are logged as a warning.
+const SLOW_FETCH_WARNING: Duration = Duration::from_secs(30);
+
+/// Awaits `future`, logging a warning if it takes more than `threshold`, and another one with the
+/// total duration when it finally completes.
+///
+/// `what` describes what is being waited for.
+async fn warn_if_slow<T>(
+    future: impl Future<Output = T>,
+    threshold: Duration,
+    what: impl Fn() -> String,
+) -> T {
+    let start = Instant::now();
+    let mut future = std::pin::pin!(future);
+    match tokio::time::timeout(threshold, future.as_mut()).await {
+        Ok(result) => result,
+        Err(_) => {
+            tracing::warn!("{} is taking more than {threshold:?}", what());
+            let result = future.await;
+            tracing::warn!("{} took {:?}", what(), start.elapsed());
+            result
+        }
+    }
+}
 
 /// An argument to a fetcher that can be used with [`FetcherCache`]
 pub trait FetcherCacheKey: Debug + Send + Sync {
@@ -92,6 +232,287 @@ pub trait CachableFetcher<Key: FetcherCacheKey>: Send + Sync {
     ) -> impl Future<Output = anyhow::Result<Presence>> + Send;
 }
 
+/// What is known about a cache entry without fetching it, see [`FetcherCache::inspect`]
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct EntryInfo {
+    /// where the entry is stored
+    pub path: PathBuf,
+    /// when the entry was last used, in seconds since the epoch
+    ///
+    /// This is only updated when the entry was not used for half of the expiration time.
+    pub last_used: u64,
+    /// total size of the files of the entry, in bytes
+    pub size: u64,
+    /// from when the entry may be removed by cleanup if it is not used, in seconds since the epoch
+    pub eligible_for_cleanup: u64,
+}
+
+/// Seconds since the epoch
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Create a directory inside `root_dir`, succeeding if it already exists
+async fn ensure_dir_exists(root_dir: &Path, subdir: &str) -> anyhow::Result<()> {
+    let path = root_dir.join(subdir);
+    match tokio::fs::create_dir(&path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        Err(e) => Err(e).context(format!("creating {} for cache", path.display())),
+    }
+}
+
+/// What a fetch needs from its [`FetcherCache`], owned so that the fetch can run in its own
+/// task, see [`FetcherCache::get`]
+struct FetchContext<Fetcher> {
+    root_dir: PathBuf,
+    fetcher: Arc<Fetcher>,
+    /// see [CacheSettings::post_fetch_command]
+    post_fetch_command: Option<PathBuf>,
+    /// see [CacheSettings::keep_failed_fetches]
+    keep_failed_fetches: bool,
+    /// see [CacheSettings::compress]
+    compress: bool,
+}
+
+impl<Fetcher> FetchContext<Fetcher> {
+    /// when the corresponding directory is not in cache, put it there
+    #[instrument(level = Level::TRACE, skip_all, fields(key=key.key.as_key()))]
+    async fn fetch<Key: FetcherCacheKey>(
+        &self,
+        key: &WriteLockedCacheEntry<Key>,
+    ) -> anyhow::Result<Option<PathBuf>>
+    where
+        Fetcher: CachableFetcher<Key>,
+    {
+        let partial_dir = self.root_dir.join(PARTIAL).join(key.key.as_key());
+        // we always clean after us, as we run in our own task
+        remove_recursively_if_exists(&partial_dir).await?;
+        let fetch = self.fetcher.fetch(&key.key, &partial_dir);
+        let fetch_result = warn_if_slow(fetch, SLOW_FETCH_WARNING, || {
+            format!("fetching {}", key.key.as_key())
+        })
+        .await;
+        let fetch_result = match fetch_result {
+            Ok(Presence::Found) if self.compress => {
+                let dir = partial_dir.clone();
+                tokio::task::spawn_blocking(move || compress_debug_files(&dir))
+                    .await?
+                    .map(|()| Presence::Found)
+            }
+            other => other,
+        };
+        let result = match fetch_result {
+            Ok(Presence::Found) => match tokio::fs::rename(&partial_dir, &key.target)
+                .await
+                .with_context(|| {
+                    format!(
+                        "renaming {} to {}",
+                        partial_dir.display(),
+                        key.target.display()
+                    )
+                }) {
+                Ok(()) => self
+                    .run_post_fetch_command(key)
+                    .await
+                    .map(|()| Some(key.target.clone())),
+                Err(e) => Err(e),
+            },
+            Ok(Presence::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        };
+        if result.is_err() && self.keep_failed_fetches {
+            self.keep_failed_fetch(key, &partial_dir).await;
+        }
+        remove_recursively_if_exists(&partial_dir).await?;
+        result
+    }
+    /// moves `partial_dir`, left by a failed fetch of `key`, to [`FAILED`], see
+    /// [CacheSettings::keep_failed_fetches]
+    async fn keep_failed_fetch<Key: FetcherCacheKey>(
+        &self,
+        key: &WriteLockedCacheEntry<Key>,
+        partial_dir: &Path,
+    ) {
+        if tokio::fs::symlink_metadata(partial_dir).await.is_err() {
+            // the fetcher failed before writing anything
+            return;
+        }
+        let failed_dir = self.root_dir.join(FAILED).join(format!(
+            "{}-{}",
+            key.key.as_key(),
+            unix_secs(SystemTime::now())
+        ));
+        let result = async {
+            ensure_dir_exists(&self.root_dir, FAILED).await?;
+            tokio::fs::rename(partial_dir, &failed_dir)
+                .await
+                .with_context(|| {
+                    format!(
+                        "renaming {} to {}",
+                        partial_dir.display(),
+                        failed_dir.display()
+                    )
+                })
+        }
+        .await;
+        match result {
+            Ok(()) => tracing::warn!(
+                "fetching {} failed, kept what was fetched in {}",
+                key.key.as_key(),
+                failed_dir.display()
+            ),
+            Err(e) => tracing::warn!(
+                "fetching {} failed, and keeping what was fetched failed: {e:#}",
+                key.key.as_key()
+            ),
+        }
+    }
+    /// runs [CacheSettings::post_fetch_command] on this freshly fetched entry, removing
+    /// the entry if the command fails
+    async fn run_post_fetch_command<Key: FetcherCacheKey>(
+        &self,
+        key: &WriteLockedCacheEntry<Key>,
+    ) -> anyhow::Result<()> {
+        let Some(command) = &self.post_fetch_command else {
+            return Ok(());
+        };
+        let result = async {
+            let status = tokio::process::Command::new(command)
+                .arg(key.key.as_key())
+                .arg(&key.target)
+                .kill_on_drop(true)
+                .status()
+                .await
+                .with_context(|| format!("running {}", command.display()))?;
+            anyhow::ensure!(status.success(), "{} {status}", command.display());
+            Ok(())
+        }
+        .await;
+        if let Err(e) = &result {
+            tracing::error!(
+                "post fetch command failed for {}: {e:#}",
+                key.target.display()
+            );
+            remove_recursively_if_exists(&key.target)
+                .await
+                .with_context(|| format!("removing {}", key.target.display()))?;
+        }
+        result.with_context(|| format!("post fetch command for {}", key.key.as_key()))
+    }
+}
+
+/// How many bytes the decompressed copies of a [DecompressedCopies] add up to at most
+const DECOMPRESSED_COPIES_MAX_SIZE: u64 = 1 << 30;
+
+/// Decompressed copies of files written by [compress_debug_files], for reads at random offsets
+/// like parsing ELF headers, so that a file read several times in a row is only decompressed
+/// once.
+///
+/// The least recently used copies are removed once they add up to more than
+/// [DECOMPRESSED_COPIES_MAX_SIZE].
+#[derive(Debug)]
+pub struct DecompressedCopies {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl DecompressedCopies {
+    /// Copies are stored in `dir`, which must exist, and add up to at most `max_size` bytes.
+    pub fn new(dir: PathBuf, max_size: u64) -> Self {
+        Self { dir, max_size }
+    }
+
+    /// Opens the decompressed copy of `compressed`, a file written by [compress_debug_files],
+    /// decompressing it first if there is none.
+    pub async fn open(&self, compressed: &Path) -> std::io::Result<tokio::fs::File> {
+        let compressed = compressed.to_owned();
+        let dir = self.dir.clone();
+        let max_size = self.max_size;
+        let file = tokio::task::spawn_blocking(move || {
+            use std::io::Seek;
+            let copy = dir.join(copy_name(&compressed)?);
+            match std::fs::File::open(&copy) {
+                Ok(file) => {
+                    // the mtime of a copy is when it was last used
+                    file.set_modified(SystemTime::now())?;
+                    return Ok(file);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+            let mut decoder = zstd::stream::read::Decoder::new(std::fs::File::open(&compressed)?)?;
+            let mut decompressed = tempfile::NamedTempFile::new_in(&dir)?;
+            std::io::copy(&mut decoder, &mut decompressed)?;
+            let mut file = decompressed.persist(&copy).map_err(|e| e.error)?;
+            file.rewind()?;
+            evict_decompressed_copies(&dir, max_size, &copy);
+            Ok(file)
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+        Ok(tokio::fs::File::from_std(file))
+    }
+}
+
+/// The name of the decompressed copy of `compressed` in [DecompressedCopies].
+///
+/// It changes when `compressed` is replaced by another file, for example when its cache entry is
+/// fetched again after expiring.
+fn copy_name(compressed: &Path) -> std::io::Result<String> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = std::fs::symlink_metadata(compressed)?;
+    let mut hash = hmac_sha256::Hash::new();
+    hash.update(compressed.as_os_str().as_encoded_bytes());
+    for number in [
+        metadata.dev(),
+        metadata.ino(),
+        metadata.size(),
+        metadata.ctime() as u64,
+        metadata.ctime_nsec() as u64,
+    ] {
+        hash.update(number.to_le_bytes());
+    }
+    Ok(hash
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// Removes the least recently used copies in `dir` until they add up to at most `max_size`
+/// bytes. `keep`, the copy being returned, is never removed.
+///
+/// Copies being written have a name starting with a dot and are left alone. Errors are ignored:
+/// other copies may be removed concurrently.
+///
+/// Blocking.
+fn evict_decompressed_copies(dir: &Path, max_size: u64, keep: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut copies: Vec<(SystemTime, u64, PathBuf)> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| !entry.file_name().as_encoded_bytes().starts_with(b"."))
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+        })
+        .collect();
+    // `keep` first, then the most recently used
+    copies.sort_by_key(|(used, _, path)| (path != keep, std::cmp::Reverse(*used)));
+    let mut total = 0u64;
+    for (_, size, path) in copies {
+        total = total.saturating_add(size);
+        if total > max_size {
+            tracing::debug!("removing decompressed copy {}", path.display());
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+}
+
 /// A lock that prevents a temporary directory from being removed
 #[derive(Clone)]
 pub struct CachedPathLock(#[allow(dead_code)] Arc<RwLockReadGuardArc<()>>);
@@ -101,44 +522,100 @@ pub struct CachedPathLock(#[allow(dead_code)] Arc<RwLockReadGuardArc<()>>);
 pub struct FetcherCache<Key: FetcherCacheKey, Fetcher: CachableFetcher<Key>> {
     root_dir: PathBuf,
     /// the underlying cached fetcher
-    pub fetcher: Fetcher,
+    pub fetcher: Arc<Fetcher>,
     phantom_key: PhantomData<Key>,
     locks: tokio::sync::Mutex<WeakValueHashMap<String, Weak<RwLock<()>>>>,
     expiration: Duration,
+    offline: bool,
+    /// read-only directories with the same layout as `root_dir`, see
+    /// [CacheSettings::read_only_tiers]
+    read_only_tiers: Vec<PathBuf>,
+    /// see [CacheSettings::post_fetch_command]
+    post_fetch_command: Option<PathBuf>,
+    /// see [CacheSettings::keep_failed_fetches]
+    keep_failed_fetches: bool,
+    /// see [CacheSettings::cleanup_batch_size]
+    cleanup_batch_size: usize,
+    /// see [CacheSettings::compress]
+    compress: bool,
+    /// where compressed debug files are decompressed to be read, Some iff `compress`
+    decompressed_copies: Option<Arc<DecompressedCopies>>,
 }
 
 impl<Key: FetcherCacheKey + 'static, Fetcher: CachableFetcher<Key> + 'static>
     FetcherCache<Key, Fetcher>
 {
-    /// Create a directory inside `self.root`, succeeding if it already exists
-    async fn ensure_dir_exists(&self, subdir: &str) -> anyhow::Result<()> {
-        let path = self.root_dir.join(subdir);
-        match tokio::fs::create_dir(&path).await {
-            Ok(()) => Ok(()),
-            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
-            Err(e) => Err(e).context(format!("creating {} for cache", path.display())),
+    /// Removes everything in [`PARTIAL`].
+    ///
+    /// Fetches clean after themselves, so what remains there was left by a process which was
+    /// killed while fetching, and is not valid anymore.
+    async fn remove_leftover_partial_fetches(&self) -> anyhow::Result<()> {
+        let partial = self.root_dir.join(PARTIAL);
+        let mut entries = tokio::fs::read_dir(&partial)
+            .await
+            .with_context(|| format!("listing {}", partial.display()))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .with_context(|| format!("listing {}", partial.display()))?
+        {
+            let path = entry.path();
+            tracing::info!("removing {} left by an interrupted fetch", path.display());
+            remove_recursively_if_exists(&path)
+                .await
+                .with_context(|| format!("removing {}", path.display()))?;
         }
+        Ok(())
     }
 
     /// Create a [`FetcherCache`] that stores fetched directories under `root_dir`.
     ///
     /// `expiration` is the order of magnitude of how recently a file must have been requested by [`FetcherCache::get`] to not be deleted by [`FetcherCache::cleanup`].
     ///
-    /// `root_dir` must already exist.
+    /// If `offline` is true, [FetcherCache::get] never calls the fetcher and only returns what is
+    /// already in cache.
+    ///
+    /// `settings` apply to all caches of the process; `root_dir` must be inside
+    /// [CacheSettings::cache_dir] for its read-only tiers to be found.
+    ///
+    /// `root_dir` must already exist, and must not be used by another [`FetcherCache`] at the same
+    /// time: fetches that were interrupted by a crash are removed from it.
     pub async fn new(
         root_dir: PathBuf,
         fetcher: Fetcher,
         expiration: Duration,
+        offline: bool,
+        settings: &CacheSettings,
     ) -> anyhow::Result<Self> {
+        let read_only_tiers = settings.read_only_tiers_of(&root_dir);
+        let decompressed_copies = settings.compress.then(|| {
+            Arc::new(DecompressedCopies::new(
+                root_dir.join(DECOMPRESSED),
+                DECOMPRESSED_COPIES_MAX_SIZE,
+            ))
+        });
         let cache = Self {
             root_dir,
-            fetcher,
+            fetcher: Arc::new(fetcher),
             phantom_key: PhantomData,
             locks: Default::default(),
             expiration,
+            offline,
+            read_only_tiers,
+            post_fetch_command: settings.post_fetch_command.clone(),
+            keep_failed_fetches: settings.keep_failed_fetches,
+            cleanup_batch_size: settings.cleanup_batch_size.max(1),
+            compress: settings.compress,
+            decompressed_copies,
         };
-        cache.ensure_dir_exists(PARTIAL).await?;
-        cache.ensure_dir_exists(CACHE).await?;
+        ensure_dir_exists(&cache.root_dir, PARTIAL).await?;
+        cache.remove_leftover_partial_fetches().await?;
+        ensure_dir_exists(&cache.root_dir, CACHE).await?;
+        // copies are cheap to make again, and copies being written may have been left by a crash
+        remove_recursively_if_exists(&cache.root_dir.join(DECOMPRESSED)).await?;
+        if cache.compress {
+            ensure_dir_exists(&cache.root_dir, DECOMPRESSED).await?;
+        }
         Ok(cache)
     }
     #[instrument(level = Level::TRACE, skip(self))]
@@ -162,7 +639,11 @@ impl<Key: FetcherCacheKey + 'static, Fetcher: CachableFetcher<Key> + 'static>
         let actual_key = key.as_key();
         let target = self.root_dir.join(CACHE).join(actual_key);
         let entry_lock = self.entry_lock(actual_key).await;
-        let lock = entry_lock.read_arc().await;
+        // the write lock is only held while fetching
+        let lock = warn_if_slow(entry_lock.read_arc(), SLOW_WAIT_WARNING, || {
+            format!("waiting for another task to fetch {actual_key}")
+        })
+        .await;
         ReadLockedCacheEntry { key, target, lock }
     }
     #[instrument(level = Level::TRACE, skip_all, fields(key=lock.key.as_key()))]
@@ -173,7 +654,11 @@ impl<Key: FetcherCacheKey + 'static, Fetcher: CachableFetcher<Key> + 'static>
         let LockedCacheEntry { key, target, lock } = lock;
         drop(lock);
         let entry_lock = self.entry_lock(key.as_key()).await;
-        let lock = entry_lock.upgradable_read_arc().await;
+        // only one task at a time holds the upgradable lock, the one which fetches
+        let lock = warn_if_slow(entry_lock.upgradable_read_arc(), SLOW_WAIT_WARNING, || {
+            format!("waiting for another task to fetch {}", key.as_key())
+        })
+        .await;
         UpgradableReadLockedCacheEntry { key, target, lock }
     }
     #[instrument(level = Level::TRACE, skip_all, fields(key=lock.key.as_key()))]
@@ -230,34 +715,56 @@ impl<Key: FetcherCacheKey + 'static, Fetcher: CachableFetcher<Key> + 'static>
             }
         }
     }
-    /// when the corresponding directory is not in cache, put it there
-    #[instrument(level = Level::TRACE, skip_all, fields(key=key.key.as_key()))]
-    async fn fetch<'key, 'cache: 'key>(
-        &'cache self,
-        key: &'key WriteLockedCacheEntry<Key>,
-    ) -> anyhow::Result<Option<PathBuf>> {
-        let partial_dir = self.root_dir.join(PARTIAL).join(key.key.as_key());
-        // we always clean after us, unless the future stops being polled
-        remove_recursively_if_exists(&partial_dir).await?;
-        let result = match self.fetcher.fetch(&key.key, &partial_dir).await {
-            Ok(Presence::Found) => tokio::fs::rename(&partial_dir, &key.target)
-                .await
-                .with_context(|| {
-                    format!(
-                        "renaming {} to {}",
-                        partial_dir.display(),
-                        key.target.display()
-                    )
-                })
-                .map(|()| Some(key.target.clone())),
-            Ok(Presence::NotFound) => Ok(None),
-            Err(e) => Err(e),
+    /// returns the corresponding directory if it is in one of the read-only tiers
+    ///
+    /// Tiers that cannot be read are skipped, so that an unavailable shared cache only makes
+    /// requests slower.
+    async fn cached_in_tiers(&self, key: &str) -> Option<PathBuf> {
+        for tier in &self.read_only_tiers {
+            let path = tier.join(CACHE).join(key);
+            match tokio::fs::symlink_metadata(&path).await {
+                Ok(_) => {
+                    tracing::trace!("found {key} in read-only tier {}", tier.display());
+                    return Some(path);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => tracing::warn!("cannot read {}: {e}", path.display()),
+            }
+        }
+        None
+    }
+    /// Fetches `key` in a task of its own, holding the write lock until it completes.
+    ///
+    /// If the caller stops polling, typically because the client disconnected, the fetch still
+    /// completes and cleans up after itself, and the next caller finds the result in cache.
+    async fn fetch_in_task(
+        &self,
+        key: WriteLockedCacheEntry<Key>,
+    ) -> anyhow::Result<(WriteLockedCacheEntry<Key>, anyhow::Result<Option<PathBuf>>)> {
+        let context = FetchContext {
+            root_dir: self.root_dir.clone(),
+            fetcher: self.fetcher.clone(),
+            post_fetch_command: self.post_fetch_command.clone(),
+            keep_failed_fetches: self.keep_failed_fetches,
+            compress: self.compress,
         };
-        remove_recursively_if_exists(&partial_dir).await?;
-        result
+        let task = async move {
+            // the spawned task does not inherit the timings collected by the caller
+            let (result, timings) = collect_unpack_timings(context.fetch(&key)).await;
+            (key, result, timings)
+        };
+        let (key, result, timings) = tokio::spawn(task.in_current_span())
+            .await
+            .context("waiting for the fetch task")?;
+        timings.add_to_collected();
+        Ok((key, result))
     }
     /// Returns the location where the file/directory for `key` is stored, fetching it if
     /// necessary.
+    ///
+    /// Entries of the read-only tiers are used as they are, and not copied to `root_dir`.
+    ///
+    /// When offline, returns `Ok(None)` for anything not already in cache.
     pub fn get(
         &self,
         key: Key,
@@ -266,8 +773,16 @@ impl<Key: FetcherCacheKey + 'static, Fetcher: CachableFetcher<Key> + 'static>
         let span = tracing::trace_span!("get", key = key.as_key());
         let future = async move {
             let lock = self.read_lock(key).await;
-            let (lock, result) = match self.cached(&lock).await? {
+            let cached = match self.cached(&lock).await? {
+                Some(cached) => Some(cached),
+                None => self.cached_in_tiers(lock.key.as_key()).await,
+            };
+            let (lock, result) = match cached {
                 Some(cached) => (lock, Some(cached)),
+                None if self.offline => {
+                    tracing::debug!("{} is not in cache and we are offline", lock.key.as_key());
+                    (lock, None)
+                }
                 None => {
                     let upgrade_lock = self.unlock_and_relock_upgradably(lock).await;
                     // somebody may have taken the lock and fetched the cache in between so we have
@@ -279,21 +794,54 @@ impl<Key: FetcherCacheKey + 'static, Fetcher: CachableFetcher<Key> + 'static>
                         ),
                         None => {
                             let write_lock = self.upgrade_upgradeable_read_lock(upgrade_lock).await;
-                            let result = self.fetch(&write_lock).await?;
-                            (self.downgrade_write_lock(write_lock), result)
+                            let (write_lock, result) = self.fetch_in_task(write_lock).await?;
+                            (self.downgrade_write_lock(write_lock), result?)
                         }
                     }
                 }
             };
-            match result {
-                None => Ok(None),
-                Some(path) => Ok(Some(
-                    RestrictedPath::new(path, Some(CachedPathLock(lock.lock.into()))).await?,
-                )),
-            }
+            let Some(path) = result else {
+                return Ok(None);
+            };
+            let path = RestrictedPath::new(path, Some(CachedPathLock(lock.lock.into()))).await?;
+            Ok(Some(match self.decompressed_copies {
+                Some(ref copies) => path.with_decompressed_copies(copies.clone()),
+                None => path,
+            }))
         };
         future.instrument(span)
     }
+    /// Returns information about the cache entry for this key, without fetching it, taking a lock
+    /// or marking it as used.
+    ///
+    /// Returns None if the key is not in cache. Read-only tiers are not inspected.
+    pub async fn inspect(&self, key: &str) -> anyhow::Result<Option<EntryInfo>> {
+        let path = self.root_dir.join(CACHE).join(key);
+        let metadata = match tokio::fs::symlink_metadata(&path).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            other => other.with_context(|| format!("stat({})", path.display()))?,
+        };
+        let mtime = metadata.modified().context("no mtime on this platform")?;
+        let path2 = path.clone();
+        // entries may be removed concurrently, so errors only make the size smaller
+        let size = tokio::task::spawn_blocking(move || {
+            walkdir::WalkDir::new(path2)
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter_map(|entry| entry.metadata().ok())
+                .filter(|metadata| metadata.is_file())
+                .map(|metadata| metadata.len())
+                .sum()
+        })
+        .await?;
+        Ok(Some(EntryInfo {
+            path,
+            last_used: unix_secs(mtime),
+            size,
+            // see _cleanup
+            eligible_for_cleanup: unix_secs(mtime + self.expiration * 2),
+        }))
+    }
     /// Drop all currently unused cache entries
     pub async fn shrink_cache(&self) -> anyhow::Result<()> {
         self._cleanup(Duration::ZERO).await
@@ -312,7 +860,12 @@ impl<Key: FetcherCacheKey + 'static, Fetcher: CachableFetcher<Key> + 'static>
         let mut dirfd = tokio::fs::read_dir(&dir)
             .await
             .with_context(|| format!("listing {} for cleanup", dir.display()))?;
+        let mut examined = 0;
         loop {
+            if examined > 0 && examined % self.cleanup_batch_size == 0 {
+                tokio::time::sleep(CLEANUP_BATCH_PAUSE).await;
+            }
+            examined += 1;
             let entry = match dirfd.next_entry().await {
                 Err(e) => {
                     tracing::warn!(
@@ -374,11 +927,24 @@ impl<Key: FetcherCacheKey + 'static, Fetcher: CachableFetcher<Key> + 'static>
     }
 
     /// Spawns a task that periodically removes unused cached paths
+    ///
+    /// The first cleanup happens at a random time within the first period, so that caches
+    /// created at the same time do not all walk their directory at once.
+    ///
+    /// The task stops once the cache is dropped.
     pub fn spawn_cleanup_task(self: Arc<Self>) {
+        let period = 2 * self.expiration;
+        let weak = Arc::downgrade(&self);
+        drop(self);
         tokio::spawn(async move {
+            let mut delay = period.mul_f64(fastrand::f64());
             loop {
-                tokio::time::sleep(2 * self.expiration).await;
-                if let Err(e) = self.cleanup().await {
+                tokio::time::sleep(delay).await;
+                delay = period;
+                let Some(this) = weak.upgrade() else {
+                    return;
+                };
+                if let Err(e) = this.cleanup().await {
                     tracing::warn!("failed to cleanup: {e}");
                 }
             }
@@ -451,6 +1017,25 @@ mod tests {
         }
     }
 
+    struct TimedFetcher;
+    impl CachableFetcher<String> for TimedFetcher {
+        fn fetch<'a>(
+            &'a self,
+            key: &'a String,
+            into: &'a Path,
+        ) -> impl Future<Output = anyhow::Result<Presence>> + Send {
+            async move {
+                crate::nar::UnpackTimings {
+                    nars: 1,
+                    ..Default::default()
+                }
+                .record(key);
+                tokio::fs::write(&into, b"").await?;
+                Ok(Presence::Found)
+            }
+        }
+    }
+
     async fn read_restricted(r: &RestrictedPath) -> String {
         let mut file = r
             .clone()
@@ -470,9 +1055,15 @@ mod tests {
     async fn does_not_fetch_twice() {
         let t = tempdir().unwrap();
         let fetcher = Arc::new(CountingFetcher::new());
-        let cache = FetcherCache::new(t.path().into(), fetcher.clone(), Duration::from_secs(1000))
-            .await
-            .unwrap();
+        let cache = FetcherCache::new(
+            t.path().into(),
+            fetcher.clone(),
+            Duration::from_secs(1000),
+            false,
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
         let first = cache.get("key".into()).await.unwrap().unwrap();
         assert_eq!(fetcher.get(), 1);
         assert_eq!(read_restricted(&first).await, "1");
@@ -482,14 +1073,451 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn cleanup_expired() {
-        setup_logging();
+    async fn collects_timings_of_fetch_task() {
+        let t = tempdir().unwrap();
+        let cache = FetcherCache::new(
+            t.path().into(),
+            TimedFetcher,
+            Duration::from_secs(1000),
+            false,
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
+        let (fetched, timings) = collect_unpack_timings(cache.get("key".into())).await;
+        fetched.unwrap().unwrap();
+        assert_eq!(timings.nars, 1);
+        // already in cache
+        let (_, timings) = collect_unpack_timings(cache.get("key".into())).await;
+        assert_eq!(timings.nars, 0);
+    }
+
+    #[tokio::test]
+    async fn removes_interrupted_fetches() {
+        let t = tempdir().unwrap();
+        let junk = t.path().join(PARTIAL).join("junk");
+        tokio::fs::create_dir_all(junk.join("subdir"))
+            .await
+            .unwrap();
+        tokio::fs::write(junk.join("subdir/file"), b"half written")
+            .await
+            .unwrap();
+        tokio::fs::write(t.path().join(PARTIAL).join("other"), b"")
+            .await
+            .unwrap();
+        let fetcher = Arc::new(CountingFetcher::new());
+        let cache = FetcherCache::new(
+            t.path().into(),
+            fetcher.clone(),
+            Duration::from_secs(1000),
+            false,
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
+        let mut entries = tokio::fs::read_dir(t.path().join(PARTIAL)).await.unwrap();
+        assert!(entries.next_entry().await.unwrap().is_none());
+        let fetched = cache.get("junk".into()).await.unwrap().unwrap();
+        assert_eq!(read_restricted(&fetched).await, "1");
+    }
+
+    #[tokio::test]
+    async fn post_fetch_command() {
+        let t = tempdir().unwrap();
+        let root_dir = t.path().join("cache_root");
+        tokio::fs::create_dir(&root_dir).await.unwrap();
+        let log = t.path().join("log");
+        let script = t.path().join("hook");
+        tokio::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$1 $2\" >> {}\n[ \"$1\" != bad ]\n",
+                log.display()
+            ),
+        )
+        .await
+        .unwrap();
+        std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let fetcher = Arc::new(CountingFetcher::new());
+        let mut cache = FetcherCache::new(
+            root_dir.clone(),
+            fetcher.clone(),
+            Duration::from_secs(1000),
+            false,
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
+        cache.post_fetch_command = Some(script);
+
+        let fetched = cache.get("good".into()).await.unwrap().unwrap();
+        assert_eq!(read_restricted(&fetched).await, "1");
+        // not run again when already in cache
+        cache.get("good".into()).await.unwrap().unwrap();
+        let target = root_dir.join(CACHE).join("good");
+        assert_eq!(
+            tokio::fs::read_to_string(&log).await.unwrap(),
+            format!("good {}\n", target.display())
+        );
+
+        let err = cache.get("bad".into()).await.unwrap_err();
+        assert!(format!("{err:#}").contains("exit status: 1"), "{err:#}");
+        assert!(!root_dir.join(CACHE).join("bad").exists());
+        assert_eq!(fetcher.get(), 2);
+    }
+
+    struct FailingFetcher;
+    impl CachableFetcher<String> for FailingFetcher {
+        fn fetch<'a>(
+            &'a self,
+            _key: &'a String,
+            into: &'a Path,
+        ) -> impl Future<Output = anyhow::Result<Presence>> + Send {
+            async move {
+                tokio::fs::create_dir(into).await?;
+                tokio::fs::write(into.join("truncated"), "half").await?;
+                anyhow::bail!("unexpected end of file")
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_failed_fetches() {
+        let t = tempdir().unwrap();
+        let mut cache = FetcherCache::new(
+            t.path().into(),
+            FailingFetcher,
+            Duration::from_secs(1000),
+            false,
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
+        cache.get("removed".into()).await.unwrap_err();
+        assert!(!t.path().join(FAILED).exists());
+        assert_eq!(count_elements_in_dir(&t.path().join(PARTIAL)), 1);
+
+        cache.keep_failed_fetches = true;
+        cache.get("kept".into()).await.unwrap_err();
+        assert_eq!(count_elements_in_dir(&t.path().join(PARTIAL)), 1);
+        let kept: Vec<_> = std::fs::read_dir(t.path().join(FAILED))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(kept.len(), 1);
+        let name = kept[0].file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("kept-"), "{name}");
+        assert_eq!(
+            std::fs::read_to_string(kept[0].join("truncated")).unwrap(),
+            "half"
+        );
+    }
 
+    #[tokio::test]
+    async fn read_only_tiers() {
         let t = tempdir().unwrap();
+        let root_dir = t.path().join("writable/sub");
+        let tier_entry = t.path().join("shared/sub").join(CACHE).join("shared_key");
+        tokio::fs::create_dir_all(&root_dir).await.unwrap();
+        tokio::fs::create_dir_all(tier_entry.parent().unwrap())
+            .await
+            .unwrap();
+        tokio::fs::write(&tier_entry, b"shared").await.unwrap();
+        let settings = CacheSettings {
+            cache_dir: t.path().join("writable"),
+            read_only_tiers: vec![t.path().join("does_not_exist"), t.path().join("shared")],
+            ..Default::default()
+        };
         let fetcher = Arc::new(CountingFetcher::new());
-        let cache = FetcherCache::new(t.path().into(), fetcher.clone(), Duration::ZERO)
+        let cache = FetcherCache::new(
+            root_dir.clone(),
+            fetcher.clone(),
+            Duration::from_secs(1000),
+            false,
+            &settings,
+        )
+        .await
+        .unwrap();
+
+        let shared = cache.get("shared_key".into()).await.unwrap().unwrap();
+        assert_eq!(read_restricted(&shared).await, "shared");
+        assert_eq!(fetcher.get(), 0);
+        drop(shared);
+
+        // fetches go to the writable directory
+        let fetched = cache.get("other_key".into()).await.unwrap().unwrap();
+        assert_eq!(read_restricted(&fetched).await, "1");
+        drop(fetched);
+        assert!(root_dir.join(CACHE).join("other_key").exists());
+        assert!(!t
+            .path()
+            .join("shared/sub")
+            .join(CACHE)
+            .join("other_key")
+            .exists());
+
+        cache.shrink_cache().await.unwrap();
+        assert!(!root_dir.join(CACHE).join("other_key").exists());
+        assert!(tier_entry.exists());
+    }
+
+    #[tokio::test]
+    async fn inspect_does_not_fetch() {
+        let t = tempdir().unwrap();
+        let fetcher = Arc::new(CountingFetcher::new());
+        let cache = FetcherCache::new(
+            t.path().into(),
+            fetcher.clone(),
+            Duration::from_secs(1000),
+            false,
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
+        assert!(cache.inspect("key").await.unwrap().is_none());
+        assert_eq!(fetcher.get(), 0);
+        drop(cache.get("key".into()).await.unwrap().unwrap());
+        let info = cache.inspect("key").await.unwrap().unwrap();
+        assert_eq!(fetcher.get(), 1);
+        // the fetcher wrote "1"
+        assert_eq!(info.size, 1);
+        assert_eq!(info.eligible_for_cleanup, info.last_used + 2000);
+    }
+
+    #[tokio::test]
+    async fn offline_does_not_fetch() {
+        let t = tempdir().unwrap();
+        let fetcher = Arc::new(CountingFetcher::new());
+        let cache = FetcherCache::new(
+            t.path().into(),
+            fetcher.clone(),
+            Duration::from_secs(1000),
+            false,
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
+        drop(cache.get("cached".into()).await.unwrap().unwrap());
+        assert_eq!(fetcher.get(), 1);
+        drop(cache);
+
+        let offline = FetcherCache::new(
+            t.path().into(),
+            fetcher.clone(),
+            Duration::from_secs(1000),
+            true,
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
+        let cached = offline.get("cached".into()).await.unwrap().unwrap();
+        assert_eq!(read_restricted(&cached).await, "1");
+        assert!(offline.get("missing".into()).await.unwrap().is_none());
+        assert_eq!(fetcher.get(), 1);
+    }
+
+    /// A fetcher which writes part of its result, then waits to be allowed to finish
+    #[derive(Default)]
+    struct GatedFetcher {
+        started: tokio::sync::Notify,
+        proceed: tokio::sync::Notify,
+    }
+    impl CachableFetcher<String> for GatedFetcher {
+        fn fetch<'a>(
+            &'a self,
+            _key: &'a String,
+            into: &'a Path,
+        ) -> impl Future<Output = anyhow::Result<Presence>> + Send {
+            async move {
+                tokio::fs::write(&into, "partial").await?;
+                self.started.notify_one();
+                self.proceed.notified().await;
+                tokio::fs::write(&into, "complete").await?;
+                Ok(Presence::Found)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_completes_when_get_is_dropped() {
+        setup_logging();
+
+        let t = tempdir().unwrap();
+        let cache = FetcherCache::new(
+            t.path().into(),
+            GatedFetcher::default(),
+            Duration::from_secs(1000),
+            false,
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
+        let fetcher = cache.fetcher.clone();
+        // the client disconnects in the middle of the fetch
+        tokio::select! {
+            _ = cache.get("key".into()) => panic!("the fetch should not have completed"),
+            _ = fetcher.started.notified() => (),
+        }
+        fetcher.proceed.notify_one();
+        // the fetch went on, so the next request does not fetch again
+        let entry = tokio::time::timeout(Duration::from_secs(10), cache.get("key".into()))
+            .await
+            .expect("the key was fetched again")
+            .unwrap()
+            .unwrap();
+        assert_eq!(read_restricted(&entry).await, "complete");
+        // and nothing was left in partial
+        assert_eq!(count_elements_in_dir(&t.path().join(PARTIAL)), 1);
+    }
+
+    /// A fetcher writing a directory with a debug file
+    struct DebugFileFetcher;
+    impl CachableFetcher<String> for DebugFileFetcher {
+        fn fetch<'a>(
+            &'a self,
+            key: &'a String,
+            into: &'a Path,
+        ) -> impl Future<Output = anyhow::Result<Presence>> + Send {
+            async move {
+                tokio::fs::create_dir_all(into.join("lib")).await?;
+                tokio::fs::write(into.join("lib/file.debug"), key.repeat(1000)).await?;
+                tokio::fs::write(into.join("README"), key).await?;
+                Ok(Presence::Found)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn compressed_debug_files() {
+        setup_logging();
+
+        let t = tempdir().unwrap();
+        let cache = FetcherCache::new(
+            t.path().into(),
+            DebugFileFetcher,
+            Duration::from_secs(1000),
+            false,
+            &CacheSettings {
+                compress: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let entry = cache.get("key".into()).await.unwrap().unwrap();
+        let stored = t.path().join(CACHE).join("key");
+        assert!(stored.join("lib/file.debug.zst").is_file());
+        assert!(!stored.join("lib/file.debug").exists());
+        assert!(stored.join("README").is_file());
+
+        let debug = entry
+            .clone()
+            .join("lib/file.debug")
+            .resolve_inside_root()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(debug.file_name().unwrap(), "file.debug");
+        let expected = "key".repeat(1000);
+        // read at random offsets
+        let mut content = String::new();
+        debug
+            .open()
+            .await
+            .unwrap()
+            .read_to_string(&mut content)
+            .await
+            .unwrap();
+        assert_eq!(content, expected);
+        // from the same decompressed copy the second time
+        let copies = || {
+            std::fs::read_dir(t.path().join(DECOMPRESSED))
+                .unwrap()
+                .count()
+        };
+        assert_eq!(copies(), 1);
+        debug.open().await.unwrap();
+        assert_eq!(copies(), 1);
+        // streamed
+        let (size, mut stream) = debug.open_decompressing().await.unwrap().unwrap();
+        assert_eq!(size, expected.len() as u64);
+        let mut content = String::new();
+        stream.read_to_string(&mut content).await.unwrap();
+        assert_eq!(content, expected);
+        // other files are stored as they are
+        let readme = entry.join("README");
+        assert_eq!(read_restricted(&readme).await, "key");
+        let readme = readme.resolve_inside_root().await.unwrap().unwrap();
+        assert!(readme.open_decompressing().await.unwrap().is_none());
+
+        // entries written compressed are not looked up compressed once compression is off
+        drop(cache);
+        let cache = FetcherCache::new(
+            t.path().into(),
+            DebugFileFetcher,
+            Duration::from_secs(1000),
+            false,
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
+        let entry = cache.get("key".into()).await.unwrap().unwrap();
+        let debug = entry.join("lib/file.debug").resolve_inside_root().await;
+        assert!(debug.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn evict_decompressed_copies() {
+        let t = tempdir().unwrap();
+        let copies_dir = t.path().join("copies");
+        std::fs::create_dir(&copies_dir).unwrap();
+        let copies = DecompressedCopies::new(copies_dir.clone(), 1500);
+        let mut compressed = Vec::new();
+        for name in ["a", "b"] {
+            let path = t.path().join(format!("{name}.debug.zst"));
+            let content = zstd::bulk::compress(name.repeat(1000).as_bytes(), 3).unwrap();
+            std::fs::write(&path, content).unwrap();
+            compressed.push(path);
+        }
+        let mut content = String::new();
+        copies
+            .open(&compressed[0])
+            .await
+            .unwrap()
+            .read_to_string(&mut content)
             .await
             .unwrap();
+        assert_eq!(content, "a".repeat(1000));
+        // both copies do not fit
+        copies.open(&compressed[1]).await.unwrap();
+        assert_eq!(std::fs::read_dir(&copies_dir).unwrap().count(), 1);
+        let mut content = String::new();
+        copies
+            .open(&compressed[1])
+            .await
+            .unwrap()
+            .read_to_string(&mut content)
+            .await
+            .unwrap();
+        assert_eq!(content, "b".repeat(1000));
+    }
+
+    #[tokio::test]
+    async fn cleanup_expired() {
+        setup_logging();
+
+        let t = tempdir().unwrap();
+        let fetcher = Arc::new(CountingFetcher::new());
+        let cache = FetcherCache::new(
+            t.path().into(),
+            fetcher.clone(),
+            Duration::ZERO,
+            false,
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
         tracing::info!("fetching key first");
         let first = cache.get("key".into()).await.unwrap().unwrap();
         assert_eq!(fetcher.get(), 1);
@@ -507,14 +1535,51 @@ mod tests {
         assert_eq!(read_restricted(&second).await, "2");
     }
 
+    #[tokio::test]
+    async fn cleanup_large_cache_in_batches() {
+        setup_logging();
+
+        let t = tempdir().unwrap();
+        let fetcher = Arc::new(CountingFetcher::new());
+        let mut cache = FetcherCache::new(
+            t.path().into(),
+            fetcher.clone(),
+            Duration::ZERO,
+            false,
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
+        cache.cleanup_batch_size = 100;
+        let entries = t.path().join(CACHE);
+        let count = || std::fs::read_dir(&entries).unwrap().count();
+        for i in 0..5000 {
+            std::fs::write(entries.join(format!("entry{i}")), "").unwrap();
+        }
+        let held = cache.get("held".into()).await.unwrap().unwrap();
+        assert_eq!(count(), 5001);
+        cache.cleanup().await.unwrap();
+        // all batches were examined, and only the entry in use remains
+        assert_eq!(count(), 1);
+        drop(held);
+        cache.cleanup().await.unwrap();
+        assert_eq!(count(), 0);
+    }
+
     #[tokio::test]
     async fn cleanup_expired_symlink() {
         setup_logging();
 
         let t = tempdir().unwrap();
-        let cache = FetcherCache::new(t.path().into(), SymlinkFetcher, Duration::ZERO)
-            .await
-            .unwrap();
+        let cache = FetcherCache::new(
+            t.path().into(),
+            SymlinkFetcher,
+            Duration::ZERO,
+            false,
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
         let n1 = count_elements_in_dir(t.path());
         tracing::info!("fetching key first");
         let first = cache.get("key".into()).await.unwrap().unwrap();
@@ -538,9 +1603,15 @@ mod tests {
 
         let t = tempdir().unwrap();
         let fetcher = Arc::new(CountingFetcher::new());
-        let cache = FetcherCache::new(t.path().into(), fetcher.clone(), Duration::ZERO)
-            .await
-            .unwrap();
+        let cache = FetcherCache::new(
+            t.path().into(),
+            fetcher.clone(),
+            Duration::ZERO,
+            false,
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
         tracing::info!("fetching key first");
         let first = cache.get("key".into()).await.unwrap().unwrap();
         assert_eq!(fetcher.get(), 1);
@@ -566,9 +1637,15 @@ mod tests {
 
         let t = tempdir().unwrap();
         let fetcher = Arc::new(CountingFetcher::new());
-        let cache = FetcherCache::new(t.path().into(), fetcher.clone(), Duration::from_secs(1000))
-            .await
-            .unwrap();
+        let cache = FetcherCache::new(
+            t.path().into(),
+            fetcher.clone(),
+            Duration::from_secs(1000),
+            false,
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
         tracing::info!("fetching key first");
         let first = cache.get("key".into()).await.unwrap().unwrap();
         assert_eq!(fetcher.get(), 1);
@@ -592,9 +1669,15 @@ mod tests {
         setup_logging();
 
         let t = tempdir().unwrap();
-        let cache = FetcherCache::new(t.path().into(), SymlinkFetcher, Duration::from_secs(1000))
-            .await
-            .unwrap();
+        let cache = FetcherCache::new(
+            t.path().into(),
+            SymlinkFetcher,
+            Duration::from_secs(1000),
+            false,
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
         tracing::info!("fetching key first");
         let first = cache.get("key".into()).await.unwrap().unwrap();
 
@@ -612,9 +1695,15 @@ mod tests {
 
         let t = tempdir().unwrap();
         let fetcher = Arc::new(CountingFetcher::new());
-        let cache = FetcherCache::new(t.path().into(), fetcher.clone(), Duration::from_secs(1000))
-            .await
-            .unwrap();
+        let cache = FetcherCache::new(
+            t.path().into(),
+            fetcher.clone(),
+            Duration::from_secs(1000),
+            false,
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
         let cache = Arc::new(cache);
 
         let fetch_and_use = |key: String| {
@@ -654,9 +1743,15 @@ mod tests {
         let t = tempdir().unwrap();
         let fetcher = Arc::new(CountingFetcher::new());
         let cache = Arc::new(
-            FetcherCache::new(t.path().into(), fetcher.clone(), Duration::from_millis(1))
-                .await
-                .unwrap(),
+            FetcherCache::new(
+                t.path().into(),
+                fetcher.clone(),
+                Duration::from_millis(1),
+                false,
+                &CacheSettings::default(),
+            )
+            .await
+            .unwrap(),
         );
 
         cache.clone().spawn_cleanup_task();
@@ -680,4 +1775,21 @@ mod tests {
         assert_eq!(fetcher.get(), 2);
         assert_eq!(read_restricted(&second).await, "2");
     }
+
+    #[tokio::test]
+    async fn warn_if_slow_returns_result() {
+        setup_logging();
+        let fast = warn_if_slow(async { 1 }, Duration::from_secs(10), || "fast".into()).await;
+        assert_eq!(fast, 1);
+        let slow = warn_if_slow(
+            async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                2
+            },
+            Duration::from_millis(1),
+            || "slow".into(),
+        )
+        .await;
+        assert_eq!(slow, 2);
+    }
 }
@@ -0,0 +1,145 @@
+//! Checking that a binary cache is set up correctly to serve debuginfo, before pointing clients
+//! at it.
+//!
+//! Useful for maintainers of binary caches created with `?index-debug-info=true`.
+
+use std::path::Path;
+
+use anyhow::Context;
+use reqwest::Url;
+
+use crate::build_id::{BuildId, PathTemplate};
+use crate::cache::CacheSettings;
+use crate::prefetch::Outcome;
+use crate::settings::Settings;
+use crate::substituter::binary_cache::DebugInfoRedirectJson;
+use crate::substituter::file::list_debuginfo_redirects;
+use crate::substituter::{file_url_to_path, substituter_from_url, BoxedSubstituter};
+use crate::Options;
+
+/// Checks that the json redirect at `path` can be parsed.
+async fn check_redirect(path: &Path) -> anyhow::Result<()> {
+    let content = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("reading {}", path.display()))?;
+    serde_json::from_slice::<DebugInfoRedirectJson>(&content)
+        .with_context(|| format!("parsing {}", path.display()))?;
+    Ok(())
+}
+
+/// Fetches the debug output of `build_id` from `substituter`, and checks that it contains the
+/// debuginfo of `build_id`.
+///
+/// `template` is where debug outputs contain debuginfo.
+async fn check_build_id(
+    substituter: &BoxedSubstituter,
+    template: &PathTemplate,
+    build_id: &BuildId,
+) -> Outcome {
+    let output = match substituter.build_id_to_debug_output(build_id).await {
+        Ok(Some(output)) => output,
+        other => return Outcome::from_option(other),
+    };
+    let relative = template.expand(build_id);
+    let debugfile = output.join(&relative);
+    match debugfile.resolve_inside_root().await {
+        Ok(Some(_)) => Outcome::Found,
+        Ok(None) => Outcome::Failed(anyhow::anyhow!(
+            "the debug output does not contain {relative}"
+        )),
+        Err(e) => Outcome::Failed(e),
+    }
+}
+
+/// Checks that the binary cache at `url` can serve debuginfo, prints a summary and fails if
+/// problems were found.
+///
+/// `build_ids` are checked by downloading and unpacking their debug output. When none are
+/// specified and the binary cache is a local directory, up to `sample` of the build ids of its
+/// `debuginfo` directory are checked instead.
+///
+/// Downloads go to a temporary directory inside the cache directory specified in `args`.
+pub async fn run_check_cache(
+    args: &Options,
+    url: &Url,
+    build_ids: &[BuildId],
+    sample: usize,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        matches!(url.scheme(), "file" | "http" | "https" | "ipfs" | "ipns"),
+        "{url} is not a binary cache"
+    );
+    // nars must really be downloaded and unpacked: no shared cache tiers nor post fetch command
+    let settings = Settings {
+        cache: CacheSettings::default(),
+        ..Settings::from_options(args)
+    };
+    tokio::fs::create_dir_all(&args.cache_dir)
+        .await
+        .with_context(|| format!("creating cache dir {:?}", args.cache_dir))?;
+    // a fresh directory, so that nars are actually downloaded and unpacked
+    let scratch = tempfile::Builder::new()
+        .prefix("check-cache")
+        .tempdir_in(&args.cache_dir)
+        .with_context(|| format!("creating a temporary directory in {:?}", args.cache_dir))?;
+    let substituter = substituter_from_url(
+        url,
+        scratch.path().to_owned(),
+        args.expiration,
+        false,
+        args.user_agent_suffix.as_deref(),
+        false,
+        &args.ipfs_gateway,
+        &settings,
+    )
+    .await?;
+    let mut problems = 0;
+
+    match substituter.check().await {
+        Ok(()) => println!("reachable: ok"),
+        Err(e) => {
+            println!("reachable: error: {e:#}");
+            anyhow::bail!("{url} is unreachable");
+        }
+    }
+
+    let mut to_check: Vec<BuildId> = build_ids.to_vec();
+    if to_check.is_empty() {
+        anyhow::ensure!(
+            url.scheme() == "file",
+            "the build ids of {url} cannot be listed, specify some with --build-id"
+        );
+        let root = file_url_to_path(url)?;
+        let debuginfo_dir = root.join("debuginfo");
+        if !debuginfo_dir.is_dir() {
+            println!(
+                "debuginfo/: missing, was the binary cache created with ?index-debug-info=true?"
+            );
+            anyhow::bail!("{url} has no debuginfo index");
+        }
+        let redirects = list_debuginfo_redirects(&debuginfo_dir)?;
+        println!("debuginfo/: {} build ids", redirects.len());
+        for (build_id, path) in &redirects {
+            if let Err(e) = check_redirect(path).await {
+                println!("{build_id}: invalid redirect: {e:#}");
+                problems += 1;
+            }
+        }
+        to_check = redirects
+            .into_iter()
+            .map(|(build_id, _)| build_id)
+            .take(sample)
+            .collect();
+    }
+
+    for build_id in &to_check {
+        let outcome = check_build_id(&substituter, &settings.debug_path_template, build_id).await;
+        if !matches!(outcome, Outcome::Found) {
+            problems += 1;
+        }
+        println!("{build_id}: debuginfo {outcome}");
+    }
+    anyhow::ensure!(problems == 0, "found {problems} problems in {url}");
+    println!("{url} looks fine");
+    Ok(())
+}
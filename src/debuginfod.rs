@@ -1,22 +1,30 @@
 //! Logic to find debuginfo in a substituter
 use std::{
+    fmt::Debug,
     future::Future,
+    ops::Range,
     path::{Path, PathBuf},
     sync::Arc,
     time::Duration,
 };
 
 use anyhow::Context;
+use tokio::io::{AsyncReadExt, AsyncWrite};
 use tracing::Level;
 
 use crate::{
-    archive_cache::{ArchiveUnpacker, SourceArchive},
-    build_id::BuildId,
-    cache::FetcherCache,
-    source_selection::{get_file_for_source, SourceMatch},
+    archive_cache::{is_archive_name, ArchiveUnpacker, SourceArchive},
+    build_id::{BuildId, PathTemplate},
+    cache::{EntryInfo, FetcherCache},
+    derivation::{self, MAX_DERIVATION_SIZE},
+    elf::{DebugAltLink, DebugLink, Elf},
+    settings::Settings,
+    source_selection::{get_file_for_source_with_limit, source_tree, SourceMatch},
     store_path::StorePath,
     substituter::BoxedSubstituter,
-    vfs::{ResolvedPath, ResolvedPathKind, RestrictedPath},
+    tar::TarWriter,
+    utils::{Compression, Presence},
+    vfs::{AsFile, LinkedDirectory, ResolvedPath, ResolvedPathKind, RestrictedPath},
 };
 
 /// The logic behind a debuginfod server: maps build ids to debug symbols, executables, and source
@@ -26,7 +34,227 @@ use crate::{
 #[derive(Clone)]
 pub struct Debuginfod {
     substituter: Arc<BoxedSubstituter>,
-    source_unpacker: Arc<FetcherCache<SourceArchive, ArchiveUnpacker>>,
+    /// None when sources are not served at all, see [Debuginfod::without_sources]
+    source_unpacker: Option<Arc<FetcherCache<SourceArchive, ArchiveUnpacker>>>,
+    /// whether symlinks to other store paths inside source directories are followed when looking
+    /// for a source file
+    follow_source_symlinks: bool,
+    /// whether the build id of debuginfo is checked before serving it
+    verify_build_id: bool,
+    /// whether the debuginfo is served when the executable cannot be found
+    executable_fallback_to_debuginfo: bool,
+    /// whether the `.gnu_debuglink` of the executable is followed when the debug output does not
+    /// contain the debuginfo
+    follow_debuglink: bool,
+    /// supplementary debug files referenced by the `.gnu_debugaltlink` of debuginfo served until
+    /// now, by build id, see [Debuginfod::alt_debuginfo]
+    alt_links: Arc<quick_cache::sync::Cache<BuildId, AltLink>>,
+    /// where debug outputs contain the debuginfo of a build id
+    debug_path_template: PathTemplate,
+    /// see [Settings::max_source_match_candidates]
+    max_source_match_candidates: usize,
+}
+
+/// Where the executable with some build id comes from, see [Debuginfod::metadata]
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct Metadata {
+    /// the executable or library, like `/nix/store/...-gnumake-4.4.1/bin/make`
+    pub executable: Option<PathBuf>,
+    /// the store path containing it, like `/nix/store/...-gnumake-4.4.1`
+    pub store_path: Option<PathBuf>,
+    /// the package name part of the store path, like `gnumake`
+    pub name: Option<String>,
+    /// the version part of the store path, like `4.4.1`
+    pub version: Option<String>,
+    /// the derivation that built the store path
+    pub deriver: Option<PathBuf>,
+    /// the store paths it refers to
+    pub references: Vec<PathBuf>,
+}
+
+/// A source file found by [Debuginfod::located_source]
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    /// the file (or directory)
+    pub path: ResolvedPath,
+    /// when the request designated a file in a store path, this store path, like
+    /// `/nix/store/...-gnumake-4.4.1/include/gnumake.h`.
+    ///
+    /// The content of store paths never changes, so the file served for this request never
+    /// changes either.
+    pub store_path: Option<StorePath>,
+    /// when the source file is only available compressed, like `main.c.gz` for a request of
+    /// `main.c`, how to decompress `path` before serving it
+    pub compression: Option<Compression>,
+}
+
+/// Where to find a supplementary debug file, see [Debuginfod::alt_debuginfo]
+#[derive(Debug, Clone)]
+struct AltLink {
+    /// build id of a debuginfo referencing the supplementary file
+    referrer: BuildId,
+    /// the path in its `.gnu_debugaltlink`
+    path: PathBuf,
+}
+
+/// How many supplementary debug files are remembered
+const MAX_ALT_LINKS: usize = 10_000;
+
+/// Symlinks to store paths found in the store paths that symlinks of a source directory point to
+/// are followed up to this depth
+const MAX_SOURCE_SYMLINK_DEPTH: usize = 4;
+
+/// At most this many symlinks to store paths are followed per source directory
+const MAX_SOURCE_SYMLINKS: usize = 64;
+
+/// The cache entries related to a build id, see [`Debuginfod::inspect`]
+#[derive(Debug, serde::Serialize)]
+pub struct BuildIdCacheInfo {
+    /// the debug output, containing the debuginfo
+    pub debuginfo: Option<EntryInfo>,
+    /// the store path containing the executable
+    pub executable: Option<EntryInfo>,
+    /// the store path containing the source
+    pub source: Option<EntryInfo>,
+    /// the unpacked source archive
+    pub unpacked_source: Option<EntryInfo>,
+}
+
+/// Returns the range of bytes occupied by the section `name` in this ELF file.
+async fn section_range(file: &ResolvedPath, name: &str) -> anyhow::Result<Option<Range<u64>>> {
+    let std_file = file
+        .open()
+        .await
+        .with_context(|| format!("opening {file:?}"))?
+        .into_std()
+        .await;
+    let name = name.to_owned();
+    tokio::task::spawn_blocking(move || {
+        Elf::parse(std::io::BufReader::new(std_file))?.section_range(&name)
+    })
+    .await?
+    .with_context(|| format!("looking for sections in {file:?}"))
+}
+
+/// Opens this ELF file and returns its build id, if any.
+pub async fn elf_build_id<F: AsFile + Debug + Sync>(file: &F) -> anyhow::Result<Option<BuildId>> {
+    let std_file = file
+        .open()
+        .await
+        .with_context(|| format!("opening {file:?}"))?
+        .into_std()
+        .await;
+    tokio::task::spawn_blocking(move || Elf::parse(std::io::BufReader::new(std_file))?.build_id())
+        .await?
+        .with_context(|| format!("reading build id of {file:?}"))
+}
+
+/// Returns the separate debug file referenced by the `.gnu_debuglink` section of this ELF file,
+/// if any.
+async fn elf_debuglink<F: AsFile + Debug + Sync>(file: &F) -> anyhow::Result<Option<DebugLink>> {
+    let std_file = file
+        .open()
+        .await
+        .with_context(|| format!("opening {file:?}"))?
+        .into_std()
+        .await;
+    tokio::task::spawn_blocking(move || Elf::parse(std::io::BufReader::new(std_file))?.debuglink())
+        .await?
+        .with_context(|| format!("reading .gnu_debuglink of {file:?}"))
+}
+
+/// Computes the CRC32 of the content of `file`, as stored in `.gnu_debuglink` sections.
+async fn file_crc32<F: AsFile + Debug + Sync>(file: &F) -> anyhow::Result<u32> {
+    let mut reader = file
+        .open()
+        .await
+        .with_context(|| format!("opening {file:?}"))?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = reader
+            .read(&mut buffer)
+            .await
+            .with_context(|| format!("reading {file:?}"))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize())
+}
+
+/// Whether a file named `name` below `lib/debug` may be the debuginfo of some build id.
+///
+/// Besides `.debug` files, debug outputs of kernels contain the debuginfo of modules under their
+/// original name, like `lib/debug/lib/modules/<version>/kernel/fs/foo.ko`.
+fn may_be_debuginfo(name: &std::ffi::OsStr) -> bool {
+    matches!(
+        Path::new(name)
+            .extension()
+            .and_then(|extension| extension.to_str()),
+        Some("debug" | "ko")
+    )
+}
+
+/// Looks for the debuginfo of `build_id` in a debug output which does not follow the
+/// `lib/debug/.build-id` layout, like `lib/debug/<name>.debug` or kernel modules, by reading the
+/// build id of the candidate files below `lib/debug`, see [may_be_debuginfo].
+async fn unsharded_debuginfo(
+    debug_output: RestrictedPath,
+    build_id: &BuildId,
+) -> anyhow::Result<Option<ResolvedPath>> {
+    let Some(lib_debug) = debug_output.join("lib/debug").resolve_inside_root().await? else {
+        return Ok(None);
+    };
+    let mut to_visit = vec![lib_debug];
+    while let Some(directory) = to_visit.pop() {
+        for name in directory.list_directory().await? {
+            // already looked up by build id
+            if name == ".build-id" {
+                continue;
+            }
+            let Some(child) = directory
+                .clone()
+                .join(&name)
+                .await?
+                .resolve_inside_root()
+                .await?
+            else {
+                continue;
+            };
+            match child.kind().await? {
+                ResolvedPathKind::Directory => to_visit.push(child),
+                ResolvedPathKind::File => {
+                    if !may_be_debuginfo(&name) {
+                        continue;
+                    }
+                    match elf_build_id(&child).await {
+                        Ok(Some(ref found)) if found == build_id => return Ok(Some(child)),
+                        Ok(_) => (),
+                        Err(e) => tracing::debug!("skipping {child:?}: {e:#}"),
+                    }
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Opens this ELF file and returns the supplementary debug file of its `.gnu_debugaltlink`
+/// section, if any.
+async fn debugaltlink(file: &ResolvedPath) -> anyhow::Result<Option<DebugAltLink>> {
+    let std_file = file
+        .open()
+        .await
+        .with_context(|| format!("opening {file:?}"))?
+        .into_std()
+        .await;
+    tokio::task::spawn_blocking(move || {
+        Elf::parse(std::io::BufReader::new(std_file))?.debugaltlink()
+    })
+    .await?
+    .with_context(|| format!("reading .gnu_debugaltlink of {file:?}"))
 }
 
 /// Creates this directory if it does not exist yet.
@@ -43,37 +271,145 @@ impl Debuginfod {
     /// cached files into `cache_path`.
     ///
     /// `duration` is an indication of how long a cached but unread path must be kept
+    ///
+    /// `settings` configure the cache of unpacked sources and where debuginfo and source files
+    /// are looked for.
     pub async fn new(
         cache_path: PathBuf,
         substituter: BoxedSubstituter,
         expiration: Duration,
+        settings: &Settings,
     ) -> anyhow::Result<Self> {
         ensure_dir_exists(&cache_path).await?;
         let source_path = cache_path.join("sources");
         ensure_dir_exists(&source_path).await?;
-        let substituter = Arc::new(substituter);
-        Ok(Self {
+        // unpacking archives is purely local, so it is allowed even in offline mode
+        let source_unpacker = FetcherCache::new(
+            source_path,
+            ArchiveUnpacker::new(settings.max_source_unpack_size),
+            expiration,
+            false,
+            &settings.cache,
+        )
+        .await?;
+        Ok(Self::from_parts(
             substituter,
-            source_unpacker: Arc::new(
-                FetcherCache::new(source_path, ArchiveUnpacker, expiration).await?,
-            ),
-        })
+            Some(Arc::new(source_unpacker)),
+            settings,
+        ))
+    }
+
+    /// Same as [Debuginfod::new], but source files are never served: archives are never unpacked
+    /// and no cache of unpacked sources is created.
+    pub fn without_sources(substituter: BoxedSubstituter, settings: &Settings) -> Self {
+        Self::from_parts(substituter, None, settings)
+    }
+
+    fn from_parts(
+        substituter: BoxedSubstituter,
+        source_unpacker: Option<Arc<FetcherCache<SourceArchive, ArchiveUnpacker>>>,
+        settings: &Settings,
+    ) -> Self {
+        Self {
+            substituter: Arc::new(substituter),
+            source_unpacker,
+            follow_source_symlinks: false,
+            verify_build_id: false,
+            executable_fallback_to_debuginfo: false,
+            follow_debuglink: false,
+            alt_links: Arc::new(quick_cache::sync::Cache::new(MAX_ALT_LINKS)),
+            debug_path_template: settings.debug_path_template.clone(),
+            max_source_match_candidates: settings.max_source_match_candidates,
+        }
+    }
+
+    /// Whether source files are served, see [Debuginfod::without_sources]
+    pub fn serves_sources(&self) -> bool {
+        self.source_unpacker.is_some()
+    }
+
+    /// Returns a [`Debuginfod`] which uses `substituter` instead, and shares the cache of unpacked
+    /// sources with `self`.
+    ///
+    /// Cleanup tasks of `substituter` are not spawned.
+    pub fn with_substituter(&self, substituter: BoxedSubstituter) -> Self {
+        Self {
+            substituter: Arc::new(substituter),
+            ..self.clone()
+        }
+    }
+
+    /// When looking for a source file, also look in the store paths that symlinks inside source
+    /// directories point to, fetching them as needed.
+    ///
+    /// This is useful for source trees aggregated from several store paths.
+    pub fn with_source_symlinks_followed(mut self, follow: bool) -> Self {
+        self.follow_source_symlinks = follow;
+        self
+    }
+
+    /// Before serving a debuginfo, check that the build id in its `.note.gnu.build-id` section is
+    /// the one requested, and consider it missing otherwise.
+    ///
+    /// Debuginfo is looked up at a path derived from the build id in the debug output, so this
+    /// protects against wrongly indexed binary caches, at the cost of parsing the file.
+    pub fn with_build_id_verified(mut self, verify: bool) -> Self {
+        self.verify_build_id = verify;
+        self
+    }
+
+    /// When the executable of a build id cannot be found, serve its debuginfo instead.
+    ///
+    /// This happens when the store path of the executable was garbage collected from the local
+    /// store, and no other substituter has it. The debuginfo lacks the sections needed at
+    /// runtime, but is enough for clients which only want symbols.
+    pub fn with_executable_fallback_to_debuginfo(mut self, fallback: bool) -> Self {
+        self.executable_fallback_to_debuginfo = fallback;
+        self
+    }
+
+    /// When the debug output of a build id contains its executable but not its debuginfo, look
+    /// for the debug file named in the `.gnu_debuglink` section of the executable, see
+    /// `Debuginfod::debuglink_debuginfo`.
+    pub fn with_debuglink_followed(mut self, follow: bool) -> Self {
+        self.follow_debuglink = follow;
+        self
     }
 
     /// Spawns tokio tasks to clear downloaded files from the cache when they have not been queried
     /// for too long.
     pub fn spawn_cleanup_task(&self) {
         self.substituter.spawn_cleanup_task();
-        self.source_unpacker.clone().spawn_cleanup_task();
+        if let Some(source_unpacker) = &self.source_unpacker {
+            source_unpacker.clone().spawn_cleanup_task();
+        }
+    }
+
+    /// Checks once that all substituters are reachable.
+    pub async fn check_substituters(&self) -> anyhow::Result<()> {
+        self.substituter.check().await
+    }
+
+    /// Returns the build ids starting with `prefix` that substituters can list, see
+    /// [crate::substituter::Substituter::build_ids_with_prefix].
+    pub async fn build_ids_with_prefix(&self, prefix: &str) -> anyhow::Result<Vec<BuildId>> {
+        self.substituter.build_ids_with_prefix(prefix).await
+    }
+
+    /// Returns the narinfo of the store path with this hash, as the first substituter which has
+    /// it serves it, see [crate::substituter::Substituter::fetch_narinfo_raw].
+    pub async fn narinfo(&self, hash: &str) -> anyhow::Result<Option<String>> {
+        self.substituter.fetch_narinfo_raw(hash).await
     }
 
     /// Reduce cache disk space usage as much as possible
     #[tracing::instrument(level=Level::DEBUG, skip_all)]
     pub async fn shrink_disk_cache(&self) -> anyhow::Result<()> {
-        match (
-            self.substituter.shrink_disk_cache().await,
-            self.source_unpacker.shrink_cache().await,
-        ) {
+        let sources = match &self.source_unpacker {
+            Some(source_unpacker) => source_unpacker.shrink_cache().await,
+            None => Ok(()),
+        };
+        match (self.substituter.shrink_disk_cache().await, sources) {
             (Ok(()), Ok(())) => Ok(()),
             (Err(e), _) => Err(e),
             (_, Err(e)) => Err(e),
@@ -120,12 +456,21 @@ impl Debuginfod {
     }
 
     /// Returns the path to ELF object with debug symbols for this build id.
+    ///
+    /// If it references a supplementary debug file in its `.gnu_debugaltlink` section, this file
+    /// is fetched in the background, so that it is ready when the client requests it by its own
+    /// build id.
     pub async fn debuginfo<'key, 'debuginfod: 'key>(
         &'debuginfod self,
         build_id: &'key BuildId,
     ) -> anyhow::Result<Option<ResolvedPath>> {
-        self.retry_on_full_disk(Self::debuginfo_noretry, build_id)
-            .await
+        let debuginfo = self
+            .retry_on_full_disk(Self::debuginfo_noretry, build_id)
+            .await?;
+        if let Some(ref file) = debuginfo {
+            self.spawn_prefetch_debugaltlink(build_id, file);
+        }
+        Ok(debuginfo)
     }
     /// Returns the path to ELF object with debug symbols for this build id.
     async fn debuginfo_noretry<'key, 'debuginfod: 'key>(
@@ -134,17 +479,245 @@ impl Debuginfod {
     ) -> anyhow::Result<Option<ResolvedPath>> {
         match self.substituter.build_id_to_debug_output(build_id).await {
             Ok(Some(nar)) => {
-                let debugfile = nar.join(build_id.in_debug_output("debug"));
-                debugfile.resolve_inside_root().await
+                let debugfile = nar.clone().join(self.debug_path_template.expand(build_id));
+                match debugfile.resolve_inside_root().await? {
+                    Some(file) if self.verify_build_id => self.check_build_id(build_id, file).await,
+                    Some(file) => Ok(Some(file)),
+                    None => match unsharded_debuginfo(nar.clone(), build_id).await? {
+                        Some(file) => Ok(Some(file)),
+                        None if self.follow_debuglink => {
+                            self.debuglink_debuginfo(nar, build_id).await
+                        }
+                        None => Ok(None),
+                    },
+                }
             }
-            Ok(None) => Ok(None),
+            Ok(None) => self.alt_debuginfo(build_id).await,
             Err(e) => Err(e),
         }
     }
 
+    /// Looks for the debuginfo of `build_id` through the `.gnu_debuglink` section of its
+    /// executable, for packages whose debug output only links to the executable.
+    ///
+    /// Like gdb, the debug file is looked for next to the executable, in a `.debug` subdirectory
+    /// next to it, and in `lib/debug` of the debug output, below the directory of the executable
+    /// in its store path or directly. The first one whose CRC32 matches the one of the section is
+    /// returned.
+    async fn debuglink_debuginfo(
+        &self,
+        debug_output: RestrictedPath,
+        build_id: &BuildId,
+    ) -> anyhow::Result<Option<ResolvedPath>> {
+        let symlink = debug_output
+            .clone()
+            .join(build_id.in_debug_output("executable"));
+        let Some(store_path) = symlink.clone().store_path_target().await? else {
+            return Ok(None);
+        };
+        let Some(executable) = self.resolve_symlinks(symlink).await? else {
+            return Ok(None);
+        };
+        let Some(debuglink) = elf_debuglink(&executable).await? else {
+            return Ok(None);
+        };
+        let Some(root) = self
+            .substituter
+            .fetch_store_path(&store_path.root())
+            .await?
+        else {
+            return Ok(None);
+        };
+        let dir = store_path.relative().parent().unwrap_or(Path::new(""));
+        let name = &debuglink.file_name;
+        let lib_debug = Path::new("lib/debug");
+        let candidates = [
+            root.clone().join(dir.join(name)),
+            root.join(dir.join(".debug").join(name)),
+            debug_output.clone().join(lib_debug.join(dir).join(name)),
+            debug_output.join(lib_debug.join(name)),
+        ];
+        for candidate in candidates {
+            let Some(file) = self.resolve_symlinks(candidate).await? else {
+                continue;
+            };
+            if !matches!(file.kind().await?, ResolvedPathKind::File) {
+                continue;
+            }
+            let crc = file_crc32(&file).await?;
+            if crc == debuglink.crc {
+                return Ok(Some(file));
+            }
+            tracing::debug!(
+                "{file:?} has CRC {crc:08x} instead of {:08x} in the .gnu_debuglink of {build_id}",
+                debuglink.crc
+            );
+        }
+        Ok(None)
+    }
+
+    /// Returns `file` if its build id is `build_id`, and None otherwise.
+    async fn check_build_id(
+        &self,
+        build_id: &BuildId,
+        file: ResolvedPath,
+    ) -> anyhow::Result<Option<ResolvedPath>> {
+        let actual = elf_build_id(&file).await?;
+        if actual.as_ref() == Some(build_id) {
+            Ok(Some(file))
+        } else {
+            tracing::error!(
+                "not serving {file:?} as debuginfo of {build_id}: its build id is {}",
+                match actual {
+                    Some(actual) => actual.to_string(),
+                    None => "missing".to_owned(),
+                }
+            );
+            Ok(None)
+        }
+    }
+
+    /// Returns, for each of these build ids in order, whether a substituter has its debug output.
+    ///
+    /// Binary caches only look for the json redirect to the debug output, without fetching it.
+    pub async fn batch_exists(&self, build_ids: &[BuildId]) -> Vec<anyhow::Result<Presence>> {
+        self.substituter.batch_exists(build_ids).await
+    }
+
+    /// Returns where the executable with this build id comes from, according to its debug output
+    /// and the narinfo of its store path.
+    ///
+    /// Returns None if no substituter has the debug output. Fields are missing when the debug
+    /// output does not link to the executable, or no substituter has its narinfo.
+    pub async fn metadata(&self, build_id: &BuildId) -> anyhow::Result<Option<Metadata>> {
+        let Some(debug_output) = self.substituter.build_id_to_debug_output(build_id).await? else {
+            return Ok(None);
+        };
+        let Some(executable) = debug_output
+            .join(build_id.in_debug_output("executable"))
+            .store_path_target()
+            .await?
+        else {
+            return Ok(Some(Metadata::default()));
+        };
+        let store_path = executable.root();
+        let (name, version) = store_path.package_name_and_version();
+        let info = self
+            .substituter
+            .path_info(&store_path)
+            .await?
+            .unwrap_or_default();
+        Ok(Some(Metadata {
+            executable: Some(executable.as_ref().to_owned()),
+            store_path: Some(store_path.as_ref().to_owned()),
+            name: Some(name),
+            version,
+            deriver: info.deriver.map(|deriver| deriver.as_ref().to_owned()),
+            references: info
+                .references
+                .iter()
+                .map(|reference| reference.as_ref().to_owned())
+                .collect(),
+        }))
+    }
+
+    /// Returns the supplementary debug file with this build id, if a debuginfo served earlier
+    /// referenced it in its `.gnu_debugaltlink` section.
+    ///
+    /// Binary caches do not index supplementary files by build id, but they are in the same
+    /// debug output as the debuginfo referencing them, or at an absolute store path.
+    async fn alt_debuginfo(&self, build_id: &BuildId) -> anyhow::Result<Option<ResolvedPath>> {
+        let Some(link) = self.alt_links.get(build_id) else {
+            return Ok(None);
+        };
+        let candidate = if link.path.is_absolute() {
+            let store_path = StorePath::new(&link.path).with_context(|| {
+                format!(
+                    "supplementary debug file {} of {} is not in the store",
+                    link.path.display(),
+                    link.referrer
+                )
+            })?;
+            let Some(root) = self.substituter.fetch_store_path(&store_path).await? else {
+                return Ok(None);
+            };
+            root.join(store_path.relative())
+        } else {
+            let Some(nar) = self
+                .substituter
+                .build_id_to_debug_output(&link.referrer)
+                .await?
+            else {
+                return Ok(None);
+            };
+            let debugfile = self.debug_path_template.expand(&link.referrer);
+            let directory = Path::new(&debugfile).parent().unwrap_or(Path::new(""));
+            nar.join(directory).join(&link.path)
+        };
+        let Some(file) = self.resolve_symlinks(candidate).await? else {
+            return Ok(None);
+        };
+        let actual = elf_build_id(&file).await?;
+        if actual.as_ref() != Some(build_id) {
+            tracing::warn!(
+                "supplementary debug file {} of {} has build id {actual:?} instead of {build_id}",
+                link.path.display(),
+                link.referrer
+            );
+            return Ok(None);
+        }
+        Ok(Some(file))
+    }
+
+    /// Reads the `.gnu_debugaltlink` section of this debuginfo in the background, and if it
+    /// references a supplementary debug file not seen yet, remembers it and fetches it.
+    fn spawn_prefetch_debugaltlink(&self, build_id: &BuildId, debuginfo: &ResolvedPath) {
+        let debuginfod = self.clone();
+        let build_id = build_id.clone();
+        let debuginfo = debuginfo.clone();
+        tokio::spawn(async move {
+            let link = match debugaltlink(&debuginfo).await {
+                Ok(Some(link)) => link,
+                Ok(None) => return,
+                Err(e) => {
+                    tracing::debug!(err=?e, "not prefetching supplementary debug file of {build_id}");
+                    return;
+                }
+            };
+            drop(debuginfo);
+            let alt_id = link.build_id;
+            if alt_id == build_id || debuginfod.alt_links.get(&alt_id).is_some() {
+                return;
+            }
+            debuginfod.alt_links.insert(
+                alt_id.clone(),
+                AltLink {
+                    referrer: build_id.clone(),
+                    path: link.path,
+                },
+            );
+            match debuginfod
+                .retry_on_full_disk(Self::debuginfo_noretry, &alt_id)
+                .await
+            {
+                Ok(Some(_)) => {
+                    tracing::debug!("prefetched supplementary debug file {alt_id} of {build_id}")
+                }
+                Ok(None) => {
+                    tracing::debug!("supplementary debug file {alt_id} of {build_id} not found")
+                }
+                Err(e) => tracing::warn!(
+                    err=?e,
+                    "prefetching supplementary debug file {alt_id} of {build_id}"
+                ),
+            }
+        });
+    }
+
     /// Returns the path to the ELF object with this build id.
     ///
-    /// It is called executable, but it could also be a share object.
+    /// It is called executable, but it could also be a share object. Its original name, like
+    /// `libz.so.1.3.1`, is that of the file in its store path, see `ResolvedPath::store_path`.
     pub async fn executable<'key, 'debuginfod: 'key>(
         &'debuginfod self,
         build_id: &'key BuildId,
@@ -160,13 +733,201 @@ impl Debuginfod {
         &'debuginfod self,
         build_id: &'key BuildId,
     ) -> anyhow::Result<Option<ResolvedPath>> {
-        match self.substituter.build_id_to_debug_output(build_id).await {
-            Ok(Some(nar)) => {
-                let symlink = nar.join(build_id.in_debug_output("executable"));
-                self.resolve_symlinks(symlink).await
+        let nar = match self.substituter.build_id_to_debug_output(build_id).await {
+            Ok(Some(nar)) => nar,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let symlink = nar.join(build_id.in_debug_output("executable"));
+        // the store path that no substituter has, if any
+        let unavailable = std::sync::Mutex::new(None);
+        let executable = symlink
+            .resolve(|store_path| {
+                let unavailable = &unavailable;
+                async move {
+                    let fetched = self.substituter.fetch_store_path(&store_path).await?;
+                    if fetched.is_none() {
+                        *unavailable.lock().unwrap() = Some(store_path);
+                    }
+                    Ok(fetched)
+                }
+            })
+            .await?;
+        if executable.is_some() {
+            return Ok(executable);
+        }
+        match unavailable.into_inner().unwrap() {
+            Some(store_path) => tracing::info!(
+                "the executable of {build_id} is in {}, which no substituter has: it was probably garbage collected from the local store",
+                store_path.root().as_ref().display()
+            ),
+            None => tracing::debug!("the debug output of {build_id} does not lead to an executable"),
+        }
+        if !self.executable_fallback_to_debuginfo {
+            return Ok(None);
+        }
+        let debuginfo = self.debuginfo_noretry(build_id).await?;
+        if debuginfo.is_some() {
+            tracing::debug!("serving the debuginfo of {build_id} instead of its executable");
+        }
+        Ok(debuginfo)
+    }
+
+    /// Returns the ELF file containing the section `name` for this build id, and the range of
+    /// bytes it occupies in the file.
+    ///
+    /// The debuginfo is tried first, then the executable, because the debuginfo does not contain
+    /// the sections needed at runtime, like `.text`.
+    pub async fn section(
+        &self,
+        build_id: &BuildId,
+        name: &str,
+    ) -> anyhow::Result<Option<(ResolvedPath, Range<u64>)>> {
+        if let Some(debuginfo) = self.debuginfo(build_id).await? {
+            if let Some(range) = section_range(&debuginfo, name).await? {
+                return Ok(Some((debuginfo, range)));
+            }
+        }
+        if let Some(executable) = self.executable(build_id).await? {
+            if let Some(range) = section_range(&executable, name).await? {
+                return Ok(Some((executable, range)));
             }
-            Ok(None) => Ok(None),
-            Err(e) => Err(e),
+        }
+        Ok(None)
+    }
+
+    /// Returns the build id of the ELF file at this store path, fetching the store path as needed.
+    ///
+    /// When the store path designates a directory, the ELF file is looked for in its `bin`
+    /// subdirectory: the program named like the package if any, otherwise the only program there.
+    ///
+    /// Returns None if the store path cannot be found, or if the file has no build id.
+    pub async fn build_id_of_store_path<'key, 'debuginfod: 'key>(
+        &'debuginfod self,
+        store_path: &'key StorePath,
+    ) -> anyhow::Result<Option<BuildId>> {
+        self.retry_on_full_disk(Self::build_id_of_store_path_noretry, store_path)
+            .await
+    }
+
+    /// Returns the build id of the ELF file at this store path, fetching the store path as needed.
+    async fn build_id_of_store_path_noretry<'key, 'debuginfod: 'key>(
+        &'debuginfod self,
+        store_path: &'key StorePath,
+    ) -> anyhow::Result<Option<BuildId>> {
+        let Some(file) = self.elf_of_store_path_noretry(store_path).await? else {
+            return Ok(None);
+        };
+        elf_build_id(&file)
+            .await
+            .with_context(|| format!("reading build id of {}", store_path.as_ref().display()))
+    }
+
+    /// Returns the ELF file at this store path, fetching the store path as needed, see
+    /// [Debuginfod::build_id_of_store_path].
+    ///
+    /// Returns None if the store path cannot be found.
+    async fn elf_of_store_path_noretry(
+        &self,
+        store_path: &StorePath,
+    ) -> anyhow::Result<Option<ResolvedPath>> {
+        let Some(cached_root) = self.substituter.fetch_store_path(store_path).await? else {
+            return Ok(None);
+        };
+        let Some(file) = self
+            .resolve_symlinks(cached_root.join(store_path.relative()))
+            .await?
+        else {
+            return Ok(None);
+        };
+        if file.kind().await? == ResolvedPathKind::File {
+            return Ok(Some(file));
+        }
+        let Some(bin) = self.resolve_symlinks(file.join("bin").await?).await? else {
+            anyhow::bail!(
+                "{} is a directory without bin/, designate a file inside it",
+                store_path.as_ref().display()
+            );
+        };
+        let programs = bin.list_directory().await?;
+        let (package_name, _) = store_path.package_name_and_version();
+        let program = match programs.iter().find(|p| **p == *package_name) {
+            Some(program) => program,
+            None => match &programs[..] {
+                [program] => program,
+                _ => anyhow::bail!(
+                    "{} has {} programs in bin/, designate one of them",
+                    store_path.as_ref().display(),
+                    programs.len()
+                ),
+            },
+        };
+        self.resolve_symlinks(bin.join(program).await?).await
+    }
+
+    /// Returns the ELF file at this store path and the range of bytes the section `name` occupies
+    /// in it, fetching the store path as needed.
+    ///
+    /// When the store path designates a directory, the ELF file is looked for in its `bin`
+    /// subdirectory, like for [Debuginfod::build_id_of_store_path].
+    ///
+    /// Returns None if the store path cannot be found or if the file does not have this section.
+    pub async fn store_path_section(
+        &self,
+        store_path: &StorePath,
+        name: &str,
+    ) -> anyhow::Result<Option<(ResolvedPath, Range<u64>)>> {
+        let Some(file) = self
+            .retry_on_full_disk(Self::elf_of_store_path_noretry, store_path)
+            .await?
+        else {
+            return Ok(None);
+        };
+        Ok(section_range(&file, name).await?.map(|range| (file, range)))
+    }
+
+    /// Returns what is cached for this build id, without fetching anything.
+    ///
+    /// Only the entry of a single source archive is reported, not those of a directory of archives.
+    pub async fn inspect(&self, build_id: &BuildId) -> anyhow::Result<BuildIdCacheInfo> {
+        let debuginfo = self.substituter.inspect_debug_output(build_id).await?;
+        let mut result = BuildIdCacheInfo {
+            debuginfo: None,
+            executable: None,
+            source: None,
+            unpacked_source: match &self.source_unpacker {
+                Some(source_unpacker) => source_unpacker.inspect(build_id).await?,
+                None => None,
+            },
+        };
+        if let Some(debuginfo) = &debuginfo {
+            result.executable = self
+                .inspect_symlink_target(
+                    &debuginfo.path.join(build_id.in_debug_output("executable")),
+                )
+                .await?;
+            result.source = self
+                .inspect_symlink_target(&debuginfo.path.join(build_id.in_debug_output("source")))
+                .await?;
+        }
+        result.debuginfo = debuginfo;
+        Ok(result)
+    }
+
+    /// Returns what is cached for the store path this symlink points to.
+    async fn inspect_symlink_target(&self, symlink: &Path) -> anyhow::Result<Option<EntryInfo>> {
+        let target = match tokio::fs::read_link(symlink).await {
+            Ok(target) => target,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("readlink({})", symlink.display())),
+        };
+        match StorePath::new(&target) {
+            Ok(store_path) => {
+                self.substituter
+                    .inspect_store_path(&store_path.root())
+                    .await
+            }
+            Err(_) => Ok(None),
         }
     }
 
@@ -175,15 +936,405 @@ impl Debuginfod {
             .await
     }
 
+    /// Unpacks this source archive into the cache, or returns the cached unpacked directory.
+    async fn unpack(&self, archive: SourceArchive) -> anyhow::Result<Option<ResolvedPath>> {
+        let Some(source_unpacker) = &self.source_unpacker else {
+            return Ok(None);
+        };
+        match source_unpacker.get(archive).await? {
+            None => Ok(None),
+            Some(x) => x.resolve_inside_root().await,
+        }
+    }
+
+    /// Returns the directories containing the unpacked sources of the executable with this build
+    /// id, and the directories containing the files that were patched during the build, highest
+    /// priority first.
+    ///
+    /// There are several source directories when the source is a directory containing archives,
+    /// for example the main source and vendored dependencies: the directory itself comes first,
+    /// then each unpacked archive.
+    ///
+    /// Overlays are `sourceoverlay` then `sourceoverlay1`, `sourceoverlay2`, etc. for packages
+    /// patched in several stages; later stages take priority.
+    ///
+    /// Source archives are unpacked into the cache as needed.
+    async fn linked_source_dirs(
+        &self,
+        build_id: &BuildId,
+    ) -> anyhow::Result<Option<(Vec<ResolvedPath>, Vec<ResolvedPath>)>> {
+        let debug_output = match self.substituter.build_id_to_debug_output(build_id).await {
+            Ok(Some(nar)) => nar,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let source_symlink = debug_output
+            .clone()
+            .join(build_id.in_debug_output("source"));
+        let Some(source) = self.resolve_symlinks(source_symlink).await? else {
+            return Ok(None);
+        };
+        let source_dirs = if source.kind().await? == ResolvedPathKind::Directory {
+            let mut source_dirs = vec![source.clone()];
+            for name in source.list_directory().await? {
+                let Some(name) = name.to_str().filter(|name| is_archive_name(name)) else {
+                    continue;
+                };
+                let Some(file) = self
+                    .resolve_symlinks(source.clone().join(name).await?)
+                    .await?
+                else {
+                    continue;
+                };
+                if file.kind().await? != ResolvedPathKind::File {
+                    continue;
+                }
+                let archive = SourceArchive::in_directory(file, name.to_owned(), build_id.clone());
+                if let Some(unpacked) = self.unpack(archive).await? {
+                    source_dirs.push(unpacked);
+                }
+            }
+            source_dirs
+        } else {
+            let file_name = source
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(str::to_owned);
+            let archive = SourceArchive::new(source, file_name, build_id.clone());
+            match self.unpack(archive).await? {
+                None => return Ok(None),
+                Some(unpacked) => vec![unpacked],
+            }
+        };
+        let overlay_symlink = debug_output
+            .clone()
+            .join(build_id.in_debug_output("sourceoverlay"));
+        let mut overlay_dirs = vec![self
+            .resolve_symlinks(overlay_symlink.clone())
+            .await?
+            .unwrap_or_else(|| {
+                // FIXME: temporary, should error
+                tracing::warn!("{overlay_symlink:?} is missing");
+                source_dirs[0].clone()
+            })];
+        for i in 1.. {
+            let overlay_symlink = debug_output
+                .clone()
+                .join(build_id.in_debug_output(&format!("sourceoverlay{i}")));
+            match self.resolve_symlinks(overlay_symlink).await? {
+                Some(overlay_dir) => overlay_dirs.push(overlay_dir),
+                None => break,
+            }
+        }
+        overlay_dirs.reverse();
+        Ok(Some((source_dirs, overlay_dirs)))
+    }
+
+    /// Returns the directories containing the sources declared in the `src` and `srcs` attributes
+    /// of the derivation of the executable with this build id.
+    ///
+    /// This is a fallback for debug outputs which do not link to their source: it only works when
+    /// a substituter knows the deriver of the executable and has the `.drv` file itself, for
+    /// example the local store.
+    async fn deriver_source_dirs(
+        &self,
+        build_id: &BuildId,
+    ) -> anyhow::Result<Option<Vec<ResolvedPath>>> {
+        let Some(debug_output) = self.substituter.build_id_to_debug_output(build_id).await? else {
+            return Ok(None);
+        };
+        let Some(executable) = debug_output
+            .join(build_id.in_debug_output("executable"))
+            .store_path_target()
+            .await?
+        else {
+            return Ok(None);
+        };
+        let deriver = self
+            .substituter
+            .path_info(&executable)
+            .await?
+            .and_then(|info| info.deriver);
+        let Some(deriver) = deriver else {
+            tracing::debug!("deriver of {executable:?} is unknown");
+            return Ok(None);
+        };
+        let Some(drv) = self.substituter.fetch_store_path(&deriver).await? else {
+            tracing::debug!("derivation {deriver:?} is not available");
+            return Ok(None);
+        };
+        let Some(drv) = drv.resolve_inside_root().await? else {
+            return Ok(None);
+        };
+        let mut content = String::new();
+        drv.open()
+            .await
+            .with_context(|| format!("opening {deriver:?}"))?
+            .take(MAX_DERIVATION_SIZE + 1)
+            .read_to_string(&mut content)
+            .await
+            .with_context(|| format!("reading {deriver:?}"))?;
+        anyhow::ensure!(
+            content.len() as u64 <= MAX_DERIVATION_SIZE,
+            "derivation {deriver:?} is too large"
+        );
+        let sources = derivation::source_paths(&content)
+            .with_context(|| format!("parsing derivation {deriver:?}"))?;
+        let mut source_dirs = Vec::new();
+        for source in sources {
+            let Some(root) = self.substituter.fetch_store_path(&source).await? else {
+                tracing::debug!("source {source:?} of {deriver:?} is not available");
+                continue;
+            };
+            let Some(resolved) = self
+                .resolve_symlinks(root.with_store_path(source.root()))
+                .await?
+            else {
+                continue;
+            };
+            if resolved.kind().await? == ResolvedPathKind::Directory {
+                source_dirs.push(resolved);
+                continue;
+            }
+            let Some(name) = source.name().to_str().filter(|name| is_archive_name(name)) else {
+                continue;
+            };
+            let archive = SourceArchive::in_directory(resolved, name.to_owned(), build_id.clone());
+            if let Some(unpacked) = self.unpack(archive).await? {
+                source_dirs.push(unpacked);
+            }
+        }
+        Ok((!source_dirs.is_empty()).then_some(source_dirs))
+    }
+
+    /// Returns the source directories and overlay directories of the executable with this build
+    /// id, see [`Self::linked_source_dirs`].
+    ///
+    /// When the debug output does not lead to the source, falls back to the sources declared in
+    /// the derivation of the executable, without overlays.
+    async fn source_dirs(
+        &self,
+        build_id: &BuildId,
+    ) -> anyhow::Result<Option<(Vec<ResolvedPath>, Vec<ResolvedPath>)>> {
+        if let Some(dirs) = self.linked_source_dirs(build_id).await? {
+            return Ok(Some(dirs));
+        }
+        Ok(self
+            .deriver_source_dirs(build_id)
+            .await?
+            .map(|source_dirs| (source_dirs, Vec::new())))
+    }
+
+    /// Returns this directory together with the store paths that symlinks inside it point to, if
+    /// [`Self::with_source_symlinks_followed`] was enabled.
+    ///
+    /// Symlinks are fetched through the substituter, and symlinks inside their targets are
+    /// followed in turn, up to [MAX_SOURCE_SYMLINK_DEPTH] and [MAX_SOURCE_SYMLINKS].
+    async fn follow_store_symlinks(&self, dir: ResolvedPath) -> anyhow::Result<LinkedDirectory> {
+        let mut result = LinkedDirectory::new(dir.clone());
+        if !self.follow_source_symlinks {
+            return Ok(result);
+        }
+        let mut todo = vec![(PathBuf::new(), dir, 0)];
+        while let Some((prefix, dir, depth)) = todo.pop() {
+            if dir.kind().await? != ResolvedPathKind::Directory {
+                continue;
+            }
+            for relative in dir.store_symlinks().await? {
+                if result.link_count() >= MAX_SOURCE_SYMLINKS {
+                    tracing::warn!(
+                        "not following more than {MAX_SOURCE_SYMLINKS} symlinks to store paths in {result:?}"
+                    );
+                    return Ok(result);
+                }
+                let Some(target) = self
+                    .resolve_symlinks(dir.clone().join(&relative).await?)
+                    .await?
+                else {
+                    tracing::debug!("{relative:?} in {dir:?} points to a missing store path");
+                    continue;
+                };
+                let location = prefix.join(relative);
+                if depth + 1 < MAX_SOURCE_SYMLINK_DEPTH {
+                    todo.push((location.clone(), target.clone(), depth + 1));
+                }
+                result.add_link(location, target);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Fetches and unpacks the sources of the executable with this build id into the cache,
+    /// without looking for a specific file.
+    pub async fn prefetch_source<'key, 'debuginfod: 'key>(
+        &'debuginfod self,
+        build_id: &'key BuildId,
+    ) -> anyhow::Result<Presence> {
+        self.retry_on_full_disk(Self::prefetch_source_noretry, build_id)
+            .await
+    }
+
+    /// Fetches and unpacks the sources of the executable with this build id into the cache,
+    /// without looking for a specific file.
+    async fn prefetch_source_noretry<'key, 'debuginfod: 'key>(
+        &'debuginfod self,
+        build_id: &'key BuildId,
+    ) -> anyhow::Result<Presence> {
+        if !self.serves_sources() {
+            return Ok(Presence::NotFound);
+        }
+        Ok(match self.source_dirs(build_id).await? {
+            Some(_) => Presence::Found,
+            None => Presence::NotFound,
+        })
+    }
+
+    /// Returns all the files of the sources of the executable with this build id: their path in
+    /// the source tree, and their location, see `source_selection::source_tree`.
+    ///
+    /// Source archives are unpacked into the cache as needed, but files are only resolved by
+    /// [Self::write_source_tar].
+    pub async fn source_tree<'key, 'debuginfod: 'key>(
+        &'debuginfod self,
+        build_id: &'key BuildId,
+    ) -> anyhow::Result<Option<Vec<(PathBuf, RestrictedPath)>>> {
+        self.retry_on_full_disk(Self::source_tree_noretry, build_id)
+            .await
+    }
+
+    /// Returns all the files of the sources of the executable with this build id.
+    async fn source_tree_noretry<'key, 'debuginfod: 'key>(
+        &'debuginfod self,
+        build_id: &'key BuildId,
+    ) -> anyhow::Result<Option<Vec<(PathBuf, RestrictedPath)>>> {
+        if !self.serves_sources() {
+            return Ok(None);
+        }
+        let Some((source_dirs, overlay_dirs)) = self.source_dirs(build_id).await? else {
+            return Ok(None);
+        };
+        let mut linked_source_dirs = Vec::new();
+        for dir in source_dirs.iter() {
+            linked_source_dirs.push(self.follow_store_symlinks(dir.clone()).await?);
+        }
+        let mut linked_overlay_dirs = Vec::new();
+        for dir in overlay_dirs.iter() {
+            linked_overlay_dirs.push(self.follow_store_symlinks(dir.clone()).await?);
+        }
+        let tree = tokio::task::spawn_blocking(move || {
+            source_tree(&linked_source_dirs, &linked_overlay_dirs)
+        })
+        .await??;
+        let mut result = Vec::with_capacity(tree.len());
+        for (path, source) in tree {
+            let location = match source {
+                SourceMatch::Source(i, p) | SourceMatch::CompressedSource(i, p, _) => {
+                    source_dirs[i].clone().join(p).await?
+                }
+                SourceMatch::Overlay(i, p) => overlay_dirs[i].clone().join(p).await?,
+            };
+            result.push((path, location));
+        }
+        Ok(Some(result))
+    }
+
+    /// Writes the files returned by [Self::source_tree] to `writer` as a tar archive, one at a
+    /// time.
+    ///
+    /// Files which cannot be resolved anymore, like symlinks to store paths no substituter has,
+    /// are skipped.
+    pub async fn write_source_tar(
+        &self,
+        files: Vec<(PathBuf, RestrictedPath)>,
+        writer: impl AsyncWrite + Unpin,
+    ) -> anyhow::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let mut tar = TarWriter::new(writer);
+        for (path, location) in files {
+            let Some(file) = self.resolve_symlinks(location).await? else {
+                tracing::debug!("skipping {path:?} from the source archive: it cannot be resolved");
+                continue;
+            };
+            if file.kind().await? != ResolvedPathKind::File {
+                continue;
+            }
+            let content = file
+                .open()
+                .await
+                .with_context(|| format!("opening {file:?}"))?;
+            let metadata = content
+                .metadata()
+                .await
+                .with_context(|| format!("stat({file:?})"))?;
+            let mode = if metadata.mode() & 0o111 != 0 {
+                0o755
+            } else {
+                0o644
+            };
+            tar.append_file(
+                &path,
+                mode,
+                metadata.mtime().max(0) as u64,
+                metadata.len(),
+                tokio::io::BufReader::new(content),
+            )
+            .await?;
+        }
+        tar.finish().await?;
+        Ok(())
+    }
+
+    /// The package name of the store path of the executable with this build id, like `gnumake`
+    /// for `/nix/store/...-gnumake-4.4.1/bin/make`.
+    ///
+    /// Returns None if it cannot be determined: this is only a hint to choose between source files.
+    async fn package_name(&self, build_id: &BuildId) -> Option<String> {
+        let result: anyhow::Result<Option<StorePath>> = async {
+            let Some(debug_output) = self.substituter.build_id_to_debug_output(build_id).await?
+            else {
+                return Ok(None);
+            };
+            debug_output
+                .join(build_id.in_debug_output("executable"))
+                .store_path_target()
+                .await
+        }
+        .await;
+        match result {
+            Ok(executable) => Some(executable?.root().package_name_and_version().0),
+            Err(e) => {
+                tracing::debug!("cannot determine the package name of {build_id}: {e:#}");
+                None
+            }
+        }
+    }
+
     /// Return the source file matching `path` that led to the compilation of the executable with
     /// the specified build id.
     ///
-    /// Matching `path` to actual source file is somewhat fuzzy.
+    /// Matching `path` to actual source file is somewhat fuzzy. The file may be compressed, see
+    /// `SourceFile::compression`.
+    ///
+    /// When the file was found in a store path rather than in an unpacked archive, this store
+    /// path is available as `ResolvedPath::store_path`.
     pub async fn source(
         &self,
         build_id: &BuildId,
         path: &str,
     ) -> anyhow::Result<Option<ResolvedPath>> {
+        Ok(self
+            .located_source(build_id, path)
+            .await?
+            .map(|source| source.path))
+    }
+
+    /// Same as [Self::source], also telling whether `path` designated a file in a store path.
+    pub async fn located_source(
+        &self,
+        build_id: &BuildId,
+        path: &str,
+    ) -> anyhow::Result<Option<SourceFile>> {
         self.retry_on_full_disk(Self::source_noretry, &(build_id, path))
             .await
     }
@@ -195,15 +1346,21 @@ impl Debuginfod {
     async fn source_noretry(
         &self,
         &(build_id, path): &(&BuildId, &str),
-    ) -> anyhow::Result<Option<ResolvedPath>> {
+    ) -> anyhow::Result<Option<SourceFile>> {
+        if !self.serves_sources() {
+            return Ok(None);
+        }
         // when gdb attempts to show the source of a function that comes
         // from a header in another library, the request is store path made
-        // relative to /
+        // relative to /, and other clients may request it as is
         // in this case, let's fetch it
-        if path.starts_with("nix/store") {
-            let absolute = PathBuf::from("/").join(path);
+        let relative = path.strip_prefix('/').unwrap_or(path);
+        if Path::new(relative).starts_with("nix/store") {
+            let absolute = PathBuf::from("/").join(relative);
             let store_path = StorePath::new(&absolute).context("invalid store path")?;
-            let demangled = store_path.demangle();
+            // such a hash cannot be in any store, and would end up in urls of binary caches
+            store_path.check_hash().context("invalid store path")?;
+            let demangled = store_path.demangle().context("invalid store path")?;
             match self
                 .substituter
                 .fetch_store_path(&demangled)
@@ -212,73 +1369,77 @@ impl Debuginfod {
             {
                 None => Ok(None),
                 Some(cached_root) => {
-                    let path = cached_root.join(demangled.relative());
-                    self.resolve_symlinks(path).await
+                    let path = cached_root
+                        .with_store_path(demangled.root())
+                        .join(demangled.relative());
+                    Ok(self.resolve_symlinks(path).await?.map(|path| SourceFile {
+                        path,
+                        store_path: Some(demangled),
+                        compression: None,
+                    }))
                 }
             }
         } else {
             // as a fallback, have a look at the source of the buildid
-            let debug_output = match self.substituter.build_id_to_debug_output(build_id).await {
-                Ok(Some(nar)) => nar,
-                Ok(None) => return Ok(None),
-                Err(e) => return Err(e),
-            };
-            let source_symlink = debug_output
-                .clone()
-                .join(build_id.in_debug_output("source"));
-            let Some(source) = self.resolve_symlinks(source_symlink).await? else {
+            let Some((source_dirs, overlay_dirs)) = self.source_dirs(build_id).await? else {
                 return Ok(None);
             };
-            let source_dir = if source.kind().await? == ResolvedPathKind::Directory {
-                source
-            } else {
-                let archive = SourceArchive::new(source, build_id.clone());
-                match self.source_unpacker.get(archive).await? {
-                    None => return Ok(None),
-                    Some(x) => match x.resolve_inside_root().await? {
-                        None => return Ok(None),
-                        Some(y) => y,
-                    },
-                }
-            };
-            let overlay_symlink = debug_output.join(build_id.in_debug_output("sourceoverlay"));
-            // let overlay_symlink_path = overlay_symlink.as_ref().to_owned();
-            let overlay_dir = self
-                .resolve_symlinks(overlay_symlink.clone())
-                .await?
-                .unwrap_or_else(|| {
-                    // FIXME: temporary, should error
-                    tracing::warn!("{overlay_symlink:?} is missing");
-                    source_dir.clone()
-                });
-            let source_dir_clone = source_dir.clone();
-            let overlay_dir_clone = overlay_dir.clone();
+            let mut linked_source_dirs = Vec::new();
+            for dir in source_dirs.iter() {
+                linked_source_dirs.push(self.follow_store_symlinks(dir.clone()).await?);
+            }
+            let mut linked_overlay_dirs = Vec::new();
+            for dir in overlay_dirs.iter() {
+                linked_overlay_dirs.push(self.follow_store_symlinks(dir.clone()).await?);
+            }
             let request = PathBuf::from(path);
-            let matching_file = match tokio::task::spawn_blocking(move || {
-                get_file_for_source(&source_dir_clone, &overlay_dir_clone, &request)
+            let package = self.package_name(build_id).await;
+            let max_candidates = self.max_source_match_candidates;
+            // the match is relative to the directory, and resolving it follows the symlinks again
+            let (matching_file, compression) = match tokio::task::spawn_blocking(move || {
+                get_file_for_source_with_limit(
+                    &linked_source_dirs,
+                    &linked_overlay_dirs,
+                    &request,
+                    package.as_deref(),
+                    max_candidates,
+                )
             })
             .await??
             {
                 None => return Ok(None),
-                Some(SourceMatch::Source(p)) => source_dir.join(p).await?,
-                Some(SourceMatch::Overlay(p)) => overlay_dir.join(p).await?,
+                Some(SourceMatch::Source(i, p)) => (source_dirs[i].clone().join(p).await?, None),
+                Some(SourceMatch::CompressedSource(i, p, compression)) => {
+                    (source_dirs[i].clone().join(p).await?, Some(compression))
+                }
+                Some(SourceMatch::Overlay(i, p)) => (overlay_dirs[i].clone().join(p).await?, None),
             };
-            self.resolve_symlinks(matching_file).await
+            Ok(self
+                .resolve_symlinks(matching_file)
+                .await?
+                .map(|path| SourceFile {
+                    path,
+                    store_path: None,
+                    compression,
+                }))
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::time::Duration;
+    use std::{path::Path, time::Duration};
 
     use tempfile::tempdir;
 
     use crate::{
         build_id::BuildId,
-        debuginfod::Debuginfod,
+        debuginfod::{Debuginfod, Metadata},
+        settings::Settings,
+        store_path::StorePath,
         substituter::file::FileSubstituter,
         test_utils::{count_elements_in_dir, file_sha256, setup_logging},
+        utils::Presence,
     };
 
     #[tokio::test]
@@ -290,24 +1451,225 @@ mod test {
             t.path().into(),
             Box::new(substituter),
             Duration::from_secs(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        // /nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make
+        let debuginfo = debuginfod
+            .debuginfo(&BuildId::new("0e20481820d3b92468102b35a5e4a29a8695c1af").unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        // /nix/store/dlkw5480vfxdi21rybli43ii782czp94-gnumake-4.4.1-debug/lib/debug/make
+        assert_eq!(
+            file_sha256(debuginfo).await,
+            "8f62cc563915e10f870bd7991ad88e535f842a8dd7afcba30c597b3bb6e728ad"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_section() {
+        setup_logging();
+        let t = tempdir().unwrap();
+        let substituter = FileSubstituter::test_fixture(t.path()).await;
+        let debuginfod = Debuginfod::new(
+            t.path().into(),
+            Box::new(substituter),
+            Duration::from_secs(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        // /nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make
+        let buildid = BuildId::new("0e20481820d3b92468102b35a5e4a29a8695c1af").unwrap();
+        // only in the debuginfo
+        let (file, range) = debuginfod
+            .section(&buildid, ".debug_info")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!range.is_empty());
+        assert_eq!(
+            file_sha256(file).await,
+            "8f62cc563915e10f870bd7991ad88e535f842a8dd7afcba30c597b3bb6e728ad"
+        );
+        // NOBITS in the debuginfo, so taken from the executable
+        let (file, range) = debuginfod
+            .section(&buildid, ".text")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!range.is_empty());
+        assert_eq!(
+            file_sha256(file).await,
+            "bef9ec5e1fe7ccacbf00b1053c6de54de9857ec3d173504190462a01ed3cc52e"
+        );
+        assert!(debuginfod
+            .section(&buildid, ".does_not_exist")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_executable_nominal() {
+        setup_logging();
+        let t = tempdir().unwrap();
+        let substituter = FileSubstituter::test_fixture(t.path()).await;
+        let debuginfod = Debuginfod::new(
+            t.path().into(),
+            Box::new(substituter),
+            Duration::from_secs(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        // /nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make
+        let buildid = BuildId::new("0e20481820d3b92468102b35a5e4a29a8695c1af").unwrap();
+        let executable = debuginfod.executable(&buildid).await.unwrap().unwrap();
+        assert_eq!(
+            file_sha256(dbg!(executable)).await,
+            "bef9ec5e1fe7ccacbf00b1053c6de54de9857ec3d173504190462a01ed3cc52e"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_executable_garbage_collected() {
+        use crate::substituter::local::LocalStoreSubstituter;
+        use crate::vfs::AsFile;
+        use tokio::io::AsyncReadExt;
+
+        setup_logging();
+        let t = tempdir().unwrap();
+        let store = tempdir().unwrap();
+        let build_id = BuildId::new("483bd7f7229bdb06462222e1e353e4f37e15c293").unwrap();
+        let dir = store.path().join("aaaa-foo-debug/lib/debug/.build-id/48");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("3bd7f7229bdb06462222e1e353e4f37e15c293.debug"),
+            "debug",
+        )
+        .unwrap();
+        // the store path of the executable is not in the store anymore
+        std::os::unix::fs::symlink(
+            "/nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gone-1.0/bin/gone",
+            dir.join("3bd7f7229bdb06462222e1e353e4f37e15c293.executable"),
+        )
+        .unwrap();
+        let substituter = || {
+            Box::new(LocalStoreSubstituter::with_store_dir(
+                store.path().to_path_buf(),
+            ))
+        };
+        let debuginfod = Debuginfod::new(
+            t.path().into(),
+            substituter(),
+            Duration::from_secs(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        assert!(debuginfod.executable(&build_id).await.unwrap().is_none());
+        let debuginfod = debuginfod
+            .with_substituter(substituter())
+            .with_executable_fallback_to_debuginfo(true);
+        let executable = debuginfod.executable(&build_id).await.unwrap().unwrap();
+        let mut content = Vec::new();
+        executable
+            .open()
+            .await
+            .unwrap()
+            .read_to_end(&mut content)
+            .await
+            .unwrap();
+        assert_eq!(content, b"debug");
+    }
+
+    #[tokio::test]
+    async fn test_debuglink() {
+        setup_logging();
+        let t = tempdir().unwrap();
+        let substituter = FileSubstituter::test_fixture(t.path()).await;
+        let debuginfod = Debuginfod::new(
+            t.path().into(),
+            Box::new(substituter),
+            Duration::from_secs(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        // the debug output of /nix/store/gzs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-stripped-1.0/bin/stripped
+        // only links to the executable, whose debug file is bin/.debug/stripped.debug
+        let buildid = BuildId::new("5e6f708192a3b4c5d6e7f8091a2b3c4d5e6f7081").unwrap();
+        assert!(debuginfod.debuginfo(&buildid).await.unwrap().is_none());
+        let debuginfod = debuginfod.with_debuglink_followed(true);
+        let debuginfo = debuginfod.debuginfo(&buildid).await.unwrap().unwrap();
+        assert_eq!(
+            file_sha256(debuginfo).await,
+            "95dc3e01998014bf876ee67d8a914d1b6804d417857ab6d8938f6e98e37b2491"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_id_of_store_path() {
+        setup_logging();
+        let t = tempdir().unwrap();
+        let substituter = FileSubstituter::test_fixture(t.path()).await;
+        let debuginfod = Debuginfod::new(
+            t.path().into(),
+            Box::new(substituter),
+            Duration::from_secs(1000),
+            &Settings::default(),
         )
         .await
         .unwrap();
-        // /nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make
-        let debuginfo = debuginfod
-            .debuginfo(&BuildId::new("0e20481820d3b92468102b35a5e4a29a8695c1af").unwrap())
+        let make = StorePath::new(Path::new(
+            "/nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make",
+        ))
+        .unwrap();
+        assert_eq!(
+            debuginfod.build_id_of_store_path(&make).await.unwrap(),
+            Some(BuildId::new("0e20481820d3b92468102b35a5e4a29a8695c1af").unwrap())
+        );
+        let header = StorePath::new(Path::new(
+            "/nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/include/gnumake.h",
+        ))
+        .unwrap();
+        debuginfod
+            .build_id_of_store_path(&header)
             .await
-            .unwrap()
-            .unwrap();
-        // /nix/store/dlkw5480vfxdi21rybli43ii782czp94-gnumake-4.4.1-debug/lib/debug/make
+            .unwrap_err();
+        let missing = StorePath::new(Path::new(
+            "/nix/store/6i1h00000000000000004kz1vfpgdrcd-gnumake-4.4.1/bin/make",
+        ))
+        .unwrap();
         assert_eq!(
-            file_sha256(debuginfo).await,
-            "8f62cc563915e10f870bd7991ad88e535f842a8dd7afcba30c597b3bb6e728ad"
+            debuginfod.build_id_of_store_path(&missing).await.unwrap(),
+            None
+        );
+        // the only program in bin/
+        let root = StorePath::new(Path::new(
+            "/nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1",
+        ))
+        .unwrap();
+        assert_eq!(
+            debuginfod.build_id_of_store_path(&root).await.unwrap(),
+            Some(BuildId::new("0e20481820d3b92468102b35a5e4a29a8695c1af").unwrap())
         );
+        // no bin/
+        let include = StorePath::new(Path::new(
+            "/nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/include",
+        ))
+        .unwrap();
+        debuginfod
+            .build_id_of_store_path(&include)
+            .await
+            .unwrap_err();
     }
 
     #[tokio::test]
-    async fn test_executable_nominal() {
+    async fn test_source_explicit_store_path() {
         setup_logging();
         let t = tempdir().unwrap();
         let substituter = FileSubstituter::test_fixture(t.path()).await;
@@ -315,20 +1677,22 @@ mod test {
             t.path().into(),
             Box::new(substituter),
             Duration::from_secs(1000),
+            &Settings::default(),
         )
         .await
         .unwrap();
         // /nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make
         let buildid = BuildId::new("0e20481820d3b92468102b35a5e4a29a8695c1af").unwrap();
-        let executable = debuginfod.executable(&buildid).await.unwrap().unwrap();
+        let path = "nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/include/gnumake.h";
+        let source = debuginfod.source(&buildid, path).await.unwrap().unwrap();
         assert_eq!(
-            file_sha256(dbg!(executable)).await,
-            "bef9ec5e1fe7ccacbf00b1053c6de54de9857ec3d173504190462a01ed3cc52e"
+            file_sha256(dbg!(source)).await,
+            "3e38df96688ba32938ece2070219684616bd157750c8ba5042ccb790a49dcacc"
         );
     }
 
     #[tokio::test]
-    async fn test_source_explicit_store_path() {
+    async fn test_source_explicit_mangled_store_path() {
         setup_logging();
         let t = tempdir().unwrap();
         let substituter = FileSubstituter::test_fixture(t.path()).await;
@@ -336,12 +1700,13 @@ mod test {
             t.path().into(),
             Box::new(substituter),
             Duration::from_secs(1000),
+            &Settings::default(),
         )
         .await
         .unwrap();
         // /nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make
         let buildid = BuildId::new("0e20481820d3b92468102b35a5e4a29a8695c1af").unwrap();
-        let path = "nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/include/gnumake.h";
+        let path = "nix/store/34J18R2RPI7JS1WHMVZM9WLIAD55RILR-gnumake-4.4.1/include/gnumake.h";
         let source = debuginfod.source(&buildid, path).await.unwrap().unwrap();
         assert_eq!(
             file_sha256(dbg!(source)).await,
@@ -350,7 +1715,7 @@ mod test {
     }
 
     #[tokio::test]
-    async fn test_source_explicit_mangled_store_path() {
+    async fn test_source_absolute_store_path() {
         setup_logging();
         let t = tempdir().unwrap();
         let substituter = FileSubstituter::test_fixture(t.path()).await;
@@ -358,17 +1723,65 @@ mod test {
             t.path().into(),
             Box::new(substituter),
             Duration::from_secs(1000),
+            &Settings::default(),
         )
         .await
         .unwrap();
         // /nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make
         let buildid = BuildId::new("0e20481820d3b92468102b35a5e4a29a8695c1af").unwrap();
-        let path = "nix/store/34J18R2RPI7JS1WHMVZM9WLIAD55RILR-gnumake-4.4.1/include/gnumake.h";
-        let source = debuginfod.source(&buildid, path).await.unwrap().unwrap();
+        for path in [
+            "/nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/include/gnumake.h",
+            "/nix/store/34J18R2RPI7JS1WHMVZM9WLIAD55RILR-gnumake-4.4.1/include/gnumake.h",
+        ] {
+            let source = debuginfod.source(&buildid, path).await.unwrap().unwrap();
+            assert_eq!(
+                file_sha256(dbg!(source)).await,
+                "3e38df96688ba32938ece2070219684616bd157750c8ba5042ccb790a49dcacc",
+                "{path}"
+            );
+        }
+        let path = "/nix/store/6I1H00000000000000004KZ1VFPGDRCD-gnumake-4.4.1/include/gnumake.h";
+        assert!(debuginfod.source(&buildid, path).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_metadata() {
+        setup_logging();
+        let t = tempdir().unwrap();
+        let substituter = FileSubstituter::test_fixture(t.path()).await;
+        let debuginfod = Debuginfod::new(
+            t.path().into(),
+            Box::new(substituter),
+            Duration::from_secs(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        // /nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make
+        let buildid = BuildId::new("0e20481820d3b92468102b35a5e4a29a8695c1af").unwrap();
+        let metadata = debuginfod.metadata(&buildid).await.unwrap().unwrap();
         assert_eq!(
-            file_sha256(dbg!(source)).await,
-            "3e38df96688ba32938ece2070219684616bd157750c8ba5042ccb790a49dcacc"
+            metadata,
+            Metadata {
+                executable: Some(
+                    "/nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make".into()
+                ),
+                store_path: Some(
+                    "/nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1".into()
+                ),
+                name: Some("gnumake".to_owned()),
+                version: Some("4.4.1".to_owned()),
+                deriver: Some(
+                    "/nix/store/dxw7pr050i88sijzrbpxp00kqyfsjmqc-gnumake-4.4.1.drv".into()
+                ),
+                references: vec![
+                    "/nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1".into(),
+                    "/nix/store/g2jzxk3s7cnkhh8yq55l4fbvf639zy37-glibc-2.40-66".into(),
+                ],
+            }
         );
+        let missing = BuildId::new(&"00".repeat(20)).unwrap();
+        assert!(debuginfod.metadata(&missing).await.unwrap().is_none());
     }
 
     #[tokio::test]
@@ -380,6 +1793,7 @@ mod test {
             t.path().into(),
             Box::new(substituter),
             Duration::from_secs(1000),
+            &Settings::default(),
         )
         .await
         .unwrap();
@@ -399,6 +1813,7 @@ mod test {
             t.path().into(),
             Box::new(substituter),
             Duration::from_secs(1000),
+            &Settings::default(),
         )
         .await
         .unwrap();
@@ -418,6 +1833,7 @@ mod test {
             t.path().into(),
             Box::new(substituter),
             Duration::from_secs(1000),
+            &Settings::default(),
         )
         .await
         .unwrap();
@@ -441,6 +1857,7 @@ mod test {
             t.path().into(),
             Box::new(substituter),
             Duration::from_secs(1000),
+            &Settings::default(),
         )
         .await
         .unwrap();
@@ -464,6 +1881,7 @@ mod test {
             t.path().into(),
             Box::new(substituter),
             Duration::from_secs(1000),
+            &Settings::default(),
         )
         .await
         .unwrap();
@@ -487,6 +1905,7 @@ mod test {
             t.path().into(),
             Box::new(substituter),
             Duration::from_secs(1000),
+            &Settings::default(),
         )
         .await
         .unwrap();
@@ -502,17 +1921,206 @@ mod test {
     }
 
     #[tokio::test]
-    async fn test_cleanup() {
+    async fn test_source_in_zstd_archive() {
         setup_logging();
         let t = tempdir().unwrap();
-        let expiration = Duration::from_millis(10);
-        let path = crate::test_utils::fixture("file_binary_cache");
-        let substituter = FileSubstituter::new(&path, t.path().to_path_buf(), expiration)
+        let substituter = FileSubstituter::test_fixture(t.path()).await;
+        let debuginfod = Debuginfod::new(
+            t.path().into(),
+            Box::new(substituter),
+            Duration::from_secs(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        // /nix/store/2zs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-hello-1.0-debug/lib/debug/.build-id/7a/5f0e8d2c1b3a4f5e6d7c8b9a0f1e2d3c4b5a69.source
+        // -> /nix/store/1zs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-hello-1.0.tar.zst
+        let buildid = BuildId::new("7a5f0e8d2c1b3a4f5e6d7c8b9a0f1e2d3c4b5a69").unwrap();
+        let source = debuginfod
+            .source(&buildid, "/build/hello-1.0/src/hello.c")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            file_sha256(source).await,
+            "3d3ba8a9ae40b8994cb00925b3a357074f8940ab36973a921c6764f9248eab1d"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_source_in_several_archives() {
+        setup_logging();
+        let t = tempdir().unwrap();
+        let substituter = FileSubstituter::test_fixture(t.path()).await;
+        let debuginfod = Debuginfod::new(
+            t.path().into(),
+            Box::new(substituter),
+            Duration::from_secs(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        // /nix/store/4zs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-hello-vendored-1.0-debug/lib/debug/.build-id/8c/6f1e9d3b2a4c5f6e7d8c9b0a1f2e3d4c5b6a70.source
+        // -> /nix/store/3zs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-vendored-sources
+        let buildid = BuildId::new("8c6f1e9d3b2a4c5f6e7d8c9b0a1f2e3d4c5b6a70").unwrap();
+        // in hello-1.0.tar.zst
+        let source = debuginfod
+            .source(&buildid, "/build/hello-1.0/src/hello.c")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            file_sha256(source).await,
+            "3d3ba8a9ae40b8994cb00925b3a357074f8940ab36973a921c6764f9248eab1d"
+        );
+        // in extra-0.1.tar.gz
+        let source = debuginfod
+            .source(&buildid, "/build/extra-0.1/lib/extra.c")
             .await
+            .unwrap()
             .unwrap();
-        let debuginfod = Debuginfod::new(t.path().into(), Box::new(substituter), expiration)
+        assert_eq!(
+            file_sha256(source).await,
+            "9059e09b40ef6ce7fdcc04bb0be5e52cd67c32c1b4e1e48f949b88052ae66936"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_source_from_deriver() {
+        setup_logging();
+        let t = tempdir().unwrap();
+        let substituter = FileSubstituter::test_fixture(t.path()).await;
+        let debuginfod = Debuginfod::new(
+            t.path().into(),
+            Box::new(substituter),
+            Duration::from_secs(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        // the debug output has no .source symlink, but the narinfo of
+        // /nix/store/7zs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-greet-1.0 has a deriver whose src is
+        // /nix/store/5zs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-greet-1.0.tar.gz
+        let buildid = BuildId::new("9b8a7c6d5e4f3021120304e5d6c7b8a9f0e1d2c3").unwrap();
+        let source = debuginfod
+            .source(&buildid, "/build/greet-1.0/greet.c")
             .await
+            .unwrap()
             .unwrap();
+        assert_eq!(
+            file_sha256(source).await,
+            "3077ec201146d9a1b01b29c11bf1528629475d85914393874bec1c23786745c4"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_source_through_store_symlink() {
+        setup_logging();
+        for follow in [false, true] {
+            let t = tempdir().unwrap();
+            let substituter = FileSubstituter::test_fixture(t.path()).await;
+            let debuginfod = Debuginfod::new(
+                t.path().into(),
+                Box::new(substituter),
+                Duration::from_secs(1000),
+                &Settings::default(),
+            )
+            .await
+            .unwrap()
+            .with_source_symlinks_followed(follow);
+            // the source /nix/store/bzs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-linked-sources contains
+            // vendor/dep -> /nix/store/azs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-dep-0.1
+            let buildid = BuildId::new("1a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d").unwrap();
+            let main = debuginfod
+                .source(&buildid, "/build/linked/main.c")
+                .await
+                .unwrap();
+            assert!(main.is_some());
+            let dep = debuginfod
+                .source(&buildid, "/build/linked/vendor/dep/src/dep.c")
+                .await
+                .unwrap();
+            match dep {
+                None => assert!(!follow),
+                Some(dep) => {
+                    assert!(follow);
+                    assert_eq!(
+                        file_sha256(dep).await,
+                        "d7dc291451e6c34c0bc312ef8dcfdf37d88b4b687d2cb758c9e1b28188cd3b6f"
+                    );
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_source_archive() {
+        setup_logging();
+        let t = tempdir().unwrap();
+        let substituter = FileSubstituter::test_fixture(t.path()).await;
+        let debuginfod = Debuginfod::new(
+            t.path().into(),
+            Box::new(substituter),
+            Duration::from_secs(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        // /nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make
+        let buildid = BuildId::new("0e20481820d3b92468102b35a5e4a29a8695c1af").unwrap();
+        let presence = debuginfod.prefetch_source(&buildid).await.unwrap();
+        assert_eq!(presence, Presence::Found);
+        let n1 = count_elements_in_dir(t.path());
+        // the archive is already unpacked, so this should not add anything to the cache
+        let path = "/build/make-4.4.1/src/main.c";
+        let source = debuginfod.source(&buildid, path).await.unwrap().unwrap();
+        assert_eq!(
+            file_sha256(dbg!(source)).await,
+            "7f0b8a02a6449507c751cdf3315a11bb0e99f22dc75a33a8b82b9e78c9f0bff0"
+        );
+        assert_eq!(count_elements_in_dir(t.path()), n1);
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_source_missing() {
+        setup_logging();
+        let t = tempdir().unwrap();
+        let substituter = FileSubstituter::test_fixture(t.path()).await;
+        let debuginfod = Debuginfod::new(
+            t.path().into(),
+            Box::new(substituter),
+            Duration::from_secs(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        let buildid = BuildId::new("483bd7f7229bdb00000000000000e4f37e15c293").unwrap();
+        let presence = debuginfod.prefetch_source(&buildid).await.unwrap();
+        assert_eq!(presence, Presence::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup() {
+        setup_logging();
+        let t = tempdir().unwrap();
+        let expiration = Duration::from_millis(10);
+        let path = crate::test_utils::fixture("file_binary_cache");
+        let substituter = FileSubstituter::new(
+            &path,
+            t.path().to_path_buf(),
+            expiration,
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        let debuginfod = Debuginfod::new(
+            t.path().into(),
+            Box::new(substituter),
+            expiration,
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
         debuginfod.spawn_cleanup_task();
         let n1;
         {
@@ -543,6 +2151,7 @@ mod test {
             t.path().into(),
             Box::new(substituter),
             Duration::from_secs(1000),
+            &Settings::default(),
         )
         .await
         .unwrap();
@@ -586,6 +2195,7 @@ mod test {
             t.path().into(),
             Box::new(substituter),
             Duration::from_secs(1000),
+            &Settings::default(),
         )
         .await
         .unwrap();
@@ -617,4 +2227,244 @@ mod test {
         // same storepath to be stored on disk
         assert_eq!(n1, n2);
     }
+
+    /// A binary cache in a temporary directory, with the debug output `out` indexed under these
+    /// build ids.
+    fn make_binary_cache(out: &Path, build_ids: &[&BuildId]) -> tempfile::TempDir {
+        use std::io::Read;
+
+        let binary_cache = tempdir().unwrap();
+        let mut nar = Vec::new();
+        nix_nar::Encoder::new(out)
+            .unwrap()
+            .read_to_end(&mut nar)
+            .unwrap();
+        std::fs::create_dir_all(binary_cache.path().join("nar")).unwrap();
+        std::fs::write(binary_cache.path().join("nar/debug.nar"), nar).unwrap();
+        std::fs::create_dir_all(binary_cache.path().join("debuginfo")).unwrap();
+        for build_id in build_ids {
+            std::fs::write(
+                binary_cache.path().join(format!("debuginfo/{build_id}")),
+                r#"{"archive":"../nar/debug.nar","member":"unused"}"#,
+            )
+            .unwrap();
+        }
+        binary_cache
+    }
+
+    #[tokio::test]
+    async fn test_verify_build_id() {
+        use crate::elf::{make_test_elf_with, make_test_note, NT_GNU_BUILD_ID, SHT_NOTE};
+
+        setup_logging();
+        let good = BuildId::new(&"aa".repeat(20)).unwrap();
+        let wrong = BuildId::new(&"bb".repeat(20)).unwrap();
+        let output = tempdir().unwrap();
+        let out = output.path().join("out");
+        // the file for `wrong` actually has build id cc...
+        for (build_id, note) in [(&good, [0xaa; 20]), (&wrong, [0xcc; 20])] {
+            let debug = make_test_elf_with(&[(
+                ".note.gnu.build-id",
+                SHT_NOTE,
+                &make_test_note(b"GNU\0", NT_GNU_BUILD_ID, &note),
+            )]);
+            let debug_path = out.join(build_id.in_debug_output("debug"));
+            std::fs::create_dir_all(debug_path.parent().unwrap()).unwrap();
+            std::fs::write(&debug_path, debug).unwrap();
+        }
+        let binary_cache = make_binary_cache(&out, &[&good, &wrong]);
+
+        for verify in [false, true] {
+            let t = tempdir().unwrap();
+            let substituter_cache = tempdir().unwrap();
+            let substituter = FileSubstituter::new(
+                binary_cache.path(),
+                substituter_cache.path().to_path_buf(),
+                Duration::from_secs(1000),
+                &Settings::default(),
+            )
+            .await
+            .unwrap();
+            let debuginfod = Debuginfod::new(
+                t.path().into(),
+                Box::new(substituter),
+                Duration::from_secs(1000),
+                &Settings::default(),
+            )
+            .await
+            .unwrap()
+            .with_build_id_verified(verify);
+            assert!(debuginfod.debuginfo(&good).await.unwrap().is_some());
+            assert_eq!(
+                debuginfod.debuginfo(&wrong).await.unwrap().is_some(),
+                !verify
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unsharded_debuginfo() {
+        use crate::elf::{make_test_elf_with, make_test_note, NT_GNU_BUILD_ID, SHT_NOTE};
+
+        setup_logging();
+        let build_id = BuildId::new(&"aa".repeat(20)).unwrap();
+        let other = BuildId::new(&"bb".repeat(20)).unwrap();
+        let output = tempdir().unwrap();
+        let out = output.path().join("out");
+        // not under lib/debug/.build-id
+        std::fs::create_dir_all(out.join("lib/debug/bin")).unwrap();
+        for (name, note) in [("bin/foo.debug", [0xaa; 20]), ("other.debug", [0xcc; 20])] {
+            let debug = make_test_elf_with(&[(
+                ".note.gnu.build-id",
+                SHT_NOTE,
+                &make_test_note(b"GNU\0", NT_GNU_BUILD_ID, &note),
+            )]);
+            std::fs::write(out.join("lib/debug").join(name), debug).unwrap();
+        }
+        let binary_cache = make_binary_cache(&out, &[&build_id, &other]);
+        let t = tempdir().unwrap();
+        let substituter_cache = tempdir().unwrap();
+        let substituter = FileSubstituter::new(
+            binary_cache.path(),
+            substituter_cache.path().to_path_buf(),
+            Duration::from_secs(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        let debuginfod = Debuginfod::new(
+            t.path().into(),
+            Box::new(substituter),
+            Duration::from_secs(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        let debuginfo = debuginfod.debuginfo(&build_id).await.unwrap().unwrap();
+        assert_eq!(debuginfo.file_name().unwrap(), "foo.debug");
+        assert!(debuginfod.debuginfo(&other).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_kernel_module_debuginfo() {
+        use crate::elf::{make_test_elf_with, make_test_note, NT_GNU_BUILD_ID, SHT_NOTE};
+
+        setup_logging();
+        let module = BuildId::new(&"dd".repeat(20)).unwrap();
+        let output = tempdir().unwrap();
+        let out = output.path().join("out");
+        let modules = out.join("lib/debug/lib/modules/6.6.30/kernel");
+        std::fs::create_dir_all(modules.join("drivers/net")).unwrap();
+        std::fs::create_dir_all(modules.join("fs")).unwrap();
+        for (name, note) in [
+            ("drivers/net/dummy.ko", [0xdd; 20]),
+            ("fs/other.ko", [0xee; 20]),
+            // not a kernel module nor a debug file
+            ("fs/README", [0xdd; 20]),
+        ] {
+            let debug = make_test_elf_with(&[(
+                ".note.gnu.build-id",
+                SHT_NOTE,
+                &make_test_note(b"GNU\0", NT_GNU_BUILD_ID, &note),
+            )]);
+            std::fs::write(modules.join(name), debug).unwrap();
+        }
+        let binary_cache = make_binary_cache(&out, &[&module]);
+        let t = tempdir().unwrap();
+        let substituter_cache = tempdir().unwrap();
+        let substituter = FileSubstituter::new(
+            binary_cache.path(),
+            substituter_cache.path().to_path_buf(),
+            Duration::from_secs(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        let debuginfod = Debuginfod::new(
+            t.path().into(),
+            Box::new(substituter),
+            Duration::from_secs(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        let debuginfo = debuginfod.debuginfo(&module).await.unwrap().unwrap();
+        assert_eq!(debuginfo.file_name().unwrap(), "dummy.ko");
+    }
+
+    #[tokio::test]
+    async fn test_debugaltlink() {
+        use crate::elf::{make_test_elf_with, make_test_note, NT_GNU_BUILD_ID, SHT_NOTE};
+        use crate::vfs::AsFile;
+        use tokio::io::AsyncReadExt;
+
+        setup_logging();
+        let referrer = BuildId::new(&"aa".repeat(20)).unwrap();
+        let alt = BuildId::new(&"bb".repeat(20)).unwrap();
+        // a debug output whose debuginfo refers to a supplementary file created by dwz
+        let output = tempdir().unwrap();
+        let out = output.path().join("out");
+        let mut link = b"../../.dwz/foo.debug\0".to_vec();
+        link.extend_from_slice(&[0xbb; 20]);
+        let debug = make_test_elf_with(&[
+            (
+                ".note.gnu.build-id",
+                SHT_NOTE,
+                &make_test_note(b"GNU\0", NT_GNU_BUILD_ID, &[0xaa; 20]),
+            ),
+            (".gnu_debugaltlink", 1, &link),
+        ]);
+        let debug_path = out.join(referrer.in_debug_output("debug"));
+        std::fs::create_dir_all(debug_path.parent().unwrap()).unwrap();
+        std::fs::write(&debug_path, debug).unwrap();
+        let dwz = make_test_elf_with(&[(
+            ".note.gnu.build-id",
+            SHT_NOTE,
+            &make_test_note(b"GNU\0", NT_GNU_BUILD_ID, &[0xbb; 20]),
+        )]);
+        std::fs::create_dir_all(out.join("lib/debug/.dwz")).unwrap();
+        std::fs::write(out.join("lib/debug/.dwz/foo.debug"), &dwz).unwrap();
+
+        let binary_cache = make_binary_cache(&out, &[&referrer]);
+
+        let t = tempdir().unwrap();
+        let substituter_cache = tempdir().unwrap();
+        let substituter = FileSubstituter::new(
+            binary_cache.path(),
+            substituter_cache.path().to_path_buf(),
+            Duration::from_secs(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        let debuginfod = Debuginfod::new(
+            t.path().into(),
+            Box::new(substituter),
+            Duration::from_secs(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        // not known until a debuginfo refers to it
+        assert!(debuginfod.debuginfo(&alt).await.unwrap().is_none());
+        assert!(debuginfod.debuginfo(&referrer).await.unwrap().is_some());
+        let mut found = None;
+        for _ in 0..100 {
+            found = debuginfod.debuginfo(&alt).await.unwrap();
+            if found.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let mut content = Vec::new();
+        found
+            .expect("supplementary debug file was not found")
+            .open()
+            .await
+            .unwrap()
+            .read_to_end(&mut content)
+            .await
+            .unwrap();
+        assert_eq!(content, dwz);
+    }
 }
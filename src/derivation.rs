@@ -0,0 +1,174 @@
+//! Just enough parsing of derivations (`.drv` files) to find the sources of a package.
+//!
+//! Derivations are serialized as ATerms:
+//! `Derive([outputs],[input derivations],[input sources],"system","builder",[args],[env])`
+
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::store_path::StorePath;
+
+/// Environment variables of the derivation which may contain the sources of the package
+const SOURCE_VARIABLES: [&str; 2] = ["src", "srcs"];
+
+/// Derivations larger than this are not parsed
+pub const MAX_DERIVATION_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Derivations nest terms only a few levels deep, more is rejected to protect the stack
+const MAX_DEPTH: usize = 8;
+
+/// A parsed ATerm
+#[derive(Debug, PartialEq, Eq)]
+enum Term {
+    String(String),
+    List(Vec<Term>),
+    /// a tuple, or a constructor application like `Derive(...)`
+    Tuple(Vec<Term>),
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+    /// how many lists or tuples we are in
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn expect(&mut self, c: char) -> anyhow::Result<()> {
+        self.rest = self
+            .rest
+            .strip_prefix(c)
+            .with_context(|| format!("expected {c:?} at {:?}", self.excerpt()))?;
+        Ok(())
+    }
+
+    fn excerpt(&self) -> String {
+        self.rest.chars().take(20).collect()
+    }
+
+    /// Parses terms separated by commas until `end`
+    fn terms(&mut self, end: char) -> anyhow::Result<Vec<Term>> {
+        anyhow::ensure!(self.depth < MAX_DEPTH, "terms are nested too deeply");
+        self.depth += 1;
+        let mut result = Vec::new();
+        if let Some(rest) = self.rest.strip_prefix(end) {
+            self.rest = rest;
+            self.depth -= 1;
+            return Ok(result);
+        }
+        loop {
+            result.push(self.term()?);
+            if let Some(rest) = self.rest.strip_prefix(end) {
+                self.rest = rest;
+                self.depth -= 1;
+                return Ok(result);
+            }
+            self.expect(',')?;
+        }
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        self.expect('"')?;
+        let mut result = String::new();
+        let mut chars = self.rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[i + 1..];
+                    return Ok(result);
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => result.push('\n'),
+                    Some((_, 'r')) => result.push('\r'),
+                    Some((_, 't')) => result.push('\t'),
+                    Some((_, other)) => result.push(other),
+                    None => break,
+                },
+                other => result.push(other),
+            }
+        }
+        anyhow::bail!("unterminated string")
+    }
+
+    fn term(&mut self) -> anyhow::Result<Term> {
+        if self.rest.starts_with('"') {
+            return self.string().map(Term::String);
+        }
+        if let Some(rest) = self.rest.strip_prefix('[') {
+            self.rest = rest;
+            return self.terms(']').map(Term::List);
+        }
+        // skip the constructor name, if any
+        self.rest = self
+            .rest
+            .trim_start_matches(|c: char| c.is_ascii_alphanumeric());
+        self.expect('(')?;
+        self.terms(')').map(Term::Tuple)
+    }
+}
+
+/// Returns the store paths listed in the `src` and `srcs` environment variables of this
+/// derivation.
+///
+/// Values which are not store paths, like urls, are ignored.
+pub fn source_paths(derivation: &str) -> anyhow::Result<Vec<StorePath>> {
+    let mut parser = Parser {
+        rest: derivation,
+        depth: 0,
+    };
+    let term = parser.term().context("parsing derivation")?;
+    anyhow::ensure!(
+        parser.rest.trim().is_empty(),
+        "trailing data after derivation"
+    );
+    let Term::Tuple(fields) = term else {
+        anyhow::bail!("derivation is not a constructor application")
+    };
+    let Some(Term::List(env)) = fields.get(6) else {
+        anyhow::bail!("derivation has no environment")
+    };
+    let mut result = Vec::new();
+    for variable in env {
+        let Term::Tuple(pair) = variable else {
+            anyhow::bail!("unexpected environment entry {variable:?}")
+        };
+        let [Term::String(name), Term::String(value)] = &pair[..] else {
+            anyhow::bail!("unexpected environment entry {variable:?}")
+        };
+        if !SOURCE_VARIABLES.contains(&name.as_str()) {
+            continue;
+        }
+        for word in value.split_whitespace() {
+            if let Ok(store_path) = StorePath::new(Path::new(word)) {
+                result.push(store_path.root());
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[test]
+fn test_source_paths() {
+    let drv = r#"Derive([("out","/nix/store/7zs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-greet-1.0","","")],[("/nix/store/9zs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-greet-1.0.tar.gz.drv",["out"])],["/nix/store/azs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-builder.sh"],"x86_64-linux","/bin/sh",["-e","/nix/store/azs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-builder.sh"],[("name","greet-1.0"),("out","/nix/store/7zs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-greet-1.0"),("script","echo \"a\\b\"\n"),("src","/nix/store/5zs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-greet-1.0.tar.gz"),("srcs","https://example.com /nix/store/bzs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-patches/sub")])"#;
+    let paths = source_paths(drv).unwrap();
+    let paths: Vec<&Path> = paths.iter().map(AsRef::as_ref).collect();
+    assert_eq!(
+        paths,
+        [
+            Path::new("/nix/store/5zs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-greet-1.0.tar.gz"),
+            Path::new("/nix/store/bzs7m2g6w3v4q7n9p0k1j2h3f4d5b6c8-patches"),
+        ]
+    );
+}
+
+#[test]
+fn test_source_paths_invalid() {
+    source_paths("Derive([").unwrap_err();
+    source_paths(&format!("Derive({})", "[".repeat(100_000))).unwrap_err();
+    source_paths(r#"Derive([],[],[],"x86_64-linux","/bin/sh",[],[("src")])"#).unwrap_err();
+    assert!(
+        source_paths(r#"Derive([],[],[],"x86_64-linux","/bin/sh",[],[])"#)
+            .unwrap()
+            .is_empty()
+    );
+}
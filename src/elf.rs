@@ -0,0 +1,496 @@
+//! Just enough ELF parsing to find the build id and the sections of an executable or library.
+//!
+//! Reference: <https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html>
+
+use std::{
+    ffi::OsStr,
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+};
+
+use anyhow::Context;
+
+use crate::build_id::BuildId;
+
+/// Section type of notes
+pub(crate) const SHT_NOTE: u32 = 7;
+/// Section type of sections which occupy no space in the file, like `.text` in debuginfo files
+const SHT_NOBITS: u32 = 8;
+/// Value of `e_shstrndx` meaning that the actual index is in the first section header
+const SHN_XINDEX: u32 = 0xffff;
+/// Section name tables larger than this are not read
+const MAX_SECTION_NAMES_SIZE: u64 = 16 * 1024 * 1024;
+/// Note type of build ids
+pub(crate) const NT_GNU_BUILD_ID: u32 = 3;
+/// Note sections larger than this are not read
+const MAX_NOTE_SECTION_SIZE: u64 = 1024 * 1024;
+/// `.gnu_debugaltlink` sections larger than this are not read
+const MAX_DEBUGALTLINK_SIZE: u64 = 64 * 1024;
+/// `.gnu_debuglink` sections larger than this are not read
+const MAX_DEBUGLINK_SIZE: u64 = 64 * 1024;
+
+/// The supplementary debug file (as created by `dwz`) that a debug file refers to in its
+/// `.gnu_debugaltlink` section
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugAltLink {
+    /// path of the supplementary file, relative to the directory of the debug file, or absolute
+    pub path: PathBuf,
+    /// build id of the supplementary file
+    pub build_id: BuildId,
+}
+
+/// The separate debug file that a stripped executable refers to in its `.gnu_debuglink` section
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugLink {
+    /// file name of the debug file, without directory
+    pub file_name: PathBuf,
+    /// CRC32 of the whole debug file
+    pub crc: u32,
+}
+
+/// The parts of a section header we care about
+#[derive(Debug)]
+struct SectionHeader {
+    /// offset of the name in the section name table
+    name: u32,
+    kind: u32,
+    link: u32,
+    offset: u64,
+    size: u64,
+}
+
+/// An ELF file whose section headers were parsed
+pub struct Elf<R> {
+    reader: R,
+    little_endian: bool,
+    sections: Vec<SectionHeader>,
+    /// index of the section containing section names
+    names_index: u32,
+}
+
+impl<R: Read + Seek> Elf<R> {
+    /// Parses the ELF header and section headers of this file.
+    pub fn parse(mut reader: R) -> anyhow::Result<Self> {
+        let mut ident = [0u8; 64];
+        reader.rewind()?;
+        reader
+            .read_exact(&mut ident)
+            .context("file too short to be an ELF file")?;
+        anyhow::ensure!(&ident[..4] == b"\x7fELF", "not an ELF file");
+        let class64 = match ident[4] {
+            1 => false,
+            2 => true,
+            other => anyhow::bail!("unknown ELF class {other}"),
+        };
+        let little_endian = match ident[5] {
+            1 => true,
+            2 => false,
+            other => anyhow::bail!("unknown ELF data encoding {other}"),
+        };
+        let mut elf = Elf {
+            reader,
+            little_endian,
+            sections: Vec::new(),
+            names_index: 0,
+        };
+        let (shoff, shentsize, mut shnum) = if class64 {
+            elf.names_index = elf.u16_at(&ident, 0x3e) as u32;
+            (
+                elf.u64_at(&ident, 0x28),
+                elf.u16_at(&ident, 0x3a),
+                elf.u16_at(&ident, 0x3c) as u64,
+            )
+        } else {
+            elf.names_index = elf.u16_at(&ident, 0x32) as u32;
+            (
+                elf.u32_at(&ident, 0x20) as u64,
+                elf.u16_at(&ident, 0x2e),
+                elf.u16_at(&ident, 0x30) as u64,
+            )
+        };
+        if shoff == 0 {
+            return Ok(elf);
+        }
+        let expected_entsize = if class64 { 64 } else { 40 };
+        anyhow::ensure!(
+            shentsize as usize == expected_entsize,
+            "unexpected section header size {shentsize}"
+        );
+        let mut entry = vec![0u8; expected_entsize];
+        let mut i = 0;
+        while i < shnum.max(1) {
+            elf.reader
+                .seek(SeekFrom::Start(shoff + i * expected_entsize as u64))?;
+            elf.reader
+                .read_exact(&mut entry)
+                .context("reading section headers")?;
+            let header = if class64 {
+                SectionHeader {
+                    name: elf.u32_at(&entry, 0),
+                    kind: elf.u32_at(&entry, 4),
+                    link: elf.u32_at(&entry, 40),
+                    offset: elf.u64_at(&entry, 24),
+                    size: elf.u64_at(&entry, 32),
+                }
+            } else {
+                SectionHeader {
+                    name: elf.u32_at(&entry, 0),
+                    kind: elf.u32_at(&entry, 4),
+                    link: elf.u32_at(&entry, 24),
+                    offset: elf.u32_at(&entry, 16) as u64,
+                    size: elf.u32_at(&entry, 20) as u64,
+                }
+            };
+            // with more than 0xff00 sections, the actual count is in the size of the first section
+            if i == 0 && shnum == 0 {
+                shnum = header.size;
+            }
+            if i == 0 && elf.names_index == SHN_XINDEX {
+                elf.names_index = header.link;
+            }
+            elf.sections.push(header);
+            i += 1;
+        }
+        Ok(elf)
+    }
+
+    fn u16_at(&self, buf: &[u8], offset: usize) -> u16 {
+        let bytes = buf[offset..offset + 2].try_into().unwrap();
+        if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        }
+    }
+
+    fn u32_at(&self, buf: &[u8], offset: usize) -> u32 {
+        let bytes = buf[offset..offset + 4].try_into().unwrap();
+        if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    }
+
+    fn u64_at(&self, buf: &[u8], offset: usize) -> u64 {
+        let bytes = buf[offset..offset + 8].try_into().unwrap();
+        if self.little_endian {
+            u64::from_le_bytes(bytes)
+        } else {
+            u64::from_be_bytes(bytes)
+        }
+    }
+
+    /// Reads the content of the section at this index
+    fn read_section(&mut self, index: usize) -> anyhow::Result<Vec<u8>> {
+        let header = &self.sections[index];
+        let mut content = vec![0u8; header.size as usize];
+        self.reader.seek(SeekFrom::Start(header.offset))?;
+        self.reader
+            .read_exact(&mut content)
+            .with_context(|| format!("reading section {index}"))?;
+        Ok(content)
+    }
+
+    /// Returns the index of the first section with this name.
+    fn section_index(&mut self, name: &str) -> anyhow::Result<Option<usize>> {
+        let names_index = self.names_index as usize;
+        let Some(names_header) = self.sections.get(names_index) else {
+            return Ok(None);
+        };
+        anyhow::ensure!(
+            names_header.size <= MAX_SECTION_NAMES_SIZE,
+            "section name table is too large"
+        );
+        let names = self.read_section(names_index)?;
+        Ok(self.sections.iter().position(|header| {
+            names
+                .get(header.name as usize..)
+                .and_then(|section_name| section_name.strip_prefix(name.as_bytes()))
+                .is_some_and(|rest| rest.first() == Some(&0))
+        }))
+    }
+
+    /// Returns the range of bytes of the file occupied by the section with this name.
+    ///
+    /// Returns None if there is no such section, or if it occupies no space in the file.
+    /// Fails if the section extends past the end of the file.
+    pub fn section_range(&mut self, name: &str) -> anyhow::Result<Option<Range<u64>>> {
+        let Some(index) = self.section_index(name)? else {
+            return Ok(None);
+        };
+        let header = &self.sections[index];
+        if header.kind == SHT_NOBITS {
+            return Ok(None);
+        }
+        let (offset, size) = (header.offset, header.size);
+        let len = self.reader.seek(SeekFrom::End(0))?;
+        let end = offset
+            .checked_add(size)
+            .filter(|&end| end <= len)
+            .with_context(|| format!("section {name} extends past the end of the file"))?;
+        Ok(Some(offset..end))
+    }
+
+    /// Returns the supplementary debug file referenced by the `.gnu_debugaltlink` section, if
+    /// any.
+    ///
+    /// The section contains a NUL terminated path followed by the build id of the supplementary
+    /// file.
+    pub fn debugaltlink(&mut self) -> anyhow::Result<Option<DebugAltLink>> {
+        let Some(index) = self.section_index(".gnu_debugaltlink")? else {
+            return Ok(None);
+        };
+        let header = &self.sections[index];
+        if header.kind == SHT_NOBITS {
+            return Ok(None);
+        }
+        anyhow::ensure!(
+            header.size <= MAX_DEBUGALTLINK_SIZE,
+            ".gnu_debugaltlink section is too large"
+        );
+        let content = self.read_section(index)?;
+        let nul = content
+            .iter()
+            .position(|&byte| byte == 0)
+            .context(".gnu_debugaltlink has no NUL terminated path")?;
+        let hex: String = content[nul + 1..]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let build_id = BuildId::new(&hex).context("invalid build id in .gnu_debugaltlink")?;
+        Ok(Some(DebugAltLink {
+            path: PathBuf::from(OsStr::from_bytes(&content[..nul])),
+            build_id,
+        }))
+    }
+
+    /// Returns the separate debug file referenced by the `.gnu_debuglink` section, if any.
+    ///
+    /// The section contains a NUL terminated file name, padded to 4 bytes, followed by the CRC32
+    /// of the debug file.
+    pub fn debuglink(&mut self) -> anyhow::Result<Option<DebugLink>> {
+        let Some(index) = self.section_index(".gnu_debuglink")? else {
+            return Ok(None);
+        };
+        let header = &self.sections[index];
+        if header.kind == SHT_NOBITS {
+            return Ok(None);
+        }
+        anyhow::ensure!(
+            header.size <= MAX_DEBUGLINK_SIZE,
+            ".gnu_debuglink section is too large"
+        );
+        let content = self.read_section(index)?;
+        let nul = content
+            .iter()
+            .position(|&byte| byte == 0)
+            .context(".gnu_debuglink has no NUL terminated file name")?;
+        let name = &content[..nul];
+        anyhow::ensure!(
+            !name.is_empty() && !name.contains(&b'/') && name != b"..",
+            "invalid file name in .gnu_debuglink"
+        );
+        let crc_offset = (nul + 1).next_multiple_of(4);
+        anyhow::ensure!(content.len() >= crc_offset + 4, ".gnu_debuglink has no CRC");
+        Ok(Some(DebugLink {
+            file_name: PathBuf::from(OsStr::from_bytes(name)),
+            crc: self.u32_at(&content, crc_offset),
+        }))
+    }
+
+    /// Returns the build id contained in the `NT_GNU_BUILD_ID` note of this file, if any.
+    pub fn build_id(&mut self) -> anyhow::Result<Option<BuildId>> {
+        for index in 0..self.sections.len() {
+            let header = &self.sections[index];
+            if header.kind != SHT_NOTE || header.size > MAX_NOTE_SECTION_SIZE {
+                continue;
+            }
+            let notes = self.read_section(index)?;
+            let mut rest = &notes[..];
+            while rest.len() >= 12 {
+                let namesz = self.u32_at(rest, 0) as usize;
+                let descsz = self.u32_at(rest, 4) as usize;
+                let kind = self.u32_at(rest, 8);
+                let desc_start = 12 + namesz.next_multiple_of(4);
+                let desc_end = desc_start + descsz;
+                anyhow::ensure!(desc_end <= rest.len(), "truncated note in section {index}");
+                if kind == NT_GNU_BUILD_ID && &rest[12..12 + namesz] == b"GNU\0" {
+                    let hex: String = rest[desc_start..desc_end]
+                        .iter()
+                        .map(|byte| format!("{byte:02x}"))
+                        .collect();
+                    return BuildId::new(&hex).map(Some);
+                }
+                rest = &rest[desc_end.next_multiple_of(4).min(rest.len())..];
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+/// A little endian ELF64 file with these sections (name, type, content), followed by `.shstrtab`.
+///
+/// `SHT_NOBITS` sections have a size of 1000 and no content.
+pub(crate) fn make_test_elf_with(sections: &[(&str, u32, &[u8])]) -> Vec<u8> {
+    let mut elf = vec![0u8; 64];
+    elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+    let mut names = vec![0u8];
+    let mut headers = vec![[0u8; 64]];
+    let section = |name: usize, kind: u32, offset: usize, size: usize| {
+        let mut header = [0u8; 64];
+        header[0..4].copy_from_slice(&(name as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&kind.to_le_bytes());
+        header[24..32].copy_from_slice(&(offset as u64).to_le_bytes());
+        header[32..40].copy_from_slice(&(size as u64).to_le_bytes());
+        header
+    };
+    for &(name, kind, content) in sections {
+        let name_offset = names.len();
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+        if kind == SHT_NOBITS {
+            headers.push(section(name_offset, kind, 0, 1000));
+        } else {
+            headers.push(section(name_offset, kind, elf.len(), content.len()));
+            elf.extend_from_slice(content);
+        }
+    }
+    let name_offset = names.len();
+    names.extend_from_slice(b".shstrtab\0");
+    headers.push(section(name_offset, 3, elf.len(), names.len()));
+    elf.extend_from_slice(&names);
+    let shoff = elf.len() as u64;
+    elf[0x28..0x30].copy_from_slice(&shoff.to_le_bytes());
+    elf[0x3a..0x3c].copy_from_slice(&64u16.to_le_bytes());
+    elf[0x3c..0x3e].copy_from_slice(&(headers.len() as u16).to_le_bytes());
+    elf[0x3e..0x40].copy_from_slice(&(headers.len() as u16 - 1).to_le_bytes());
+    for header in headers {
+        elf.extend_from_slice(&header);
+    }
+    elf
+}
+
+#[cfg(test)]
+/// A little endian ELF64 file with a note section `.note.gnu.build-id` and an empty `.bss`
+fn make_test_elf(notes: &[u8]) -> Vec<u8> {
+    make_test_elf_with(&[
+        (".note.gnu.build-id", SHT_NOTE, notes),
+        (".bss", SHT_NOBITS, &[]),
+    ])
+}
+
+#[cfg(test)]
+pub(crate) fn make_test_note(name: &[u8], kind: u32, desc: &[u8]) -> Vec<u8> {
+    let mut note = Vec::new();
+    note.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    note.extend_from_slice(&kind.to_le_bytes());
+    note.extend_from_slice(name);
+    note.resize(note.len().next_multiple_of(4), 0);
+    note.extend_from_slice(desc);
+    note.resize(note.len().next_multiple_of(4), 0);
+    note
+}
+
+#[test]
+fn test_build_id() {
+    let build_id = [
+        0x48, 0x3b, 0xd7, 0xf7, 0x22, 0x9b, 0xdb, 0x06, 0x46, 0x22, 0x22, 0xe1, 0xe3, 0x53, 0xe4,
+        0xf3, 0x7e, 0x15, 0xc2, 0x93,
+    ];
+    let mut notes = make_test_note(b"GNU\0", 1, &[0; 16]);
+    notes.extend(make_test_note(b"GNU\0", NT_GNU_BUILD_ID, &build_id));
+    let file = std::io::Cursor::new(make_test_elf(&notes));
+    let mut elf = Elf::parse(file).unwrap();
+    assert_eq!(
+        elf.build_id().unwrap(),
+        Some(BuildId::new("483bd7f7229bdb06462222e1e353e4f37e15c293").unwrap())
+    );
+}
+
+#[test]
+fn test_no_build_id() {
+    let notes = make_test_note(b"GNU\0", 1, &[0; 16]);
+    let file = std::io::Cursor::new(make_test_elf(&notes));
+    assert_eq!(Elf::parse(file).unwrap().build_id().unwrap(), None);
+}
+
+#[test]
+fn test_section_range() {
+    let notes = make_test_note(b"GNU\0", 1, &[0; 16]);
+    let file = std::io::Cursor::new(make_test_elf(&notes));
+    let mut elf = Elf::parse(file).unwrap();
+    assert_eq!(
+        elf.section_range(".note.gnu.build-id").unwrap(),
+        Some(64..64 + notes.len() as u64)
+    );
+    assert_eq!(elf.section_range(".note.gnu").unwrap(), None);
+    assert_eq!(elf.section_range(".bss").unwrap(), None);
+    assert_eq!(elf.section_range(".text").unwrap(), None);
+
+    // the size of the first section after the null section header
+    let mut content = make_test_elf(&notes);
+    let shoff = u64::from_le_bytes(content[0x28..0x30].try_into().unwrap()) as usize;
+    let size_offset = shoff + 64 + 32;
+    for size in [notes.len() as u64 + 1_000_000, u64::MAX] {
+        content[size_offset..size_offset + 8].copy_from_slice(&size.to_le_bytes());
+        let file = std::io::Cursor::new(content.clone());
+        let mut elf = Elf::parse(file).unwrap();
+        assert!(elf.section_range(".note.gnu.build-id").is_err());
+    }
+}
+
+#[test]
+fn test_debugaltlink() {
+    let build_id = [0x48; 20];
+    let mut content = b"../../.dwz/foo-1.0.debug\0".to_vec();
+    content.extend_from_slice(&build_id);
+    let file = std::io::Cursor::new(make_test_elf_with(&[(".gnu_debugaltlink", 1, &content)]));
+    assert_eq!(
+        Elf::parse(file).unwrap().debugaltlink().unwrap(),
+        Some(DebugAltLink {
+            path: "../../.dwz/foo-1.0.debug".into(),
+            build_id: BuildId::new(&"48".repeat(20)).unwrap(),
+        })
+    );
+
+    let notes = make_test_note(b"GNU\0", 1, &[0; 16]);
+    let file = std::io::Cursor::new(make_test_elf(&notes));
+    assert_eq!(Elf::parse(file).unwrap().debugaltlink().unwrap(), None);
+
+    let file = std::io::Cursor::new(make_test_elf_with(&[(".gnu_debugaltlink", 1, b"no nul")]));
+    assert!(Elf::parse(file).unwrap().debugaltlink().is_err());
+}
+
+#[test]
+fn test_debuglink() {
+    let mut content = b"foo.debug\0\0\0".to_vec();
+    content.extend_from_slice(&0x12345678u32.to_le_bytes());
+    let file = std::io::Cursor::new(make_test_elf_with(&[(".gnu_debuglink", 1, &content)]));
+    assert_eq!(
+        Elf::parse(file).unwrap().debuglink().unwrap(),
+        Some(DebugLink {
+            file_name: "foo.debug".into(),
+            crc: 0x12345678,
+        })
+    );
+
+    let notes = make_test_note(b"GNU\0", 1, &[0; 16]);
+    let file = std::io::Cursor::new(make_test_elf(&notes));
+    assert_eq!(Elf::parse(file).unwrap().debuglink().unwrap(), None);
+
+    for invalid in [&b"foo.debug\0"[..], b"../foo.debug\0\0\0\0\0\0"] {
+        let file = std::io::Cursor::new(make_test_elf_with(&[(".gnu_debuglink", 1, invalid)]));
+        assert!(Elf::parse(file).unwrap().debuglink().is_err());
+    }
+}
+
+#[test]
+fn test_not_elf() {
+    let file = std::io::Cursor::new(vec![b'#'; 100]);
+    assert!(Elf::parse(file).is_err());
+}
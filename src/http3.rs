@@ -0,0 +1,194 @@
+//! Serving the same routes as the TCP listeners over HTTP/3, see `--http3`.
+//!
+//! QUIC mandates TLS, so the listener needs a certificate and its private key.
+
+use std::{net::SocketAddr, path::Path, sync::Arc};
+
+use anyhow::Context;
+use axum::{body::Body, extract::ConnectInfo, Router};
+use bytes::{Buf, Bytes, BytesMut};
+use http::{Request, Response, StatusCode};
+use http_body_util::BodyExt;
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+/// Request bodies larger than this are refused, like axum does by default for the TCP listeners
+const MAX_REQUEST_BODY: usize = 2 << 20;
+
+/// An HTTP/3 connection
+type H3Connection = h3::server::Connection<h3_quinn::Connection, Bytes>;
+
+/// A request stream of an HTTP/3 connection
+type H3Stream = h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+/// Opens a QUIC endpoint on the udp address `addr`, presenting the certificate chain of the PEM
+/// file `certificate` and the private key of the PEM file `key`.
+pub fn bind(addr: SocketAddr, certificate: &Path, key: &Path) -> anyhow::Result<quinn::Endpoint> {
+    let certificates = CertificateDer::pem_file_iter(certificate)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("reading certificates from {}", certificate.display()))?;
+    anyhow::ensure!(
+        !certificates.is_empty(),
+        "{} contains no certificate",
+        certificate.display()
+    );
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("reading private key from {}", key.display()))?;
+    let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS13])
+    .context("configuring TLS 1.3")?
+    .with_no_client_auth()
+    .with_single_cert(certificates, key)
+    .context("loading the certificate and private key")?;
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls)
+        .context("configuring QUIC with TLS")?;
+    let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    quinn::Endpoint::server(config, addr).with_context(|| format!("opening udp socket on {addr}"))
+}
+
+/// Serves `app` on the connections accepted by `endpoint`, until it is closed.
+///
+/// Like for the TCP listeners, handlers can get the address of the client with [ConnectInfo].
+pub async fn serve(endpoint: quinn::Endpoint, app: Router) -> std::io::Result<()> {
+    while let Some(incoming) = endpoint.accept().await {
+        let app = app.clone();
+        tokio::spawn(async move {
+            let remote = incoming.remote_address();
+            if let Err(e) = serve_connection(incoming, app).await {
+                tracing::debug!("http3 connection from {remote} failed: {e:#}");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Serves the requests of one QUIC connection, each in a task of its own.
+async fn serve_connection(incoming: quinn::Incoming, app: Router) -> anyhow::Result<()> {
+    let connection = incoming.await.context("QUIC handshake")?;
+    let remote = connection.remote_address();
+    let mut connection: H3Connection =
+        h3::server::Connection::new(h3_quinn::Connection::new(connection))
+            .await
+            .context("HTTP/3 handshake")?;
+    while let Some(resolver) = connection.accept().await.context("accepting request")? {
+        let app = app.clone();
+        tokio::spawn(async move {
+            let result = async {
+                let (request, stream) = resolver
+                    .resolve_request()
+                    .await
+                    .context("reading request headers")?;
+                serve_request(request, stream, app, remote).await
+            };
+            if let Err(e) = result.await {
+                tracing::debug!("http3 request from {remote} failed: {e:#}");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Reads the body of `request` from `stream`, and sends the response of `app` to it.
+async fn serve_request(
+    request: Request<()>,
+    mut stream: H3Stream,
+    app: Router,
+    remote: SocketAddr,
+) -> anyhow::Result<()> {
+    let mut body = BytesMut::new();
+    while let Some(mut chunk) = stream.recv_data().await.context("reading request body")? {
+        if body.len() + chunk.remaining() > MAX_REQUEST_BODY {
+            let response = Response::builder()
+                .status(StatusCode::PAYLOAD_TOO_LARGE)
+                .body(())?;
+            stream.send_response(response).await?;
+            return Ok(stream.finish().await?);
+        }
+        body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    let (parts, ()) = request.into_parts();
+    let mut request = Request::from_parts(parts, Body::from(body.freeze()));
+    request.extensions_mut().insert(ConnectInfo(remote));
+    let response = match tower_service::Service::call(&mut app.clone(), request).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    };
+    let (parts, mut body) = response.into_parts();
+    stream
+        .send_response(Response::from_parts(parts, ()))
+        .await
+        .context("sending response headers")?;
+    while let Some(frame) = body.frame().await {
+        let frame = frame.context("producing response body")?;
+        if let Ok(data) = frame.into_data() {
+            stream
+                .send_data(data)
+                .await
+                .context("sending response body")?;
+        }
+    }
+    stream.finish().await.context("finishing response")?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_http3_roundtrip() {
+    use crate::test_utils::fixture;
+    use axum::routing::get;
+
+    let certificate = fixture("http3_certificate.pem");
+    let endpoint = bind(
+        "127.0.0.1:0".parse().unwrap(),
+        &certificate,
+        &fixture("http3_key.pem"),
+    )
+    .unwrap();
+    let addr = endpoint.local_addr().unwrap();
+    let app = Router::new().route(
+        "/peer",
+        get(|ConnectInfo(peer): ConnectInfo<SocketAddr>| async move { peer.ip().to_string() }),
+    );
+    tokio::spawn(serve(endpoint, app));
+
+    let mut roots = rustls::RootCertStore::empty();
+    for certificate in CertificateDer::pem_file_iter(&certificate).unwrap() {
+        roots.add(certificate.unwrap()).unwrap();
+    }
+    let mut tls = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS13])
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    let mut client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+    client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+        quinn::crypto::rustls::QuicClientConfig::try_from(tls).unwrap(),
+    )));
+    let connection = client.connect(addr, "localhost").unwrap().await.unwrap();
+    let (mut driver, mut send_request) = h3::client::new(h3_quinn::Connection::new(connection))
+        .await
+        .unwrap();
+    tokio::spawn(async move { driver.wait_idle().await });
+
+    for (path, status, expected) in [
+        ("/peer", StatusCode::OK, "127.0.0.1"),
+        ("/missing", StatusCode::NOT_FOUND, ""),
+    ] {
+        let request = Request::get(format!("https://localhost{path}"))
+            .body(())
+            .unwrap();
+        let mut stream = send_request.send_request(request).await.unwrap();
+        stream.finish().await.unwrap();
+        let response = stream.recv_response().await.unwrap();
+        assert_eq!(response.status(), status, "{path}");
+        let mut body = Vec::new();
+        while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+            body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+        }
+        assert_eq!(String::from_utf8(body).unwrap(), expected, "{path}");
+    }
+}
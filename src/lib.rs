@@ -0,0 +1,532 @@
+//! A debuginfod server suitable to serve debug symbols from nix substituters.
+//!
+//! ### Architecture
+//!
+//! Support for various kinds of substituters is in [substituter].
+//!
+//! Substituters should not be queries too often for the same store path so a cache implementation
+//! is provided in `cache::FetcherCache`.
+//!
+//! The logic mapping build ids to debug symbols, sources, etc. and which is
+//! substituter-independent is in [Debuginfod].
+//!
+//! Functions in [Debuginfod] are reexposed as a server in `server`, and can be used to populate
+//! the cache ahead of time in `prefetch`. `resolve_pid` checks which libraries of a running
+//! process have debug symbols, and `check_cache` checks that a binary cache is set up correctly.
+//! [run] is the entry point of the `nixseparatedebuginfod2` executable.
+//!
+//! ### Library
+//!
+//! Besides [run] and its [Options], the library only exposes what programs looking up debug
+//! symbols themselves need: [Debuginfod] and the [Substituter]s it queries. With the
+//! `test-support` feature, [substituter::memory] provides a substituter for their tests.
+
+#![warn(missing_docs)]
+
+use std::{net::SocketAddr, num::NonZeroUsize, path::PathBuf, time::Duration};
+
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use reqwest::Url;
+use tracing_subscriber::prelude::*;
+
+mod archive_cache;
+mod build_id;
+mod cache;
+mod check_cache;
+mod debuginfod;
+mod derivation;
+mod elf;
+#[cfg(feature = "http3")]
+mod http3;
+mod nar;
+mod prefetch;
+mod rate_limit;
+mod resolve_pid;
+mod server;
+mod settings;
+mod source_selection;
+mod store_path;
+pub mod substituter;
+mod tar;
+mod utils;
+mod vfs;
+
+pub use build_id::BuildId;
+pub use debuginfod::Debuginfod;
+pub use settings::Settings;
+pub use store_path::StorePath;
+pub use substituter::Substituter;
+
+#[cfg(test)]
+mod test_utils;
+
+/// A debuginfod implementation that fetches debuginfo and sources from nix substituters
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Options {
+    /// Address for the server
+    ///
+    /// If omitted, systemd socket activation is expected.
+    #[arg(short, long)]
+    listen_address: Option<SocketAddr>,
+    /// How many times to retry opening `--listen-address` when it is still in use, for example
+    /// by a previous instance which is being restarted.
+    ///
+    /// Attempts are 500ms apart. Other errors are not retried.
+    #[arg(long, default_value_t = 0)]
+    bind_retry: u32,
+    /// Also serve over HTTP/3 on this udp address, for example `[::]:1949`, in addition to the
+    /// tcp listeners.
+    ///
+    /// QUIC requires TLS: `--tls-certificate` and `--tls-key` must be specified. Responses of the
+    /// tcp listeners advertise it with an `Alt-Svc` header.
+    #[cfg(feature = "http3")]
+    #[arg(long, requires_all = ["tls_certificate", "tls_key"])]
+    http3: Option<SocketAddr>,
+    /// PEM file containing the certificate chain presented by the `--http3` listener
+    #[cfg(feature = "http3")]
+    #[arg(long)]
+    tls_certificate: Option<PathBuf>,
+    /// PEM file containing the private key of `--tls-certificate`
+    #[cfg(feature = "http3")]
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+    /// Url under which clients reach the server, for example when it is behind a reverse proxy.
+    ///
+    /// Used for urls in responses, like in the usage examples served at `/`. Defaults to the
+    /// address the server listens on.
+    #[arg(long)]
+    public_url: Option<Url>,
+    /// Serve everything under this path, like `/debuginfod`, instead of at the root.
+    ///
+    /// For reverse proxies which forward a subpath to the server without stripping it. The admin
+    /// socket of `--admin-listen` is not affected.
+    #[arg(long, value_parser = server::parse_path_prefix)]
+    path_prefix: Option<String>,
+    /// Substituter containing the debug symbols.
+    ///
+    /// Can be specified several times, all subsituters will be tried in sequence.
+    ///
+    /// Supported subsituter URLs:
+    ///
+    /// - `local:` to serve debug symbols already present in the local store
+    ///
+    /// - `https://cache.nixos.org` for example for http substituters (aka http binary caches)
+    ///
+    /// - `file:///some/dir` for directories created by `nix copy ... --to
+    /// file:///some/dir?index-debug-info`
+    ///
+    /// - `ipfs://<cid>` or `ipns://<name>` for binary caches published on IPFS, fetched through
+    ///   `--ipfs-gateway`
+    ///
+    /// - `debuginfod-cache:///home/user/.cache/debuginfod_client` to reuse what elfutils clients
+    ///   like `debuginfod-find` already downloaded
+    ///
+    /// - `unpacked:///some/dir` for directories containing the `debuginfo`, `executable` and
+    ///   `source` of each build id in `<first 2 hex digits>/<other hex digits>/`, or in the
+    ///   directory given by `?layout={id_prefix}{id_rest}` for example
+    #[arg(short, long)]
+    substituter: Vec<Url>,
+    /// File containing substituter urls, one per line, added after those passed with
+    /// `--substituter`.
+    ///
+    /// Blank lines and lines starting with `#` are ignored.
+    #[arg(long)]
+    substituters_file: Option<PathBuf>,
+    /// Directory where files downloaded from the substituter are stored
+    #[arg(short, long, default_value_t = default_cache_directory())]
+    cache_dir: String,
+    /// Cache directory of another instance, typically shared on NFS, where files are looked for
+    /// when they are not in `--cache-dir`. It is only read; may be repeated.
+    #[arg(long)]
+    shared_cache_dir: Vec<PathBuf>,
+    /// Program run after each file or directory is fetched into the cache, with a name
+    /// identifying it and its path in the cache as arguments, for example to log or sign it.
+    ///
+    /// If the program exits with a non-zero status, the fetch fails and nothing is cached.
+    #[arg(long)]
+    post_fetch_command: Option<PathBuf>,
+    /// Instead of removing what a failed fetch downloaded or unpacked, move it to a `failed`
+    /// directory in the cache, and log where, for debugging.
+    #[arg(long)]
+    keep_failed_fetches: bool,
+    /// Store the debug files fetched into the cache compressed with zstd, to save disk space.
+    ///
+    /// They are decompressed when served, at the cost of some CPU time: range requests decompress
+    /// the file up to the end of the range, and requests for sections or build id checks
+    /// (`--verify-build-id`) read a decompressed copy, kept in the cache directory until 1GiB of
+    /// more recently used copies evict it.
+    ///
+    /// Debug files stored compressed are not found anymore once this option is turned off, until
+    /// their cache entries expire.
+    #[arg(long)]
+    compress_cache: bool,
+    /// How many cache entries the periodic cleanup examines at once, before pausing to let
+    /// requests through.
+    #[arg(long, default_value_t = 1000)]
+    cleanup_batch_size: usize,
+    /// How long a fetched file should be kept in cache. Only a rough indication.
+    ///
+    /// Accepted syntax: `1 day` `3s` `15 minutes` etc.
+    #[arg(short, long, value_parser = humantime::parse_duration)]
+    expiration: Duration,
+    /// Never download anything: only serve what is already in the cache directory, and what
+    /// `local:` and `file://` substituters provide.
+    ///
+    /// Cached files still expire according to `--expiration`.
+    #[arg(long, alias = "read-only-cache")]
+    offline: bool,
+    /// Refuse to start the server if a substituter is unreachable at startup.
+    ///
+    /// Without this flag, unreachable substituters are only logged as warnings.
+    #[arg(long)]
+    check_substituters: bool,
+    /// Appended to the User-Agent of requests to http substituters, to let cache operators
+    /// identify this deployment.
+    #[arg(long)]
+    user_agent_suffix: Option<String>,
+    /// Proxy through which all http substituters are fetched, instead of those of the
+    /// `HTTP_PROXY`, `HTTPS_PROXY` and `ALL_PROXY` environment variables.
+    ///
+    /// A substituter url can specify its own proxy with a `?proxy=` query param, for example
+    /// `https://cache.example.org?proxy=http://proxy.example.org:3128`.
+    #[arg(long)]
+    proxy: Option<Url>,
+    /// Comma separated hosts, domains and ip ranges which are fetched without proxy, instead of
+    /// those of the `NO_PROXY` environment variable.
+    ///
+    /// Applies to all proxies, including those of the environment and of `?proxy=`.
+    #[arg(long)]
+    no_proxy: Option<String>,
+    /// Header sent with all requests to the http substituters at some url or under it, as
+    /// `URL=Name: Value`, for example to authenticate to a cache behind an access proxy. Can be
+    /// repeated.
+    ///
+    /// `${VAR}` in the value is replaced by the environment variable `VAR`, so that secrets are
+    /// not visible in the command line.
+    #[arg(long, value_parser = substituter::http::SubstituterHeader::parse)]
+    substituter_header: Vec<substituter::http::SubstituterHeader>,
+    /// Whether to negotiate HTTP/2 with https substituters supporting it, so that concurrent
+    /// requests to the same cache share one connection.
+    ///
+    /// Set to false to only use HTTP/1.1.
+    #[arg(long, action = clap::ArgAction::Set, default_value_t = true)]
+    http2: bool,
+    /// How long idle connections to http substituters are kept open for later requests.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "90s")]
+    pool_idle_timeout: Duration,
+    /// How many idle connections to each http substituter are kept open for later requests.
+    #[arg(long, default_value_t = 32)]
+    pool_max_idle_per_host: usize,
+    /// Http gateway through which `ipfs://` and `ipns://` substituters are fetched.
+    #[arg(long, default_value = "http://127.0.0.1:8080")]
+    ipfs_gateway: Url,
+    /// Copy store paths served from the local store (`local:`) into the cache directory instead
+    /// of serving them from `/nix/store` directly.
+    ///
+    /// Slower and uses more disk space, but what was served once survives garbage collection of
+    /// the store until it expires according to `--expiration`.
+    #[arg(long)]
+    copy_into_cache: bool,
+    /// File listing build ids, one per line, whose debuginfo is fetched into the cache in the
+    /// background as soon as the server listens.
+    ///
+    /// Blank lines and lines starting with `#` are ignored. Failures are only logged.
+    #[arg(long)]
+    warm_list: Option<PathBuf>,
+    /// Do not serve source files at all: source requests fail with 404 and source archives are
+    /// never unpacked.
+    ///
+    /// Useful for deployments which only serve debuginfo and executables.
+    #[arg(long)]
+    no_sources: bool,
+    /// When looking for a source file, also look in the store paths that symlinks inside the
+    /// source directory point to, fetching them from the substituters as needed.
+    ///
+    /// Useful for source trees aggregated from several store paths, at the cost of more downloads.
+    #[arg(long)]
+    follow_source_symlinks: bool,
+    /// When the source tree contains more files with the requested name than this, like
+    /// `Makefile` in a huge project, only serve one whose path matches the request exactly
+    /// instead of the closest one.
+    ///
+    /// Bounds the time spent ranking candidates.
+    #[arg(long, default_value_t = source_selection::DEFAULT_MAX_SOURCE_MATCH_CANDIDATES)]
+    max_source_match_candidates: usize,
+    /// When a source request designates a directory, answer with the names of its entries as
+    /// JSON instead of 404.
+    ///
+    /// Meant for interactive use, to find the path of a source file.
+    #[arg(long)]
+    browse: bool,
+    /// Accept truncated build ids (at least 8 hexadecimal characters) in requests, and serve the
+    /// only build id starting with them, if `local:` or `file://` substituters know exactly one.
+    ///
+    /// When several build ids match, the request fails with 409 conflict.
+    #[arg(long)]
+    allow_prefix_match: bool,
+    /// Refuse requests of clients making more than this many requests per second on average,
+    /// with 429 too many requests.
+    ///
+    /// Clients are identified by their ip address, see `--trust-proxy`.
+    #[arg(long)]
+    rate_limit: Option<f64>,
+    /// How many requests a client can make at once before `--rate-limit` applies.
+    ///
+    /// Defaults to the rate limit rounded up.
+    #[arg(long, requires = "rate_limit")]
+    rate_limit_burst: Option<u32>,
+    /// Identify clients by the last address of the `X-Forwarded-For` header for `--rate-limit`,
+    /// when the server is behind a reverse proxy which sets it.
+    ///
+    /// Without a reverse proxy, clients could set this header themselves.
+    #[arg(long)]
+    trust_proxy: bool,
+    /// Before serving a debuginfo, check that its `.note.gnu.build-id` section contains the
+    /// requested build id, and answer 404 otherwise.
+    ///
+    /// Protects against wrongly indexed binary caches, at the cost of reading each served file.
+    #[arg(long)]
+    verify_build_id: bool,
+    /// When the executable of a build id cannot be found, for example because it was garbage
+    /// collected from the local store and no other substituter has it, serve its debuginfo
+    /// instead.
+    #[arg(long)]
+    executable_fallback_to_debuginfo: bool,
+    /// When the debug output of a build id links to its executable but does not contain its
+    /// debuginfo, serve the debug file named in the `.gnu_debuglink` section of the executable,
+    /// if its CRC matches.
+    #[arg(long)]
+    follow_debuglink: bool,
+    /// Where debug outputs contain the debuginfo of a build id, relative to their root.
+    /// `{id_prefix}` stands for the first two characters of the build id and `{id_rest}` for
+    /// the others.
+    #[arg(long, value_parser = build_id::PathTemplate::new, default_value = build_id::DEFAULT_DEBUG_PATH_TEMPLATE)]
+    debug_path_template: build_id::PathTemplate,
+    /// Enables the `/admin/...` endpoints, which must then be queried with the header
+    /// `Authorization: Bearer <token>`.
+    ///
+    /// Note that the token is visible to other users of the machine in the command line.
+    #[arg(long)]
+    admin_token: Option<String>,
+    /// Serves the `/admin/...` endpoints on this unix socket, for example
+    /// `unix:/run/nixseparatedebuginfod2/admin.sock`, instead of the public listen address.
+    ///
+    /// Permissions of the socket then control who can use them, and `--admin-token` is not needed
+    /// on it.
+    #[arg(long, value_parser = server::parse_admin_listen)]
+    admin_listen: Option<PathBuf>,
+    /// Only serve these build ids (and those of `--allow-build-ids-file`), answering 403 for
+    /// others. Can be repeated.
+    #[arg(long, value_parser = BuildId::new)]
+    allow_build_id: Vec<BuildId>,
+    /// Never serve this build id, answering 403 instead, even if it is allowed. Can be repeated.
+    #[arg(long, value_parser = BuildId::new)]
+    deny_build_id: Vec<BuildId>,
+    /// Like `--allow-build-id` for the build ids listed in this file, one per line.
+    ///
+    /// Blank lines and lines starting with `#` are ignored.
+    #[arg(long)]
+    allow_build_ids_file: Option<PathBuf>,
+    /// Like `--deny-build-id` for the build ids listed in this file, one per line.
+    ///
+    /// Blank lines and lines starting with `#` are ignored.
+    #[arg(long)]
+    deny_build_ids_file: Option<PathBuf>,
+    /// How many nars may be decompressed and unpacked at the same time, each on its own thread.
+    ///
+    /// Defaults to the number of CPUs.
+    #[arg(long)]
+    decompress_threads: Option<NonZeroUsize>,
+    /// Refuse to unpack nars larger than this once decompressed, so that a small maliciously
+    /// crafted archive cannot fill the disk.
+    ///
+    /// Accepted syntax: `4096`, `512MiB`, `4G` etc.
+    #[arg(long, value_parser = utils::parse_size, default_value = "4GiB")]
+    max_nar_size: u64,
+    /// Refuse to decompress nars compressed with formats outside this comma separated list of
+    /// `none`, `xz` and `zstd`, for example `--allowed-compression zstd,none` to never run the xz
+    /// decompressor.
+    ///
+    /// Defaults to all formats.
+    #[arg(long, value_parser = utils::CompressionSet::parse)]
+    allowed_compression: Option<utils::CompressionSet>,
+    /// Refuse to unpack source archives whose files add up to more than this, so that a giant
+    /// source archive cannot fill the disk.
+    ///
+    /// Accepted syntax: `4096`, `512MiB`, `4G` etc.
+    #[arg(long, value_parser = utils::parse_size, default_value = "8GiB")]
+    max_source_unpack_size: u64,
+    /// Refuse to read metadata files of binary caches (narinfo, json redirects to debuginfo)
+    /// larger than this.
+    ///
+    /// Accepted syntax: `4096`, `512KiB`, `2M` etc.
+    #[arg(long, value_parser = utils::parse_size, default_value = "1MiB")]
+    max_metadata_size: u64,
+    /// Answer 406 not acceptable instead of serving debuginfo larger than this, to save bandwidth.
+    /// elfutils clients understand it as the file being too large.
+    ///
+    /// Also applies to executables and source files, unless `--max-executable-response-size`
+    /// or `--max-source-response-size` are specified. Accepted syntax: `4096`, `512MiB`, `4G` etc.
+    #[arg(long, value_parser = utils::parse_size)]
+    max_response_size: Option<u64>,
+    /// Like `--max-response-size`, for executables.
+    #[arg(long, value_parser = utils::parse_size)]
+    max_executable_response_size: Option<u64>,
+    /// Like `--max-response-size`, for source files.
+    #[arg(long, value_parser = utils::parse_size)]
+    max_source_response_size: Option<u64>,
+    /// How long clients and http caches may keep debuginfo and executables, in the
+    /// `Cache-Control` header. They only depend on the build id, so they are marked immutable.
+    ///
+    /// Accepted syntax: `1 day` `3s` `15 minutes` etc.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "365days")]
+    max_age: Duration,
+    /// Like `--max-age`, but for source files, which are found by heuristics that may improve.
+    ///
+    /// Source files requested by store path (`/nix/store/...`) use `--max-age` instead.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1day")]
+    source_max_age: Duration,
+    /// Log more: `-v` logs debug messages, `-vv` trace messages, `-vvv` trace messages of
+    /// dependencies too.
+    ///
+    /// Ignored when the `RUST_LOG` environment variable is set.
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Only log warnings and errors.
+    ///
+    /// Ignored when the `RUST_LOG` environment variable is set.
+    #[arg(short, long)]
+    quiet: bool,
+    /// What to do. Runs the server if omitted.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Alternative actions to running the server
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Download debuginfo, executable and sources for these build ids into the cache and exit
+    ///
+    /// Useful to populate the cache before going offline.
+    Prefetch {
+        /// Build id to prefetch. Can be specified several times.
+        #[arg(short, long = "build-id", required = true)]
+        build_id: Vec<String>,
+    },
+    /// Print the build id of each ELF file mapped by a running process, and whether its
+    /// debuginfo is available, then exit
+    ///
+    /// Debuginfo which is found is downloaded into the cache.
+    ResolvePid {
+        /// Process id of the running process
+        pid: u32,
+    },
+    /// Check that a binary cache can serve debuginfo, print a summary and exit
+    ///
+    /// Debug outputs are downloaded and unpacked into a temporary directory of the cache
+    /// directory. `--substituter` is not needed.
+    CheckCache {
+        /// Url of the binary cache, as for `--substituter`
+        url: Url,
+        /// Build id whose debuginfo is checked. Can be specified several times.
+        ///
+        /// Mandatory unless the binary cache is a `file://` url, whose build ids are listed.
+        #[arg(short, long = "build-id", value_parser = BuildId::new)]
+        build_id: Vec<BuildId>,
+        /// How many of the listed build ids of a `file://` binary cache are checked
+        #[arg(long, default_value_t = 10)]
+        sample: usize,
+    },
+}
+
+fn default_cache_directory() -> String {
+    let parent = std::env::var("XDG_CACHE_HOME").unwrap_or_else(|_| {
+        std::env::var("CACHE_DIRECTORY").unwrap_or_else(|_| {
+            std::env::var("HOME")
+                .map(|x| x + "/.cache")
+                .unwrap_or_else(|_| "/tmp".into())
+        })
+    });
+
+    // the directory returned by this function may be wiped, so when modifying this code ensure it
+    // cannot return $HOME or something like that
+    const MYNAME: &str = env!("CARGO_PKG_NAME");
+    const {
+        assert!(!MYNAME.is_empty());
+    }
+
+    format!("{parent}/{}", MYNAME)
+}
+
+/// The log filter to use when `RUST_LOG` is not set
+fn default_log_filter(verbose: u8, quiet: bool) -> &'static str {
+    match (quiet, verbose) {
+        (true, _) => "nixseparatedebuginfod2=warn,tower_http=warn",
+        (false, 0) => "nixseparatedebuginfod2=info,tower_http=debug",
+        (false, 1) => "nixseparatedebuginfod2=debug,tower_http=debug",
+        (false, 2) => "nixseparatedebuginfod2=trace,tower_http=debug",
+        (false, _) => "trace",
+    }
+}
+
+#[test]
+fn test_verbosity_flags() {
+    let parse = |flags: &[&str]| {
+        let args = Options::try_parse_from(
+            ["nixseparatedebuginfod2", "-e", "1d", "-s", "local:"]
+                .iter()
+                .chain(flags),
+        )?;
+        Ok::<_, clap::Error>(default_log_filter(args.verbose, args.quiet))
+    };
+    assert_eq!(
+        parse(&[]).unwrap(),
+        "nixseparatedebuginfod2=info,tower_http=debug"
+    );
+    assert_eq!(
+        parse(&["-vv"]).unwrap(),
+        "nixseparatedebuginfod2=trace,tower_http=debug"
+    );
+    assert_eq!(parse(&["-v", "-v", "-v", "-v"]).unwrap(), "trace");
+    assert_eq!(
+        parse(&["--quiet"]).unwrap(),
+        "nixseparatedebuginfod2=warn,tower_http=warn"
+    );
+    parse(&["-q", "-v"]).unwrap_err();
+}
+
+/// Runs the server, or the subcommand, specified by the command line arguments `args`, after
+/// setting up logging.
+pub async fn run(args: Options) -> anyhow::Result<()> {
+    let filter = std::env::var("RUST_LOG")
+        .unwrap_or_else(|_| default_log_filter(args.verbose, args.quiet).to_owned());
+    let fmt_layer = tracing_subscriber::fmt::layer().without_time().with_filter(
+        tracing_subscriber::EnvFilter::builder()
+            .parse(&filter)
+            .context("parsing RUST_LOG env var")?,
+    );
+    let registry = tracing_subscriber::registry().with(fmt_layer);
+
+    #[cfg(feature = "tokio-console")]
+    let registry = registry.with(console_subscriber::spawn());
+    #[cfg(feature = "tracing-chrome")]
+    let (chrome_layer, _guard) = tracing_chrome::ChromeLayerBuilder::new().build();
+    #[cfg(feature = "tracing-chrome")]
+    let registry = registry.with(chrome_layer);
+
+    registry.init();
+
+    anyhow::ensure!(matches!(args.command, Some(Command::CheckCache { .. })) || !args.substituter.is_empty() || args.substituters_file.is_some(), "no substituter specified with --substituter option. Pass `--substituter local: --substituter https://cache.nixos.org` for example.");
+    match args.command {
+        None => server::run_server(args).await,
+        Some(Command::Prefetch { ref build_id }) => prefetch::run_prefetch(&args, build_id).await,
+        Some(Command::ResolvePid { pid }) => resolve_pid::run_resolve_pid(&args, pid).await,
+        Some(Command::CheckCache {
+            ref url,
+            ref build_id,
+            sample,
+        }) => check_cache::run_check_cache(&args, url, build_id, sample).await,
+    }
+}
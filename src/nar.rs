@@ -1,41 +1,483 @@
 //! utilities about NAR files (nix archives)
+//!
+//! Nars are unpacked in process by [unpack_compressed_nar], so `nix-store` does
+//! not need to be installed.
 use anyhow::Context;
 use futures::StreamExt;
-use nix_nar::Decoder;
-use std::pin::pin;
+use nix_nar::{Content, Decoder, NarError};
+use std::cell::RefCell;
+use std::fs::{OpenOptions, Permissions};
+use std::future::Future;
+use std::io::Read;
+use std::num::NonZeroUsize;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, PathBuf};
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Instant;
 use std::{path::Path, time::Duration};
-use tokio::io::{AsyncBufRead, AsyncRead};
+use tokio::io::{AsyncBufRead, AsyncRead, ReadBuf};
+use tokio::sync::Semaphore;
 use tokio_util::codec::{FramedRead, LinesCodec};
 
+use crate::utils::{CompressionSet, DecompressingReader};
+
+/// Default of [UnpackSettings::max_nar_size]
+pub const DEFAULT_MAX_NAR_SIZE: u64 = 4 << 30;
+
+/// How nars are decompressed and unpacked, see [unpack_compressed_nar]
+#[derive(Debug, Clone)]
+pub struct UnpackSettings {
+    /// Nars larger than this once decompressed are rejected, so that a small maliciously crafted
+    /// compressed nar cannot fill the disk.
+    pub max_nar_size: u64,
+    /// Nars compressed with other formats are rejected, for example to avoid running a
+    /// decompressor one does not trust.
+    pub allowed_compressions: CompressionSet,
+    /// Limits how many nars are decompressed and unpacked at the same time, each on its own
+    /// blocking thread, see [unpack_permits]. Shared by all clones of these settings.
+    pub permits: Arc<Semaphore>,
+}
+
+impl Default for UnpackSettings {
+    fn default() -> Self {
+        Self {
+            max_nar_size: DEFAULT_MAX_NAR_SIZE,
+            allowed_compressions: CompressionSet::ALL,
+            permits: unpack_permits(None),
+        }
+    }
+}
+
+/// Permits to unpack `threads` nars at the same time, or as many as there are CPUs if None.
+pub fn unpack_permits(threads: Option<NonZeroUsize>) -> Arc<Semaphore> {
+    let threads = threads.map_or_else(
+        || std::thread::available_parallelism().map_or(4, NonZeroUsize::get),
+        NonZeroUsize::get,
+    );
+    Arc::new(Semaphore::new(threads))
+}
+
+/// Where the time went while fetching and unpacking compressed nars.
+///
+/// Timings are measured inside the streaming pipeline: `network` is the time the unpacker waited
+/// for compressed bytes, `decompress` the rest of the time spent reading decompressed bytes, and
+/// `restore` the time spent writing files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UnpackTimings {
+    /// how many nars were unpacked
+    pub nars: u64,
+    /// time spent waiting for the substituter
+    pub network: Duration,
+    /// time spent decompressing
+    pub decompress: Duration,
+    /// time spent writing unpacked files
+    pub restore: Duration,
+    /// size of the nars as downloaded
+    pub compressed_bytes: u64,
+    /// size of the nars once decompressed
+    pub decompressed_bytes: u64,
+}
+
+impl UnpackTimings {
+    fn add(&mut self, other: &UnpackTimings) {
+        self.nars += other.nars;
+        self.network += other.network;
+        self.decompress += other.decompress;
+        self.restore += other.restore;
+        self.compressed_bytes += other.compressed_bytes;
+        self.decompressed_bytes += other.decompressed_bytes;
+    }
+
+    /// Logs these timings, and adds them to those gathered by [collect_unpack_timings], if
+    /// called from there.
+    pub fn record(&self, nar_name: &str) {
+        tracing::debug!(
+            nar = nar_name,
+            network_ms = self.network.as_millis(),
+            decompress_ms = self.decompress.as_millis(),
+            restore_ms = self.restore.as_millis(),
+            compressed_bytes = self.compressed_bytes,
+            decompressed_bytes = self.decompressed_bytes,
+            "unpacked nar"
+        );
+        self.add_to_collected();
+    }
+
+    /// Adds these timings to those collected by the enclosing [collect_unpack_timings], if any.
+    ///
+    /// Tasks spawned by the future passed to [collect_unpack_timings] do not inherit it: they
+    /// must collect their timings themselves, and the spawner add them with this function.
+    pub fn add_to_collected(&self) {
+        let _ = COLLECTED_TIMINGS.try_with(|collected| collected.borrow_mut().add(self));
+    }
+}
+
+tokio::task_local! {
+    static COLLECTED_TIMINGS: RefCell<UnpackTimings>;
+}
+
+/// Runs `future`, and returns the sum of the [UnpackTimings] recorded while it ran.
+///
+/// Only nars unpacked by this task are counted: a nar that another request was already fetching
+/// is not.
+///
+/// Calls can be nested: the timings collected by the inner call are also counted by the outer
+/// one.
+pub async fn collect_unpack_timings<F: Future>(future: F) -> (F::Output, UnpackTimings) {
+    let (output, timings) = COLLECTED_TIMINGS
+        .scope(RefCell::new(UnpackTimings::default()), async {
+            let output = future.await;
+            (
+                output,
+                COLLECTED_TIMINGS.with(|collected| *collected.borrow()),
+            )
+        })
+        .await;
+    timings.add_to_collected();
+    (output, timings)
+}
+
+/// The nar is empty, truncated or not a nar at all, as opposed to failing to be written to disk.
+///
+/// Put in the chain of the [anyhow::Error] returned when unpacking fails, see [is_invalid_nar].
+#[derive(Debug)]
+pub struct InvalidNarError(pub String);
+
+impl std::fmt::Display for InvalidNarError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidNarError {}
+
+/// Whether unpacking a nar failed because of its content, for example because the substituter
+/// served an empty file, rather than because of the local machine.
+pub fn is_invalid_nar(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<InvalidNarError>().is_some()
+}
+
+/// Whether `error`, returned by the thread unpacking a nar after reading `decompressed_bytes`,
+/// comes from the content of the nar.
+fn is_malformed(error: &anyhow::Error, decompressed_bytes: u64) -> bool {
+    // nothing could be written before reading the magic of the nar
+    decompressed_bytes == 0
+        || error.chain().any(|source| {
+            matches!(
+                source.downcast_ref::<NarError>(),
+                Some(NarError::ParseError(_))
+            ) || source
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+        })
+}
+
+/// Counters shared by the stages of [unpack_nar_named]
+#[derive(Default)]
+struct UnpackCounters {
+    /// nanoseconds spent waiting for compressed bytes
+    waiting: AtomicU64,
+    /// nanoseconds spent reading decompressed bytes, including `waiting`
+    reading: AtomicU64,
+    compressed_bytes: AtomicU64,
+    decompressed_bytes: AtomicU64,
+}
+
+impl UnpackCounters {
+    fn add_time(counter: &AtomicU64, duration: Duration) {
+        counter.fetch_add(
+            duration.as_nanos().try_into().unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    fn get_time(counter: &AtomicU64) -> Duration {
+        Duration::from_nanos(counter.load(Ordering::Relaxed))
+    }
+}
+
+/// Measures how long reads of compressed bytes are pending, and how many bytes are read.
+struct WaitTimingReader<R> {
+    inner: R,
+    waiting_since: Option<Instant>,
+    counters: Arc<UnpackCounters>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for WaitTimingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        match result {
+            Poll::Pending => {
+                this.waiting_since.get_or_insert_with(Instant::now);
+            }
+            Poll::Ready(_) => {
+                if let Some(since) = this.waiting_since.take() {
+                    UnpackCounters::add_time(&this.counters.waiting, since.elapsed());
+                }
+                let read = (buf.filled().len() - before) as u64;
+                this.counters
+                    .compressed_bytes
+                    .fetch_add(read, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+}
+
+/// Fails reads once more than `limit` bytes were read, see [UnpackSettings::max_nar_size].
+struct SizeLimitedReader<R> {
+    inner: R,
+    read: u64,
+    limit: u64,
+}
+
+impl<R: Read> Read for SizeLimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read += read as u64;
+        if self.read > self.limit {
+            return Err(std::io::Error::other(format!(
+                "decompressed nar is larger than the limit of {} bytes, see --max-nar-size",
+                self.limit
+            )));
+        }
+        Ok(read)
+    }
+}
+
+/// Measures how long reads of decompressed bytes take, and how many bytes are read.
+struct TimingReader<R> {
+    inner: R,
+    counters: Arc<UnpackCounters>,
+}
+
+impl<R: Read> Read for TimingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = Instant::now();
+        let result = self.inner.read(buf);
+        UnpackCounters::add_time(&self.counters.reading, start.elapsed());
+        if let Ok(read) = result {
+            self.counters
+                .decompressed_bytes
+                .fetch_add(read as u64, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+/// Checks that `path`, the path of an entry relative to the root of a nar, stays inside the nar.
+fn validate_entry_path(path: &Path) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        path.file_name().is_some(),
+        "nar entry {path:?} has an empty name"
+    );
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => anyhow::ensure!(
+                !name.as_encoded_bytes().contains(&0),
+                "nar entry {path:?} contains a NUL byte"
+            ),
+            other => anyhow::bail!("nar entry {path:?} contains forbidden component {other:?}"),
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the target of the symlink at `path` (relative to the root of the nar) either
+/// points inside the nar or to the nix store.
+///
+/// Symlinks to the store are resolved later by [crate::vfs::RestrictedPath].
+fn validate_symlink_target(path: &Path, target: &Path) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !target.as_os_str().as_encoded_bytes().contains(&0),
+        "target of symlink {path:?} contains a NUL byte"
+    );
+    if target.is_absolute() {
+        anyhow::ensure!(
+            target.starts_with(crate::store_path::NIX_STORE),
+            "symlink {path:?} points to {target:?} outside the nar and the store"
+        );
+        return Ok(());
+    }
+    // depth of the current position relative to the root of the nar
+    let mut depth = path.components().count().saturating_sub(1);
+    for component in target.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                anyhow::ensure!(
+                    depth > 0,
+                    "symlink {path:?} points to {target:?} outside the nar"
+                );
+                depth -= 1;
+            }
+            Component::Normal(_) => depth += 1,
+            Component::RootDir | Component::Prefix(_) => {
+                anyhow::bail!("unreachable: relative symlink target {target:?} has {component:?}")
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Creates the files described by the entries of this nar inside `destination`.
+///
+/// Contrary to [Decoder::unpack], entries with suspicious names are an error instead of being
+/// skipped, and no file is ever written through a symlink of the nar.
+fn unpack_nar_entries<R: Read>(decoder: &Decoder<R>, destination: &Path) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        std::fs::symlink_metadata(destination).is_err(),
+        "nar unpack destination {destination:?} already exists"
+    );
+    for entry in decoder.entries().context("reading nar entries")? {
+        let entry = entry.context("reading nar entry")?;
+        let relative = entry.path.map(PathBuf::from);
+        let dst_path = match relative {
+            None => destination.to_path_buf(),
+            Some(ref relative) => {
+                validate_entry_path(relative)?;
+                let dst_path = destination.join(relative);
+                // parents were created by previous entries, so if they are directories and not
+                // symlinks, writing there cannot escape destination
+                let parent = dst_path.parent().context("nar entry has no parent")?;
+                let is_dir = std::fs::symlink_metadata(parent)
+                    .map(|m| m.is_dir())
+                    .unwrap_or(false);
+                anyhow::ensure!(
+                    is_dir,
+                    "parent of nar entry {relative:?} is not a directory of the nar"
+                );
+                dst_path
+            }
+        };
+        let display_path = relative.as_deref().unwrap_or(Path::new(""));
+        match entry.content {
+            Content::Directory => std::fs::create_dir(&dst_path)
+                .with_context(|| format!("creating directory {display_path:?} from nar"))?,
+            Content::Symlink { target } => {
+                validate_symlink_target(display_path, target.as_std_path())?;
+                std::os::unix::fs::symlink(target, &dst_path)
+                    .with_context(|| format!("creating symlink {display_path:?} from nar"))?
+            }
+            Content::File {
+                executable,
+                mut data,
+                ..
+            } => {
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&dst_path)
+                    .with_context(|| format!("creating file {display_path:?} from nar"))?;
+                std::io::copy(&mut data, &mut file)
+                    .with_context(|| format!("writing file {display_path:?} from nar"))?;
+                let mode = if executable { 0o555 } else { 0o444 };
+                file.set_permissions(Permissions::from_mode(mode))
+                    .with_context(|| format!("setting permissions of {display_path:?}"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Unpacks the nar passed in argument to the specified path.
 ///
 /// The path must not exist yet, but its parent must be an existing directory.
 ///
 /// In case of error no guarantee is given that destination is clean.
+#[cfg(test)]
 pub async fn unpack_nar<'a, T: AsyncRead + Send + std::fmt::Debug + 'a>(
     nar: T,
     destination: &'a Path,
+    settings: &UnpackSettings,
 ) -> anyhow::Result<()> {
     let nar_name = format!("{nar:?}");
+    unpack_nar_named(nar, b".nar", nar_name, destination, settings).await?;
+    Ok(())
+}
+
+/// Unpacks the nar passed in argument, compressed according to the extension of `path_or_url`
+/// (see [DecompressingReader]), to the specified path.
+///
+/// The path must not exist yet, but its parent must be an existing directory.
+///
+/// Decompression and unpacking run on a blocking thread, so that they do not slow down the async
+/// runtime; only the compressed bytes are read on the runtime. At most as many nars as
+/// [UnpackSettings::permits] allows are unpacked at the same time, others wait.
+///
+/// Returns where the time went, for the caller to [UnpackTimings::record].
+pub async fn unpack_compressed_nar<'a, T: AsyncRead + Send + 'a>(
+    nar: T,
+    path_or_url: &[u8],
+    destination: &'a Path,
+    settings: &UnpackSettings,
+) -> anyhow::Result<UnpackTimings> {
+    let nar_name = String::from_utf8_lossy(path_or_url).into_owned();
+    unpack_nar_named(nar, path_or_url, nar_name, destination, settings).await
+}
+
+/// Implementation of [unpack_compressed_nar], with `nar_name` for error messages
+async fn unpack_nar_named<'a, T: AsyncRead + Send + 'a>(
+    nar: T,
+    path_or_url: &[u8],
+    nar_name: String,
+    destination: &'a Path,
+    settings: &UnpackSettings,
+) -> anyhow::Result<UnpackTimings> {
     let mut async_reader = pin!(nar);
     let (static_async_reader, mut static_async_writer) = tokio::io::simplex(1_000_000);
-    let sync_reader = tokio_util::io::SyncIoBridge::new(static_async_reader);
+    let counters = Arc::new(UnpackCounters::default());
+    let timed_reader = WaitTimingReader {
+        inner: static_async_reader,
+        waiting_since: None,
+        counters: counters.clone(),
+    };
+    let decompressing_reader = DecompressingReader::new(
+        tokio::io::BufReader::new(timed_reader),
+        path_or_url,
+        settings.allowed_compressions,
+    )?;
+    // the async decoder is polled by the blocking thread reading from the bridge
+    let sync_reader = TimingReader {
+        inner: SizeLimitedReader {
+            inner: tokio_util::io::SyncIoBridge::new(decompressing_reader),
+            read: 0,
+            limit: settings.max_nar_size,
+        },
+        counters: counters.clone(),
+    };
     let destination2 = destination.to_path_buf();
+    let _permit = settings
+        .permits
+        .acquire()
+        .await
+        .context("nar unpack semaphore closed")?;
     let unpacker = tokio::task::spawn_blocking(move || {
+        let start = Instant::now();
         let decoder = Decoder::new(sync_reader)?;
-        decoder.unpack(destination2)
+        unpack_nar_entries(&decoder, &destination2)?;
+        anyhow::Ok(start.elapsed())
     });
     let mut unpacker = pin!(unpacker);
     let mut feeder = pin!(tokio::io::copy(&mut async_reader, &mut static_async_writer));
     let unpacker_result = tokio::select! {
         unpacker_result = &mut unpacker => {
             match unpacker_result {
-                Ok(Ok(())) => {
+                Ok(Ok(elapsed)) => {
                     // feeder should already have finished
                     tokio::time::timeout(Duration::from_secs(1), feeder).await
                         .with_context(|| format!("nar unpacking of {nar_name} finished without reading all nar"))?
                         .with_context(|| format!("failed to feed successful nar unpacking of {nar_name}"))?;
-                    Ok(Ok(()))
+                    Ok(Ok(elapsed))
                 },
                 // intentionnally don't wait for the feeder as the unpacker will never read the
                 // rest of the nar if it failed halfway there
@@ -54,28 +496,85 @@ pub async fn unpack_nar<'a, T: AsyncRead + Send + std::fmt::Debug + 'a>(
             }
         },
     };
-    unpacker_result
-        .context("failed to join handle")?
-        .with_context(|| format!("failed to unpack nar {nar_name}"))?;
-    Ok(())
+    let elapsed = match unpacker_result.context("failed to join handle")? {
+        Ok(elapsed) => elapsed,
+        Err(e) => {
+            let decompressed_bytes = counters.decompressed_bytes.load(Ordering::Relaxed);
+            let e = if is_malformed(&e, decompressed_bytes) {
+                e.context(InvalidNarError(format!(
+                    "{nar_name} is empty, truncated or not a nar"
+                )))
+            } else {
+                e
+            };
+            return Err(e.context(format!("failed to unpack nar {nar_name}")));
+        }
+    };
+    let waiting = UnpackCounters::get_time(&counters.waiting);
+    let reading = UnpackCounters::get_time(&counters.reading);
+    Ok(UnpackTimings {
+        nars: 1,
+        network: waiting,
+        decompress: reading.saturating_sub(waiting),
+        restore: elapsed.saturating_sub(reading),
+        compressed_bytes: counters.compressed_bytes.load(Ordering::Relaxed),
+        decompressed_bytes: counters.decompressed_bytes.load(Ordering::Relaxed),
+    })
 }
 
-const NAR_URL_KEY: &str = "URL: ";
+/// Keys of narinfo lines, compared case-insensitively
+const NAR_URL_KEY: &str = "URL";
+const DERIVER_KEY: &str = "Deriver";
+const REFERENCES_KEY: &str = "References";
 
 const NAR_MAX_LINES_LENGTH: usize = 1024;
 
-/// Parses a narinfo to find the relative location of the corresponing nar.
-pub async fn narinfo_to_nar_location<T: AsyncBufRead>(narinfo: T) -> anyhow::Result<String> {
+/// The fields of a narinfo we care about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NarInfo {
+    /// location of the nar relative to the binary cache
+    pub url: String,
+    /// `hash-name.drv` of the derivation that built this store path, if known
+    pub deriver: Option<String>,
+    /// `hash-name` of the store paths this store path refers to
+    pub references: Vec<String>,
+}
+
+/// Parses a narinfo to find the relative location of the corresponing nar, its deriver and
+/// references.
+///
+/// Narinfos written by other tools than nix are accepted as well: keys are case-insensitive, and
+/// whitespace around keys and values, carriage returns and a byte order mark are ignored.
+pub async fn narinfo_to_nar_location<T: AsyncBufRead>(narinfo: T) -> anyhow::Result<NarInfo> {
     let narinfo = pin!(narinfo);
     let decoder = LinesCodec::new_with_max_length(NAR_MAX_LINES_LENGTH);
     let mut lines = pin!(FramedRead::new(narinfo, decoder));
+    let mut url = None;
+    let mut deriver = None;
+    let mut references = Vec::new();
     while let Some(line) = lines.next().await {
         let line = line.context("parsing narinfo line")?;
-        if let Some(suffix) = line.strip_prefix(NAR_URL_KEY) {
-            return Ok(suffix.to_owned());
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let key = key.trim_start_matches('\u{feff}').trim();
+        let value = value.trim();
+        if key.eq_ignore_ascii_case(NAR_URL_KEY) {
+            url = Some(value.to_owned());
+        } else if key.eq_ignore_ascii_case(DERIVER_KEY) {
+            deriver = Some(value.to_owned());
+        } else if key.eq_ignore_ascii_case(REFERENCES_KEY) {
+            references = value.split_whitespace().map(str::to_owned).collect();
         }
     }
-    anyhow::bail!("narinfo dit not have an URL:")
+    let Some(url) = url else {
+        anyhow::bail!("narinfo dit not have an URL:")
+    };
+    Ok(NarInfo {
+        url,
+        deriver,
+        references,
+    })
 }
 
 #[tokio::test]
@@ -84,9 +583,300 @@ async fn test_narinfo_to_nar_location() {
         crate::test_utils::fixture("file_binary_cache/8avg418ydn50ha9wlyrv2f5pj4qccldg.narinfo");
     let fd = tokio::fs::File::open(&narinfo).await.unwrap();
     let bufread = tokio::io::BufReader::new(fd);
-    let url = narinfo_to_nar_location(bufread).await.unwrap();
+    let narinfo = narinfo_to_nar_location(bufread).await.unwrap();
     assert_eq!(
-        url,
+        narinfo.url,
         "nar/078h1d26cqf628a2qy8660q6a5v5ga38mh036w5c0y49k9bxsaq9.nar.xz"
     );
 }
+
+#[tokio::test]
+async fn test_narinfo_deriver() {
+    let narinfo = b"StorePath: /nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1\nURL: nar/foo.nar.xz\nReferences: 34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1 g2jzxk3s7cnkhh8yq55l4fbvf639zy37-glibc-2.40-66\nDeriver: 9p8gq7hc1h0mr6m8gh4q7l5hmivxm0q3-gnumake-4.4.1.drv\n";
+    assert_eq!(
+        narinfo_to_nar_location(&narinfo[..]).await.unwrap(),
+        NarInfo {
+            url: "nar/foo.nar.xz".to_owned(),
+            deriver: Some("9p8gq7hc1h0mr6m8gh4q7l5hmivxm0q3-gnumake-4.4.1.drv".to_owned()),
+            references: vec![
+                "34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1".to_owned(),
+                "g2jzxk3s7cnkhh8yq55l4fbvf639zy37-glibc-2.40-66".to_owned(),
+            ],
+        }
+    );
+    narinfo_to_nar_location(&b"Deriver: foo.drv\n"[..])
+        .await
+        .unwrap_err();
+}
+
+#[tokio::test]
+async fn test_narinfo_formatting_variations() {
+    for narinfo in [
+        &b"StorePath: /nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1\r\nURL: nar/foo.nar.xz\r\nDeriver: bar.drv\r\n"[..],
+        b"StorePath:\t/nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1\nURL:\tnar/foo.nar.xz\nDeriver:\tbar.drv\n",
+        b"\xef\xbb\xbfURL: nar/foo.nar.xz\n  deriver : bar.drv  \n",
+        b"url:nar/foo.nar.xz\nDERIVER: bar.drv",
+    ] {
+        assert_eq!(
+            narinfo_to_nar_location(narinfo).await.unwrap(),
+            NarInfo {
+                url: "nar/foo.nar.xz".to_owned(),
+                deriver: Some("bar.drv".to_owned()),
+                references: Vec::new(),
+            },
+            "{}",
+            String::from_utf8_lossy(narinfo)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file system object to be serialized as a nar by [make_nar]
+    enum Node<'a> {
+        File(&'a str),
+        Symlink(&'a str),
+        Directory(Vec<(&'a str, Node<'a>)>),
+    }
+
+    fn write_str(out: &mut Vec<u8>, s: &str) {
+        out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        out.extend_from_slice(s.as_bytes());
+        out.resize(out.len().next_multiple_of(8), 0);
+    }
+
+    fn write_node(out: &mut Vec<u8>, node: &Node) {
+        write_str(out, "(");
+        write_str(out, "type");
+        match node {
+            Node::File(contents) => {
+                write_str(out, "regular");
+                write_str(out, "contents");
+                write_str(out, contents);
+            }
+            Node::Symlink(target) => {
+                write_str(out, "symlink");
+                write_str(out, "target");
+                write_str(out, target);
+            }
+            Node::Directory(entries) => {
+                write_str(out, "directory");
+                for (name, child) in entries {
+                    write_str(out, "entry");
+                    write_str(out, "(");
+                    write_str(out, "name");
+                    write_str(out, name);
+                    write_str(out, "node");
+                    write_node(out, child);
+                    write_str(out, ")");
+                }
+            }
+        }
+        write_str(out, ")");
+    }
+
+    /// Serializes `node` as a nar, without checking anything
+    fn make_nar(node: &Node) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_str(&mut out, "nix-archive-1");
+        write_node(&mut out, node);
+        out
+    }
+
+    async fn unpack(node: Node<'_>) -> (tempfile::TempDir, anyhow::Result<()>) {
+        let t = tempfile::tempdir().unwrap();
+        let nar = make_nar(&node);
+        let result = unpack_nar(&nar[..], &t.path().join("out"), &Default::default()).await;
+        (t, result)
+    }
+
+    #[tokio::test]
+    async fn unpack_xz() {
+        use tokio::io::AsyncWriteExt;
+
+        let nar = make_nar(&Node::Directory(vec![("a", Node::File("content"))]));
+        let mut encoder = async_compression::tokio::write::XzEncoder::new(Vec::new());
+        encoder.write_all(&nar).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        let compressed = encoder.into_inner();
+        let t = tempfile::tempdir().unwrap();
+        let out = t.path().join("out");
+        let timings = unpack_compressed_nar(
+            &compressed[..],
+            b"nar/abc.nar.xz",
+            &out,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(out.join("a")).unwrap(), "content");
+        assert_eq!(timings.nars, 1);
+        assert_eq!(timings.compressed_bytes, compressed.len() as u64);
+        assert_eq!(timings.decompressed_bytes, nar.len() as u64);
+        unpack_compressed_nar(
+            &nar[..],
+            b"nar/abc.nar.bz2",
+            &t.path().join("out2"),
+            &Default::default(),
+        )
+        .await
+        .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn unpack_too_large() {
+        use tokio::io::AsyncWriteExt;
+
+        let zeros = "\0".repeat(1024 * 1024);
+        let nar = make_nar(&Node::Directory(vec![("zeros", Node::File(&zeros))]));
+        let mut encoder = async_compression::tokio::write::XzEncoder::new(Vec::new());
+        encoder.write_all(&nar).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        let compressed = encoder.into_inner();
+        assert!(compressed.len() < 1024);
+        let t = tempfile::tempdir().unwrap();
+        let err = unpack_nar_named(
+            &compressed[..],
+            b"nar/zeros.nar.xz",
+            "zeros".to_owned(),
+            &t.path().join("out"),
+            &UnpackSettings {
+                max_nar_size: 64 * 1024,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("--max-nar-size"), "{err:#}");
+        assert!(!is_invalid_nar(&err));
+        unpack_nar_named(
+            &compressed[..],
+            b"nar/zeros.nar.xz",
+            "zeros".to_owned(),
+            &t.path().join("out2"),
+            &UnpackSettings {
+                max_nar_size: nar.len() as u64,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn unpack_empty() {
+        let t = tempfile::tempdir().unwrap();
+        let err = unpack_nar(&b""[..], &t.path().join("out"), &Default::default())
+            .await
+            .unwrap_err();
+        assert!(is_invalid_nar(&err), "{err:#}");
+        let err = unpack_compressed_nar(
+            &b""[..],
+            b"nar/abc.nar.xz",
+            &t.path().join("out2"),
+            &Default::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(is_invalid_nar(&err), "{err:#}");
+        let nar = make_nar(&Node::Directory(vec![("a", Node::File("content"))]));
+        let err = unpack_nar(
+            &nar[..nar.len() / 2],
+            &t.path().join("out3"),
+            &Default::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(is_invalid_nar(&err), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn unpack_nominal() {
+        let (t, result) = unpack(Node::Directory(vec![
+            ("a", Node::File("content")),
+            (
+                "b",
+                Node::Directory(vec![
+                    ("c", Node::Symlink("../a")),
+                    ("d", Node::Symlink("/nix/store/hash-name/file")),
+                ]),
+            ),
+        ]))
+        .await;
+        result.unwrap();
+        let out = t.path().join("out");
+        assert_eq!(std::fs::read_to_string(out.join("b/c")).unwrap(), "content");
+        assert_eq!(
+            std::fs::read_link(out.join("b/d")).unwrap(),
+            Path::new("/nix/store/hash-name/file")
+        );
+    }
+
+    #[tokio::test]
+    async fn unpack_dotdot_entry() {
+        let (t, result) = unpack(Node::Directory(vec![(
+            "..",
+            Node::Directory(vec![("escaped", Node::File("x"))]),
+        )]))
+        .await;
+        let err = format!("{:#}", result.unwrap_err());
+        assert!(dbg!(&err).contains("\"..\""));
+        assert!(!t.path().join("escaped").exists());
+    }
+
+    #[tokio::test]
+    async fn unpack_absolute_entry() {
+        let t2 = tempfile::tempdir().unwrap();
+        let target = t2.path().join("escaped");
+        let (_t, result) = unpack(Node::Directory(vec![(
+            target.to_str().unwrap(),
+            Node::File("x"),
+        )]))
+        .await;
+        result.unwrap_err();
+        assert!(!target.exists());
+    }
+
+    #[tokio::test]
+    async fn unpack_nul_entry() {
+        let (_t, result) = unpack(Node::Directory(vec![("a\0b", Node::File("x"))])).await;
+        let err = format!("{:#}", result.unwrap_err());
+        assert!(dbg!(&err).contains("NUL"));
+    }
+
+    #[tokio::test]
+    async fn unpack_write_through_symlink() {
+        let t2 = tempfile::tempdir().unwrap();
+        let (_t, result) = unpack(Node::Directory(vec![
+            ("link", Node::Symlink(t2.path().to_str().unwrap())),
+            ("link/escaped", Node::File("x")),
+        ]))
+        .await;
+        result.unwrap_err();
+        assert!(!t2.path().join("escaped").exists());
+    }
+
+    #[tokio::test]
+    async fn unpack_escaping_relative_symlink() {
+        let (_t, result) = unpack(Node::Directory(vec![(
+            "a",
+            Node::Directory(vec![("link", Node::Symlink("../../etc/passwd"))]),
+        )]))
+        .await;
+        let err = format!("{:#}", result.unwrap_err());
+        assert!(dbg!(&err).contains("a/link"));
+    }
+
+    #[tokio::test]
+    async fn unpack_escaping_absolute_symlink() {
+        let (_t, result) = unpack(Node::Directory(vec![(
+            "link",
+            Node::Symlink("/etc/passwd"),
+        )]))
+        .await;
+        let err = format!("{:#}", result.unwrap_err());
+        assert!(dbg!(&err).contains("link"));
+    }
+}
@@ -0,0 +1,190 @@
+//! Populating the cache without starting a server, or while the server starts.
+//!
+//! Useful to download debug symbols and sources while online before debugging offline, or to
+//! make sure frequently requested build ids are in cache before traffic arrives.
+
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use futures::StreamExt as _;
+
+use crate::build_id::BuildId;
+use crate::debuginfod::Debuginfod;
+use crate::server::debuginfod_from_options;
+use crate::utils::Presence;
+use crate::Options;
+
+/// What happened when prefetching one kind of file for a build id
+pub enum Outcome {
+    /// the file is in cache
+    Found,
+    /// no substituter has the file
+    NotFound,
+    /// looking for the file failed
+    Failed(anyhow::Error),
+}
+
+impl Outcome {
+    /// The outcome of a lookup returning `Ok(None)` when the file is not found
+    pub fn from_option<T>(result: anyhow::Result<Option<T>>) -> Self {
+        match result {
+            Ok(Some(_)) => Outcome::Found,
+            Ok(None) => Outcome::NotFound,
+            Err(e) => Outcome::Failed(e),
+        }
+    }
+
+    fn from_presence(result: anyhow::Result<Presence>) -> Self {
+        match result {
+            Ok(Presence::Found) => Outcome::Found,
+            Ok(Presence::NotFound) => Outcome::NotFound,
+            Err(e) => Outcome::Failed(e),
+        }
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Found => write!(f, "ok"),
+            Outcome::NotFound => write!(f, "not found"),
+            Outcome::Failed(e) => write!(f, "error: {e:#}"),
+        }
+    }
+}
+
+/// Fetches debuginfo, executable and sources of `build_id` into the cache of `debuginfod`.
+///
+/// Returns whether the build id was found and no error occured.
+async fn prefetch_one(debuginfod: &Debuginfod, build_id: &str) -> bool {
+    let build_id = match BuildId::new(build_id) {
+        Ok(b) => b,
+        Err(e) => {
+            println!("{build_id}: invalid build id: {e:#}");
+            return false;
+        }
+    };
+    let debuginfo = Outcome::from_option(debuginfod.debuginfo(&build_id).await);
+    let executable = Outcome::from_option(debuginfod.executable(&build_id).await);
+    let source = Outcome::from_presence(debuginfod.prefetch_source(&build_id).await);
+    println!("{build_id}: debuginfo {debuginfo}, executable {executable}, source {source}");
+    // sources are not always available, so only debuginfo is mandatory
+    matches!(debuginfo, Outcome::Found)
+        && !matches!(executable, Outcome::Failed(_))
+        && !matches!(source, Outcome::Failed(_))
+}
+
+/// Downloads and unpacks everything related to these build ids into the cache directory
+/// specified in `args`, then returns.
+///
+/// Fails if any of the build ids could not be prefetched.
+pub async fn run_prefetch(args: &Options, build_ids: &[String]) -> anyhow::Result<()> {
+    let debuginfod = debuginfod_from_options(args).await?;
+    let mut failures = 0;
+    for build_id in build_ids {
+        if !prefetch_one(&debuginfod, build_id).await {
+            failures += 1;
+        }
+    }
+    anyhow::ensure!(
+        failures == 0,
+        "failed to prefetch {failures} out of {} build ids",
+        build_ids.len()
+    );
+    Ok(())
+}
+
+/// How many build ids of the warm list are fetched at the same time
+const WARM_CONCURRENCY: usize = 4;
+
+/// Parses a warm list: one build id per line.
+///
+/// Blank lines and lines starting with `#` are ignored, as well as leading and trailing
+/// whitespace. Build ids are not validated.
+fn parse_warm_list(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+#[test]
+fn test_parse_warm_list() {
+    let content = "# last week\n0e20481820d3b92468102b35a5e4a29a8695c1af\n\n  483bd7f7229bdb06462222e1e353e4f37e15c293 \n";
+    assert_eq!(
+        parse_warm_list(content),
+        [
+            "0e20481820d3b92468102b35a5e4a29a8695c1af",
+            "483bd7f7229bdb06462222e1e353e4f37e15c293"
+        ]
+    );
+}
+
+/// Reads the build ids of the warm list at `path`, see [spawn_warm].
+pub async fn read_warm_list(path: &Path) -> anyhow::Result<Vec<String>> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("reading warm list {path:?}"))?;
+    Ok(parse_warm_list(&content))
+}
+
+/// Fetches the debuginfo of these build ids into the cache, a few at a time.
+///
+/// Failures are logged and otherwise ignored.
+async fn warm(debuginfod: &Debuginfod, build_ids: &[String]) {
+    futures::stream::iter(build_ids)
+        .for_each_concurrent(WARM_CONCURRENCY, async |build_id| {
+            let outcome = match BuildId::new(build_id) {
+                Ok(build_id) => Outcome::from_option(debuginfod.debuginfo(&build_id).await),
+                Err(e) => Outcome::Failed(e.context("invalid build id")),
+            };
+            match outcome {
+                Outcome::Failed(_) => tracing::warn!("warming {build_id}: {outcome}"),
+                _ => tracing::debug!("warming {build_id}: {outcome}"),
+            }
+        })
+        .await;
+    tracing::info!("done warming the cache with {} build ids", build_ids.len());
+}
+
+/// Fetches the debuginfo of these build ids into the cache in the background, while the server
+/// serves requests.
+pub fn spawn_warm(debuginfod: Arc<Debuginfod>, build_ids: Vec<String>) {
+    tokio::spawn(async move { warm(&debuginfod, &build_ids).await });
+}
+
+#[tokio::test]
+async fn test_warm() {
+    use crate::settings::Settings;
+    use crate::substituter::file::FileSubstituter;
+    use crate::test_utils::count_elements_in_dir;
+
+    let t = tempfile::tempdir().unwrap();
+    let substituter = FileSubstituter::test_fixture(t.path()).await;
+    let debuginfod = Debuginfod::new(
+        t.path().into(),
+        Box::new(substituter),
+        std::time::Duration::from_secs(1000),
+        &Settings::default(),
+    )
+    .await
+    .unwrap();
+    let before = count_elements_in_dir(t.path());
+    warm(
+        &debuginfod,
+        &[
+            // /nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make
+            "0e20481820d3b92468102b35a5e4a29a8695c1af".to_owned(),
+            // missing
+            "0000000000000000000000000000000000000000".to_owned(),
+            "invalid".to_owned(),
+        ],
+    )
+    .await;
+    // the debug output was unpacked in the cache
+    assert!(count_elements_in_dir(t.path()) > before);
+}
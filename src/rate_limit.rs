@@ -0,0 +1,137 @@
+//! Limiting how many requests each client can make, so that a single misbehaving client cannot
+//! make the server fetch everything at once.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use http::HeaderMap;
+
+/// The tokens of one client, see [RateLimiter]
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    /// how many requests the client can make right now
+    tokens: f64,
+    /// when `tokens` was computed
+    updated: Instant,
+}
+
+/// A token bucket per client ip address.
+///
+/// Each client can make `burst` requests at once, then `rate` requests per second.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Allows `rate` requests per second with bursts of `burst` requests.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a request of `client` at time `now`.
+    ///
+    /// Returns how long the client should wait before retrying if it made too many requests.
+    pub fn check(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Forgets clients whose bucket is full again at time `now`, as if they had never made a
+    /// request.
+    pub fn cleanup(&self, now: Instant) {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * self.rate < self.burst
+        });
+    }
+
+    /// How many clients are currently tracked
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+}
+
+#[test]
+fn test_rate_limiter() {
+    let limiter = RateLimiter::new(2.0, 3);
+    let start = Instant::now();
+    let client: IpAddr = "192.0.2.1".parse().unwrap();
+    let other: IpAddr = "2001:db8::1".parse().unwrap();
+    for _ in 0..3 {
+        limiter.check(client, start).unwrap();
+    }
+    let wait = limiter.check(client, start).unwrap_err();
+    assert_eq!(wait, Duration::from_millis(500));
+    // other clients are not affected
+    limiter.check(other, start).unwrap();
+    // 2 requests per second
+    let later = start + Duration::from_millis(500);
+    limiter.check(client, later).unwrap();
+    limiter.check(client, later).unwrap_err();
+    assert_eq!(limiter.len(), 2);
+    // the bucket of `other` is full again after 0.5s, that of `client` after 1.5s
+    limiter.cleanup(start + Duration::from_secs(1));
+    assert_eq!(limiter.len(), 1);
+    limiter.cleanup(later + Duration::from_secs(2));
+    assert_eq!(limiter.len(), 0);
+}
+
+/// The ip address of the client which made a request received from `peer`.
+///
+/// If `trust_proxy` is set, `peer` is a reverse proxy and the client is the last address the
+/// proxy appended to `X-Forwarded-For`, if any. Otherwise, `X-Forwarded-For` could be forged by
+/// clients to evade rate limiting, and is ignored.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trust_proxy: bool) -> IpAddr {
+    if !trust_proxy {
+        return peer;
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .next_back()
+        .and_then(|last| last.trim().parse().ok())
+        .unwrap_or(peer)
+}
+
+#[test]
+fn test_client_ip() {
+    let peer: IpAddr = "10.0.0.1".parse().unwrap();
+    let mut headers = HeaderMap::new();
+    assert_eq!(client_ip(peer, &headers, true), peer);
+    headers.insert(
+        "x-forwarded-for",
+        "198.51.100.7, 192.0.2.1".parse().unwrap(),
+    );
+    assert_eq!(client_ip(peer, &headers, false), peer);
+    assert_eq!(
+        client_ip(peer, &headers, true),
+        "192.0.2.1".parse::<IpAddr>().unwrap()
+    );
+    headers.insert("x-forwarded-for", "garbage".parse().unwrap());
+    assert_eq!(client_ip(peer, &headers, true), peer);
+}
@@ -0,0 +1,94 @@
+//! Checking which of the ELF files mapped by a running process have debug symbols available.
+//!
+//! Useful to confirm that the substituters are set up correctly before starting a debugger.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::debuginfod::elf_build_id;
+use crate::prefetch::Outcome;
+use crate::server::debuginfod_from_options;
+use crate::Options;
+
+/// Returns the files mapped in memory according to the content of `/proc/<pid>/maps`, in order
+/// of first appearance and without duplicates.
+///
+/// Anonymous and special mappings like `[heap]` are skipped, as well as files deleted since they
+/// were mapped.
+fn parse_maps(content: &str) -> Vec<PathBuf> {
+    let mut result: Vec<PathBuf> = Vec::new();
+    for line in content.lines() {
+        // address perms offset dev inode pathname, where pathname may contain spaces
+        let Some(pathname) = line.splitn(6, ' ').nth(5) else {
+            continue;
+        };
+        let pathname = pathname.trim_start();
+        if !pathname.starts_with('/') || pathname.ends_with(" (deleted)") {
+            continue;
+        }
+        let path = PathBuf::from(pathname);
+        if !result.contains(&path) {
+            result.push(path);
+        }
+    }
+    result
+}
+
+#[test]
+fn test_parse_maps() {
+    let content = "\
+55d0c3a00000-55d0c3a28000 r--p 00000000 00:1f 123 /nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make
+55d0c3a28000-55d0c3a8f000 r-xp 00028000 00:1f 123 /nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make
+55d0c4c0e000-55d0c4c2f000 rw-p 00000000 00:00 0                          [heap]
+7f1b2c000000-7f1b2c021000 rw-p 00000000 00:00 0 
+7f1b2d400000-7f1b2d428000 r--p 00000000 00:1f 456                        /nix/store/g2jzxk3s7cnkhh8yq55l4fbvf639zy37-glibc-2.40-66/lib/libc.so.6
+7f1b2d600000-7f1b2d601000 r--p 00000000 00:1f 789                        /tmp/with space.so
+7f1b2d700000-7f1b2d701000 r--p 00000000 00:1f 790                        /tmp/old.so (deleted)
+7ffd8a5f2000-7ffd8a5f4000 r-xp 00000000 00:00 0                          [vdso]
+";
+    assert_eq!(
+        parse_maps(content),
+        [
+            "/nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make",
+            "/nix/store/g2jzxk3s7cnkhh8yq55l4fbvf639zy37-glibc-2.40-66/lib/libc.so.6",
+            "/tmp/with space.so",
+        ]
+        .map(PathBuf::from)
+    );
+}
+
+/// Prints the build id of each ELF file mapped by process `pid`, and whether its debuginfo could
+/// be fetched from the substituters specified in `args`.
+///
+/// Fails if the mappings of the process cannot be read, or if a lookup failed. Debuginfo which
+/// is found is fetched into the cache.
+pub async fn run_resolve_pid(args: &Options, pid: u32) -> anyhow::Result<()> {
+    let maps_path = Path::new("/proc").join(pid.to_string()).join("maps");
+    let maps = tokio::fs::read_to_string(&maps_path)
+        .await
+        .with_context(|| format!("reading {}", maps_path.display()))?;
+    let debuginfod = debuginfod_from_options(args).await?;
+    let mut failures = 0;
+    for path in parse_maps(&maps) {
+        let build_id = match elf_build_id(&path).await {
+            Ok(Some(build_id)) => build_id,
+            Ok(None) => {
+                println!("{}: no build id", path.display());
+                continue;
+            }
+            Err(e) => {
+                // for example locale archives or fonts
+                tracing::debug!("skipping {}: {e:#}", path.display());
+                continue;
+            }
+        };
+        let debuginfo = Outcome::from_option(debuginfod.debuginfo(&build_id).await);
+        if matches!(debuginfo, Outcome::Failed(_)) {
+            failures += 1;
+        }
+        println!("{}: {build_id}: debuginfo {debuginfo}", path.display());
+    }
+    anyhow::ensure!(failures == 0, "failed to look up {failures} build ids");
+    Ok(())
+}
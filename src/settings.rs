@@ -0,0 +1,93 @@
+//! Settings from the command line which change how files are fetched, stored and served.
+//!
+//! They are collected once in a [Settings] and passed to the constructors of what they
+//! configure, like [crate::cache::FetcherCache::new], [crate::substituter::substituter_from_url]
+//! and [crate::debuginfod::Debuginfod::new].
+
+use std::path::PathBuf;
+
+use crate::{
+    archive_cache::DEFAULT_MAX_SOURCE_UNPACK_SIZE,
+    build_id::PathTemplate,
+    cache::CacheSettings,
+    nar::{unpack_permits, UnpackSettings},
+    source_selection::DEFAULT_MAX_SOURCE_MATCH_CANDIDATES,
+    substituter::{
+        binary_cache::DEFAULT_MAX_METADATA_SIZE,
+        http::{ConnectionSettings, HttpSettings, ProxySettings},
+    },
+    utils::CompressionSet,
+    Options,
+};
+
+/// Settings shared by the components of a [crate::debuginfod::Debuginfod] instance
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// how fetched files are stored in the cache
+    pub cache: CacheSettings,
+    /// how nars are decompressed and unpacked
+    pub unpack: UnpackSettings,
+    /// how http substituters connect
+    pub http: HttpSettings,
+    /// metadata files of binary caches (narinfo, json redirects to debuginfo) larger than this
+    /// are not read
+    pub max_metadata_size: u64,
+    /// where debug outputs contain the debuginfo of a build id
+    pub debug_path_template: PathTemplate,
+    /// above this many files with the requested name in a source tree, only exact matches are
+    /// considered, see `source_selection::get_file_for_source_with_limit`
+    pub max_source_match_candidates: usize,
+    /// source archives whose files add up to more than this are not unpacked
+    pub max_source_unpack_size: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            cache: CacheSettings::default(),
+            unpack: UnpackSettings::default(),
+            http: HttpSettings::default(),
+            max_metadata_size: DEFAULT_MAX_METADATA_SIZE,
+            debug_path_template: PathTemplate::default(),
+            max_source_match_candidates: DEFAULT_MAX_SOURCE_MATCH_CANDIDATES,
+            max_source_unpack_size: DEFAULT_MAX_SOURCE_UNPACK_SIZE,
+        }
+    }
+}
+
+impl Settings {
+    /// The settings specified by command line arguments.
+    pub fn from_options(args: &Options) -> Self {
+        Self {
+            cache: CacheSettings {
+                cache_dir: PathBuf::from(&args.cache_dir),
+                read_only_tiers: args.shared_cache_dir.clone(),
+                post_fetch_command: args.post_fetch_command.clone(),
+                keep_failed_fetches: args.keep_failed_fetches,
+                cleanup_batch_size: args.cleanup_batch_size,
+                compress: args.compress_cache,
+            },
+            unpack: UnpackSettings {
+                max_nar_size: args.max_nar_size,
+                allowed_compressions: args.allowed_compression.unwrap_or(CompressionSet::ALL),
+                permits: unpack_permits(args.decompress_threads),
+            },
+            http: HttpSettings {
+                proxy: ProxySettings {
+                    proxy: args.proxy.clone(),
+                    no_proxy: args.no_proxy.clone(),
+                },
+                connection: ConnectionSettings {
+                    http2: args.http2,
+                    pool_idle_timeout: args.pool_idle_timeout,
+                    pool_max_idle_per_host: args.pool_max_idle_per_host,
+                },
+                headers: args.substituter_header.clone(),
+            },
+            max_metadata_size: args.max_metadata_size,
+            debug_path_template: args.debug_path_template.clone(),
+            max_source_match_candidates: args.max_source_match_candidates,
+            max_source_unpack_size: args.max_source_unpack_size,
+        }
+    }
+}
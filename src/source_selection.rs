@@ -1,20 +1,48 @@
 //! Determine which file corresponds to the requested path
 
 use std::{
-    ffi::OsStr,
-    path::{Path, PathBuf},
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    path::{Component, Path, PathBuf},
 };
 
-use tracing::Level;
-
+use crate::utils::Compression;
 use crate::vfs::WalkableDirectory;
 
+/// Default limit of candidates of [get_file_for_source_with_limit]
+pub const DEFAULT_MAX_SOURCE_MATCH_CANDIDATES: usize = 1000;
+
+/// Extensions of source files shipped compressed, like `main.c.gz` for `main.c`
+const COMPRESSED_EXTENSIONS: &[(&str, Compression)] = &[
+    (".gz", Compression::Gzip),
+    (".xz", Compression::Xz),
+    (".zst", Compression::Zstd),
+];
+
+/// If `name` is `file_name` compressed, like `main.c.gz` for `main.c`, its compression.
+fn compressed_variant(name: &OsStr, file_name: &OsStr) -> Option<Compression> {
+    let extension = name
+        .as_encoded_bytes()
+        .strip_prefix(file_name.as_encoded_bytes())?;
+    COMPRESSED_EXTENSIONS
+        .iter()
+        .find(|(candidate, _)| candidate.as_bytes() == extension)
+        .map(|&(_, compression)| compression)
+}
+
 /// Returns the set of files in this directory with the specified file name
 ///
+/// With `compressed`, compressed versions of the file like `<file_name>.gz` are returned as well,
+/// with their compression, unless the uncompressed file exists next to them.
+///
 /// Paths are returned relative to `dir`.
 ///
 /// Errors are ignored.
-fn find_file_in_dir<T: WalkableDirectory>(dir: &T, file_name: &OsStr) -> Vec<PathBuf> {
+fn find_file_in_dir<T: WalkableDirectory>(
+    dir: &T,
+    file_name: &OsStr,
+    compressed: bool,
+) -> Vec<(PathBuf, Option<Compression>)> {
     let mut result = Vec::new();
     for file in dir.list_files_recursively() {
         match file {
@@ -22,16 +50,85 @@ fn find_file_in_dir<T: WalkableDirectory>(dir: &T, file_name: &OsStr) -> Vec<Pat
                 tracing::warn!("failed to walk source {dir:?}: {:#}", e);
                 continue;
             }
-            Ok(f) => {
-                if f.file_name() == Some(file_name) {
-                    result.push(f)
+            Ok(f) => match f.file_name() {
+                Some(name) if name == file_name => result.push((f, None)),
+                Some(name) if compressed => {
+                    if let Some(compression) = compressed_variant(name, file_name) {
+                        result.push((f, Some(compression)))
+                    }
                 }
-            }
+                _ => (),
+            },
         }
     }
+    let uncompressed: Vec<PathBuf> = result
+        .iter()
+        .filter(|(_, compression)| compression.is_none())
+        .map(|(f, _)| f.clone())
+        .collect();
+    result.retain(|(f, compression)| {
+        compression.is_none() || !uncompressed.contains(&f.with_file_name(file_name))
+    });
     result
 }
 
+#[test]
+fn test_compressed_variant() {
+    let name = OsStr::new("main.c");
+    for (candidate, expected) in [
+        ("main.c.gz", Some(Compression::Gzip)),
+        ("main.c.xz", Some(Compression::Xz)),
+        ("main.c.zst", Some(Compression::Zstd)),
+        ("main.c", None),
+        ("main.c.orig", None),
+        ("main.cc.gz", None),
+        ("xmain.c.gz", None),
+    ] {
+        assert_eq!(
+            compressed_variant(OsStr::new(candidate), name),
+            expected,
+            "{candidate}"
+        );
+    }
+}
+
+/// Collapses `.` and `..` components of `path` without accessing the file system.
+///
+/// `..` at the root is dropped, and leading `..` of relative paths are kept.
+fn normalize_lexically(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => match result.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    result.pop();
+                }
+                Some(Component::RootDir | Component::Prefix(_)) => (),
+                Some(Component::ParentDir) | None => result.push(".."),
+                Some(Component::CurDir) => unreachable!(),
+            },
+            other => result.push(other),
+        }
+    }
+    result
+}
+
+#[test]
+fn test_normalize_lexically() {
+    for (path, expected) in [
+        (
+            "/build/glibc/io/../sysdeps/./x.c",
+            "/build/glibc/sysdeps/x.c",
+        ),
+        ("/../a/b/../../c", "/c"),
+        ("../a/../../b", "../../b"),
+        ("a/./b/", "a/b"),
+    ] {
+        assert_eq!(normalize_lexically(Path::new(path)), Path::new(expected));
+    }
+}
+
 /// a number that expresses how close the candidate path is to the reference. higher is closer.
 fn matching_measure(candidate: &Path, reference: &Path) -> usize {
     candidate
@@ -42,7 +139,72 @@ fn matching_measure(candidate: &Path, reference: &Path) -> usize {
         .unwrap_or_else(|| candidate.iter().count())
 }
 
-/// returns the path with higher matching_measure
+/// Whether `candidate` matches `reference` exactly, except maybe for its top directory, which is
+/// often renamed, like `source` for `foo-1.0`.
+fn is_exact_match(candidate: &Path, reference: &Path) -> bool {
+    matching_measure(candidate, reference) + 1 >= candidate.iter().count()
+}
+
+/// Whether a directory named `component` plausibly contains the source of `package`, like
+/// `foo-1.2` or `Foo-v1.2` for package `foo`.
+fn is_package_dir(component: &OsStr, package: &str) -> bool {
+    let component = component.to_string_lossy().to_lowercase();
+    match component.strip_prefix(&package.to_lowercase()) {
+        Some(rest) => rest.is_empty() || rest.starts_with(['-', '_', '.']),
+        None => false,
+    }
+}
+
+#[test]
+fn test_is_package_dir() {
+    for (component, expected) in [
+        ("foo", true),
+        ("foo-1.2", true),
+        ("Foo-v1.2", true),
+        ("foo_bar", true),
+        ("foobar-1.2", false),
+        ("source", false),
+        ("bar-foo", false),
+    ] {
+        assert_eq!(
+            is_package_dir(OsStr::new(component), "foo"),
+            expected,
+            "{component}"
+        );
+    }
+}
+
+/// Whether the top directory of the source tree, when `candidate` is the file designated by
+/// `reference`, is named after `package` (either in the source tree or in `reference`).
+///
+/// The top directory is where `reference` and candidates most often differ: sources fetched from
+/// a git repository are unpacked as `source` in nix store paths, but may have been built in a
+/// `/build/<name>-<rev>/` directory, or the other way around.
+fn root_is_package_dir(candidate: &Path, reference: &Path, package: &str) -> bool {
+    let measure = matching_measure(candidate, reference);
+    let depth = candidate.iter().count();
+    if measure == depth {
+        // the whole candidate matched, the top directory of the source tree is above
+        reference
+            .iter()
+            .rev()
+            .nth(measure)
+            .is_some_and(|c| is_package_dir(c, package))
+    } else if measure + 1 == depth {
+        // everything matched but the top directory of the candidate
+        candidate
+            .iter()
+            .next()
+            .is_some_and(|c| is_package_dir(c, package))
+    } else {
+        false
+    }
+}
+
+/// returns the index of the path with higher matching_measure
+///
+/// When several paths have the same matching_measure, those whose top directory is named after
+/// `package` are preferred, see [root_is_package_dir].
 ///
 /// None if `candidates` is empty
 ///
@@ -50,76 +212,229 @@ fn matching_measure(candidate: &Path, reference: &Path) -> usize {
 fn best_matching_measure(
     candidates: &[PathBuf],
     reference: &Path,
-) -> anyhow::Result<Option<PathBuf>> {
+    package: Option<&str>,
+) -> anyhow::Result<Option<usize>> {
     let ranked: Vec<_> = candidates
         .iter()
-        .map(|c| (matching_measure(c, reference), c))
+        .map(|c| {
+            (
+                matching_measure(c, reference),
+                package.is_some_and(|package| root_is_package_dir(c, reference, package)),
+            )
+        })
         .collect();
-    let Some(best) = ranked.iter().map(|(measure, _)| measure).max() else {
+    let Some(best) = ranked.iter().max() else {
         return Ok(None);
     };
     let equals: Vec<_> = ranked
         .iter()
-        .filter_map(|(measure, c)| if measure == best { Some(c) } else { None })
+        .enumerate()
+        .filter_map(|(i, measure)| if measure == best { Some(i) } else { None })
         .collect();
     if equals.len() != 1 {
         anyhow::bail!(
             "cannot tell {:?} apart for target {}",
-            &equals,
+            equals.iter().map(|&i| &candidates[i]).collect::<Vec<_>>(),
             reference.display()
         );
     }
-    Ok(Some(equals[0].to_path_buf()))
+    Ok(Some(equals[0]))
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 /// Where the file should be taken
 pub enum SourceMatch {
-    /// take the file from the source
-    Source(PathBuf),
-    /// take the file from the overlay because it has been patched during build
-    Overlay(PathBuf),
+    /// take the file from the source directory with this index
+    Source(usize, PathBuf),
+    /// take the file from the overlay with this index because it has been patched during build
+    Overlay(usize, PathBuf),
+    /// like [SourceMatch::Source], but the file is compressed, like `main.c.gz` for a request of
+    /// `main.c`, and must be decompressed before serving
+    CompressedSource(usize, PathBuf, Compression),
 }
 
-/// Attempts to find a file that matches the request in an existing directory of source files
+/// Attempts to find a file that matches the request in existing directories of source files
+///
+/// `source_dirs` are searched together, for example when a package is built from several source
+/// archives. `overlay_dirs` contain files patched during the build, highest priority first. The
+/// file is taken from the first overlay that contains a patched version of it, otherwise from the
+/// source directory where it was found.
+///
+/// `.` and `..` components of `request` are collapsed before matching.
 ///
-/// Returns a path relative to `source_dir`
+/// Source files may be compressed, like `main.c.gz` for a request of `main.c`, when the
+/// uncompressed file does not exist next to them.
+///
+/// `package` is the name of the package the file belongs to, if known. It helps telling apart
+/// files which match `request` equally well when the top directory of `request` and of the source
+/// directories differ.
+///
+/// Returns a path relative to the source dir or overlay dir in question
 ///
 /// Returns None if no file matches
 ///
 /// Returns Err if several file match and we don't know which one is the best one.
-#[tracing::instrument(level=Level::DEBUG)]
+///
+/// When the source directories contain more files with the requested name than
+/// [DEFAULT_MAX_SOURCE_MATCH_CANDIDATES], like `Makefile` in a huge project, only those matching
+/// `request` exactly but for their top directory are considered.
+#[cfg(test)]
+#[tracing::instrument(level = tracing::Level::DEBUG)]
 pub fn get_file_for_source<T: WalkableDirectory>(
-    source_dir: &T,
-    overlay_dir: &T,
+    source_dirs: &[T],
+    overlay_dirs: &[T],
     request: &Path,
+    package: Option<&str>,
 ) -> anyhow::Result<Option<SourceMatch>> {
+    get_file_for_source_with_limit(
+        source_dirs,
+        overlay_dirs,
+        request,
+        package,
+        DEFAULT_MAX_SOURCE_MATCH_CANDIDATES,
+    )
+}
+
+/// [get_file_for_source] with an explicit limit of candidates, above which only exact matches
+/// are considered
+pub fn get_file_for_source_with_limit<T: WalkableDirectory>(
+    source_dirs: &[T],
+    overlay_dirs: &[T],
+    request: &Path,
+    package: Option<&str>,
+    max_candidates: usize,
+) -> anyhow::Result<Option<SourceMatch>> {
+    let request = &normalize_lexically(request);
     let Some(filename) = request.file_name() else {
         anyhow::bail!("requested path {} has no filename", request.display())
     };
-    let candidates = find_file_in_dir(source_dir, filename);
-    let best_source = match best_matching_measure(&candidates, request) {
+    // candidates as if they were not compressed, for matching
+    let mut candidates = Vec::new();
+    let mut found_files = Vec::new();
+    for (i, source_dir) in source_dirs.iter().enumerate() {
+        for (path, compression) in find_file_in_dir(source_dir, filename, true) {
+            candidates.push(path.with_file_name(filename));
+            found_files.push((i, path, compression));
+        }
+    }
+    if candidates.len() > max_candidates {
+        tracing::debug!(
+            "{} files named {filename:?}, only considering exact matches of {}",
+            candidates.len(),
+            request.display()
+        );
+        let exact: Vec<bool> = candidates
+            .iter()
+            .map(|candidate| is_exact_match(candidate, request))
+            .collect();
+        let mut keep = exact.iter();
+        candidates.retain(|_| *keep.next().unwrap());
+        let mut keep = exact.iter();
+        found_files.retain(|_| *keep.next().unwrap());
+    }
+    let best_source = match best_matching_measure(&candidates, request, package) {
         Err(e) => return Err(e),
         Ok(None) => return Ok(None),
         Ok(Some(x)) => x,
     };
-    let overlay_candidates = find_file_in_dir(overlay_dir, filename);
-    let matching_overlay_candiates: Vec<_> = overlay_candidates
-        .iter()
-        .filter(|c| match best_matching_measure(&candidates, c) {
-            Err(_) => false,
-            Ok(None) => false,
-            Ok(Some(ref f)) => f == &best_source,
-        })
-        .collect();
-    match &matching_overlay_candiates[..] {
-        [] => Ok(Some(SourceMatch::Source(best_source))),
-        [best_overlay] => Ok(Some(SourceMatch::Overlay(best_overlay.into()))),
-        _ => {
-            tracing::warn!("several overlay files {matching_overlay_candiates:?} may correspond to source match {best_source:?}, returning source match");
-            Ok(Some(SourceMatch::Source(best_source)))
+    for (i, overlay_dir) in overlay_dirs.iter().enumerate() {
+        let overlay_candidates: Vec<_> = find_file_in_dir(overlay_dir, filename, false)
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        let matching_overlay_candiates: Vec<_> = overlay_candidates
+            .iter()
+            .filter(|c| match best_matching_measure(&candidates, c, None) {
+                Err(_) => false,
+                Ok(None) => false,
+                Ok(Some(f)) => f == best_source,
+            })
+            .collect();
+        match &matching_overlay_candiates[..] {
+            [] => (),
+            [best_overlay] => return Ok(Some(SourceMatch::Overlay(i, best_overlay.into()))),
+            _ => {
+                tracing::warn!("several overlay files {matching_overlay_candiates:?} in {overlay_dir:?} may correspond to source match {:?}, ignoring this overlay", candidates[best_source]);
+            }
         }
     }
+    Ok(Some(match found_files.swap_remove(best_source) {
+        (i, path, None) => SourceMatch::Source(i, path),
+        (i, path, Some(compression)) => SourceMatch::CompressedSource(i, path, compression),
+    }))
+}
+
+/// Lists all the files of a source tree, to serve it whole: their path in the tree, sorted, and
+/// where to take each of them.
+///
+/// Files of `source_dirs` are at their path relative to their directory; when several source
+/// directories contain the same path, the first one wins. A file of `overlay_dirs` replaces the
+/// source file it is a patched version of, matched as in [get_file_for_source], highest priority
+/// overlay first. Overlay files matching no source file, like generated headers, are added at
+/// their path relative to the overlay.
+///
+/// Compressed source files are listed as they are.
+pub fn source_tree<T: WalkableDirectory>(
+    source_dirs: &[T],
+    overlay_dirs: &[T],
+) -> anyhow::Result<Vec<(PathBuf, SourceMatch)>> {
+    let mut tree = Vec::new();
+    let mut seen = HashSet::new();
+    // indices in `tree` of source files by file name
+    let mut by_name: HashMap<OsString, Vec<usize>> = HashMap::new();
+    for (i, source_dir) in source_dirs.iter().enumerate() {
+        for file in source_dir.list_files_recursively() {
+            let file = file?;
+            if !seen.insert(file.clone()) {
+                continue;
+            }
+            if let Some(name) = file.file_name() {
+                by_name.entry(name.to_owned()).or_default().push(tree.len());
+            }
+            tree.push((file.clone(), SourceMatch::Source(i, file)));
+        }
+    }
+    let mut patched = HashSet::new();
+    for (i, overlay_dir) in overlay_dirs.iter().enumerate() {
+        for file in overlay_dir.list_files_recursively() {
+            let file = file?;
+            let Some(name) = file.file_name() else {
+                continue;
+            };
+            let indices = by_name.get(name).map(Vec::as_slice).unwrap_or_default();
+            let candidates: Vec<PathBuf> = indices.iter().map(|&j| tree[j].0.clone()).collect();
+            match best_matching_measure(&candidates, &file, None) {
+                Ok(Some(best)) => {
+                    if patched.insert(indices[best]) {
+                        tree[indices[best]].1 = SourceMatch::Overlay(i, file);
+                    }
+                }
+                Ok(None) => {
+                    if seen.insert(file.clone()) {
+                        tree.push((file.clone(), SourceMatch::Overlay(i, file)));
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("ignoring overlay file {file:?} in {overlay_dir:?}: {e:#}")
+                }
+            }
+        }
+    }
+    tree.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(tree)
+}
+
+#[test]
+fn test_is_exact_match() {
+    let reference = Path::new("/build/foo-1.0/src/Makefile");
+    assert!(is_exact_match(Path::new("foo-1.0/src/Makefile"), reference));
+    assert!(is_exact_match(Path::new("source/src/Makefile"), reference));
+    assert!(is_exact_match(Path::new("src/Makefile"), reference));
+    assert!(!is_exact_match(Path::new("source/lib/Makefile"), reference));
+    assert!(!is_exact_match(
+        Path::new("source/tests/src/Makefile"),
+        reference
+    ));
 }
 
 #[cfg(test)]
@@ -138,15 +453,16 @@ fn get_file_for_source_simple() {
     let dir = make_test_source_path(vec!["soft-version/src/main.c", "soft-version/src/Makefile"]);
     let overlay = make_test_source_path(vec![]);
     let res = get_file_for_source(
-        &dir.path(),
-        &overlay.path(),
+        &[dir.path()],
+        &[overlay.path()],
         "/source/soft-version/src/main.c".as_ref(),
+        None,
     )
     .unwrap()
     .unwrap();
     assert_eq!(
         res,
-        SourceMatch::Source(PathBuf::from("soft-version/src/main.c"))
+        SourceMatch::Source(0, PathBuf::from("soft-version/src/main.c"))
     );
 }
 
@@ -155,15 +471,16 @@ fn get_file_for_source_different_dir() {
     let dir = make_test_source_path(vec!["lib/core-net/network.c", "lib/plat/optee/network.c"]);
     let overlay = make_test_source_path(vec![]);
     let res = get_file_for_source(
-        &dir.path(),
-        &overlay.path(),
+        &[dir.path()],
+        &[overlay.path()],
         "/build/source/lib/core-net/network.c".as_ref(),
+        None,
     )
     .unwrap()
     .unwrap();
     assert_eq!(
         res,
-        SourceMatch::Source(PathBuf::from("lib/core-net/network.c"))
+        SourceMatch::Source(0, PathBuf::from("lib/core-net/network.c"))
     );
 }
 
@@ -175,15 +492,16 @@ fn get_file_for_source_regression_pr_7() {
     ]);
     let overlay = make_test_source_path(vec![]);
     let res = get_file_for_source(
-        &dir.path(),
-        &overlay.path(),
+        &[dir.path()],
+        &[overlay.path()],
         "build/source/lib/core-net/network.c".as_ref(),
+        None,
     )
     .unwrap()
     .unwrap();
     assert_eq!(
         res,
-        SourceMatch::Source(PathBuf::from("store/source/lib/core-net/network.c"))
+        SourceMatch::Source(0, PathBuf::from("store/source/lib/core-net/network.c"))
     );
 }
 
@@ -195,9 +513,10 @@ fn get_file_for_source_no_right_filename() {
     ]);
     let overlay = make_test_source_path(vec![]);
     let res = get_file_for_source(
-        &dir.path(),
-        &overlay.path(),
+        &[dir.path()],
+        &[overlay.path()],
         "build/source/lib/core-net/somethingelse.c".as_ref(),
+        None,
     );
     assert_eq!(res.unwrap(), None);
 }
@@ -211,15 +530,34 @@ fn get_file_for_source_glibc() {
     ]);
     let overlay = make_test_source_path(vec![]);
     let res = get_file_for_source(
-        &dir.path(),
-        &overlay.path(),
+        &[dir.path()],
+        &[overlay.path()],
         "/build/glibc-2.37/io/../sysdeps/unix/sysv/linux/openat64.c".as_ref(),
+        None,
     );
     assert_eq!(
         res.unwrap().unwrap(),
-        SourceMatch::Source(PathBuf::from(
-            "glibc-2.37/sysdeps/unix/sysv/linux/openat64.c"
-        ))
+        SourceMatch::Source(
+            0,
+            PathBuf::from("glibc-2.37/sysdeps/unix/sysv/linux/openat64.c")
+        )
+    );
+}
+
+#[test]
+fn get_file_for_source_parent_dir() {
+    let dir = make_test_source_path(vec!["pkg/x.c", "sub/x.c"]);
+    let overlay = make_test_source_path(vec![]);
+    // compared literally, `..` would not match anything and both candidates would be tied
+    let res = get_file_for_source(
+        &[dir.path()],
+        &[overlay.path()],
+        "/build/pkg/sub/../x.c".as_ref(),
+        None,
+    );
+    assert_eq!(
+        res.unwrap().unwrap(),
+        SourceMatch::Source(0, PathBuf::from("pkg/x.c"))
     );
 }
 
@@ -228,13 +566,14 @@ fn get_file_for_source_misleading_dir() {
     let dir = make_test_source_path(vec!["store/store/wrong/dir/file", "good/dir/store/file"]);
     let overlay = make_test_source_path(vec![]);
     let res = get_file_for_source(
-        &dir.path(),
-        &overlay.path(),
+        &[dir.path()],
+        &[overlay.path()],
         "/build/project/store/file".as_ref(),
+        None,
     );
     assert_eq!(
         res.unwrap().unwrap(),
-        SourceMatch::Source(PathBuf::from("good/dir/store/file"))
+        SourceMatch::Source(0, PathBuf::from("good/dir/store/file"))
     );
 }
 
@@ -248,9 +587,10 @@ fn get_file_for_source_ambiguous() {
     let dir = make_test_source_path(sources.clone());
     let overlay = make_test_source_path(vec![]);
     let res = get_file_for_source(
-        &dir.path(),
-        &overlay.path(),
+        &[dir.path()],
+        &[overlay.path()],
         "/build/glibc-2.37/fakeexample/openat64.c".as_ref(),
+        None,
     );
     assert!(res.is_err());
     let msg = dbg!(res.unwrap_err().to_string());
@@ -266,15 +606,16 @@ fn get_file_for_source_overlay_nothing_to_do() {
     let dir = make_test_source_path(vec!["lib/core-net/network.c", "lib/plat/optee/network.c"]);
     let overlay = make_test_source_path(vec!["lib/different"]);
     let res = get_file_for_source(
-        &dir.path(),
-        &overlay.path(),
+        &[dir.path()],
+        &[overlay.path()],
         "/build/source/lib/core-net/network.c".as_ref(),
+        None,
     )
     .unwrap()
     .unwrap();
     assert_eq!(
         res,
-        SourceMatch::Source(PathBuf::from("lib/core-net/network.c"))
+        SourceMatch::Source(0, PathBuf::from("lib/core-net/network.c"))
     );
 }
 
@@ -283,15 +624,16 @@ fn get_file_for_source_overlay_easy() {
     let dir = make_test_source_path(vec!["lib/core-net/network.c", "lib/plat/optee/network.c"]);
     let overlay = make_test_source_path(vec!["source/lib/core-net/network.c"]);
     let res = get_file_for_source(
-        &dir.path(),
-        &overlay.path(),
+        &[dir.path()],
+        &[overlay.path()],
         "/build/source/lib/core-net/network.c".as_ref(),
+        None,
     )
     .unwrap()
     .unwrap();
     assert_eq!(
         res,
-        SourceMatch::Overlay(PathBuf::from("source/lib/core-net/network.c"))
+        SourceMatch::Overlay(0, PathBuf::from("source/lib/core-net/network.c"))
     );
 }
 
@@ -300,15 +642,16 @@ fn get_file_for_source_overlay_other_path_patched() {
     let dir = make_test_source_path(vec!["lib/core-net/network.c", "lib/plat/optee/network.c"]);
     let overlay = make_test_source_path(vec!["source/lib/core-net/network.c"]);
     let res = get_file_for_source(
-        &dir.path(),
-        &overlay.path(),
+        &[dir.path()],
+        &[overlay.path()],
         "/build/source/lib/plat/optee/network.c".as_ref(),
+        None,
     )
     .unwrap()
     .unwrap();
     assert_eq!(
         res,
-        SourceMatch::Source(PathBuf::from("lib/plat/optee/network.c"))
+        SourceMatch::Source(0, PathBuf::from("lib/plat/optee/network.c"))
     );
 }
 
@@ -320,14 +663,248 @@ fn get_file_for_source_overlay_choice() {
         "source/lib/plat/optee/network.c",
     ]);
     let res = get_file_for_source(
-        &dir.path(),
-        &overlay.path(),
+        &[dir.path()],
+        &[overlay.path()],
         "/build/source/lib/plat/optee/network.c".as_ref(),
+        None,
     )
     .unwrap()
     .unwrap();
     assert_eq!(
         res,
-        SourceMatch::Overlay(PathBuf::from("source/lib/plat/optee/network.c"))
+        SourceMatch::Overlay(0, PathBuf::from("source/lib/plat/optee/network.c"))
+    );
+}
+
+#[test]
+fn get_file_for_source_several_overlays() {
+    let dir = make_test_source_path(vec!["lib/core-net/network.c", "lib/plat/optee/network.c"]);
+    let late = make_test_source_path(vec!["source/lib/core-net/network.c"]);
+    let early = make_test_source_path(vec![
+        "source/lib/core-net/network.c",
+        "source/lib/plat/optee/network.c",
+    ]);
+    let overlays = [late.path(), early.path()];
+    let res = get_file_for_source(
+        &[dir.path()],
+        &overlays,
+        "/build/source/lib/core-net/network.c".as_ref(),
+        None,
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        res,
+        SourceMatch::Overlay(0, PathBuf::from("source/lib/core-net/network.c"))
+    );
+    let res = get_file_for_source(
+        &[dir.path()],
+        &overlays,
+        "/build/source/lib/plat/optee/network.c".as_ref(),
+        None,
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        res,
+        SourceMatch::Overlay(1, PathBuf::from("source/lib/plat/optee/network.c"))
+    );
+}
+
+#[test]
+fn get_file_for_source_several_source_dirs() {
+    let main = make_test_source_path(vec!["hello-1.0/src/hello.c"]);
+    let vendored = make_test_source_path(vec!["extra-0.1/lib/extra.c", "extra-0.1/src/hello.c"]);
+    let sources = [main.path(), vendored.path()];
+    let overlay = make_test_source_path(vec![]);
+    let res = get_file_for_source(
+        &sources,
+        &[overlay.path()],
+        "/build/extra-0.1/lib/extra.c".as_ref(),
+        None,
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        res,
+        SourceMatch::Source(1, PathBuf::from("extra-0.1/lib/extra.c"))
+    );
+    let res = get_file_for_source(
+        &sources,
+        &[overlay.path()],
+        "/build/hello-1.0/src/hello.c".as_ref(),
+        None,
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        res,
+        SourceMatch::Source(0, PathBuf::from("hello-1.0/src/hello.c"))
+    );
+}
+
+#[test]
+fn get_file_for_source_git_checkout() {
+    // fetchFromGitHub: the store path is the root of the checkout, built in /build/<name>-<rev>
+    let dir = make_test_source_path(vec!["src/main.c", "vendor/zlib/src/main.c"]);
+    let overlay = make_test_source_path(vec![]);
+    let request: &Path = "/build/foo-v1.2/src/main.c".as_ref();
+    let res = get_file_for_source(&[dir.path()], &[overlay.path()], request, None);
+    assert!(res.is_err());
+    let res = get_file_for_source(&[dir.path()], &[overlay.path()], request, Some("foo"));
+    assert_eq!(
+        res.unwrap().unwrap(),
+        SourceMatch::Source(0, PathBuf::from("src/main.c"))
+    );
+}
+
+#[test]
+fn get_file_for_source_renamed_top_dir() {
+    // the archive unpacks to foo-1.2, but the build happened in /build/source
+    let dir = make_test_source_path(vec!["foo-1.2/src/main.c", "foo-1.2/vendor/bar/src/main.c"]);
+    let overlay = make_test_source_path(vec![]);
+    let request: &Path = "/build/source/src/main.c".as_ref();
+    let res = get_file_for_source(&[dir.path()], &[overlay.path()], request, None);
+    assert!(res.is_err());
+    let res = get_file_for_source(&[dir.path()], &[overlay.path()], request, Some("foo"));
+    assert_eq!(
+        res.unwrap().unwrap(),
+        SourceMatch::Source(0, PathBuf::from("foo-1.2/src/main.c"))
+    );
+}
+
+#[test]
+fn get_file_for_source_compressed() {
+    let dir = make_test_source_path(vec![
+        "foo-1.2/src/main.c.gz",
+        "foo-1.2/src/util.c.xz",
+        "foo-1.2/src/util.c",
+    ]);
+    let overlay = make_test_source_path(vec![]);
+    let res = get_file_for_source(
+        &[dir.path()],
+        &[overlay.path()],
+        "/build/foo-1.2/src/main.c".as_ref(),
+        None,
+    );
+    assert_eq!(
+        res.unwrap().unwrap(),
+        SourceMatch::CompressedSource(0, PathBuf::from("foo-1.2/src/main.c.gz"), Compression::Gzip)
+    );
+    // the uncompressed file is preferred
+    let res = get_file_for_source(
+        &[dir.path()],
+        &[overlay.path()],
+        "/build/foo-1.2/src/util.c".as_ref(),
+        None,
+    );
+    assert_eq!(
+        res.unwrap().unwrap(),
+        SourceMatch::Source(0, PathBuf::from("foo-1.2/src/util.c"))
+    );
+    // compressed files can also be requested as is
+    let res = get_file_for_source(
+        &[dir.path()],
+        &[overlay.path()],
+        "/build/foo-1.2/src/util.c.xz".as_ref(),
+        None,
+    );
+    assert_eq!(
+        res.unwrap().unwrap(),
+        SourceMatch::Source(0, PathBuf::from("foo-1.2/src/util.c.xz"))
+    );
+}
+
+#[test]
+fn get_file_for_source_many_candidates() {
+    let dir = tempfile::TempDir::new().unwrap();
+    for i in 0..50 {
+        let sub = dir.path().join(format!("foo-1.0/dir{i}"));
+        std::fs::create_dir_all(&sub).unwrap();
+        std::fs::write(sub.join("Makefile"), "content").unwrap();
+    }
+    for sub in ["foo-1.0/src", "foo-1.0/lib/sub"] {
+        let sub = dir.path().join(sub);
+        std::fs::create_dir_all(&sub).unwrap();
+        std::fs::write(sub.join("Makefile"), "content").unwrap();
+    }
+    let overlay = make_test_source_path(vec![]);
+    let find = |request: &str, max_candidates| {
+        get_file_for_source_with_limit(
+            &[dir.path()],
+            &[overlay.path()],
+            request.as_ref(),
+            None,
+            max_candidates,
+        )
+        .unwrap()
+    };
+    // exact matches are still found
+    assert_eq!(
+        find("/build/foo-1.0/src/Makefile", 10),
+        Some(SourceMatch::Source(
+            0,
+            PathBuf::from("foo-1.0/src/Makefile")
+        ))
+    );
+    assert_eq!(
+        find("/build/source/src/Makefile", 10),
+        Some(SourceMatch::Source(
+            0,
+            PathBuf::from("foo-1.0/src/Makefile")
+        ))
+    );
+    // fuzzy matches only below the limit
+    assert_eq!(
+        find("/build/foo-1.0/sub/Makefile", 100),
+        Some(SourceMatch::Source(
+            0,
+            PathBuf::from("foo-1.0/lib/sub/Makefile")
+        ))
+    );
+    assert_eq!(find("/build/foo-1.0/sub/Makefile", 10), None);
+}
+
+#[test]
+fn test_source_tree() {
+    let main = make_test_source_path(vec![
+        "lib/core-net/network.c",
+        "lib/plat/optee/network.c",
+        "README",
+    ]);
+    let vendored = make_test_source_path(vec!["README", "dep/dep.c"]);
+    let late = make_test_source_path(vec!["source/lib/core-net/network.c"]);
+    let early = make_test_source_path(vec![
+        "source/lib/core-net/network.c",
+        "source/lib/plat/optee/network.c",
+        "source/config.h",
+    ]);
+    let tree = source_tree(
+        &[main.path(), vendored.path()],
+        &[late.path(), early.path()],
+    )
+    .unwrap();
+    let expected = [
+        ("README", SourceMatch::Source(0, "README".into())),
+        ("dep/dep.c", SourceMatch::Source(1, "dep/dep.c".into())),
+        (
+            "lib/core-net/network.c",
+            SourceMatch::Overlay(0, "source/lib/core-net/network.c".into()),
+        ),
+        (
+            "lib/plat/optee/network.c",
+            SourceMatch::Overlay(1, "source/lib/plat/optee/network.c".into()),
+        ),
+        (
+            "source/config.h",
+            SourceMatch::Overlay(1, "source/config.h".into()),
+        ),
+    ];
+    assert_eq!(
+        tree,
+        expected
+            .into_iter()
+            .map(|(path, source)| (PathBuf::from(path), source))
+            .collect::<Vec<_>>()
     );
 }
@@ -1,5 +1,6 @@
 //! Utils to work with Nix store paths, i.e. `/nix/store/xxx`.
 
+use anyhow::Context;
 use std::{
     ffi::OsStr,
     os::unix::ffi::{OsStrExt, OsStringExt},
@@ -9,6 +10,13 @@ use std::{
 /// `/nix/store`
 pub const NIX_STORE: &str = "/nix/store";
 const HASH_LEN: usize = 32;
+/// The characters of the base32 alphabet nix uses for the hash part of store paths
+const NIX_BASE32_CHARS: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Whether `hash` is the hash part of a store path, like `34j18r2rpi7js1whmvzm9wliad55rilr`.
+pub fn is_store_path_hash(hash: &str) -> bool {
+    hash.len() == HASH_LEN && hash.bytes().all(|c| NIX_BASE32_CHARS.contains(&c))
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 /// A Nix store path (not necessarily its root)
@@ -60,6 +68,42 @@ impl StorePath {
         std::str::from_utf8(os_hash).unwrap()
     }
 
+    /// Checks that the hash part only contains characters nix uses in store path hashes.
+    ///
+    /// Mangled hashes (see [StorePath::demangle]) are accepted as well.
+    pub fn check_hash(&self) -> anyhow::Result<()> {
+        match self
+            .hash()
+            .chars()
+            .find(|c| !NIX_BASE32_CHARS.contains(&(c.to_ascii_lowercase() as u8)))
+        {
+            None => Ok(()),
+            Some(c) => anyhow::bail!(
+                "hash {} contains {c:?} which is not a nix base32 character",
+                self.hash()
+            ),
+        }
+    }
+
+    /// Splits the name part of the store path (after `hash-`) into a package name and a version,
+    /// like nix does: the version starts after the first dash not followed by a letter.
+    ///
+    /// For example `gnumake-4.4.1` is `gnumake` version `4.4.1`, and `glibc-2.40-66` is `glibc`
+    /// version `2.40-66`.
+    pub fn package_name_and_version(&self) -> (String, Option<String>) {
+        let name = self.name().to_string_lossy();
+        let name = name.get(HASH_LEN + 1..).unwrap_or_default();
+        let version_start = name
+            .char_indices()
+            .zip(name.chars().skip(1))
+            .find(|&((_, c), next)| c == '-' && !next.is_alphabetic())
+            .map(|((i, _), _)| i);
+        match version_start {
+            Some(i) => (name[..i].to_owned(), Some(name[i + 1..].to_owned())),
+            None => (name.to_owned(), None),
+        }
+    }
+
     /// Returns the suffix of the path, excluding `/nix/store/hash-name/`
     pub fn relative(&self) -> &Path {
         self.0
@@ -156,6 +200,68 @@ fn test_store_path_relative_bare_path() {
     assert_eq!(path.relative(), Path::new(""));
 }
 
+#[test]
+fn test_store_path_check_hash() {
+    for valid in [
+        "/nix/store/hbqzhmrscihnl9vgvw9nqhlzc64r1gwl-sl-5.05",
+        "/nix/store/JW65XNML1FGF4BFGZGISZCK3LFJWXG6L-GCC-12.3.0/include/c++/12.3.0/bits/vector.tcc",
+    ] {
+        StorePath::new(Path::new(valid))
+            .unwrap()
+            .check_hash()
+            .unwrap();
+    }
+    for invalid in [
+        "/nix/store/hbqzhmrscihnl9vgvw9nqhlzc64r1gwe-sl-5.05",
+        "/nix/store/hbqzhmrscihnl9vgvw9nqhlzc64r1gw?-sl-5.05",
+        "/nix/store/hbqzhmrscihnl9vgvw9nqhlzc64r1g..-sl-5.05",
+    ] {
+        StorePath::new(Path::new(invalid))
+            .unwrap()
+            .check_hash()
+            .unwrap_err();
+    }
+}
+
+#[test]
+fn test_package_name_and_version() {
+    for (path, name, version) in [
+        (
+            "/nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make",
+            "gnumake",
+            Some("4.4.1"),
+        ),
+        (
+            "/nix/store/g2jzxk3s7cnkhh8yq55l4fbvf639zy37-glibc-2.40-66",
+            "glibc",
+            Some("2.40-66"),
+        ),
+        (
+            "/nix/store/1jx9ksck44ply8ivjz8kqrwmyj4jqz8q-python3.13-lxml-5.4.0",
+            "python3.13-lxml",
+            Some("5.4.0"),
+        ),
+        (
+            "/nix/store/80nn028rq690b6qk8qprkvfbln38crdx-systemd-minimal-257.6-debug",
+            "systemd-minimal",
+            Some("257.6-debug"),
+        ),
+        (
+            "/nix/store/hbqzhmrscihnl9vgvw9nqhlzc64r1gwl-source",
+            "source",
+            None,
+        ),
+    ] {
+        assert_eq!(
+            StorePath::new(Path::new(path))
+                .unwrap()
+                .package_name_and_version(),
+            (name.to_owned(), version.map(str::to_owned)),
+            "{path}"
+        );
+    }
+}
+
 impl StorePath {
     /// To remove references, gcc is patched to replace the hash part
     /// of store path by an uppercase version in debug symbols.
@@ -164,12 +270,18 @@ impl StorePath {
     /// of template instantiation from libraries that live in other derivations.
     ///
     /// This function undoes the mangling.
-    pub fn demangle(self) -> StorePath {
-        let mut as_bytes = self.0.into_os_string().into_vec();
-        let len = as_bytes.len();
-        let store_len = NIX_STORE.len();
-        as_bytes[len.min(store_len + 1)..len.min(store_len + 1 + HASH_LEN)].make_ascii_lowercase();
-        StorePath::new(OsStr::from_bytes(&as_bytes).as_ref()).unwrap()
+    ///
+    /// The result is normalized: redundant slashes and `.` components are removed.
+    pub fn demangle(self) -> anyhow::Result<StorePath> {
+        let mut name = self.name().to_owned().into_vec();
+        name.get_mut(..HASH_LEN)
+            .context("store path name is too short to have a hash")?
+            .make_ascii_lowercase();
+        let demangled = Path::new(NIX_STORE)
+            .join(OsStr::from_bytes(&name))
+            .join(self.relative());
+        StorePath::new(&demangled)
+            .with_context(|| format!("demangled store path {} is invalid", demangled.display()))
     }
 }
 
@@ -178,7 +290,7 @@ fn test_demangle_nominal() {
     assert_eq!(
         StorePath::new(Path::new(
             "/nix/store/JW65XNML1FGF4BFGZGISZCK3LFJWXG6L-GCC-12.3.0/include/c++/12.3.0/bits/vector.tcc"
-        )).unwrap().demangle(),
+        )).unwrap().demangle().unwrap(),
         StorePath::new(Path::new(
             "/nix/store/jw65xnml1fgf4bfgzgiszck3lfjwxg6l-GCC-12.3.0/include/c++/12.3.0/bits/vector.tcc"
         )).unwrap()
@@ -190,9 +302,52 @@ fn test_demangle_noop() {
     assert_eq!(
         StorePath::new(Path::new(
             "/nix/store/jw65xnml1fgf4bfgzgiszck3lfjwxg6l-gcc-12.3.0/include/c++/12.3.0/bits/vector.tcc"
-        )).unwrap().demangle(),
+        )).unwrap().demangle().unwrap(),
         StorePath::new(Path::new(
             "/nix/store/jw65xnml1fgf4bfgzgiszck3lfjwxg6l-gcc-12.3.0/include/c++/12.3.0/bits/vector.tcc"
         )).unwrap()
     );
 }
+
+#[test]
+fn test_demangle_odd_paths() {
+    let hash = "JW65XNML1FGF4BFGZGISZCK3LFJWXG6L";
+    let mut paths: Vec<Vec<u8>> = vec![
+        format!("/nix/store//{hash}-gcc/include").into(),
+        format!("/nix/./store/{hash}-gcc").into(),
+        format!("/nix/store/{hash}-é/x").into(),
+        format!("/nix/store/{hash}--").into(),
+        format!("/nix/store/{hash}-/").into(),
+        b"/nix/store/\xff\xfe".to_vec(),
+    ];
+    for len in 0..HASH_LEN + 4 {
+        paths.push(format!("/nix/store/{}", &format!("{hash}-gcc")[..len]).into());
+    }
+    // random bytes after /nix/store/, biased towards characters found in store paths
+    let mut rng = fastrand::Rng::with_seed(1598);
+    let alphabet = b"aZ09-./\xc3\xa9\xff";
+    for _ in 0..10000 {
+        let mut path = b"/nix/store/".to_vec();
+        for _ in 0..rng.usize(0..50) {
+            path.push(alphabet[rng.usize(..alphabet.len())]);
+        }
+        paths.push(path);
+    }
+    for path in paths {
+        let path = Path::new(OsStr::from_bytes(&path));
+        if let Ok(store_path) = StorePath::new(path) {
+            let demangled = store_path.clone().demangle().unwrap();
+            assert_eq!(demangled.hash(), store_path.hash().to_ascii_lowercase());
+            assert_eq!(demangled.relative(), store_path.relative());
+        }
+    }
+}
+
+#[test]
+fn test_is_store_path_hash() {
+    assert!(is_store_path_hash("34j18r2rpi7js1whmvzm9wliad55rilr"));
+    assert!(!is_store_path_hash("34j18r2rpi7js1whmvzm9wliad55ril"));
+    assert!(!is_store_path_hash("34J18R2RPI7JS1WHMVZM9WLIAD55RILR"));
+    assert!(!is_store_path_hash("34j18r2rpi7js1whmvzm9wliad55rile"));
+    assert!(!is_store_path_hash("../../../../../../../etc/passwd"));
+}
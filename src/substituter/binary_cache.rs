@@ -1,25 +1,29 @@
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Context;
+use futures::StreamExt as _;
 use serde::Deserialize;
 use tokio::io::AsyncBufRead;
 use tokio::io::AsyncReadExt;
 
 use crate::cache::CachableFetcher;
+use crate::cache::EntryInfo;
 use crate::cache::FetcherCache;
 use crate::cache::FetcherCacheKey;
-use crate::nar::unpack_nar;
-use crate::store_path::StorePath;
+use crate::nar::{is_invalid_nar, unpack_compressed_nar, UnpackSettings};
+use crate::settings::Settings;
+use crate::store_path::{StorePath, NIX_STORE};
 use crate::utils::percent_encode_to_filename;
-use crate::utils::DecompressingReader;
+use crate::vfs::AsFile;
 use crate::vfs::RestrictedPath;
 use crate::{
     build_id::BuildId,
     nar::narinfo_to_nar_location,
-    substituter::{Priority, Substituter},
+    substituter::{PathInfo, Priority, Substituter},
     utils::Presence,
 };
 /// Structure of the metadata files created by the `index-debug-info` option of binary caches
@@ -28,6 +32,7 @@ pub struct DebugInfoRedirectJson {
     /// relative path to the nar.xz
     pub archive: String,
     /// relative path to the file inside of the nar
+    #[allow(dead_code)]
     pub member: String,
 }
 
@@ -145,40 +150,119 @@ pub trait BinaryCache: std::fmt::Debug + Send + Sync {
 
     /// Same as [Substituter::priority]
     fn priority(&self) -> Priority;
+
+    /// Same as [Substituter::check]
+    fn check(&self) -> impl std::future::Future<Output = anyhow::Result<()>> + Send;
+
+    /// Same as [Substituter::build_ids_with_prefix]
+    ///
+    /// The default implementation finds nothing, for binary caches which cannot list their
+    /// debuginfo redirects.
+    fn build_ids_with_prefix(
+        &self,
+        prefix: &str,
+    ) -> impl std::future::Future<Output = anyhow::Result<Vec<BuildId>>> + Send {
+        let _ = prefix;
+        async { Ok(Vec::new()) }
+    }
+}
+
+impl<T: BinaryCache> BinaryCache for Arc<T> {
+    fn stream_location(
+        &self,
+        what: &NarRelativeLocation,
+    ) -> impl std::future::Future<Output = anyhow::Result<Option<impl AsyncBufRead + Send>>> + Send
+    {
+        self.as_ref().stream_location(what)
+    }
+
+    fn priority(&self) -> Priority {
+        self.as_ref().priority()
+    }
+
+    fn check(&self) -> impl std::future::Future<Output = anyhow::Result<()>> + Send {
+        self.as_ref().check()
+    }
+
+    fn build_ids_with_prefix(
+        &self,
+        prefix: &str,
+    ) -> impl std::future::Future<Output = anyhow::Result<Vec<BuildId>>> + Send {
+        self.as_ref().build_ids_with_prefix(prefix)
+    }
 }
 
-const SMALL_FILE_SIZE: u64 = 1024 * 1024 - 1;
-/// Returns the content of this stream if it is smaller than [SMALL_FILE_SIZE]
-async fn read_small_stream(s: impl AsyncBufRead) -> anyhow::Result<Vec<u8>> {
+/// Default of [crate::settings::Settings::max_metadata_size]
+pub const DEFAULT_MAX_METADATA_SIZE: u64 = 1024 * 1024;
+
+/// Returns the content of the metadata file `what` read from this stream if it is not larger
+/// than `limit` bytes
+async fn read_limited_stream(
+    s: impl AsyncBufRead,
+    what: &NarRelativeLocation,
+    limit: u64,
+) -> anyhow::Result<Vec<u8>> {
     let mut buf = Vec::new();
     let original = std::pin::pin!(s);
-    let mut limited = original.take(SMALL_FILE_SIZE + 1);
-    limited.read_to_end(&mut buf).await?;
+    let mut limited = original.take(limit.saturating_add(1));
+    limited
+        .read_to_end(&mut buf)
+        .await
+        .with_context(|| format!("reading {}", what.location()))?;
     anyhow::ensure!(
-        buf.len() <= SMALL_FILE_SIZE as usize,
-        "stream is too large, refusing to parse"
+        buf.len() as u64 <= limit,
+        "{} is larger than {limit} bytes, refusing to parse it (see --max-metadata-size)",
+        what.location()
     );
     Ok(buf)
 }
 
 #[tokio::test]
 async fn read_small_stream_small() {
-    let content = vec![b'A'; SMALL_FILE_SIZE as usize];
+    let what = NarRelativeLocation::new("debuginfo/foo").unwrap();
+    let content = vec![b'A'; DEFAULT_MAX_METADATA_SIZE as usize];
     let reader = tokio::io::BufReader::new(&content[..]);
-    assert_eq!(read_small_stream(reader).await.unwrap(), content);
+    assert_eq!(
+        read_limited_stream(reader, &what, DEFAULT_MAX_METADATA_SIZE)
+            .await
+            .unwrap(),
+        content
+    );
 }
 
 #[tokio::test]
 async fn read_small_stream_big() {
-    let content = vec![b'A'; SMALL_FILE_SIZE as usize + 1];
+    let what = NarRelativeLocation::new("debuginfo/foo").unwrap();
+    let content = vec![b'A'; DEFAULT_MAX_METADATA_SIZE as usize + 1];
     let reader = tokio::io::BufReader::new(&content[..]);
-    read_small_stream(reader).await.unwrap_err();
+    let err = read_limited_stream(reader, &what, DEFAULT_MAX_METADATA_SIZE)
+        .await
+        .unwrap_err();
+    let message = format!("{err:#}");
+    assert!(message.contains("debuginfo/foo"), "{message}");
+    assert!(message.contains("--max-metadata-size"), "{message}");
 }
 
 #[tokio::test]
 async fn read_small_stream_infinite() {
+    let what = NarRelativeLocation::new("debuginfo/foo").unwrap();
     let reader = tokio::io::BufReader::new(tokio::io::repeat(b'A'));
-    read_small_stream(reader).await.unwrap_err();
+    read_limited_stream(reader, &what, DEFAULT_MAX_METADATA_SIZE)
+        .await
+        .unwrap_err();
+}
+
+#[tokio::test]
+async fn read_limited_stream_custom_limit() {
+    let what = NarRelativeLocation::new("foo.narinfo").unwrap();
+    let content = vec![b'A'; 3 << 20];
+    let reader = tokio::io::BufReader::new(&content[..]);
+    assert_eq!(
+        read_limited_stream(reader, &what, 4 << 20).await.unwrap(),
+        content
+    );
+    let reader = tokio::io::BufReader::new(&content[..]);
+    read_limited_stream(reader, &what, 1000).await.unwrap_err();
 }
 
 impl FetcherCacheKey for NarRelativeLocation {
@@ -187,24 +271,77 @@ impl FetcherCacheKey for NarRelativeLocation {
     }
 }
 
-impl<T: BinaryCache> CachableFetcher<NarRelativeLocation> for T {
+/// Fetches and unpacks nars from a [BinaryCache], so that they can be kept on disk by a
+/// [FetcherCache].
+struct NarFetcher<T: BinaryCache> {
+    inner: Arc<T>,
+    unpack: UnpackSettings,
+}
+
+impl<T: BinaryCache> CachableFetcher<NarRelativeLocation> for NarFetcher<T> {
     /// Fetch a nar by nar location
     ///
     /// `into` must not exist yet, but its parent must be an existing directory.
     ///
     /// In case of error, `into` may contain garbage
+    ///
+    /// Nars which are empty or truncated are reported as [Presence::NotFound].
     async fn fetch<'a>(
         &'a self,
         key: &'a NarRelativeLocation,
         into: &'a Path,
     ) -> anyhow::Result<Presence> {
-        let Some(nar_stream) = self.stream_location(key).await? else {
-            tracing::debug!("{} is missing from {:?}", key.location(), &self);
+        let start = std::time::Instant::now();
+        let Some(nar_stream) = self.inner.stream_location(key).await? else {
+            tracing::debug!("{} is missing from {:?}", key.location(), &self.inner);
             return Ok(Presence::NotFound);
         };
-        let decompressing_nar_reader =
-            DecompressingReader::new(nar_stream, key.location().as_bytes())?;
-        unpack_nar(decompressing_nar_reader, into).await?;
+        let latency = start.elapsed();
+        let nar_location = key.location().as_bytes();
+        let mut timings =
+            match unpack_compressed_nar(nar_stream, nar_location, into, &self.unpack).await {
+                Ok(timings) => timings,
+                Err(e) if is_invalid_nar(&e) => {
+                    // a broken upstream, let other substituters have a chance
+                    tracing::warn!(
+                        "{} from {:?} cannot be unpacked, considering it missing: {e:#}",
+                        key.location(),
+                        &self.inner
+                    );
+                    return Ok(Presence::NotFound);
+                }
+                Err(e) => return Err(e),
+            };
+        timings.network += latency;
+        timings.record(key.location());
+        Ok(Presence::Found)
+    }
+}
+
+/// Fetches small metadata files (narinfo, debuginfo redirects) from a [BinaryCache] as is, so that
+/// they can be kept on disk by a [FetcherCache].
+struct MetadataFetcher<T: BinaryCache> {
+    inner: Arc<T>,
+    /// see [crate::settings::Settings::max_metadata_size]
+    max_size: u64,
+}
+
+impl<T: BinaryCache> CachableFetcher<NarRelativeLocation> for MetadataFetcher<T> {
+    async fn fetch<'a>(
+        &'a self,
+        key: &'a NarRelativeLocation,
+        into: &'a Path,
+    ) -> anyhow::Result<Presence> {
+        let Some(stream) = self.inner.stream_location(key).await? else {
+            tracing::debug!("{} is missing from {:?}", key.location(), &self.inner);
+            return Ok(Presence::NotFound);
+        };
+        let content = read_limited_stream(stream, key, self.max_size)
+            .await
+            .with_context(|| format!("downloading {}", key.location()))?;
+        tokio::fs::write(into, content)
+            .await
+            .with_context(|| format!("writing {}", into.display()))?;
         Ok(Presence::Found)
     }
 }
@@ -243,86 +380,319 @@ fn small_nar_relative_location_roundtrip() {
     assert_eq!(a.location(), &b.location);
 }
 
+/// How long to wait for `nix-cache-info` when creating a [CachedBinaryCache]
+const CACHE_INFO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Returns the `Priority:` declared in the `nix-cache-info` file of this binary cache, if any.
+///
+/// Failures are only logged: the binary cache may well be reachable later.
+async fn declared_priority(cache: &impl BinaryCache, max_metadata_size: u64) -> Option<u32> {
+    let what = NarRelativeLocation::new("nix-cache-info").ok()?;
+    let fetch = async {
+        anyhow::Ok(match cache.stream_location(&what).await? {
+            None => None,
+            Some(stream) => Some(read_limited_stream(stream, &what, max_metadata_size).await?),
+        })
+    };
+    let content = match tokio::time::timeout(CACHE_INFO_TIMEOUT, fetch).await {
+        Ok(Ok(Some(content))) => content,
+        Ok(Ok(None)) => return None,
+        Ok(Err(e)) => {
+            tracing::debug!("could not fetch nix-cache-info: {e:#}");
+            return None;
+        }
+        Err(_) => {
+            tracing::debug!("timeout fetching nix-cache-info");
+            return None;
+        }
+    };
+    parse_cache_info_priority(&String::from_utf8_lossy(&content))
+}
+
+/// Returns the value of the `Priority:` line of the content of a `nix-cache-info` file
+fn parse_cache_info_priority(cache_info: &str) -> Option<u32> {
+    cache_info.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        if key.trim() == "Priority" {
+            value.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+#[test]
+fn test_parse_cache_info_priority() {
+    assert_eq!(
+        parse_cache_info_priority("StoreDir: /nix/store\nWantMassQuery: 1\nPriority: 40\n"),
+        Some(40)
+    );
+    assert_eq!(parse_cache_info_priority("StoreDir: /nix/store\n"), None);
+    assert_eq!(parse_cache_info_priority("Priority: high\n"), None);
+}
+
 type MemoryCache<K> = quick_cache::sync::Cache<K, SmallNarRelativeLocation>;
 const MEMORY_CACHE_SIZE: usize = 1000;
 /// A substituter implemented on top of a BinaryCache, with caching so that requesting twice the same
 /// store path will not download it twice
 pub struct CachedBinaryCache<T: BinaryCache> {
-    nar_cache: Arc<FetcherCache<NarRelativeLocation, T>>,
+    nar_cache: Arc<FetcherCache<NarRelativeLocation, NarFetcher<T>>>,
+    /// Only for binary caches that are not local, where requests are slow or impossible offline.
+    metadata_cache: Option<Arc<FetcherCache<NarRelativeLocation, MetadataFetcher<T>>>>,
     debuginfo_lookup_cache: MemoryCache<BuildId>,
     store_path_lookup_cache: MemoryCache<StorePath>,
+    offline: bool,
+    /// see [crate::settings::Settings::max_metadata_size]
+    max_metadata_size: u64,
+    /// The `Priority:` of the `nix-cache-info` file of the binary cache, if it has one
+    declared_priority: Option<u32>,
+    /// Index in the candidates of [CachedBinaryCache::find_debuginfo_redirect] of the last one
+    /// that was found, tried first next time because a binary cache uses always the same layout
+    redirect_layout_hint: AtomicU8,
 }
 
+/// Subdirectory of the cache directory of a [CachedBinaryCache] where metadata files are kept
+const METADATA: &str = "metadata";
+
 impl<T: BinaryCache + 'static> CachedBinaryCache<T> {
     /// turn an uncached BinaryCache into a cached substituter
     ///
     /// cache_dir is where downloaded nars are kept for approximately `expiration`
-    pub async fn wrap(inner: T, cache_dir: PathBuf, expiration: Duration) -> anyhow::Result<Self> {
-        let nar_cache = Arc::new(FetcherCache::new(cache_dir, inner, expiration).await?);
+    ///
+    /// If `offline` is true, nothing is downloaded: only nars already in `cache_dir` are served.
+    pub async fn wrap(
+        inner: T,
+        cache_dir: PathBuf,
+        expiration: Duration,
+        offline: bool,
+        settings: &Settings,
+    ) -> anyhow::Result<Self> {
+        let inner = Arc::new(inner);
+        let metadata_cache = if inner.priority() > Priority::Local {
+            let metadata_dir = cache_dir.join(METADATA);
+            tokio::fs::create_dir_all(&metadata_dir)
+                .await
+                .with_context(|| format!("mkdir({metadata_dir:?})"))?;
+            let fetcher = MetadataFetcher {
+                inner: inner.clone(),
+                max_size: settings.max_metadata_size,
+            };
+            Some(Arc::new(
+                FetcherCache::new(metadata_dir, fetcher, expiration, offline, &settings.cache)
+                    .await?,
+            ))
+        } else {
+            None
+        };
+        let fetcher = NarFetcher {
+            inner,
+            unpack: settings.unpack.clone(),
+        };
+        let nar_cache = Arc::new(
+            FetcherCache::new(cache_dir, fetcher, expiration, offline, &settings.cache).await?,
+        );
+        let declared_priority = if offline {
+            None
+        } else {
+            declared_priority(nar_cache.fetcher.inner.as_ref(), settings.max_metadata_size).await
+        };
         let debuginfo_lookup_cache = MemoryCache::new(MEMORY_CACHE_SIZE);
         let store_path_lookup_cache = MemoryCache::new(MEMORY_CACHE_SIZE);
         Ok(Self {
             nar_cache,
+            metadata_cache,
             debuginfo_lookup_cache,
             store_path_lookup_cache,
+            offline,
+            max_metadata_size: settings.max_metadata_size,
+            declared_priority,
+            redirect_layout_hint: AtomicU8::new(0),
         })
     }
 
     fn inner(&self) -> &T {
-        &self.nar_cache.fetcher
+        &self.nar_cache.fetcher.inner
+    }
+
+    /// Returns the content of this small file of the binary cache, or None if it does not exist.
+    ///
+    /// Goes through the on-disk metadata cache if there is one.
+    async fn read_metadata(&self, what: &NarRelativeLocation) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(ref metadata_cache) = self.metadata_cache else {
+            return match self.inner().stream_location(what).await? {
+                None => Ok(None),
+                Some(stream) => Ok(Some(
+                    read_limited_stream(stream, what, self.max_metadata_size).await?,
+                )),
+            };
+        };
+        let Some(path) = metadata_cache.get(what.clone()).await? else {
+            return Ok(None);
+        };
+        let Some(resolved) = path.resolve_inside_root().await? else {
+            anyhow::bail!("cached {} is a dangling symlink", what.location());
+        };
+        let file = resolved
+            .open()
+            .await
+            .with_context(|| format!("opening cached {}", what.location()))?;
+        Ok(Some(
+            read_limited_stream(
+                tokio::io::BufReader::new(file),
+                what,
+                self.max_metadata_size,
+            )
+            .await?,
+        ))
     }
 }
 
-impl<T: BinaryCache + 'static> std::fmt::Debug for CachedBinaryCache<T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_tuple("CachedSubstituter")
-            .field(&self.inner())
-            .finish()
+impl<T: BinaryCache + 'static> CachedBinaryCache<T> {
+    /// Like [Self::read_metadata] but never downloads anything: returns None if the file is not
+    /// in the metadata cache.
+    async fn peek_metadata(&self, what: &NarRelativeLocation) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.metadata_cache {
+            // without metadata cache, the binary cache is local anyway
+            None => self.read_metadata(what).await,
+            Some(ref metadata_cache) => match metadata_cache.inspect(what.as_key()).await? {
+                None => Ok(None),
+                Some(info) => {
+                    Ok(Some(tokio::fs::read(&info.path).await.with_context(
+                        || format!("reading cached {}", what.location()),
+                    )?))
+                }
+            },
+        }
     }
 }
 
-#[async_trait::async_trait]
-impl<T: BinaryCache + 'static> Substituter for CachedBinaryCache<T> {
-    #[tracing::instrument(level=tracing::Level::DEBUG)]
-    async fn build_id_to_debug_output(
+impl<T: BinaryCache + 'static> CachedBinaryCache<T> {
+    /// Looks for the json redirect written by `index-debug-info` for this build id, and returns
+    /// the location of the nar of the debug output it points to.
+    ///
+    /// The redirect may be at `debuginfo/{id}`, `debuginfo/{id}.debug`, or sharded like
+    /// `.build-id` directories at `debuginfo/{id[..2]}/{id[2..]}.debug`. Its `archive` is relative
+    /// to the directory containing it. The location where the last redirect was found is tried
+    /// first.
+    ///
+    /// If `peek` is true, only looks at what is already cached, see [Self::peek_metadata].
+    async fn find_debuginfo_redirect(
         &self,
         build_id: &BuildId,
-    ) -> anyhow::Result<Option<RestrictedPath>> {
-        let nar_location = match self
+        peek: bool,
+    ) -> anyhow::Result<Option<NarRelativeLocation>> {
+        let candidates = [
+            format!("debuginfo/{}", build_id),
+            format!("debuginfo/{}.debug", build_id),
+            format!("debuginfo/{}/{}.debug", &build_id[..2], &build_id[2..]),
+        ];
+        let hint =
+            (self.redirect_layout_hint.load(Ordering::Relaxed) as usize).min(candidates.len() - 1);
+        let order = std::iter::once(hint).chain((0..candidates.len()).filter(|&i| i != hint));
+        for (attempt, index) in order.enumerate() {
+            let location = NarRelativeLocation::new(&candidates[index])?;
+            let json_bytes = match peek {
+                true => self.peek_metadata(&location).await,
+                false => self.read_metadata(&location).await,
+            };
+            let json_bytes = match json_bytes {
+                Ok(Some(x)) => x,
+                Ok(None) => continue,
+                // only the error of the last candidate is reported
+                Err(e) if attempt + 1 < candidates.len() => {
+                    tracing::debug!(err=?e, "failed to read {location:?}, trying next candidate");
+                    continue;
+                }
+                Err(e) => return Err(e).context("looking for json redirect to debuginfo"),
+            };
+            let redirect: DebugInfoRedirectJson = serde_json::from_slice(&json_bytes)
+                .with_context(|| format!("unexpected format for {location:?} in {self:?}"))?;
+            self.redirect_layout_hint
+                .store(index as u8, Ordering::Relaxed);
+            let directory = Path::new(location.location())
+                .parent()
+                .unwrap_or(Path::new(""));
+            return NarRelativeLocation::new(&format!(
+                "{}/{}",
+                directory.display(),
+                &redirect.archive
+            ))
+            .map(Some);
+        }
+        tracing::debug!("{candidates:?} are missing from {self:?}");
+        Ok(None)
+    }
+
+    /// Returns the location of the nar of the debug output for this build id, remembering it in
+    /// memory.
+    async fn debug_output_location(
+        &self,
+        build_id: &BuildId,
+    ) -> anyhow::Result<Option<NarRelativeLocation>> {
+        match self
             .debuginfo_lookup_cache
             .get_value_or_guard_async(build_id)
             .await
         {
-            Ok(small_location) => small_location.into(),
+            Ok(small_location) => Ok(Some(small_location.into())),
             Err(placeholder) => {
-                let location1 = NarRelativeLocation::new(&format!("debuginfo/{}", build_id))?;
-                let location2 = NarRelativeLocation::new(&format!("debuginfo/{}.debug", build_id))?;
-                let maybe_json_stream = match self.inner().stream_location(&location1).await {
-                    Ok(Some(x)) => Some(x),
-                    Err(_) | Ok(None) => self.inner().stream_location(&location2).await?,
-                };
-                let Some(json_stream) = maybe_json_stream else {
-                    tracing::debug!("{location1:?} and {location2:?} are missing from {self:?}");
+                let Some(nar_path) = self.find_debuginfo_redirect(build_id, false).await? else {
                     return Ok(None);
                 };
-                let json_bytes = read_small_stream(json_stream)
-                    .await
-                    .context("looking for json redirect to debuginfo")?;
-                let redirect: DebugInfoRedirectJson = serde_json::from_slice(&json_bytes)
-                    .with_context(|| {
-                        format!("unexpected format for {location1:?} or {location2:?} in {self:?}")
-                    })?;
-                let nar_path =
-                    NarRelativeLocation::new(&format!("debuginfo/{}", &redirect.archive))?;
                 if let Err(e) = placeholder.insert(nar_path.clone().into()) {
                     tracing::trace!(err=?e, nar_path=nar_path.location(), "weird, cannot insert into cache");
                 };
-                nar_path
+                Ok(Some(nar_path))
             }
+        }
+    }
+}
+
+/// How many build ids [CachedBinaryCache::batch_exists] looks up at the same time
+const BATCH_CONCURRENCY: usize = 8;
+
+impl<T: BinaryCache + 'static> std::fmt::Debug for CachedBinaryCache<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("CachedSubstituter")
+            .field(&self.inner())
+            .finish()
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: BinaryCache + 'static> Substituter for CachedBinaryCache<T> {
+    #[tracing::instrument(level=tracing::Level::DEBUG)]
+    async fn build_id_to_debug_output(
+        &self,
+        build_id: &BuildId,
+    ) -> anyhow::Result<Option<RestrictedPath>> {
+        let Some(nar_location) = self.debug_output_location(build_id).await? else {
+            return Ok(None);
         };
         self.nar_cache.get(nar_location).await
     }
 
+    /// Only looks for the json redirects, [BATCH_CONCURRENCY] at a time, without fetching the
+    /// nars.
+    async fn batch_exists(&self, build_ids: &[BuildId]) -> Vec<anyhow::Result<Presence>> {
+        // collected so that the type of the stream does not involve closures, which trips the
+        // Send check of async_trait
+        let lookups: Vec<_> = build_ids
+            .iter()
+            .map(|build_id| self.debug_output_location(build_id))
+            .collect();
+        futures::stream::iter(lookups)
+            .buffered(BATCH_CONCURRENCY)
+            .map(|location| {
+                location.map(|location| match location {
+                    Some(_) => Presence::Found,
+                    None => Presence::NotFound,
+                })
+            })
+            .collect()
+            .await
+    }
+
     #[tracing::instrument(level=tracing::Level::DEBUG)]
     async fn fetch_store_path(
         &self,
@@ -337,15 +707,14 @@ impl<T: BinaryCache + 'static> Substituter for CachedBinaryCache<T> {
             Err(placeholder) => {
                 let narinfo_path =
                     NarRelativeLocation::new(&format!("{}.narinfo", store_path.hash()))?;
-                let Some(narinfo_stream) = self.inner().stream_location(&narinfo_path).await?
-                else {
+                let Some(narinfo) = self.read_metadata(&narinfo_path).await? else {
                     tracing::debug!("{narinfo_path:?} is missing from {self:?}");
                     return Ok(None);
                 };
-                let nar_path = narinfo_to_nar_location(narinfo_stream)
+                let narinfo = narinfo_to_nar_location(&narinfo[..])
                     .await
                     .with_context(|| format!("parsing {narinfo_path:?}"))?;
-                let nar_path = NarRelativeLocation::new(&nar_path)?;
+                let nar_path = NarRelativeLocation::new(&narinfo.url)?;
                 if let Err(e) = placeholder.insert(nar_path.clone().into()) {
                     tracing::trace!(err=?e, nar_path=nar_path.location(), "weird, cannot insert into cache");
                 };
@@ -355,15 +724,230 @@ impl<T: BinaryCache + 'static> Substituter for CachedBinaryCache<T> {
         self.nar_cache.get(nar_location).await
     }
 
+    async fn inspect_debug_output(&self, build_id: &BuildId) -> anyhow::Result<Option<EntryInfo>> {
+        let nar_location: NarRelativeLocation = match self.debuginfo_lookup_cache.get(build_id) {
+            Some(small_location) => small_location.into(),
+            None => match self.find_debuginfo_redirect(build_id, true).await? {
+                Some(nar_path) => nar_path,
+                None => return Ok(None),
+            },
+        };
+        self.nar_cache.inspect(nar_location.as_key()).await
+    }
+
+    async fn inspect_store_path(
+        &self,
+        store_path: &StorePath,
+    ) -> anyhow::Result<Option<EntryInfo>> {
+        let nar_location: NarRelativeLocation =
+            match self.store_path_lookup_cache.get(&store_path.root()) {
+                Some(small_location) => small_location.into(),
+                None => {
+                    let narinfo_path =
+                        NarRelativeLocation::new(&format!("{}.narinfo", store_path.hash()))?;
+                    let Some(narinfo) = self.peek_metadata(&narinfo_path).await? else {
+                        return Ok(None);
+                    };
+                    let narinfo = narinfo_to_nar_location(&narinfo[..])
+                        .await
+                        .with_context(|| format!("parsing {narinfo_path:?}"))?;
+                    NarRelativeLocation::new(&narinfo.url)?
+                }
+            };
+        self.nar_cache.inspect(nar_location.as_key()).await
+    }
+
+    #[tracing::instrument(level=tracing::Level::DEBUG)]
+    async fn path_info(&self, store_path: &StorePath) -> anyhow::Result<Option<PathInfo>> {
+        let narinfo_path = NarRelativeLocation::new(&format!("{}.narinfo", store_path.hash()))?;
+        let Some(narinfo) = self.read_metadata(&narinfo_path).await? else {
+            return Ok(None);
+        };
+        let narinfo = narinfo_to_nar_location(&narinfo[..])
+            .await
+            .with_context(|| format!("parsing {narinfo_path:?}"))?;
+        let deriver = narinfo
+            .deriver
+            .map(|deriver| StorePath::new(&Path::new(NIX_STORE).join(deriver)))
+            .transpose()
+            .with_context(|| format!("invalid Deriver in {narinfo_path:?}"))?;
+        let references = narinfo
+            .references
+            .iter()
+            .map(|reference| StorePath::new(&Path::new(NIX_STORE).join(reference)))
+            .collect::<anyhow::Result<_>>()
+            .with_context(|| format!("invalid References in {narinfo_path:?}"))?;
+        Ok(Some(PathInfo {
+            deriver,
+            references,
+        }))
+    }
+
+    async fn build_ids_with_prefix(&self, prefix: &str) -> anyhow::Result<Vec<BuildId>> {
+        self.inner().build_ids_with_prefix(prefix).await
+    }
+
+    async fn fetch_narinfo_raw(&self, hash: &str) -> anyhow::Result<Option<String>> {
+        let narinfo_path = NarRelativeLocation::new(&format!("{hash}.narinfo"))?;
+        let Some(narinfo) = self.read_metadata(&narinfo_path).await? else {
+            return Ok(None);
+        };
+        let narinfo =
+            String::from_utf8(narinfo).with_context(|| format!("decoding {narinfo_path:?}"))?;
+        Ok(Some(narinfo))
+    }
+
     fn priority(&self) -> Priority {
-        BinaryCache::priority(self.inner())
+        match self.declared_priority {
+            Some(priority) => Priority::BinaryCache(priority),
+            None => BinaryCache::priority(self.inner()),
+        }
     }
 
     fn spawn_cleanup_task(&self) {
-        self.nar_cache.clone().spawn_cleanup_task()
+        self.nar_cache.clone().spawn_cleanup_task();
+        if let Some(ref metadata_cache) = self.metadata_cache {
+            metadata_cache.clone().spawn_cleanup_task();
+        }
     }
 
     async fn shrink_disk_cache(&self) -> anyhow::Result<()> {
+        if let Some(ref metadata_cache) = self.metadata_cache {
+            metadata_cache.shrink_cache().await?;
+        }
         self.nar_cache.shrink_cache().await
     }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        if self.offline {
+            // we are not supposed to contact it anyway
+            return Ok(());
+        }
+        self.inner().check().await
+    }
+}
+
+#[tokio::test]
+async fn test_redirect_layout_hint() {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    /// A binary cache in memory, recording which files are requested
+    #[derive(Debug, Default)]
+    struct RecordingBinaryCache {
+        files: HashMap<String, Vec<u8>>,
+        requests: Mutex<Vec<String>>,
+    }
+    impl BinaryCache for RecordingBinaryCache {
+        async fn stream_location(
+            &self,
+            what: &NarRelativeLocation,
+        ) -> anyhow::Result<Option<impl AsyncBufRead + Send>> {
+            self.requests
+                .lock()
+                .unwrap()
+                .push(what.location().to_owned());
+            Ok(self
+                .files
+                .get(what.location())
+                .map(|content| std::io::Cursor::new(content.clone())))
+        }
+        fn priority(&self) -> Priority {
+            Priority::Local
+        }
+        async fn check(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    let first = BuildId::new("0e20481820d3b92468102b35a5e4a29a8695c1af").unwrap();
+    let second = BuildId::new("b87e34547e94f167f4b737f3a25955477a485cc7").unwrap();
+    let mut inner = RecordingBinaryCache::default();
+    for build_id in [&first, &second] {
+        inner.files.insert(
+            format!("debuginfo/{build_id}.debug"),
+            br#"{"archive": "../nar/foo.nar.xz", "member": "foo"}"#.to_vec(),
+        );
+    }
+    let t = tempfile::tempdir().unwrap();
+    let cache = CachedBinaryCache::wrap(
+        inner,
+        t.path().into(),
+        Duration::from_secs(1000),
+        false,
+        &Settings::default(),
+    )
+    .await
+    .unwrap();
+    // forget the request for nix-cache-info
+    cache.inner().requests.lock().unwrap().clear();
+    for build_id in [&first, &second] {
+        assert_eq!(
+            cache
+                .find_debuginfo_redirect(build_id, false)
+                .await
+                .unwrap()
+                .unwrap()
+                .location(),
+            "nar/foo.nar.xz"
+        );
+    }
+    // the second lookup goes directly to the layout where the first redirect was found
+    assert_eq!(
+        *cache.inner().requests.lock().unwrap(),
+        [
+            format!("debuginfo/{first}"),
+            format!("debuginfo/{first}.debug"),
+            format!("debuginfo/{second}.debug"),
+        ]
+    );
+    // other layouts are still tried when the hint is wrong
+    cache.inner().requests.lock().unwrap().clear();
+    let missing = BuildId::new("0000000000000000000000000000000000000000").unwrap();
+    assert!(cache
+        .find_debuginfo_redirect(&missing, false)
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        *cache.inner().requests.lock().unwrap(),
+        [
+            format!("debuginfo/{missing}.debug"),
+            format!("debuginfo/{missing}"),
+            format!("debuginfo/00/{}.debug", &missing[2..]),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_fetch_empty_nar() {
+    /// A binary cache serving an empty file for every location
+    #[derive(Debug)]
+    struct EmptyBinaryCache;
+    impl BinaryCache for EmptyBinaryCache {
+        async fn stream_location(
+            &self,
+            _what: &NarRelativeLocation,
+        ) -> anyhow::Result<Option<impl AsyncBufRead + Send>> {
+            Ok(Some(&b""[..]))
+        }
+        fn priority(&self) -> Priority {
+            Priority::Local
+        }
+        async fn check(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    let t = tempfile::tempdir().unwrap();
+    let location = NarRelativeLocation::new("nar/foo.nar.xz").unwrap();
+    let fetcher = NarFetcher {
+        inner: Arc::new(EmptyBinaryCache),
+        unpack: UnpackSettings::default(),
+    };
+    let presence = fetcher
+        .fetch(&location, &t.path().join("out"))
+        .await
+        .unwrap();
+    assert_eq!(presence, Presence::NotFound);
 }
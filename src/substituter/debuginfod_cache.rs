@@ -0,0 +1,454 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+
+use crate::{
+    build_id::{BuildId, PathTemplate},
+    cache::{CachableFetcher, CacheSettings, EntryInfo, FetcherCache, FetcherCacheKey},
+    store_path::StorePath,
+    utils::{copy_recursively, Presence},
+    vfs::RestrictedPath,
+};
+
+use super::{PathInfo, Priority, Substituter};
+
+/// Name of the debuginfo of a build id in the client cache of elfutils
+const DEBUGINFO: &str = "debuginfo";
+/// Name of the executable of a build id in the client cache of elfutils
+const EXECUTABLE: &str = "executable";
+/// Name of the source files of a build id in the client cache of elfutils.
+///
+/// Either a directory, or a prefix of files whose name is the path of the source with `/` replaced
+/// by `#`.
+const SOURCE: &str = "source";
+
+/// Where the client cache of elfutils keeps the files of a build id, relative to its root
+const ELFUTILS_LAYOUT: &str = "{id_prefix}{id_rest}";
+
+/// Default layout of `unpacked://` substituters, see [DebuginfodCacheSubstituter::with_layout]
+pub const UNPACKED_LAYOUT: &str = "{id_prefix}/{id_rest}";
+
+impl FetcherCacheKey for BuildId {
+    fn as_key(&self) -> &str {
+        self
+    }
+}
+
+/// Hardlinks `from` to `to`, or copies it when they are on different filesystems.
+fn link_or_copy(from: &Path, to: &Path) -> std::io::Result<()> {
+    let meta = std::fs::symlink_metadata(from)?;
+    if meta.is_file() && std::fs::hard_link(from, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to).map(|_| ())
+}
+
+/// Returns the relative path of the source file stored as `name` in the client cache of elfutils,
+/// like `build/src/main.c` for `source#build#src#main.c`.
+///
+/// Returns None for other files, and for files whose path elfutils replaced by a hash because it
+/// was too long.
+fn escaped_source_path(name: &str) -> Option<PathBuf> {
+    let escaped = name.strip_prefix(SOURCE)?.strip_prefix('#')?;
+    let path: PathBuf = escaped.split('#').filter(|c| !c.is_empty()).collect();
+    let normal = path.components().all(|c| matches!(c, Component::Normal(_)));
+    (normal && path.components().next().is_some()).then_some(path)
+}
+
+#[test]
+fn test_escaped_source_path() {
+    assert_eq!(
+        escaped_source_path("source#build#src#main.c"),
+        Some(PathBuf::from("build/src/main.c"))
+    );
+    assert_eq!(
+        escaped_source_path("source##nix#store#foo#a.c"),
+        Some(PathBuf::from("nix/store/foo/a.c"))
+    );
+    assert_eq!(escaped_source_path("source"), None);
+    assert_eq!(escaped_source_path("source#"), None);
+    assert_eq!(escaped_source_path("source#build#..#..#etc#passwd"), None);
+    assert_eq!(escaped_source_path("debuginfo"), None);
+    assert_eq!(escaped_source_path("sources#a.c"), None);
+}
+
+/// Lays out the files of `build_id` from the client cache of elfutils at `from` like a debug
+/// output at `into`.
+fn make_debug_output(build_id: &BuildId, from: &Path, into: &Path) -> anyhow::Result<Presence> {
+    let mut found = false;
+    let debug = into.join(build_id.in_debug_output("debug"));
+    let dir = debug.parent().context("debug file has no parent")?;
+    std::fs::create_dir_all(dir).with_context(|| format!("mkdir -p {dir:?}"))?;
+    for (name, extension) in [(DEBUGINFO, "debug"), (EXECUTABLE, "executable")] {
+        let source = from.join(name);
+        if std::fs::symlink_metadata(&source).is_err() {
+            continue;
+        }
+        let target = into.join(build_id.in_debug_output(extension));
+        link_or_copy(&source, &target)
+            .with_context(|| format!("copying {source:?} to {target:?}"))?;
+        found = true;
+    }
+    if !found {
+        return Ok(Presence::NotFound);
+    }
+    let sources = into.join(build_id.in_debug_output("source"));
+    let source_dir = from.join(SOURCE);
+    if std::fs::symlink_metadata(&source_dir).is_ok_and(|meta| meta.is_dir()) {
+        copy_recursively(&source_dir, &sources)
+            .with_context(|| format!("copying {source_dir:?} to {sources:?}"))?;
+    }
+    for entry in std::fs::read_dir(from).with_context(|| format!("opening {from:?}"))? {
+        let entry = entry.with_context(|| format!("reading {from:?}"))?;
+        let Some(relative) = entry.file_name().to_str().and_then(escaped_source_path) else {
+            continue;
+        };
+        let target = sources.join(relative);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent).with_context(|| format!("mkdir -p {parent:?}"))?;
+        }
+        link_or_copy(&entry.path(), &target)
+            .with_context(|| format!("copying {:?} to {target:?}", entry.path()))?;
+    }
+    if sources.exists() {
+        // elfutils does not know about patched sources
+        let overlay = into.join(build_id.in_debug_output("sourceoverlay"));
+        std::fs::create_dir(&overlay).with_context(|| format!("mkdir {overlay:?}"))?;
+    }
+    Ok(Presence::Found)
+}
+
+/// Copies the files of a build id out of the client cache of elfutils, in the layout of a debug
+/// output
+struct DebugOutputMaker {
+    root: PathBuf,
+    /// where the files of a build id are, relative to `root`
+    layout: PathTemplate,
+}
+
+impl DebugOutputMaker {
+    /// The directory containing the files of `build_id`
+    fn entry(&self, build_id: &BuildId) -> PathBuf {
+        self.root.join(self.layout.expand(build_id))
+    }
+}
+
+impl CachableFetcher<BuildId> for DebugOutputMaker {
+    async fn fetch<'a>(&'a self, key: &'a BuildId, into: &'a Path) -> anyhow::Result<Presence> {
+        let from = self.entry(key);
+        match tokio::fs::symlink_metadata(&from).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Presence::NotFound),
+            Err(e) => return Err(e).with_context(|| format!("stat({from:?})")),
+            Ok(_) => (),
+        }
+        let build_id = key.clone();
+        let into = into.to_owned();
+        tokio::task::spawn_blocking(move || make_debug_output(&build_id, &from, &into))
+            .await?
+            .with_context(|| format!("copying {key} out of the debuginfod client cache"))
+    }
+}
+
+/// Serves the debuginfo, executables and sources already downloaded by `debuginfod-find` and
+/// other clients using elfutils, in `~/.cache/debuginfod_client` by default.
+///
+/// Their files are hardlinked (or copied) to the cache directory so that they remain available
+/// for as long as they are served, even if elfutils cleans its own cache.
+///
+/// Elfutils only knows build ids, so store paths cannot be fetched from there. Source files are
+/// only found when requested by a path outside the store.
+///
+/// Other directories with the same files for each build id can be served with another layout,
+/// see [DebuginfodCacheSubstituter::with_layout].
+pub struct DebuginfodCacheSubstituter {
+    root: PathBuf,
+    copies: Arc<FetcherCache<BuildId, DebugOutputMaker>>,
+}
+
+impl std::fmt::Debug for DebuginfodCacheSubstituter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DebuginfodCacheSubstituter")
+            .field("root", &self.root)
+            .finish()
+    }
+}
+
+impl DebuginfodCacheSubstituter {
+    /// A new `DebuginfodCacheSubstituter` serving the elfutils client cache at `root`, with copies
+    /// kept in `cache_dir` for approximately `expiration`.
+    pub async fn new(
+        root: &Path,
+        cache_dir: PathBuf,
+        expiration: Duration,
+        settings: &CacheSettings,
+    ) -> anyhow::Result<Self> {
+        let layout = PathTemplate::new(ELFUTILS_LAYOUT)?;
+        Self::with_layout(root, layout, cache_dir, expiration, settings).await
+    }
+
+    /// Like [DebuginfodCacheSubstituter::new], but the `debuginfo`, `executable` and `source`
+    /// files of a build id are in the directory `layout` relative to `root`, instead of the
+    /// directory named by the build id.
+    ///
+    /// This serves `unpacked://` substituters, with [UNPACKED_LAYOUT] by default.
+    pub async fn with_layout(
+        root: &Path,
+        layout: PathTemplate,
+        cache_dir: PathBuf,
+        expiration: Duration,
+        settings: &CacheSettings,
+    ) -> anyhow::Result<Self> {
+        let maker = DebugOutputMaker {
+            root: root.to_owned(),
+            layout,
+        };
+        // reading a local directory is possible even offline
+        let copies =
+            Arc::new(FetcherCache::new(cache_dir, maker, expiration, false, settings).await?);
+        Ok(Self {
+            root: root.to_owned(),
+            copies,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Substituter for DebuginfodCacheSubstituter {
+    async fn build_id_to_debug_output(
+        &self,
+        build_id: &BuildId,
+    ) -> anyhow::Result<Option<RestrictedPath>> {
+        self.copies.get(build_id.clone()).await
+    }
+
+    /// Looks for the files in the client cache of elfutils without copying them.
+    async fn batch_exists(&self, build_ids: &[BuildId]) -> Vec<anyhow::Result<Presence>> {
+        let mut result = Vec::with_capacity(build_ids.len());
+        for build_id in build_ids {
+            let dir = self.copies.fetcher.entry(build_id);
+            let mut presence = Ok(Presence::NotFound);
+            for name in [DEBUGINFO, EXECUTABLE] {
+                let path = dir.join(name);
+                match tokio::fs::symlink_metadata(&path).await {
+                    Ok(_) => {
+                        presence = Ok(Presence::Found);
+                        break;
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                    Err(e) => {
+                        presence = Err(e).with_context(|| format!("stat({path:?})"));
+                        break;
+                    }
+                }
+            }
+            result.push(presence);
+        }
+        result
+    }
+
+    // elfutils indexes everything by build id
+    async fn fetch_store_path(
+        &self,
+        _store_path: &StorePath,
+    ) -> anyhow::Result<Option<RestrictedPath>> {
+        Ok(None)
+    }
+
+    async fn path_info(&self, _store_path: &StorePath) -> anyhow::Result<Option<PathInfo>> {
+        Ok(None)
+    }
+
+    async fn inspect_debug_output(&self, build_id: &BuildId) -> anyhow::Result<Option<EntryInfo>> {
+        self.copies.inspect(build_id.as_key()).await
+    }
+
+    async fn inspect_store_path(
+        &self,
+        _store_path: &StorePath,
+    ) -> anyhow::Result<Option<EntryInfo>> {
+        Ok(None)
+    }
+
+    fn priority(&self) -> Priority {
+        Priority::LocalUnpacked
+    }
+
+    fn spawn_cleanup_task(&self) {
+        self.copies.clone().spawn_cleanup_task();
+    }
+
+    async fn shrink_disk_cache(&self) -> anyhow::Result<()> {
+        self.copies.shrink_cache().await
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        let mut entries = tokio::fs::read_dir(&self.root)
+            .await
+            .with_context(|| format!("opening {}", self.root.display()))?;
+        entries
+            .next_entry()
+            .await
+            .with_context(|| format!("reading {}", self.root.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::AsFile;
+    use reqwest::Url;
+    use tokio::io::AsyncReadExt;
+
+    const BUILD_ID: &str = "0e20481820d3b92468102b35a5e4a29a8695c1af";
+
+    async fn read(path: RestrictedPath) -> String {
+        let mut content = String::new();
+        path.resolve_inside_root()
+            .await
+            .unwrap()
+            .unwrap()
+            .open()
+            .await
+            .unwrap()
+            .read_to_string(&mut content)
+            .await
+            .unwrap();
+        content
+    }
+
+    #[tokio::test]
+    async fn test_debuginfod_cache_layout() {
+        let t = tempfile::tempdir().unwrap();
+        let root = t.path().join("debuginfod_client");
+        let entry = root.join(BUILD_ID);
+        std::fs::create_dir_all(entry.join("source/build/lib")).unwrap();
+        std::fs::write(entry.join(DEBUGINFO), "debug").unwrap();
+        std::fs::write(entry.join(EXECUTABLE), "exe").unwrap();
+        std::fs::write(entry.join("source/build/lib/util.c"), "util").unwrap();
+        std::fs::write(entry.join("source#build#src#main.c"), "main").unwrap();
+        let cache = t.path().join("cache");
+        std::fs::create_dir(&cache).unwrap();
+        let substituter = DebuginfodCacheSubstituter::new(
+            &root,
+            cache,
+            Duration::from_secs(1000),
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
+        substituter.check().await.unwrap();
+
+        let build_id = BuildId::new(BUILD_ID).unwrap();
+        let missing = BuildId::new(&BUILD_ID.replace('0', "1")).unwrap();
+        let presence = substituter
+            .batch_exists(&[build_id.clone(), missing.clone()])
+            .await;
+        assert_eq!(presence[0].as_ref().unwrap(), &Presence::Found);
+        assert_eq!(presence[1].as_ref().unwrap(), &Presence::NotFound);
+        assert!(substituter
+            .build_id_to_debug_output(&missing)
+            .await
+            .unwrap()
+            .is_none());
+
+        let output = substituter
+            .build_id_to_debug_output(&build_id)
+            .await
+            .unwrap()
+            .unwrap();
+        for (file, expected) in [
+            (build_id.in_debug_output("debug"), "debug"),
+            (build_id.in_debug_output("executable"), "exe"),
+            (
+                build_id.in_debug_output("source") + "/build/lib/util.c",
+                "util",
+            ),
+            (
+                build_id.in_debug_output("source") + "/build/src/main.c",
+                "main",
+            ),
+        ] {
+            assert_eq!(read(output.clone().join(&file)).await, expected, "{file}");
+        }
+        assert!(substituter
+            .inspect_debug_output(&build_id)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_unpacked_layout() {
+        let t = tempfile::tempdir().unwrap();
+        let root = t.path().join("archive");
+        let build_id = BuildId::new(BUILD_ID).unwrap();
+        let entry = root.join(&BUILD_ID[..2]).join(&BUILD_ID[2..]);
+        std::fs::create_dir_all(entry.join("source/src")).unwrap();
+        std::fs::write(entry.join(DEBUGINFO), "debug").unwrap();
+        std::fs::write(entry.join("source/src/main.c"), "main").unwrap();
+        let cache = t.path().join("cache");
+        std::fs::create_dir(&cache).unwrap();
+        let url = Url::from_directory_path(&root).unwrap().to_string();
+        let url = Url::parse(&url.replacen("file://", "unpacked://", 1)).unwrap();
+        let substituter = crate::substituter::substituter_from_url(
+            &url,
+            cache,
+            Duration::from_secs(1000),
+            false,
+            None,
+            false,
+            &Url::parse("http://127.0.0.1:8080").unwrap(),
+            &crate::settings::Settings::default(),
+        )
+        .await
+        .unwrap();
+        substituter.check().await.unwrap();
+        assert_eq!(
+            substituter
+                .batch_exists(std::slice::from_ref(&build_id))
+                .await[0]
+                .as_ref()
+                .unwrap(),
+            &Presence::Found
+        );
+        let output = substituter
+            .build_id_to_debug_output(&build_id)
+            .await
+            .unwrap()
+            .unwrap();
+        for (file, expected) in [
+            (build_id.in_debug_output("debug"), "debug"),
+            (build_id.in_debug_output("source") + "/src/main.c", "main"),
+        ] {
+            assert_eq!(read(output.clone().join(&file)).await, expected, "{file}");
+        }
+
+        // the layout can be changed
+        let flat = t.path().join("flat");
+        std::fs::create_dir_all(flat.join(format!("{BUILD_ID}.d"))).unwrap();
+        std::fs::write(flat.join(format!("{BUILD_ID}.d")).join(EXECUTABLE), "exe").unwrap();
+        let cache = t.path().join("cache2");
+        std::fs::create_dir(&cache).unwrap();
+        let substituter = DebuginfodCacheSubstituter::with_layout(
+            &flat,
+            PathTemplate::new("{id_prefix}{id_rest}.d").unwrap(),
+            cache,
+            Duration::from_secs(1000),
+            &CacheSettings::default(),
+        )
+        .await
+        .unwrap();
+        let output = substituter
+            .build_id_to_debug_output(&build_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let executable = build_id.in_debug_output("executable");
+        assert_eq!(read(output.join(&executable)).await, "exe");
+    }
+}
@@ -7,6 +7,8 @@ use std::{
 use anyhow::Context;
 use tokio::io::AsyncBufRead;
 
+use crate::build_id::BuildId;
+use crate::settings::Settings;
 use crate::substituter::binary_cache::{BinaryCache, CachedBinaryCache, NarRelativeLocation};
 
 use super::Priority;
@@ -56,6 +58,97 @@ impl BinaryCache for FileSubstituterInner {
     fn priority(&self) -> Priority {
         Priority::Local
     }
+
+    async fn build_ids_with_prefix(&self, prefix: &str) -> anyhow::Result<Vec<BuildId>> {
+        let debuginfo_dir = self.path.join("debuginfo");
+        let prefix = prefix.to_owned();
+        tokio::task::spawn_blocking(move || {
+            if !debuginfo_dir.is_dir() {
+                return Ok(Vec::new());
+            }
+            Ok(list_debuginfo_redirects(&debuginfo_dir)?
+                .into_iter()
+                .map(|(build_id, _)| build_id)
+                .filter(|build_id| build_id.has_prefix(&prefix))
+                .collect())
+        })
+        .await?
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        let metadata = tokio::fs::metadata(&self.path)
+            .await
+            .with_context(|| format!("stat({})", self.path.display()))?;
+        anyhow::ensure!(
+            metadata.is_dir(),
+            "{} is not a directory",
+            self.path.display()
+        );
+        Ok(())
+    }
+}
+
+/// Returns the build ids of the json redirects in the `debuginfo` directory of a local binary
+/// cache, with the path of the redirect, sorted by build id.
+///
+/// Both `debuginfo/<id>.debug` (or without extension) and `debuginfo/<2 chars>/<rest>.debug`
+/// layouts are recognized; other files are ignored.
+pub fn list_debuginfo_redirects(debuginfo_dir: &Path) -> anyhow::Result<Vec<(BuildId, PathBuf)>> {
+    fn parse_name(name: &str) -> Option<BuildId> {
+        BuildId::new(name.strip_suffix(".debug").unwrap_or(name)).ok()
+    }
+    let mut result = Vec::new();
+    for entry in std::fs::read_dir(debuginfo_dir)
+        .with_context(|| format!("listing {}", debuginfo_dir.display()))?
+    {
+        let entry = entry.with_context(|| format!("listing {}", debuginfo_dir.display()))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = entry.path();
+        if path.is_dir() && name.len() == 2 {
+            for subentry in
+                std::fs::read_dir(&path).with_context(|| format!("listing {}", path.display()))?
+            {
+                let subentry = subentry.with_context(|| format!("listing {}", path.display()))?;
+                let subname = subentry.file_name().to_string_lossy().into_owned();
+                if let Some(build_id) = parse_name(&format!("{name}{subname}")) {
+                    result.push((build_id, subentry.path()));
+                }
+            }
+        } else if let Some(build_id) = parse_name(&name) {
+            result.push((build_id, path));
+        }
+    }
+    result.sort();
+    Ok(result)
+}
+
+#[test]
+fn test_list_debuginfo_redirects() {
+    let t = tempfile::tempdir().unwrap();
+    let first = "0e20481820d3b92468102b35a5e4a29a8695c1af";
+    let second = "b87e34547e94f167f4b737f3a25955477a485cc7";
+    std::fs::write(t.path().join(format!("{second}.debug")), "{}").unwrap();
+    std::fs::create_dir(t.path().join("0e")).unwrap();
+    std::fs::write(
+        t.path().join("0e").join(format!("{}.debug", &first[2..])),
+        "{}",
+    )
+    .unwrap();
+    std::fs::write(t.path().join("README"), "").unwrap();
+    let redirects = list_debuginfo_redirects(t.path()).unwrap();
+    assert_eq!(
+        redirects,
+        [
+            (
+                BuildId::new(first).unwrap(),
+                t.path().join("0e").join(format!("{}.debug", &first[2..]))
+            ),
+            (
+                BuildId::new(second).unwrap(),
+                t.path().join(format!("{second}.debug"))
+            ),
+        ]
+    );
 }
 
 /// A substituter for the `file://` scheme
@@ -70,9 +163,11 @@ impl CachedBinaryCache<FileSubstituterInner> {
         path: &Path,
         cache_dir: PathBuf,
         expiration: Duration,
+        settings: &Settings,
     ) -> anyhow::Result<Self> {
         let inner = FileSubstituterInner::new(path);
-        CachedBinaryCache::wrap(inner, cache_dir, expiration).await
+        // reading from the file system is allowed even offline
+        CachedBinaryCache::wrap(inner, cache_dir, expiration, false, settings).await
     }
 
     #[cfg(test)]
@@ -82,9 +177,14 @@ impl CachedBinaryCache<FileSubstituterInner> {
     pub async fn test_fixture(cache_dir: &Path) -> Self {
         let path = crate::test_utils::fixture("file_binary_cache");
         assert!(path.exists());
-        FileSubstituter::new(&path, cache_dir.to_path_buf(), Duration::from_hours(1000))
-            .await
-            .unwrap()
+        FileSubstituter::new(
+            &path,
+            cache_dir.to_path_buf(),
+            Duration::from_hours(1000),
+            &Settings::default(),
+        )
+        .await
+        .unwrap()
     }
 }
 
@@ -110,6 +210,65 @@ async fn test_build_id_to_debug_output() {
     );
 }
 
+#[tokio::test]
+async fn test_build_id_to_debug_output_sharded() {
+    use crate::substituter::Substituter;
+    use crate::test_utils::setup_logging;
+    setup_logging();
+    // a binary cache whose debuginfo index is sharded in subdirectories
+    let binary_cache = tempfile::tempdir().unwrap();
+    let nar = "1f6bsl93q74r96y8ndavdhycrzddlry7k6s8f58a864jkyq29x3j.nar";
+    std::fs::create_dir_all(binary_cache.path().join("nar")).unwrap();
+    std::fs::copy(
+        crate::test_utils::fixture("file_binary_cache")
+            .join("nar")
+            .join(nar),
+        binary_cache.path().join("nar").join(nar),
+    )
+    .unwrap();
+    std::fs::create_dir_all(binary_cache.path().join("debuginfo/7a")).unwrap();
+    std::fs::write(
+        binary_cache
+            .path()
+            .join("debuginfo/7a/5f0e8d2c1b3a4f5e6d7c8b9a0f1e2d3c4b5a69.debug"),
+        format!(r#"{{"archive":"../../nar/{nar}","member":"lib/debug/.build-id/7a/5f0e8d2c1b3a4f5e6d7c8b9a0f1e2d3c4b5a69.debug"}}"#),
+    )
+    .unwrap();
+    let cache_dir = tempfile::tempdir().unwrap();
+    let substituter = FileSubstituter::new(
+        binary_cache.path(),
+        cache_dir.path().to_path_buf(),
+        Duration::from_hours(1000),
+        &Settings::default(),
+    )
+    .await
+    .unwrap();
+    let out = substituter
+        .build_id_to_debug_output(
+            &crate::build_id::BuildId::new("7a5f0e8d2c1b3a4f5e6d7c8b9a0f1e2d3c4b5a69").unwrap(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    let debug = out.join("lib/debug/.build-id/7a/5f0e8d2c1b3a4f5e6d7c8b9a0f1e2d3c4b5a69.debug");
+    assert!(debug.resolve_inside_root().await.unwrap().is_some());
+    assert!(substituter
+        .build_id_to_debug_output(
+            &crate::build_id::BuildId::new("7b5f0e8d2c1b3a4f5e6d7c8b9a0f1e2d3c4b5a69").unwrap(),
+        )
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_priority_from_nix_cache_info() {
+    use crate::substituter::{Priority, Substituter};
+    let cache_dir = tempfile::tempdir().unwrap();
+    let substituter = FileSubstituter::test_fixture(cache_dir.path()).await;
+    assert_eq!(substituter.priority(), Priority::BinaryCache(30));
+}
+
 #[tokio::test]
 async fn test_fetch_store_path() {
     use crate::substituter::Substituter;
@@ -140,3 +299,37 @@ async fn test_fetch_store_path() {
         "bef9ec5e1fe7ccacbf00b1053c6de54de9857ec3d173504190462a01ed3cc52e"
     );
 }
+
+#[tokio::test]
+async fn test_check() {
+    use crate::substituter::Substituter;
+    let cache_dir = tempfile::tempdir().unwrap();
+    let substituter = FileSubstituter::test_fixture(cache_dir.path()).await;
+    substituter.check().await.unwrap();
+    let missing = FileSubstituter::new(
+        &cache_dir.path().join("missing"),
+        cache_dir.path().to_path_buf(),
+        Duration::from_hours(1000),
+        &Settings::default(),
+    )
+    .await
+    .unwrap();
+    missing.check().await.unwrap_err();
+}
+
+#[tokio::test]
+async fn test_build_ids_with_prefix() {
+    use crate::substituter::Substituter;
+
+    let cache_dir = tempfile::tempdir().unwrap();
+    let substituter = FileSubstituter::test_fixture(cache_dir.path()).await;
+    assert_eq!(
+        substituter.build_ids_with_prefix("b87e3454").await.unwrap(),
+        [BuildId::new("b87e34547e94f167f4b737f3a25955477a485cc7").unwrap()]
+    );
+    assert!(substituter
+        .build_ids_with_prefix("00000000")
+        .await
+        .unwrap()
+        .is_empty());
+}
@@ -1,24 +1,323 @@
-use std::{fmt::Debug, path::PathBuf, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    path::PathBuf,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
 
 use anyhow::Context;
 use futures::StreamExt;
-use http::StatusCode;
-use reqwest::{Client, Url};
+use http::{header::LOCATION, HeaderName, HeaderValue, Method, StatusCode};
+use reqwest::{Client, NoProxy, Proxy, Response, Url};
 use tokio::io::AsyncBufRead;
 use tokio_util::io::StreamReader;
 
+use crate::settings::Settings;
 use crate::substituter::binary_cache::{BinaryCache, CachedBinaryCache, NarRelativeLocation};
 
-use super::Priority;
+use super::{Priority, TransientError};
 
 const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
+/// Header carrying a random id identifying each request, for cache operators to correlate their
+/// logs with ours
+const REQUEST_ID: &str = "x-request-id";
+
+/// A new random request id
+fn new_request_id() -> String {
+    format!("{:016x}{:016x}", fastrand::u64(..), fastrand::u64(..))
+}
+
+/// How many redirects [HttpSubstituterInner::send] follows, like reqwest by default
+const MAX_REDIRECTS: usize = 10;
+
+/// How long [BinaryCache::check] waits for an answer
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which proxy http substituters go through, see [HttpSettings::proxy]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ProxySettings {
+    /// Proxy for all requests, replacing those of `HTTP_PROXY`, `HTTPS_PROXY` and `ALL_PROXY`
+    pub proxy: Option<Url>,
+    /// Comma separated hosts, domains and ip ranges that are never proxied, replacing `NO_PROXY`
+    pub no_proxy: Option<String>,
+}
+
+/// How http substituters manage their connections, see [HttpSettings::connection]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ConnectionSettings {
+    /// whether HTTP/2 is negotiated with https servers supporting it, so that concurrent requests
+    /// share one connection
+    pub http2: bool,
+    /// how long idle connections are kept open for later requests
+    pub pool_idle_timeout: Duration,
+    /// how many idle connections are kept open to each host
+    pub pool_max_idle_per_host: usize,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        DEFAULT_CONNECTION_SETTINGS
+    }
+}
+
+/// Defaults of [ConnectionSettings], suited to many small requests of narinfos and json
+/// redirects to the same few hosts.
+const DEFAULT_CONNECTION_SETTINGS: ConnectionSettings = ConnectionSettings {
+    http2: true,
+    pool_idle_timeout: Duration::from_secs(90),
+    pool_max_idle_per_host: 32,
+};
+
+/// A header sent with all requests to the http substituters under some url, see
+/// [HttpSettings::headers]
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct SubstituterHeader {
+    /// the header is sent to substituters at this url or under it, always with a trailing slash
+    prefix: Url,
+    name: HeaderName,
+    value: HeaderValue,
+}
+
+impl Debug for SubstituterHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // the value is typically a secret
+        f.debug_struct("SubstituterHeader")
+            .field("prefix", &self.prefix.as_str())
+            .field("name", &self.name)
+            .field("value", &"<redacted>")
+            .finish()
+    }
+}
+
+impl SubstituterHeader {
+    /// Parses `URL=Name: Value`, where `${VAR}` in the value is replaced by the environment
+    /// variable `VAR`, so that secrets need not be on the command line.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        Self::parse_with_env(s, |name| std::env::var(name))
+    }
+
+    /// Same as [SubstituterHeader::parse], reading variables with `env`.
+    fn parse_with_env(
+        s: &str,
+        env: impl Fn(&str) -> Result<String, std::env::VarError>,
+    ) -> anyhow::Result<Self> {
+        let (prefix, header) = s
+            .split_once('=')
+            .with_context(|| format!("{s:?} is not of the form `URL=Name: Value`"))?;
+        let prefix =
+            Url::parse(prefix).with_context(|| format!("invalid substituter url {prefix:?}"))?;
+        anyhow::ensure!(
+            matches!(prefix.scheme(), "http" | "https"),
+            "{prefix} is not an http substituter url"
+        );
+        anyhow::ensure!(
+            prefix.query().is_none() && prefix.fragment().is_none(),
+            "{prefix} must not have a query nor a fragment"
+        );
+        let prefix = with_trailing_slash(prefix)?;
+        let (name, value) = header
+            .split_once(':')
+            .with_context(|| format!("{header:?} is not of the form `Name: Value`"))?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .with_context(|| format!("invalid header name {:?}", name.trim()))?;
+        let mut value = HeaderValue::from_str(&interpolate_env(value.trim(), env)?)
+            // do not reveal the value
+            .map_err(|_| anyhow::anyhow!("invalid value for header {name}"))?;
+        value.set_sensitive(true);
+        Ok(Self {
+            prefix,
+            name,
+            value,
+        })
+    }
+
+    /// Whether this header is sent to the substituter at `url`: `url` has the same scheme, host
+    /// and port as the prefix, and its path is the path of the prefix or under it.
+    fn applies_to(&self, url: &Url) -> bool {
+        url.origin() == self.prefix.origin()
+            && match with_trailing_slash(url.clone()) {
+                Ok(url) => url.path().starts_with(self.prefix.path()),
+                Err(_) => false,
+            }
+    }
+}
+
+/// Replaces each `${VAR}` in `template` by the value of the variable `VAR` according to `env`.
+fn interpolate_env(
+    template: &str,
+    env: impl Fn(&str) -> Result<String, std::env::VarError>,
+) -> anyhow::Result<String> {
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let variable = &rest[start + 2..];
+        let end = variable
+            .find('}')
+            .with_context(|| format!("unterminated ${{ in {template:?}"))?;
+        let name = &variable[..end];
+        let value = env(name)
+            .with_context(|| format!("reading environment variable {name} for {template:?}"))?;
+        result.push_str(&value);
+        rest = &variable[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// How http substituters connect, see [HttpSubstituterInner::new]
+#[derive(Debug, Clone, Default)]
+pub struct HttpSettings {
+    /// Proxies of substituters without a `?proxy=` query param.
+    ///
+    /// By default, proxies are taken from the `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY` and
+    /// `NO_PROXY` environment variables. Each setting replaces the corresponding variables.
+    pub proxy: ProxySettings,
+    /// How connections are managed
+    pub connection: ConnectionSettings,
+    /// Headers sent with all requests to the substituters they apply to, for example to
+    /// authenticate to caches behind an access proxy
+    pub headers: Vec<SubstituterHeader>,
+}
+
+/// The proxy settings of the substituter at `url`: `default`, except for the proxy specified by
+/// its `?proxy=` query param, if any.
+fn proxy_settings(url: &Url, default: &ProxySettings) -> anyhow::Result<ProxySettings> {
+    let mut settings = default.clone();
+    if let Some((_, proxy)) = url.query_pairs().find(|(key, _)| key == "proxy") {
+        let proxy =
+            Url::parse(&proxy).with_context(|| format!("parsing proxy {proxy:?} of {url}"))?;
+        settings.proxy = Some(proxy);
+    }
+    Ok(settings)
+}
+
+/// The first of these environment variables which is set and not empty
+fn env_var(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .find_map(|name| std::env::var(name).ok().filter(|value| !value.is_empty()))
+}
+
+/// Configures the proxies of `builder` according to `settings`.
+fn configure_proxy(
+    mut builder: reqwest::ClientBuilder,
+    settings: &ProxySettings,
+) -> anyhow::Result<reqwest::ClientBuilder> {
+    let no_proxy = match &settings.no_proxy {
+        Some(no_proxy) => NoProxy::from_string(no_proxy),
+        None => NoProxy::from_env(),
+    };
+    if let Some(proxy) = &settings.proxy {
+        let proxy = Proxy::all(proxy.as_str())
+            .with_context(|| format!("configuring proxy {proxy}"))?
+            .no_proxy(no_proxy);
+        return Ok(builder.proxy(proxy));
+    }
+    if settings.no_proxy.is_none() {
+        // reqwest reads the environment itself
+        return Ok(builder);
+    }
+    // the proxies of the environment, with our exceptions instead of NO_PROXY
+    type ProxyKind = fn(&str) -> reqwest::Result<Proxy>;
+    let env_proxies: [(&[&str], ProxyKind); 3] = [
+        (&["http_proxy", "HTTP_PROXY"], |url| Proxy::http(url)),
+        (&["https_proxy", "HTTPS_PROXY"], |url| Proxy::https(url)),
+        (&["all_proxy", "ALL_PROXY"], |url| Proxy::all(url)),
+    ];
+    for (names, kind) in env_proxies {
+        if let Some(proxy) = env_var(names) {
+            let proxy = kind(&proxy)
+                .with_context(|| format!("configuring proxy {proxy} from {}", names[1]))?
+                .no_proxy(no_proxy.clone());
+            builder = builder.proxy(proxy);
+        }
+    }
+    Ok(builder)
+}
+
+/// Everything that makes two http clients behave differently
+///
+/// Any new setting of [shared_client] must be added here.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ClientKey {
+    /// scheme, host and port
+    origin: String,
+    user_agent: String,
+    proxy: ProxySettings,
+    connection: ConnectionSettings,
+}
+
+/// http clients shared by all substituters of the process, so that substituters to the same host
+/// share their pool of keep-alive connections
+static CLIENTS: LazyLock<Mutex<HashMap<ClientKey, Client>>> = LazyLock::new(Default::default);
+
+/// Returns an http client to connect to `url`, reusing the one of a previous substituter with
+/// the same origin and settings if any.
+fn shared_client(
+    url: &Url,
+    user_agent: String,
+    proxy: ProxySettings,
+    connection: ConnectionSettings,
+) -> anyhow::Result<Client> {
+    let key = ClientKey {
+        origin: url.origin().ascii_serialization(),
+        user_agent,
+        proxy,
+        connection,
+    };
+    let mut clients = CLIENTS.lock().unwrap();
+    if let Some(client) = clients.get(&key) {
+        tracing::debug!("reusing http client for {}", key.origin);
+        return Ok(client.clone());
+    }
+    // narinfo and debuginfo json redirects are small text files that compress well.
+    // NARs are already compressed, so servers typically don't compress them further.
+    let builder = Client::builder()
+        .user_agent(&key.user_agent)
+        .gzip(true)
+        .brotli(true)
+        .zstd(true)
+        .deflate(true)
+        // see [HttpSubstituterInner::send]
+        .redirect(reqwest::redirect::Policy::none())
+        .pool_idle_timeout(key.connection.pool_idle_timeout)
+        .pool_max_idle_per_host(key.connection.pool_max_idle_per_host);
+    let builder = if key.connection.http2 {
+        // nars are large downloads that should not be limited by the default window
+        builder.http2_adaptive_window(true)
+    } else {
+        builder.http1_only()
+    };
+    let client = configure_proxy(builder, &key.proxy)?
+        .build()
+        .with_context(|| format!("creating an http client to connect to {url}"))?;
+    clients.insert(key, client.clone());
+    Ok(client)
+}
+
+/// Returns `url` with a trailing slash, so that [Url::join] keeps its last path segment: otherwise
+/// `https://cache.example.org/prefix` joined with `nix-cache-info` would be
+/// `https://cache.example.org/nix-cache-info`.
+fn with_trailing_slash(mut url: Url) -> anyhow::Result<Url> {
+    anyhow::ensure!(!url.cannot_be_a_base(), "{url} cannot be a base url");
+    url.path_segments_mut()
+        .expect("checked above")
+        .pop_if_empty()
+        .push("");
+    Ok(url)
+}
+
 /// Fetching from `http://` and `https://` substituters.
 ///
 /// The substituter must have been created with `?index-debug-info=true`.
 pub struct HttpSubstituterInner {
     url: Url,
     client: Client,
+    /// the headers of [HttpSettings::headers] which apply to `url`
+    headers: Vec<SubstituterHeader>,
 }
 
 impl Debug for HttpSubstituterInner {
@@ -31,13 +330,83 @@ impl Debug for HttpSubstituterInner {
 
 impl HttpSubstituterInner {
     /// Create an http or https substituter with this base url.
-    pub fn new(url: Url) -> anyhow::Result<Self> {
-        let client = Client::builder()
-            .user_agent(USER_AGENT)
-            .build()
-            .with_context(|| format!("creating an http client to connect to {url}"))?;
-        Ok(Self { url, client })
+    ///
+    /// `user_agent_suffix` is appended to the default User-Agent.
+    ///
+    /// Requests go through the proxy of the `?proxy=` query param of `url` if any, or as
+    /// configured by `settings`, and carry the headers of `settings` which apply to `url`.
+    ///
+    /// Substituters with the same scheme, host, port and settings share their connections.
+    pub fn new(
+        url: Url,
+        user_agent_suffix: Option<&str>,
+        settings: &HttpSettings,
+    ) -> anyhow::Result<Self> {
+        let proxy = proxy_settings(&url, &settings.proxy)?;
+        let user_agent = match user_agent_suffix {
+            Some(suffix) => format!("{USER_AGENT} {suffix}"),
+            None => USER_AGENT.to_owned(),
+        };
+        let client = shared_client(&url, user_agent, proxy, settings.connection.clone())?;
+        let headers = settings
+            .headers
+            .iter()
+            .filter(|header| header.applies_to(&url))
+            .cloned()
+            .collect();
+        let url = with_trailing_slash(url)?;
+        Ok(Self {
+            url,
+            client,
+            headers,
+        })
+    }
+
+    /// Sends a `method` request to `url`, following redirects.
+    ///
+    /// Redirects are followed here instead of by reqwest, which would send the configured headers
+    /// to whatever host the cache redirects to, like the CDN serving its nars: each hop only
+    /// carries the headers which apply to its url.
+    async fn send(
+        &self,
+        method: Method,
+        url: &Url,
+        request_id: &str,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Response> {
+        let mut url = url.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let mut request = self
+                .client
+                .request(method.clone(), url.clone())
+                .header(REQUEST_ID, request_id);
+            for header in self.headers.iter().filter(|header| header.applies_to(&url)) {
+                request = request.header(header.name.clone(), header.value.clone());
+            }
+            if let Some(timeout) = timeout {
+                request = request.timeout(timeout);
+            }
+            let response = request
+                .send()
+                .await
+                .with_context(|| format!("connecting to {url} (request id {request_id})"))?;
+            let location = match response.headers().get(LOCATION) {
+                Some(location) if response.status().is_redirection() => location,
+                _ => return Ok(response),
+            };
+            let next = location
+                .to_str()
+                .ok()
+                .and_then(|location| url.join(location).ok())
+                .with_context(|| {
+                    format!("{url} redirected to an invalid location (request id {request_id})")
+                })?;
+            tracing::debug!(request_id, "{url} redirected to {next}");
+            url = next;
+        }
+        anyhow::bail!("{url} redirected more than {MAX_REDIRECTS} times (request id {request_id})")
     }
+
     fn make_url(&self, rest: &NarRelativeLocation) -> anyhow::Result<Url> {
         self.url
             .join(rest.location())
@@ -54,19 +423,22 @@ impl BinaryCache for HttpSubstituterInner {
         what: &NarRelativeLocation,
     ) -> anyhow::Result<Option<impl AsyncBufRead + Send>> {
         let url = self.make_url(what)?;
-        let response = self
-            .client
-            .get(url.clone())
-            .send()
-            .await
-            .with_context(|| format!("connecting to {url}"))?;
+        let request_id = new_request_id();
+        tracing::debug!(request_id, "GET {url}");
+        let response = self.send(Method::GET, &url, &request_id, None).await?;
         match response.status() {
             StatusCode::OK => (),
             StatusCode::NOT_FOUND => {
                 tracing::trace!("404");
                 return Ok(None);
             }
-            other => anyhow::bail!("{url} returned {other:?}"),
+            other if other.is_server_error() || other == StatusCode::TOO_MANY_REQUESTS => {
+                return Err(TransientError(format!(
+                    "{url} returned {other:?} (request id {request_id})"
+                ))
+                .into())
+            }
+            other => anyhow::bail!("{url} returned {other:?} (request id {request_id})"),
         };
         let stream = response.bytes_stream();
         let reader = StreamReader::new(stream.map(|r| r.map_err(std::io::Error::other)));
@@ -77,6 +449,22 @@ impl BinaryCache for HttpSubstituterInner {
     fn priority(&self) -> Priority {
         Priority::Unknown
     }
+
+    /// sends a HEAD query for `nix-cache-info`, which all binary caches have
+    async fn check(&self) -> anyhow::Result<()> {
+        let url = self.make_url(&NarRelativeLocation::new("nix-cache-info")?)?;
+        let request_id = new_request_id();
+        tracing::debug!(request_id, "HEAD {url}");
+        let response = self
+            .send(Method::HEAD, &url, &request_id, Some(CHECK_TIMEOUT))
+            .await?;
+        anyhow::ensure!(
+            response.status().is_success(),
+            "{url} returned {:?} (request id {request_id})",
+            response.status()
+        );
+        Ok(())
+    }
 }
 
 /// A substituter fetching from `http://` or `https://` binary caches
@@ -85,9 +473,20 @@ pub type HttpSubstituter = CachedBinaryCache<HttpSubstituterInner>;
 impl CachedBinaryCache<HttpSubstituterInner> {
     /// Constructs a `HttpSubstituter` which downloads from `url` to a cache directory `cache_dir`
     /// where NARs are keps for approximately `expiration`
-    pub async fn new(url: Url, cache_dir: PathBuf, expiration: Duration) -> anyhow::Result<Self> {
-        let inner = HttpSubstituterInner::new(url)?;
-        CachedBinaryCache::wrap(inner, cache_dir, expiration).await
+    ///
+    /// If `offline` is true, no request is made and only what is already in `cache_dir` is served.
+    ///
+    /// `user_agent_suffix` is appended to the default User-Agent.
+    pub async fn new(
+        url: Url,
+        cache_dir: PathBuf,
+        expiration: Duration,
+        offline: bool,
+        user_agent_suffix: Option<&str>,
+        settings: &Settings,
+    ) -> anyhow::Result<Self> {
+        let inner = HttpSubstituterInner::new(url, user_agent_suffix, &settings.http)?;
+        CachedBinaryCache::wrap(inner, cache_dir, expiration, offline, settings).await
     }
 }
 
@@ -112,6 +511,9 @@ mod tests {
             HTTP_BINARY_CACHE.clone(),
             cache_dir.path().to_path_buf(),
             DEFAULT_EXPIRATION,
+            false,
+            None,
+            &Settings::default(),
         )
         .await
         .unwrap();
@@ -144,6 +546,9 @@ mod tests {
             HTTP_BINARY_CACHE.clone(),
             cache_dir.path().to_path_buf(),
             DEFAULT_EXPIRATION,
+            false,
+            None,
+            &Settings::default(),
         )
         .await
         .unwrap();
@@ -159,10 +564,16 @@ mod tests {
     async fn test_fetch_store_path_bad_host() {
         let url = Url::parse("https://255.255.255.255/doesnotexist").unwrap();
         let cache_dir = tempfile::tempdir().unwrap();
-        let substituter =
-            HttpSubstituter::new(url, cache_dir.path().to_path_buf(), DEFAULT_EXPIRATION)
-                .await
-                .unwrap();
+        let substituter = HttpSubstituter::new(
+            url,
+            cache_dir.path().to_path_buf(),
+            DEFAULT_EXPIRATION,
+            false,
+            None,
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
         let store_path = StorePath::new(Path::new(
             "/nix/store/n11lk1q63oooooooooooooja1shs3yr7-source/src/systemctl/systemctl.c",
         ))
@@ -180,6 +591,9 @@ mod tests {
             HTTP_BINARY_CACHE.clone(),
             cache_dir.path().to_path_buf(),
             DEFAULT_EXPIRATION,
+            false,
+            None,
+            &Settings::default(),
         )
         .await
         .unwrap();
@@ -212,6 +626,9 @@ mod tests {
             HTTP_BINARY_CACHE.clone(),
             cache_dir.path().to_path_buf(),
             DEFAULT_EXPIRATION,
+            false,
+            None,
+            &Settings::default(),
         )
         .await
         .unwrap();
@@ -226,13 +643,558 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_build_id_to_debug_output_error() {
+    async fn test_offline() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let build_id = BuildId::new("b87e34547e94f167f4b737f3a25955477a485cc7").unwrap();
+        let online = HttpSubstituter::new(
+            HTTP_BINARY_CACHE.clone(),
+            cache_dir.path().to_path_buf(),
+            DEFAULT_EXPIRATION,
+            false,
+            None,
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        drop(
+            online
+                .build_id_to_debug_output(&build_id)
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+        drop(online);
+
+        // connecting to this url fails, so everything must come from the cache
         let url = Url::parse("https://255.255.255.255/doesnotexist").unwrap();
+        let offline = HttpSubstituter::new(
+            url,
+            cache_dir.path().to_path_buf(),
+            DEFAULT_EXPIRATION,
+            true,
+            None,
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
+        let out = offline
+            .build_id_to_debug_output(&build_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            file_sha256(
+                out.join("lib/debug/.build-id/b8/7e34547e94f167f4b737f3a25955477a485cc7.debug")
+                    .resolve_inside_root()
+                    .await
+                    .unwrap()
+                    .unwrap()
+            )
+            .await,
+            "b7b38a0c43ec066a034e38f86f5f0926867b9eb2144fd8a7aac88c7c38bf5566"
+        );
+        let store_path = StorePath::new(Path::new(
+            "/nix/store/2qw62845796lyx649ck67zbk04pv8xhf-source/src/systemctl/systemctl.c",
+        ))
+        .unwrap();
+        assert!(offline
+            .fetch_store_path(&store_path)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_priority_from_nix_cache_info() {
         let cache_dir = tempfile::tempdir().unwrap();
+        for (offline, priority) in [
+            (false, Priority::BinaryCache(30)),
+            // nix-cache-info is not fetched when offline
+            (true, Priority::Unknown),
+        ] {
+            let substituter = HttpSubstituter::new(
+                HTTP_BINARY_CACHE.clone(),
+                cache_dir.path().to_path_buf(),
+                DEFAULT_EXPIRATION,
+                offline,
+                None,
+                &Settings::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(substituter.priority(), priority);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_encoding() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let server = tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let n = socket.read(&mut buf).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_lowercase()
+        });
+        let substituter = HttpSubstituterInner::new(url, None, &Default::default()).unwrap();
+        let location = NarRelativeLocation::new("debuginfo/foo.debug").unwrap();
+        assert!(substituter
+            .stream_location(&location)
+            .await
+            .unwrap()
+            .is_none());
+        let request = server.await.unwrap();
+        let accept_encoding = request
+            .lines()
+            .find_map(|l| l.strip_prefix("accept-encoding:"))
+            .unwrap();
+        for encoding in ["gzip", "br", "zstd"] {
+            assert!(dbg!(accept_encoding).contains(encoding));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_user_agent_and_request_id() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let server = tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let n = socket.read(&mut buf).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_lowercase()
+        });
+        let substituter =
+            HttpSubstituterInner::new(url, Some("deployment/42"), &Default::default()).unwrap();
+        let location = NarRelativeLocation::new("debuginfo/foo.debug").unwrap();
+        assert!(substituter
+            .stream_location(&location)
+            .await
+            .unwrap()
+            .is_none());
+        let request = server.await.unwrap();
+        let user_agent = request
+            .lines()
+            .find_map(|l| l.strip_prefix("user-agent:"))
+            .unwrap();
+        assert_eq!(
+            user_agent.trim(),
+            format!("{} deployment/42", USER_AGENT.to_lowercase())
+        );
+        let request_id = request
+            .lines()
+            .find_map(|l| l.strip_prefix("x-request-id:"))
+            .unwrap();
+        assert_eq!(request_id.trim().len(), 32);
+    }
+
+    #[test]
+    fn test_parse_substituter_header() {
+        let env = |name: &str| match name {
+            "SECRET" => Ok("hunter2".to_owned()),
+            _ => Err(std::env::VarError::NotPresent),
+        };
+        let header = SubstituterHeader::parse_with_env(
+            "https://cache.example.org=CF-Access-Client-Secret: s-${SECRET}",
+            env,
+        )
+        .unwrap();
+        assert_eq!(header.prefix.as_str(), "https://cache.example.org/");
+        assert_eq!(header.name, "cf-access-client-secret");
+        assert_eq!(header.value, "s-hunter2");
+        assert!(!format!("{header:?}").contains("hunter2"));
+        let header = SubstituterHeader::parse("https://cache.example.org=X-Tenant:a=b:c").unwrap();
+        assert_eq!(header.value, "a=b:c");
+        for invalid in [
+            "X-Tenant: a",
+            "https://cache.example.org=no colon",
+            "https://cache.example.org=bad name: value",
+            "https://cache.example.org=X-Secret: ${UNSET}",
+            "https://cache.example.org=X-Secret: ${unterminated",
+            "file:///cache=X-Tenant: a",
+            "https://cache.example.org?proxy=X-Tenant: a",
+        ] {
+            SubstituterHeader::parse_with_env(invalid, env).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_substituter_header_applies_to() {
+        let header =
+            SubstituterHeader::parse("https://cache.example.org/prefix=X-Tenant: a").unwrap();
+        for url in [
+            "https://cache.example.org/prefix",
+            "https://cache.example.org/prefix/",
+            "https://cache.example.org/prefix/sub?priority=30",
+            "https://cache.example.org:443/prefix",
+        ] {
+            assert!(header.applies_to(&Url::parse(url).unwrap()), "{url}");
+        }
+        for url in [
+            "https://cache.example.org/",
+            "https://cache.example.org/prefix2",
+            "http://cache.example.org/prefix",
+            "https://cache.example.org:8443/prefix",
+            "https://cache.example.org.example.com/prefix",
+        ] {
+            assert!(!header.applies_to(&Url::parse(url).unwrap()), "{url}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_headers_are_sent() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let server = tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut requests = Vec::new();
+            let mut buf = vec![0; 4096];
+            for _ in 0..2 {
+                let n = socket.read(&mut buf).await.unwrap();
+                requests.push(String::from_utf8_lossy(&buf[..n]).to_lowercase());
+                socket
+                    .write_all(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n")
+                    .await
+                    .unwrap();
+            }
+            requests
+        });
+        let headers = vec![
+            SubstituterHeader::parse(&format!("{url}=X-Tenant: debuginfod")).unwrap(),
+            SubstituterHeader::parse(&format!("{url}=CF-Access-Client-Id: client")).unwrap(),
+            SubstituterHeader::parse("https://other.example.org=X-Other: secret").unwrap(),
+        ];
+        let substituter = HttpSubstituterInner::new(
+            url,
+            None,
+            &HttpSettings {
+                headers,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        for location in ["debuginfo/foo.debug", "nar/foo.nar"] {
+            let location = NarRelativeLocation::new(location).unwrap();
+            assert!(substituter
+                .stream_location(&location)
+                .await
+                .unwrap()
+                .is_none());
+        }
+        let requests = server.await.unwrap();
+        assert_eq!(requests.len(), 2);
+        for request in requests {
+            let lines: Vec<&str> = request.lines().collect();
+            assert!(lines.contains(&"x-tenant: debuginfod"), "{request}");
+            assert!(lines.contains(&"cf-access-client-id: client"), "{request}");
+            assert!(!request.contains("x-other"), "{request}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_headers_are_not_sent_across_origins() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        /// Accepts one connection, answers `response` and returns the request.
+        async fn answer_once(listener: tokio::net::TcpListener, response: String) -> String {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let n = socket.read(&mut buf).await.unwrap();
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_lowercase()
+        }
+
+        let cache = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cdn = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", cache.local_addr().unwrap())).unwrap();
+        // another port is another origin
+        let cdn_url = format!("http://{}/nar/foo.nar", cdn.local_addr().unwrap());
+        let cache = tokio::spawn(answer_once(
+            cache,
+            format!("HTTP/1.1 302 Found\r\nlocation: {cdn_url}\r\ncontent-length: 0\r\n\r\n"),
+        ));
+        let cdn = tokio::spawn(answer_once(
+            cdn,
+            "HTTP/1.1 200 OK\r\ncontent-length: 3\r\n\r\nnar".to_owned(),
+        ));
         let substituter =
-            HttpSubstituter::new(url, cache_dir.path().to_path_buf(), DEFAULT_EXPIRATION)
+            HttpSubstituterInner::new(
+                url.clone(),
+                None,
+                &HttpSettings {
+                    headers: vec![
+                        SubstituterHeader::parse(&format!("{url}=X-Secret: s3cr3t")).unwrap()
+                    ],
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let location = NarRelativeLocation::new("nar/foo.nar").unwrap();
+        let mut reader = substituter
+            .stream_location(&location)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut content = String::new();
+        reader.read_to_string(&mut content).await.unwrap();
+        assert_eq!(content, "nar");
+        let cache = cache.await.unwrap();
+        assert!(cache.contains("x-secret: s3cr3t"), "{cache}");
+        let cdn = cdn.await.unwrap();
+        assert!(cdn.starts_with("get /nar/foo.nar"), "{cdn}");
+        assert!(!cdn.contains("x-secret"), "{cdn}");
+    }
+
+    /// A server accepting one connection, answering 404 and returning the request line.
+    async fn request_line_server() -> (std::net::SocketAddr, tokio::task::JoinHandle<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let n = socket.read(&mut buf).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n")
                 .await
                 .unwrap();
+            String::from_utf8_lossy(&buf[..n])
+                .lines()
+                .next()
+                .unwrap()
+                .to_owned()
+        });
+        (address, server)
+    }
+
+    #[tokio::test]
+    async fn test_proxy() {
+        let location = NarRelativeLocation::new("debuginfo/foo.debug").unwrap();
+        // through the proxy
+        let (proxy, proxy_server) = request_line_server().await;
+        let (origin, origin_server) = request_line_server().await;
+        let settings = ProxySettings {
+            proxy: Some(Url::parse(&format!("http://{proxy}")).unwrap()),
+            no_proxy: None,
+        };
+        let url = Url::parse(&format!("http://{origin}/")).unwrap();
+        let settings = HttpSettings {
+            proxy: settings,
+            ..Default::default()
+        };
+        let substituter = HttpSubstituterInner::new(url, None, &settings).unwrap();
+        assert!(substituter
+            .stream_location(&location)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            proxy_server.await.unwrap(),
+            format!("GET http://{origin}/debuginfo/foo.debug HTTP/1.1")
+        );
+        origin_server.abort();
+
+        // no_proxy bypasses the proxy
+        let (proxy, proxy_server) = request_line_server().await;
+        let (origin, origin_server) = request_line_server().await;
+        let settings = ProxySettings {
+            proxy: Some(Url::parse(&format!("http://{proxy}")).unwrap()),
+            no_proxy: Some("example.org,127.0.0.1".to_owned()),
+        };
+        let url = Url::parse(&format!("http://{origin}/")).unwrap();
+        let settings = HttpSettings {
+            proxy: settings,
+            ..Default::default()
+        };
+        let substituter = HttpSubstituterInner::new(url, None, &settings).unwrap();
+        assert!(substituter
+            .stream_location(&location)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            origin_server.await.unwrap(),
+            "GET /debuginfo/foo.debug HTTP/1.1"
+        );
+        proxy_server.abort();
+    }
+
+    #[test]
+    fn test_proxy_query_param() {
+        let url =
+            Url::parse("https://cache.example.org/?proxy=http%3A%2F%2Fproxy.example.org%3A3128")
+                .unwrap();
+        assert_eq!(
+            proxy_settings(&url, &Default::default())
+                .unwrap()
+                .proxy
+                .unwrap()
+                .as_str(),
+            "http://proxy.example.org:3128/"
+        );
+        let url = Url::parse("https://cache.example.org/?proxy=not%20a%20url").unwrap();
+        proxy_settings(&url, &Default::default()).unwrap_err();
+    }
+
+    #[test]
+    fn test_make_url() {
+        let location = NarRelativeLocation::new("debuginfo/foo.debug").unwrap();
+        for (url, expected) in [
+            (
+                "https://cache.example.org",
+                "https://cache.example.org/debuginfo/foo.debug",
+            ),
+            (
+                "https://cache.example.org/",
+                "https://cache.example.org/debuginfo/foo.debug",
+            ),
+            (
+                "https://cache.example.org/prefix",
+                "https://cache.example.org/prefix/debuginfo/foo.debug",
+            ),
+            (
+                "https://cache.example.org/prefix/",
+                "https://cache.example.org/prefix/debuginfo/foo.debug",
+            ),
+            (
+                "https://cache.example.org/a/b?priority=10",
+                "https://cache.example.org/a/b/debuginfo/foo.debug",
+            ),
+        ] {
+            let substituter =
+                HttpSubstituterInner::new(Url::parse(url).unwrap(), None, &Default::default())
+                    .unwrap();
+            assert_eq!(substituter.make_url(&location).unwrap().as_str(), expected);
+        }
+    }
+
+    #[test]
+    fn test_connection_settings_are_not_shared() {
+        let url = Url::parse("https://connection-settings.invalid/").unwrap();
+        let http1 = ConnectionSettings {
+            http2: false,
+            ..Default::default()
+        };
+        for connection in [ConnectionSettings::default(), http1.clone(), http1] {
+            shared_client(&url, USER_AGENT.to_owned(), Default::default(), connection).unwrap();
+        }
+        let clients = CLIENTS.lock().unwrap();
+        let origin = url.origin().ascii_serialization();
+        assert_eq!(clients.keys().filter(|key| key.origin == origin).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_same_origin_shares_connections() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            // only one connection is accepted
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            for _ in 0..2 {
+                let _ = socket.read(&mut buf).await.unwrap();
+                socket
+                    .write_all(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n")
+                    .await
+                    .unwrap();
+            }
+        });
+        let location = NarRelativeLocation::new("debuginfo/foo.debug").unwrap();
+        for url in [
+            format!("http://{address}/?priority=10"),
+            format!("http://{address}/?priority=20"),
+        ] {
+            let substituter =
+                HttpSubstituterInner::new(Url::parse(&url).unwrap(), None, &Default::default())
+                    .unwrap();
+            let result = tokio::time::timeout(
+                Duration::from_secs(10),
+                substituter.stream_location(&location),
+            )
+            .await
+            .expect("the second substituter did not reuse the connection of the first one");
+            assert!(result.unwrap().is_none());
+        }
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_server_error_is_transient() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let _ = socket.read(&mut buf).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+        });
+        let substituter = HttpSubstituterInner::new(url, None, &Default::default()).unwrap();
+        let location = NarRelativeLocation::new("debuginfo/foo.debug").unwrap();
+        let Err(e) = substituter.stream_location(&location).await else {
+            panic!("503 should be an error");
+        };
+        assert!(crate::substituter::is_transient(&e));
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        HttpSubstituterInner::new(HTTP_BINARY_CACHE.clone(), None, &Default::default())
+            .unwrap()
+            .check()
+            .await
+            .unwrap();
+        let url = Url::parse("https://255.255.255.255/doesnotexist").unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        HttpSubstituterInner::new(url.clone(), None, &Default::default())
+            .unwrap()
+            .check()
+            .await
+            .unwrap_err();
+        // offline substituters are never contacted, so they are always fine
+        HttpSubstituter::new(
+            url,
+            cache_dir.path().to_path_buf(),
+            DEFAULT_EXPIRATION,
+            true,
+            None,
+            &Settings::default(),
+        )
+        .await
+        .unwrap()
+        .check()
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_build_id_to_debug_output_error() {
+        let url = Url::parse("https://255.255.255.255/doesnotexist").unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let substituter = HttpSubstituter::new(
+            url,
+            cache_dir.path().to_path_buf(),
+            DEFAULT_EXPIRATION,
+            false,
+            None,
+            &Settings::default(),
+        )
+        .await
+        .unwrap();
 
         substituter
             .build_id_to_debug_output(
@@ -0,0 +1,242 @@
+use std::{fmt::Debug, path::PathBuf, time::Duration};
+
+use anyhow::Context;
+use reqwest::Url;
+use tokio::io::AsyncBufRead;
+
+use crate::settings::Settings;
+use crate::substituter::binary_cache::{BinaryCache, CachedBinaryCache, NarRelativeLocation};
+use crate::substituter::http::{HttpSettings, HttpSubstituterInner};
+
+use super::Priority;
+
+/// Gateways answer slowly, or never, for content that nobody provides anymore, so after this long
+/// without an answer a file is considered missing
+const GATEWAY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Returns the url under which the gateway at `gateway` serves the content of this `ipfs://` or
+/// `ipns://` url.
+///
+/// For example `ipfs://bafy.../cache` is served at `http://127.0.0.1:8080/ipfs/bafy.../cache/`.
+pub fn gateway_url(gateway: &Url, url: &Url) -> anyhow::Result<Url> {
+    let namespace = match url.scheme() {
+        scheme @ ("ipfs" | "ipns") => scheme,
+        other => anyhow::bail!("{url} has scheme {other}, expected ipfs or ipns"),
+    };
+    let root = match url.host_str() {
+        Some(root) if !root.is_empty() => root,
+        _ => anyhow::bail!("{url} has no content id or name"),
+    };
+    let mut result = gateway.clone();
+    {
+        let mut segments = result
+            .path_segments_mut()
+            .map_err(|()| anyhow::anyhow!("ipfs gateway {gateway} cannot be a base url"))?;
+        segments.pop_if_empty().push(namespace).push(root);
+        for segment in url.path_segments().into_iter().flatten() {
+            if !segment.is_empty() {
+                segments.push(segment);
+            }
+        }
+        // so that joining relative paths keeps the last segment
+        segments.push("");
+    }
+    Ok(result)
+}
+
+#[test]
+fn test_gateway_url() {
+    let gateway = Url::parse("http://127.0.0.1:8080").unwrap();
+    for (url, expected) in [
+        (
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi",
+            "http://127.0.0.1:8080/ipfs/bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/",
+        ),
+        (
+            "ipfs://QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG/cache/",
+            "http://127.0.0.1:8080/ipfs/QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG/cache/",
+        ),
+        (
+            "ipns://cache.example.org/nix",
+            "http://127.0.0.1:8080/ipns/cache.example.org/nix/",
+        ),
+    ] {
+        assert_eq!(
+            gateway_url(&gateway, &Url::parse(url).unwrap())
+                .unwrap()
+                .as_str(),
+            expected,
+            "{url}"
+        );
+    }
+    let gateway = Url::parse("https://gateway.example.org/prefix/").unwrap();
+    assert_eq!(
+        gateway_url(&gateway, &Url::parse("ipns://cache.example.org").unwrap())
+            .unwrap()
+            .as_str(),
+        "https://gateway.example.org/prefix/ipns/cache.example.org/"
+    );
+    gateway_url(&gateway, &Url::parse("ipfs:///cache").unwrap()).unwrap_err();
+    gateway_url(&gateway, &Url::parse("https://cache.nixos.org").unwrap()).unwrap_err();
+}
+
+/// Fetching from `ipfs://` and `ipns://` substituters through an http gateway.
+///
+/// The binary cache must have been created with `?index-debug-info=true`.
+pub struct IpfsSubstituterInner {
+    url: Url,
+    http: HttpSubstituterInner,
+    /// see [GATEWAY_TIMEOUT]
+    timeout: Duration,
+}
+
+impl Debug for IpfsSubstituterInner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IpfsSubstituter")
+            .field("url", &self.url.as_str())
+            .field("gateway", &self.http)
+            .finish()
+    }
+}
+
+impl IpfsSubstituterInner {
+    /// Create a substituter for this `ipfs://` or `ipns://` url, fetching through `gateway`.
+    ///
+    /// `user_agent_suffix` is appended to the default User-Agent.
+    pub fn new(
+        url: Url,
+        gateway: &Url,
+        user_agent_suffix: Option<&str>,
+        settings: &HttpSettings,
+    ) -> anyhow::Result<Self> {
+        let http =
+            HttpSubstituterInner::new(gateway_url(gateway, &url)?, user_agent_suffix, settings)?;
+        Ok(Self {
+            url,
+            http,
+            timeout: GATEWAY_TIMEOUT,
+        })
+    }
+}
+
+impl BinaryCache for IpfsSubstituterInner {
+    /// Like for http substituters, except that files for which the gateway does not answer in
+    /// time are considered missing.
+    async fn stream_location(
+        &self,
+        what: &NarRelativeLocation,
+    ) -> anyhow::Result<Option<impl AsyncBufRead + Send>> {
+        match tokio::time::timeout(self.timeout, self.http.stream_location(what)).await {
+            Ok(result) => result,
+            Err(_) => {
+                tracing::debug!(
+                    "{} did not answer for {} in time, considering it missing",
+                    self.url,
+                    what.location()
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    fn priority(&self) -> Priority {
+        Priority::Remote
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        self.http
+            .check()
+            .await
+            .with_context(|| format!("checking {} through its gateway", self.url))
+    }
+}
+
+/// A substituter fetching from `ipfs://` or `ipns://` binary caches through an http gateway
+pub type IpfsSubstituter = CachedBinaryCache<IpfsSubstituterInner>;
+
+impl CachedBinaryCache<IpfsSubstituterInner> {
+    /// Constructs an `IpfsSubstituter` which downloads from `url` through the http gateway at
+    /// `gateway` to a cache directory `cache_dir` where NARs are kept for approximately
+    /// `expiration`
+    ///
+    /// If `offline` is true, no request is made and only what is already in `cache_dir` is served.
+    ///
+    /// `user_agent_suffix` is appended to the default User-Agent.
+    pub async fn new(
+        url: Url,
+        gateway: &Url,
+        cache_dir: PathBuf,
+        expiration: Duration,
+        offline: bool,
+        user_agent_suffix: Option<&str>,
+        settings: &Settings,
+    ) -> anyhow::Result<Self> {
+        let inner = IpfsSubstituterInner::new(url, gateway, user_agent_suffix, &settings.http)?;
+        CachedBinaryCache::wrap(inner, cache_dir, expiration, offline, settings).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// A gateway accepting one connection, answering with `response` if any, and returning the
+    /// request line.
+    async fn gateway(response: Option<&'static [u8]>) -> (Url, tokio::task::JoinHandle<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let n = socket.read(&mut buf).await.unwrap();
+            match response {
+                Some(response) => socket.write_all(response).await.unwrap(),
+                None => tokio::time::sleep(Duration::from_secs(10)).await,
+            }
+            String::from_utf8_lossy(&buf[..n])
+                .lines()
+                .next()
+                .unwrap()
+                .to_owned()
+        });
+        (url, server)
+    }
+
+    #[tokio::test]
+    async fn test_stream_location_through_gateway() {
+        let (gateway, server) =
+            gateway(Some(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n")).await;
+        let url = Url::parse("ipns://cache.example.org").unwrap();
+        let substituter =
+            IpfsSubstituterInner::new(url, &gateway, None, &Default::default()).unwrap();
+        let location = NarRelativeLocation::new("debuginfo/foo.debug").unwrap();
+        assert!(substituter
+            .stream_location(&location)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            server.await.unwrap(),
+            "GET /ipns/cache.example.org/debuginfo/foo.debug HTTP/1.1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_location_timeout() {
+        let (gateway, server) = gateway(None).await;
+        let url = Url::parse("ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi")
+            .unwrap();
+        let mut substituter =
+            IpfsSubstituterInner::new(url, &gateway, None, &Default::default()).unwrap();
+        substituter.timeout = Duration::from_millis(100);
+        let location = NarRelativeLocation::new("nar/foo.nar.xz").unwrap();
+        assert!(substituter
+            .stream_location(&location)
+            .await
+            .unwrap()
+            .is_none());
+        server.abort();
+    }
+}
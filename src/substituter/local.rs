@@ -1,50 +1,367 @@
-use std::{os::unix::ffi::OsStrExt, path::PathBuf};
+use std::{
+    collections::HashMap,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use anyhow::Context;
-use quick_cache::sync::Cache;
 
 use crate::{
     build_id::BuildId,
-    store_path::{StorePath, NIX_STORE},
+    cache::{CachableFetcher, CacheSettings, EntryInfo, FetcherCache, FetcherCacheKey},
+    elf::Elf,
+    store_path::StorePath,
+    utils::{copy_recursively, Presence},
     vfs::RestrictedPath,
 };
 
-use super::{Priority, Substituter};
+use super::{PathInfo, Priority, Substituter};
+
+/// Name of the file where [LocalStoreSubstituter] persists its [StoreIndex] in its cache
+/// directory
+pub const INDEX_FILE: &str = "build-id-index.json";
 
-/// serves store paths directly available locally in `/nix/store`
+/// Which `-debug` store path contains the debuginfo of each build id, as of `mtime`
 #[derive(Debug)]
+struct StoreIndex {
+    /// mtime of the store directory when the scan started
+    mtime: SystemTime,
+    debug_outputs: HashMap<BuildId, PathBuf>,
+}
+
+/// How a [StoreIndex] is persisted to disk
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct PersistedIndex {
+    mtime: SystemTime,
+    /// build id to name of the `-debug` store path
+    debug_outputs: HashMap<String, String>,
+}
+
+/// Reads the index of `store_dir` persisted at `index_file` by [save_index].
+fn load_index(index_file: &Path, store_dir: &Path) -> anyhow::Result<StoreIndex> {
+    let content = std::fs::read(index_file).with_context(|| format!("reading {index_file:?}"))?;
+    let persisted: PersistedIndex =
+        serde_json::from_slice(&content).with_context(|| format!("parsing {index_file:?}"))?;
+    let mut debug_outputs = HashMap::with_capacity(persisted.debug_outputs.len());
+    for (build_id, name) in persisted.debug_outputs {
+        anyhow::ensure!(
+            !name.contains('/') && !matches!(name.as_str(), "" | "." | ".."),
+            "invalid store path name {name:?} in {index_file:?}"
+        );
+        debug_outputs.insert(BuildId::new(&build_id)?, store_dir.join(name));
+    }
+    Ok(StoreIndex {
+        mtime: persisted.mtime,
+        debug_outputs,
+    })
+}
+
+/// Writes `index` to `index_file`, atomically.
+fn save_index(index: &StoreIndex, index_file: &Path) -> anyhow::Result<()> {
+    let debug_outputs = index
+        .debug_outputs
+        .iter()
+        .filter_map(|(build_id, path)| {
+            let name = path.file_name()?.to_str()?;
+            Some((build_id.to_string(), name.to_owned()))
+        })
+        .collect();
+    let persisted = PersistedIndex {
+        mtime: index.mtime,
+        debug_outputs,
+    };
+    let dir = index_file.parent().unwrap_or(Path::new("."));
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("creating a temporary file in {dir:?}"))?;
+    let content = serde_json::to_vec(&persisted)?;
+    std::io::Write::write_all(&mut tmp, &content)
+        .with_context(|| format!("writing {:?}", tmp.path()))?;
+    tmp.persist(index_file)
+        .with_context(|| format!("renaming to {index_file:?}"))?;
+    Ok(())
+}
+
+/// The name of a top-level store path, like `34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1`
+#[derive(Debug)]
+struct StorePathName(String);
+
+impl FetcherCacheKey for StorePathName {
+    fn as_key(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Copies top-level store paths out of the store
+struct StoreCopier {
+    store_dir: PathBuf,
+}
+
+impl CachableFetcher<StorePathName> for StoreCopier {
+    async fn fetch<'a>(
+        &'a self,
+        key: &'a StorePathName,
+        into: &'a Path,
+    ) -> anyhow::Result<Presence> {
+        let source = self.store_dir.join(&key.0);
+        match tokio::fs::symlink_metadata(&source).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Presence::NotFound),
+            Err(e) => return Err(e).with_context(|| format!("stat({source:?})")),
+            Ok(_) => (),
+        }
+        let into = into.to_owned();
+        tokio::task::spawn_blocking(move || copy_unless_collected(&source, &into, copy_recursively))
+            .await?
+            .with_context(|| format!("copying {key:?} into the cache"))
+    }
+}
+
+/// Copies the store path `source` to `into` with `copy`, telling apart a store path garbage
+/// collected during the copy from a failure.
+///
+/// The store path may be removed at any time by the garbage collector. When it does not exist
+/// anymore after the copy, the copy may be incomplete whether it failed or not, and the store
+/// path is reported as not found, so that another substituter can be tried.
+fn copy_unless_collected(
+    source: &Path,
+    into: &Path,
+    copy: impl FnOnce(&Path, &Path) -> std::io::Result<()>,
+) -> anyhow::Result<Presence> {
+    let result = copy(source, into);
+    match std::fs::symlink_metadata(source) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            tracing::debug!("{source:?} was garbage collected while being copied");
+            Ok(Presence::NotFound)
+        }
+        _ => {
+            result?;
+            Ok(Presence::Found)
+        }
+    }
+}
+
+/// serves store paths directly available locally in `/nix/store`, or in another directory like a
+/// mounted squashfs or erofs image of a store
 pub struct LocalStoreSubstituter {
-    cache: Cache<BuildId, PathBuf>,
+    store_dir: PathBuf,
+    /// rebuilt when the mtime of the store changes, that is when store paths are added or removed
+    index: tokio::sync::Mutex<Option<Arc<StoreIndex>>>,
+    /// when set, the index is saved there and reused by the next run if the store did not
+    /// change, instead of scanning the store again
+    index_file: Option<PathBuf>,
+    /// when set, store paths are copied there and served from the copy instead of the store
+    copies: Option<Arc<FetcherCache<StorePathName, StoreCopier>>>,
 }
 
-fn find_buildid_in_store(build_id: &BuildId) -> anyhow::Result<Option<PathBuf>> {
-    let expected = build_id.in_debug_output("debug");
-    for direntry in std::fs::read_dir(NIX_STORE).context("opening local store")? {
-        let direntry = direntry.context("iterating local store")?;
-        if !direntry.file_name().as_bytes().ends_with(b"-debug") {
+impl std::fmt::Debug for LocalStoreSubstituter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LocalStoreSubstituter")
+            .field("store_dir", &self.store_dir)
+            .field("index_file", &self.index_file)
+            .field("copy_into_cache", &self.copies.is_some())
+            .finish()
+    }
+}
+
+/// Lists the build ids for which `debug_output` contains debuginfo.
+///
+/// Besides the `lib/debug/.build-id/ab/cdef.debug` layout, the build id of the other `.debug`
+/// files below `lib/debug`, like `lib/debug/<name>.debug`, is read from their ELF notes.
+fn build_ids_in_debug_output(debug_output: &Path) -> std::io::Result<Vec<BuildId>> {
+    let mut result = Vec::new();
+    let lib_debug = debug_output.join("lib/debug");
+    let build_id_dir = lib_debug.join(".build-id");
+    let prefixes = match std::fs::read_dir(build_id_dir) {
+        Ok(prefixes) => Some(prefixes),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    for prefix in prefixes.into_iter().flatten() {
+        let prefix = prefix?;
+        let prefix_name = prefix.file_name();
+        let Some(prefix_name) = prefix_name.to_str() else {
             continue;
+        };
+        for file in std::fs::read_dir(prefix.path())? {
+            let file_name = file?.file_name();
+            let Some(rest) = file_name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".debug"))
+            else {
+                continue;
+            };
+            if let Ok(build_id) = BuildId::new(&format!("{prefix_name}{rest}")) {
+                result.push(build_id);
+            }
         }
-        let path = direntry.path();
-        if path.join(&expected).exists() {
-            return Ok(Some(path));
+    }
+    let unsharded = walkdir::WalkDir::new(&lib_debug)
+        .into_iter()
+        .filter_entry(|entry| entry.file_name() != ".build-id");
+    for entry in unsharded {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e)
+                if e.io_error().map(std::io::Error::kind) == Some(std::io::ErrorKind::NotFound) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !entry.file_type().is_file()
+            || entry.path().extension() != Some(std::ffi::OsStr::new("debug"))
+        {
+            continue;
+        }
+        let build_id = std::fs::File::open(entry.path())
+            .map_err(anyhow::Error::from)
+            .and_then(|file| Elf::parse(std::io::BufReader::new(file))?.build_id());
+        match build_id {
+            Ok(Some(build_id)) => result.push(build_id),
+            Ok(None) => (),
+            Err(e) => tracing::debug!("skipping {:?}: {e:#}", entry.path()),
         }
     }
-    Ok(None)
+    Ok(result)
 }
 
-impl Default for LocalStoreSubstituter {
-    fn default() -> Self {
-        Self::new()
+/// Scans all `-debug` store paths of `store_dir`.
+fn index_store(store_dir: &Path) -> anyhow::Result<StoreIndex> {
+    let mtime = std::fs::metadata(store_dir)
+        .and_then(|m| m.modified())
+        .with_context(|| format!("stat({store_dir:?})"))?;
+    let mut debug_outputs = HashMap::new();
+    for direntry in std::fs::read_dir(store_dir).context("opening local store")? {
+        let direntry = direntry.context("iterating local store")?;
+        if !direntry.file_name().as_bytes().ends_with(b"-debug") {
+            continue;
+        }
+        let path = direntry.path();
+        match build_ids_in_debug_output(&path) {
+            Ok(build_ids) => {
+                for build_id in build_ids {
+                    debug_outputs.insert(build_id, path.clone());
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => tracing::warn!("failed to index {path:?}: {e}"),
+        }
     }
+    tracing::debug!("indexed {} build ids in {store_dir:?}", debug_outputs.len());
+    Ok(StoreIndex {
+        mtime,
+        debug_outputs,
+    })
 }
 
 impl LocalStoreSubstituter {
-    /// A new `LocalStoreSubstituter` for `/nix/store` (hardcoded)
-    pub fn new() -> Self {
+    /// A new `LocalStoreSubstituter` for the store at `store_dir`, usually `/nix/store`.
+    ///
+    /// Store paths are looked up by name in `store_dir`, so it can be the store of another
+    /// system, like a mounted image.
+    pub fn with_store_dir(store_dir: PathBuf) -> Self {
         LocalStoreSubstituter {
-            cache: Cache::new(100),
+            store_dir,
+            index: Default::default(),
+            index_file: None,
+            copies: None,
+        }
+    }
+
+    /// Persists the index of the store at `index_file`.
+    ///
+    /// Listing a huge store is slow, especially on a squashfs image, so it is only done again
+    /// when the mtime of the store changed since the index was saved.
+    pub fn with_index_file(mut self, index_file: PathBuf) -> Self {
+        self.index_file = Some(index_file);
+        self
+    }
+
+    /// Copies the store paths served to `cache_dir` instead of serving them from the store
+    /// directly.
+    ///
+    /// This way, what was served once keeps being served from `cache_dir` until it expires, even
+    /// if the store path is garbage collected in the meantime.
+    pub async fn with_copies(
+        mut self,
+        cache_dir: PathBuf,
+        expiration: Duration,
+        settings: &CacheSettings,
+    ) -> anyhow::Result<Self> {
+        let copier = StoreCopier {
+            store_dir: self.store_dir.clone(),
+        };
+        // copying from the local store is possible even offline
+        self.copies = Some(Arc::new(
+            FetcherCache::new(cache_dir, copier, expiration, false, settings).await?,
+        ));
+        Ok(self)
+    }
+
+    /// Returns the top-level store path `name`, either directly in the store or copied to the
+    /// cache.
+    async fn serve(&self, name: &std::ffi::OsStr) -> anyhow::Result<Option<RestrictedPath>> {
+        let path = self.store_dir.join(name);
+        match &self.copies {
+            Some(copies) => {
+                let name = name
+                    .to_str()
+                    .with_context(|| format!("non utf8 store path {path:?}"))?;
+                copies.get(StorePathName(name.to_owned())).await
+            }
+            None => {
+                let restricted = RestrictedPath::new(path.clone(), None)
+                    .await
+                    .with_context(|| format!("RestrictedPath::new({path:?})"))?;
+                // checked last, so that a store path garbage collected meanwhile is not found
+                // instead of failing later
+                match tokio::fs::symlink_metadata(&path).await {
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                    Err(e) => Err(e).context(format!("stat({})", path.display())),
+                    Ok(_) => Ok(Some(restricted)),
+                }
+            }
         }
     }
+
+    /// Returns an index of the store, scanning the store again if it changed since the last scan.
+    async fn index(&self) -> anyhow::Result<Arc<StoreIndex>> {
+        let mut index = self.index.lock().await;
+        let mtime = tokio::fs::metadata(&self.store_dir)
+            .await
+            .and_then(|m| m.modified())
+            .with_context(|| format!("stat({:?})", self.store_dir))?;
+        if let Some(ref current) = *index {
+            if current.mtime == mtime {
+                return Ok(current.clone());
+            }
+        }
+        let store_dir = self.store_dir.clone();
+        let index_file = self.index_file.clone();
+        let first = index.is_none();
+        let new = tokio::task::spawn_blocking(move || {
+            let Some(index_file) = index_file else {
+                return index_store(&store_dir);
+            };
+            if first {
+                match load_index(&index_file, &store_dir) {
+                    Ok(persisted) if persisted.mtime == mtime => return Ok(persisted),
+                    Ok(_) => tracing::debug!("{index_file:?} is outdated"),
+                    Err(e) => tracing::debug!("not reusing the index of the store: {e:#}"),
+                }
+            }
+            let new = index_store(&store_dir)?;
+            if let Err(e) = save_index(&new, &index_file) {
+                tracing::warn!("failed to save the index of {store_dir:?}: {e:#}");
+            }
+            Ok(new)
+        })
+        .await??;
+        let new = Arc::new(new);
+        *index = Some(new.clone());
+        Ok(new)
+    }
 }
 
 #[async_trait::async_trait]
@@ -53,43 +370,63 @@ impl Substituter for LocalStoreSubstituter {
         &self,
         build_id: &BuildId,
     ) -> anyhow::Result<Option<RestrictedPath>> {
-        let actual_path = match self.cache.get_value_or_guard_async(build_id).await {
-            Ok(actual_path) => actual_path,
-            Err(placeholder) => {
-                let build_id_copy = build_id.clone();
-                match tokio::task::spawn_blocking(move || find_buildid_in_store(&build_id_copy))
-                    .await??
-                {
-                    None => return Ok(None),
-                    Some(path) => {
-                        if let Err(e) = placeholder.insert(path.clone()) {
-                            tracing::debug!(err=?e, ?path, "weird, could not insert path into cache");
-                        }
-                        path
-                    }
-                }
-            }
+        let index = self.index().await?;
+        let Some(name) = index
+            .debug_outputs
+            .get(build_id)
+            .and_then(|path| path.file_name())
+        else {
+            return Ok(None);
         };
-        Ok(Some(
-            RestrictedPath::new(actual_path.clone(), None)
-                .await
-                .with_context(|| format!("RestrictedPath::new({actual_path:?})"))?,
-        ))
+        self.serve(name).await
+    }
+
+    async fn build_ids_with_prefix(&self, prefix: &str) -> anyhow::Result<Vec<BuildId>> {
+        let index = self.index().await?;
+        Ok(index
+            .debug_outputs
+            .keys()
+            .filter(|build_id| build_id.has_prefix(prefix))
+            .cloned()
+            .collect())
     }
 
     async fn fetch_store_path(
         &self,
         store_path: &StorePath,
     ) -> anyhow::Result<Option<RestrictedPath>> {
-        let store_path = store_path.root();
-        match tokio::fs::metadata(store_path.as_ref()).await {
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
-            Err(e) => Err(e).context(format!("stat({})", store_path.as_ref().display())),
-            Ok(_) => Ok(Some(
-                RestrictedPath::new(store_path.as_ref().to_path_buf(), None)
-                    .await
-                    .with_context(|| format!("RestrictedPath::new({store_path:?})"))?,
-            )),
+        self.serve(store_path.name()).await
+    }
+
+    // the deriver is only recorded in the nix database, which we do not read
+    async fn path_info(&self, _store_path: &StorePath) -> anyhow::Result<Option<PathInfo>> {
+        Ok(None)
+    }
+
+    // without --copy-into-cache, nothing is cached
+    async fn inspect_debug_output(&self, build_id: &BuildId) -> anyhow::Result<Option<EntryInfo>> {
+        let Some(copies) = &self.copies else {
+            return Ok(None);
+        };
+        let index = self.index().await?;
+        let Some(name) = index
+            .debug_outputs
+            .get(build_id)
+            .and_then(|path| path.file_name())
+            .and_then(|name| name.to_str())
+        else {
+            return Ok(None);
+        };
+        copies.inspect(name).await
+    }
+
+    async fn inspect_store_path(
+        &self,
+        store_path: &StorePath,
+    ) -> anyhow::Result<Option<EntryInfo>> {
+        match (&self.copies, store_path.name().to_str()) {
+            (Some(copies), Some(name)) => copies.inspect(name).await,
+            _ => Ok(None),
         }
     }
 
@@ -97,11 +434,293 @@ impl Substituter for LocalStoreSubstituter {
         Priority::LocalUnpacked
     }
 
-    // nothing to do
-    fn spawn_cleanup_task(&self) {}
+    fn spawn_cleanup_task(&self) {
+        if let Some(copies) = &self.copies {
+            copies.clone().spawn_cleanup_task();
+        }
+    }
 
-    // nothing to do
     async fn shrink_disk_cache(&self) -> anyhow::Result<()> {
+        match &self.copies {
+            Some(copies) => copies.shrink_cache().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        let mut entries = tokio::fs::read_dir(&self.store_dir)
+            .await
+            .with_context(|| format!("opening {}", self.store_dir.display()))?;
+        entries
+            .next_entry()
+            .await
+            .with_context(|| format!("reading {}", self.store_dir.display()))?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::AsFile;
+
+    fn make_debug_output(store: &Path, name: &str, build_id: &str) {
+        let dir = store
+            .join(name)
+            .join("lib/debug/.build-id")
+            .join(&build_id[..2]);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(format!("{}.debug", &build_id[2..])), "").unwrap();
+    }
+
+    #[test]
+    fn index_store_nominal() {
+        let store = tempfile::tempdir().unwrap();
+        make_debug_output(
+            store.path(),
+            "aaaa-foo-debug",
+            "483bd7f7229bdb06462222e1e353e4f37e15c293",
+        );
+        make_debug_output(
+            store.path(),
+            "bbbb-foo",
+            "0e20481820d3b92468102b35a5e4a29a8695c1af",
+        );
+        std::fs::create_dir(store.path().join("cccc-empty-debug")).unwrap();
+        let index = index_store(store.path()).unwrap();
+        assert_eq!(
+            index.debug_outputs,
+            HashMap::from([(
+                BuildId::new("483bd7f7229bdb06462222e1e353e4f37e15c293").unwrap(),
+                store.path().join("aaaa-foo-debug")
+            )])
+        );
+    }
+
+    #[test]
+    fn index_store_unsharded() {
+        use crate::elf::{make_test_elf_with, make_test_note, NT_GNU_BUILD_ID, SHT_NOTE};
+
+        let store = tempfile::tempdir().unwrap();
+        let lib_debug = store.path().join("aaaa-foo-debug/lib/debug");
+        std::fs::create_dir_all(&lib_debug).unwrap();
+        let debug = make_test_elf_with(&[(
+            ".note.gnu.build-id",
+            SHT_NOTE,
+            &make_test_note(b"GNU\0", NT_GNU_BUILD_ID, &[0xaa; 20]),
+        )]);
+        std::fs::write(lib_debug.join("foo.debug"), debug).unwrap();
+        std::fs::write(lib_debug.join("not-elf.debug"), "").unwrap();
+        let index = index_store(store.path()).unwrap();
+        assert_eq!(
+            index.debug_outputs,
+            HashMap::from([(
+                BuildId::new(&"aa".repeat(20)).unwrap(),
+                store.path().join("aaaa-foo-debug")
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn index_refreshed_when_store_changes() {
+        let store = tempfile::tempdir().unwrap();
+        let substituter = LocalStoreSubstituter::with_store_dir(store.path().to_path_buf());
+        let build_id = BuildId::new("483bd7f7229bdb06462222e1e353e4f37e15c293").unwrap();
+        assert!(substituter
+            .build_id_to_debug_output(&build_id)
+            .await
+            .unwrap()
+            .is_none());
+        let first = substituter.index().await.unwrap();
+        // no change: the index is reused
+        assert!(Arc::ptr_eq(&first, &substituter.index().await.unwrap()));
+
+        make_debug_output(
+            store.path(),
+            "aaaa-foo-debug",
+            "483bd7f7229bdb06462222e1e353e4f37e15c293",
+        );
+        // make sure the mtime changes even on file systems with coarse timestamps
+        let later = first.mtime + std::time::Duration::from_secs(1);
+        std::fs::File::open(store.path())
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(substituter
+            .build_id_to_debug_output(&build_id)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn index_file_reused() {
+        let store = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let index_file = cache.path().join(INDEX_FILE);
+        let build_id = "483bd7f7229bdb06462222e1e353e4f37e15c293";
+        make_debug_output(store.path(), "aaaa-foo-debug", build_id);
+        let new_substituter = || {
+            LocalStoreSubstituter::with_store_dir(store.path().to_path_buf())
+                .with_index_file(index_file.clone())
+        };
+        assert_eq!(
+            new_substituter()
+                .build_ids_with_prefix("483bd7f7")
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        // does not change the mtime of the store itself: a scan would not find the build id
+        std::fs::remove_dir_all(store.path().join("aaaa-foo-debug/lib")).unwrap();
+        assert_eq!(
+            new_substituter()
+                .build_ids_with_prefix("483bd7f7")
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        // without the index, or when it is outdated, the store is scanned again
+        assert!(
+            LocalStoreSubstituter::with_store_dir(store.path().to_path_buf())
+                .build_ids_with_prefix("483bd7f7")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        let mtime = std::fs::metadata(store.path()).unwrap().modified().unwrap();
+        std::fs::File::open(store.path())
+            .unwrap()
+            .set_modified(mtime + Duration::from_secs(1))
+            .unwrap();
+        assert!(new_substituter()
+            .build_ids_with_prefix("483bd7f7")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn load_index_garbage() {
+        let dir = tempfile::tempdir().unwrap();
+        let index_file = dir.path().join(INDEX_FILE);
+        std::fs::write(&index_file, "not json").unwrap();
+        load_index(&index_file, dir.path()).unwrap_err();
+        std::fs::write(
+            &index_file,
+            r#"{"mtime":{"secs_since_epoch":0,"nanos_since_epoch":0},"debug_outputs":{"483bd7f7229bdb06462222e1e353e4f37e15c293":"../../etc"}}"#,
+        )
+        .unwrap();
+        load_index(&index_file, dir.path()).unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn build_ids_with_prefix() {
+        let store = tempfile::tempdir().unwrap();
+        make_debug_output(
+            store.path(),
+            "aaaa-foo-debug",
+            "483bd7f7229bdb06462222e1e353e4f37e15c293",
+        );
+        make_debug_output(
+            store.path(),
+            "bbbb-bar-debug",
+            "483bd7f7ffffffffffffffffffffffffffffffff",
+        );
+        let substituter = LocalStoreSubstituter::with_store_dir(store.path().to_path_buf());
+        assert_eq!(
+            substituter
+                .build_ids_with_prefix("483bd7f72")
+                .await
+                .unwrap(),
+            [BuildId::new("483bd7f7229bdb06462222e1e353e4f37e15c293").unwrap()]
+        );
+        assert_eq!(
+            substituter
+                .build_ids_with_prefix("483BD7F7")
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(substituter
+            .build_ids_with_prefix("00000000")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn copy_into_cache() {
+        let store = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let build_id = "483bd7f7229bdb06462222e1e353e4f37e15c293";
+        make_debug_output(store.path(), "aaaa-foo-debug", build_id);
+        let substituter = LocalStoreSubstituter::with_store_dir(store.path().to_path_buf())
+            .with_copies(
+                cache.path().to_path_buf(),
+                Duration::from_secs(3600),
+                &CacheSettings::default(),
+            )
+            .await
+            .unwrap();
+        let path = substituter
+            .build_id_to_debug_output(&BuildId::new(build_id).unwrap())
+            .await
+            .unwrap()
+            .unwrap()
+            .join(format!("lib/debug/.build-id/48/{}.debug", &build_id[2..]));
+        // the copy survives garbage collection of the original
+        std::fs::remove_dir_all(store.path().join("aaaa-foo-debug")).unwrap();
+        let resolved = path.resolve_inside_root().await.unwrap().unwrap();
+        resolved.open().await.unwrap();
+    }
+
+    #[test]
+    fn copy_garbage_collected() {
+        let store = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let source = store.path().join("aaaa-foo");
+        let collect = |from: &Path, to: &Path| -> std::io::Result<()> {
+            std::fs::create_dir(to)?;
+            std::fs::remove_dir_all(from)?;
+            Err(std::io::ErrorKind::NotFound.into())
+        };
+        // collected in the middle of the copy
+        std::fs::create_dir_all(source.join("bin")).unwrap();
+        let presence =
+            copy_unless_collected(&source, &cache.path().join("failed"), collect).unwrap();
+        assert_eq!(presence, Presence::NotFound);
+        // collected right after a copy which missed part of it
+        std::fs::create_dir_all(source.join("bin")).unwrap();
+        let presence = copy_unless_collected(&source, &cache.path().join("partial"), |from, _| {
+            std::fs::remove_dir_all(from)
+        })
+        .unwrap();
+        assert_eq!(presence, Presence::NotFound);
+        // other failures are errors
+        std::fs::create_dir_all(source.join("bin")).unwrap();
+        copy_unless_collected(&source, &cache.path().join("error"), |_, _| {
+            Err(std::io::ErrorKind::PermissionDenied.into())
+        })
+        .unwrap_err();
+        let presence =
+            copy_unless_collected(&source, &cache.path().join("ok"), copy_recursively).unwrap();
+        assert_eq!(presence, Presence::Found);
+    }
+
+    #[tokio::test]
+    async fn check() {
+        let store = tempfile::tempdir().unwrap();
+        LocalStoreSubstituter::with_store_dir(store.path().to_path_buf())
+            .check()
+            .await
+            .unwrap();
+        LocalStoreSubstituter::with_store_dir(store.path().join("missing"))
+            .check()
+            .await
+            .unwrap_err();
+    }
+}
@@ -0,0 +1,253 @@
+//! A substituter serving debug outputs and store paths described in memory, to test code using
+//! substituters without binary caches or nix.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{build_id::BuildId, cache::EntryInfo, store_path::StorePath, vfs::RestrictedPath};
+
+use super::{PathInfo, Priority, Substituter};
+
+/// A file or symlink of a [MemoryTree]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryEntry {
+    /// a regular file with this content
+    File(Vec<u8>),
+    /// a symlink to this target, for example a store path
+    Symlink(PathBuf),
+}
+
+/// The content of a debug output or store path: the path of each file or symlink relative to its
+/// root. Parent directories are created as needed, and the empty path is the root itself.
+pub type MemoryTree = BTreeMap<PathBuf, MemoryEntry>;
+
+/// A substituter whose debug outputs and store paths are [MemoryTree]s, written to a directory
+/// when they are first fetched.
+///
+/// It does not cache anything, so cleaning up the directory is up to the caller.
+#[derive(Debug)]
+pub struct MemorySubstituter {
+    into: PathBuf,
+    debug_outputs: HashMap<BuildId, MemoryTree>,
+    store_paths: HashMap<StorePath, MemoryTree>,
+    path_infos: HashMap<StorePath, PathInfo>,
+}
+
+impl MemorySubstituter {
+    /// A substituter with nothing in it, writing what it is asked for to `into`.
+    pub fn new(into: PathBuf) -> Self {
+        Self {
+            into,
+            debug_outputs: HashMap::new(),
+            store_paths: HashMap::new(),
+            path_infos: HashMap::new(),
+        }
+    }
+
+    /// Adds the debug output of this build id.
+    pub fn with_debug_output<P: Into<PathBuf>>(
+        mut self,
+        build_id: BuildId,
+        tree: impl IntoIterator<Item = (P, MemoryEntry)>,
+    ) -> Self {
+        let tree = tree.into_iter().map(|(k, v)| (k.into(), v)).collect();
+        self.debug_outputs.insert(build_id, tree);
+        self
+    }
+
+    /// Adds this store path, ignoring its subdirectory if any.
+    pub fn with_store_path<P: Into<PathBuf>>(
+        mut self,
+        store_path: &StorePath,
+        tree: impl IntoIterator<Item = (P, MemoryEntry)>,
+    ) -> Self {
+        let tree = tree.into_iter().map(|(k, v)| (k.into(), v)).collect();
+        self.store_paths.insert(store_path.root(), tree);
+        self
+    }
+
+    /// Sets what [Substituter::path_info] returns for this store path.
+    pub fn with_path_info(mut self, store_path: &StorePath, info: PathInfo) -> Self {
+        self.path_infos.insert(store_path.root(), info);
+        self
+    }
+
+    /// Writes `tree` to `into/name` unless it was already written, and returns it.
+    async fn materialize(&self, name: &str, tree: &MemoryTree) -> anyhow::Result<RestrictedPath> {
+        let destination = self.into.join(name);
+        if tokio::fs::symlink_metadata(&destination).await.is_err() {
+            tokio::fs::create_dir_all(&self.into)
+                .await
+                .with_context(|| format!("creating {}", self.into.display()))?;
+            // written aside then renamed, so that concurrent fetches never see a partial tree
+            let scratch = tempfile::tempdir_in(&self.into)
+                .with_context(|| format!("creating a temporary directory in {:?}", self.into))?;
+            let root = scratch.path().join("root");
+            for (relative, entry) in tree {
+                write_entry(&root, relative, entry).await?;
+            }
+            if tokio::fs::symlink_metadata(&root).await.is_err() {
+                // an empty tree is an empty directory
+                tokio::fs::create_dir(&root).await?;
+            }
+            if let Err(e) = tokio::fs::rename(&root, &destination).await {
+                // unless another fetch was faster
+                if tokio::fs::symlink_metadata(&destination).await.is_err() {
+                    return Err(e).with_context(|| format!("renaming to {destination:?}"));
+                }
+            }
+        }
+        RestrictedPath::new(destination, None).await
+    }
+}
+
+/// Writes `entry` at `root/relative`, creating its parent directories.
+async fn write_entry(root: &Path, relative: &Path, entry: &MemoryEntry) -> anyhow::Result<()> {
+    let path = match relative.as_os_str().is_empty() {
+        true => root.to_owned(),
+        false => root.join(relative),
+    };
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("creating {}", parent.display()))?;
+    }
+    match entry {
+        MemoryEntry::File(content) => tokio::fs::write(&path, content).await,
+        MemoryEntry::Symlink(target) => tokio::fs::symlink(target, &path).await,
+    }
+    .with_context(|| format!("writing {}", path.display()))
+}
+
+#[async_trait::async_trait]
+impl Substituter for MemorySubstituter {
+    async fn build_id_to_debug_output(
+        &self,
+        build_id: &BuildId,
+    ) -> anyhow::Result<Option<RestrictedPath>> {
+        match self.debug_outputs.get(build_id) {
+            None => Ok(None),
+            Some(tree) => self
+                .materialize(&format!("debug-{build_id}"), tree)
+                .await
+                .map(Some),
+        }
+    }
+
+    async fn fetch_store_path(
+        &self,
+        store_path: &StorePath,
+    ) -> anyhow::Result<Option<RestrictedPath>> {
+        let root = store_path.root();
+        match self.store_paths.get(&root) {
+            None => Ok(None),
+            Some(tree) => {
+                let name = root.name().to_str().context("non utf8 store path")?;
+                self.materialize(name, tree).await.map(Some)
+            }
+        }
+    }
+
+    async fn path_info(&self, store_path: &StorePath) -> anyhow::Result<Option<PathInfo>> {
+        Ok(self.path_infos.get(&store_path.root()).cloned())
+    }
+
+    async fn inspect_debug_output(&self, _build_id: &BuildId) -> anyhow::Result<Option<EntryInfo>> {
+        Ok(None)
+    }
+
+    async fn inspect_store_path(
+        &self,
+        _store_path: &StorePath,
+    ) -> anyhow::Result<Option<EntryInfo>> {
+        Ok(None)
+    }
+
+    fn priority(&self) -> Priority {
+        Priority::LocalUnpacked
+    }
+
+    fn spawn_cleanup_task(&self) {}
+
+    async fn shrink_disk_cache(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_memory_substituter() {
+    use crate::build_id::PathTemplate;
+    use crate::debuginfod::Debuginfod;
+    use crate::settings::Settings;
+    use crate::vfs::AsFile;
+    use tokio::io::AsyncReadExt;
+
+    let t = tempfile::tempdir().unwrap();
+    let build_id = BuildId::new("0123456789abcdef0123456789abcdef01234567").unwrap();
+    let source = StorePath::new(Path::new(
+        "/nix/store/2qw62845796lyx649ck67zbk04pv8xhf-source",
+    ))
+    .unwrap();
+    let substituter = MemorySubstituter::new(t.path().join("substituter"))
+        .with_debug_output(
+            build_id.clone(),
+            [
+                (
+                    PathTemplate::default().expand(&build_id),
+                    MemoryEntry::File(b"debug symbols".to_vec()),
+                ),
+                (
+                    build_id.in_debug_output("source"),
+                    MemoryEntry::Symlink(source.as_ref().to_owned()),
+                ),
+            ],
+        )
+        .with_store_path(
+            &source,
+            [("src/main.c", MemoryEntry::File(b"int main() {}".to_vec()))],
+        );
+    let debuginfod = Debuginfod::new(
+        t.path().join("cache"),
+        Box::new(substituter),
+        std::time::Duration::from_secs(1000),
+        &Settings::default(),
+    )
+    .await
+    .unwrap();
+    let read = |file: crate::vfs::ResolvedPath| async move {
+        let mut content = String::new();
+        file.open()
+            .await
+            .unwrap()
+            .read_to_string(&mut content)
+            .await
+            .unwrap();
+        content
+    };
+
+    let debuginfo = debuginfod.debuginfo(&build_id).await.unwrap().unwrap();
+    assert_eq!(read(debuginfo).await, "debug symbols");
+    // fetched twice, written once
+    let debuginfo = debuginfod.debuginfo(&build_id).await.unwrap().unwrap();
+    assert_eq!(read(debuginfo).await, "debug symbols");
+    let main = debuginfod
+        .source(&build_id, "/build/source/src/main.c")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        main.store_path().unwrap().relative(),
+        Path::new("src/main.c")
+    );
+    assert_eq!(read(main).await, "int main() {}");
+    let missing = BuildId::new("1123456789abcdef0123456789abcdef01234567").unwrap();
+    assert!(debuginfod.debuginfo(&missing).await.unwrap().is_none());
+}
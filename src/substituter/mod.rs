@@ -5,33 +5,48 @@
 //!
 //! > An additional store from which Nix can obtain store objects instead of building them. Often the substituter is a binary cache, but any store can serve as substituter.
 //!
-//! So the [LocalStoreSubstituter] serves a substituters which is not a binary cache, but
-//! [HttpSubstituter] and [FileSubstituter] refer to substituters which are binary caches.
+//! So the `LocalStoreSubstituter` serves a substituters which is not a binary cache, but
+//! `HttpSubstituter` and `FileSubstituter` refer to substituters which are binary caches.
 
 /// Common code between substituters which are actually binary caches
-pub mod binary_cache;
+pub(crate) mod binary_cache;
+/// support for `debuginfod-cache://` substituters, reusing the client cache of elfutils, and
+/// `unpacked://` substituters with the same files in another layout
+pub(crate) mod debuginfod_cache;
 /// support for `file://` substituters
-pub mod file;
+pub(crate) mod file;
 /// support for `http://` and `https://` substituters
-pub mod http;
+pub(crate) mod http;
+/// support for `ipfs://` and `ipns://` substituters, through an http gateway
+pub(crate) mod ipfs;
 /// serve debuginfo from your own store
-pub mod local;
+pub(crate) mod local;
+/// substituters described in memory, to test code using substituters
+#[cfg(any(test, feature = "test-support"))]
+pub mod memory;
 /// combine several substituters in one single virtual one
-pub mod multiplex;
+pub(crate) mod multiplex;
+/// count how substituters answer requests
+pub(crate) mod stats;
 
-use std::{
-    path::{Path, PathBuf},
-    sync::Arc,
-    time::Duration,
-};
+use std::{os::unix::ffi::OsStrExt, path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::Context;
+use debuginfod_cache::DebuginfodCacheSubstituter;
 use file::FileSubstituter;
 use http::HttpSubstituter;
+use ipfs::IpfsSubstituter;
 use local::LocalStoreSubstituter;
 use reqwest::Url;
 
-use crate::{build_id::BuildId, store_path::StorePath, vfs::RestrictedPath};
+use crate::{
+    build_id::{BuildId, PathTemplate},
+    cache::EntryInfo,
+    settings::Settings,
+    store_path::{StorePath, NIX_STORE},
+    utils::Presence,
+    vfs::RestrictedPath,
+};
 
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone, Copy)]
 /// Encodes if a substituters should be tried first or last in case several substituters are
@@ -41,12 +56,76 @@ pub enum Priority {
     LocalUnpacked,
     /// Data is local but compressed
     Local,
+    /// Declared by the `Priority:` of the `nix-cache-info` file of a binary cache. As in nix,
+    /// lower values are tried first.
+    BinaryCache(u32),
     /// Unknown
     Unknown,
     /// Data must be downloaded from the internet
     Remote,
 }
 
+/// An error that may go away if the same request is retried later, like an upstream server
+/// answering 503.
+///
+/// Substituters put it in the chain of an [anyhow::Error], see [is_transient].
+#[derive(Debug)]
+pub struct TransientError(pub String);
+
+impl std::fmt::Display for TransientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TransientError {}
+
+/// Whether this error was caused by a temporary condition (network timeout, overloaded upstream
+/// server, etc.) such that retrying later may succeed.
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    fn is_transient_reqwest(e: &reqwest::Error) -> bool {
+        e.is_timeout() || e.is_connect()
+    }
+    error.chain().any(|source| {
+        if source.is::<TransientError>() {
+            true
+        } else if let Some(e) = source.downcast_ref::<reqwest::Error>() {
+            is_transient_reqwest(e)
+        } else if let Some(e) = source.downcast_ref::<std::io::Error>() {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+            ) || e
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<reqwest::Error>())
+                .is_some_and(is_transient_reqwest)
+        } else {
+            false
+        }
+    })
+}
+
+#[test]
+fn test_is_transient() {
+    let transient = anyhow::Error::new(TransientError("503".into())).context("fetching");
+    assert!(is_transient(&transient));
+    let timeout = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::TimedOut));
+    assert!(is_transient(&timeout));
+    assert!(!is_transient(&anyhow::anyhow!("corrupted nar")));
+}
+
+/// What a substituter knows about a store path besides its content, see
+/// [Substituter::path_info]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathInfo {
+    /// the derivation (`.drv`) that built the store path, if known
+    pub deriver: Option<StorePath>,
+    /// the store paths it refers to
+    pub references: Vec<StorePath>,
+}
+
 /// Fetching debuginfo from a nix substituter
 #[async_trait::async_trait]
 pub trait Substituter: std::fmt::Debug {
@@ -61,6 +140,43 @@ pub trait Substituter: std::fmt::Debug {
         build_id: &BuildId,
     ) -> anyhow::Result<Option<RestrictedPath>>;
 
+    /// Returns, for each of these build ids in order, whether the substituter has its debug
+    /// output.
+    ///
+    /// The default implementation looks them up one after the other with
+    /// [Substituter::build_id_to_debug_output], which fetches them. Substituters that can tell
+    /// without fetching the debug output should override it.
+    async fn batch_exists(&self, build_ids: &[BuildId]) -> Vec<anyhow::Result<Presence>>
+    where
+        Self: Sync,
+    {
+        let mut result = Vec::with_capacity(build_ids.len());
+        for build_id in build_ids {
+            result.push(
+                self.build_id_to_debug_output(build_id)
+                    .await
+                    .map(|output| match output {
+                        Some(_) => Presence::Found,
+                        None => Presence::NotFound,
+                    }),
+            );
+        }
+        result
+    }
+
+    /// Returns the build ids starting with `prefix` of the debug outputs the substituter has, for
+    /// clients which only know a truncated build id.
+    ///
+    /// Only substituters which can list their debug outputs find anything, the default
+    /// implementation returns nothing.
+    async fn build_ids_with_prefix(&self, prefix: &str) -> anyhow::Result<Vec<BuildId>>
+    where
+        Self: Sync,
+    {
+        let _ = prefix;
+        Ok(Vec::new())
+    }
+
     /// Fetches the requested store path and returns the path on the
     /// file-system where this output is cached.
     ///
@@ -72,6 +188,38 @@ pub trait Substituter: std::fmt::Debug {
         store_path: &StorePath,
     ) -> anyhow::Result<Option<RestrictedPath>>;
 
+    /// Returns what the substituter knows about this store path from its narinfo, like the
+    /// derivation (`.drv`) that built it.
+    ///
+    /// Returns None if the substituter does not have this store path or its narinfo. Does not
+    /// fetch the store path itself.
+    async fn path_info(&self, store_path: &StorePath) -> anyhow::Result<Option<PathInfo>>;
+
+    /// Returns the narinfo of the store path with this hash as the substituter serves it, before
+    /// parsing, for debugging.
+    ///
+    /// Returns None if the substituter does not have it, or has no narinfo files, which is what
+    /// the default implementation does.
+    async fn fetch_narinfo_raw(&self, hash: &str) -> anyhow::Result<Option<String>>
+    where
+        Self: Sync,
+    {
+        let _ = hash;
+        Ok(None)
+    }
+
+    /// Returns information about the cached debug output for this build id, without fetching
+    /// anything.
+    ///
+    /// Returns None if it is not in cache, or if the substituter does not cache anything.
+    async fn inspect_debug_output(&self, build_id: &BuildId) -> anyhow::Result<Option<EntryInfo>>;
+
+    /// Returns information about the cached copy of this store path, without fetching anything.
+    ///
+    /// Returns None if it is not in cache, or if the substituter does not cache anything.
+    async fn inspect_store_path(&self, store_path: &StorePath)
+        -> anyhow::Result<Option<EntryInfo>>;
+
     /// A value indicating if this substituter should be tried first if several are available
     ///
     /// Low values mean first
@@ -79,15 +227,20 @@ pub trait Substituter: std::fmt::Debug {
 
     /// Spawn periodic cleaning of caches, if any.
     ///
-    /// May leak resources, as the trait does not provide a method to stop them.
+    /// Cleaning stops once the caches are dropped.
     fn spawn_cleanup_task(&self);
 
     /// Attempt to free as much disk space from the cache as possible
     async fn shrink_disk_cache(&self) -> anyhow::Result<()>;
+
+    /// Checks once that the substituter is reachable, for example at startup.
+    ///
+    /// Returns an error explaining why it is not.
+    async fn check(&self) -> anyhow::Result<()>;
 }
 
 #[async_trait::async_trait]
-impl<S: Substituter + Send + Sync> Substituter for Arc<S> {
+impl<S: Substituter + Send + Sync + ?Sized> Substituter for Arc<S> {
     async fn build_id_to_debug_output(
         &self,
         build_id: &BuildId,
@@ -95,6 +248,14 @@ impl<S: Substituter + Send + Sync> Substituter for Arc<S> {
         self.as_ref().build_id_to_debug_output(build_id).await
     }
 
+    async fn batch_exists(&self, build_ids: &[BuildId]) -> Vec<anyhow::Result<Presence>> {
+        self.as_ref().batch_exists(build_ids).await
+    }
+
+    async fn build_ids_with_prefix(&self, prefix: &str) -> anyhow::Result<Vec<BuildId>> {
+        self.as_ref().build_ids_with_prefix(prefix).await
+    }
+
     async fn fetch_store_path(
         &self,
         store_path: &StorePath,
@@ -102,6 +263,25 @@ impl<S: Substituter + Send + Sync> Substituter for Arc<S> {
         self.as_ref().fetch_store_path(store_path).await
     }
 
+    async fn path_info(&self, store_path: &StorePath) -> anyhow::Result<Option<PathInfo>> {
+        self.as_ref().path_info(store_path).await
+    }
+
+    async fn fetch_narinfo_raw(&self, hash: &str) -> anyhow::Result<Option<String>> {
+        self.as_ref().fetch_narinfo_raw(hash).await
+    }
+
+    async fn inspect_debug_output(&self, build_id: &BuildId) -> anyhow::Result<Option<EntryInfo>> {
+        self.as_ref().inspect_debug_output(build_id).await
+    }
+
+    async fn inspect_store_path(
+        &self,
+        store_path: &StorePath,
+    ) -> anyhow::Result<Option<EntryInfo>> {
+        self.as_ref().inspect_store_path(store_path).await
+    }
+
     fn priority(&self) -> Priority {
         self.as_ref().priority()
     }
@@ -113,46 +293,130 @@ impl<S: Substituter + Send + Sync> Substituter for Arc<S> {
     async fn shrink_disk_cache(&self) -> anyhow::Result<()> {
         self.as_ref().shrink_disk_cache().await
     }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        self.as_ref().check().await
+    }
 }
 
 /// A substituters of unspecified implementation.
 pub type BoxedSubstituter = Box<dyn Substituter + Send + Sync + 'static>;
 
+/// A substituter of unspecified implementation which can be part of several
+/// `multiplex::MultiplexingSubstituter`, see [BoxedSubstituter].
+pub type SharedSubstituter = Arc<dyn Substituter + Send + Sync + 'static>;
+
 /// Returns a substituter corresponding to the specified url.
 ///
-/// Query params are ignored
+/// Query params are ignored, except `?layout=` of `unpacked://` substituters which is the
+/// `PathTemplate` of the directory of each build id.
 ///
 /// Returns an error if no implementation can handle this url.
 ///
 /// Cache for this substituter will be stored in `cache_path` (directory, must already exist) and
 /// expire after approximately `expiration`.
+///
+/// If `offline` is true, substituters that need network access only serve what is already in
+/// `cache_path`.
+///
+/// `user_agent_suffix` is appended to the User-Agent of http requests.
+///
+/// If `copy_into_cache` is true, `local:` copies the store paths it serves to `cache_path`
+/// instead of serving them from the store directly. In any case, it persists its index of the
+/// store in `cache_path`.
+///
+/// `ipfs://` and `ipns://` substituters are fetched through the http gateway at `ipfs_gateway`.
+///
+/// `settings` configure how the substituter fetches, unpacks and stores files.
+#[allow(clippy::too_many_arguments)]
 pub async fn substituter_from_url(
     url: &Url,
     cache_path: PathBuf,
     expiration: Duration,
+    offline: bool,
+    user_agent_suffix: Option<&str>,
+    copy_into_cache: bool,
+    ipfs_gateway: &Url,
+    settings: &Settings,
 ) -> anyhow::Result<BoxedSubstituter> {
     match url.scheme() {
         "file" => {
-            let path = Path::new(url.path());
-            let _ = tokio::fs::metadata(path).await.with_context(|| {
-                format!(
-                    "cannot use {} as Substituter: {} does not exist",
-                    url,
-                    path.display()
-                )
-            })?;
-            let file_substituter = FileSubstituter::new(path, cache_path, expiration)
+            let path = &file_url_to_path(url)?;
+            let file_substituter = FileSubstituter::new(path, cache_path, expiration, settings)
                 .await
                 .with_context(|| format!("creating a file substituter for {path:?}"))?;
             Ok(Box::new(file_substituter))
         }
         "http" | "https" => {
-            let http_substituter = HttpSubstituter::new(url.clone(), cache_path, expiration)
-                .await
-                .with_context(|| format!("creating an http substituter from {url}"))?;
+            let http_substituter = HttpSubstituter::new(
+                url.clone(),
+                cache_path,
+                expiration,
+                offline,
+                user_agent_suffix,
+                settings,
+            )
+            .await
+            .with_context(|| format!("creating an http substituter from {url}"))?;
             Ok(Box::new(http_substituter))
         }
-        "local" => Ok(Box::new(LocalStoreSubstituter::new())),
+        "ipfs" | "ipns" => {
+            let ipfs_substituter = IpfsSubstituter::new(
+                url.clone(),
+                ipfs_gateway,
+                cache_path,
+                expiration,
+                offline,
+                user_agent_suffix,
+                settings,
+            )
+            .await
+            .with_context(|| format!("creating an ipfs substituter from {url}"))?;
+            Ok(Box::new(ipfs_substituter))
+        }
+        "debuginfod-cache" => {
+            let path = &file_url_to_path(url)?;
+            let substituter =
+                DebuginfodCacheSubstituter::new(path, cache_path, expiration, &settings.cache)
+                    .await
+                    .with_context(|| {
+                        format!("creating a debuginfod cache substituter for {path:?}")
+                    })?;
+            Ok(Box::new(substituter))
+        }
+        "unpacked" => {
+            let path = &file_url_to_path(url)?;
+            let layout = match url.query_pairs().find(|(key, _)| key == "layout") {
+                Some((_, layout)) => PathTemplate::new(&layout)
+                    .with_context(|| format!("parsing layout {layout:?} of {url}"))?,
+                None => PathTemplate::new(debuginfod_cache::UNPACKED_LAYOUT)?,
+            };
+            let substituter = DebuginfodCacheSubstituter::with_layout(
+                path,
+                layout,
+                cache_path,
+                expiration,
+                &settings.cache,
+            )
+            .await
+            .with_context(|| format!("creating an unpacked substituter for {path:?}"))?;
+            Ok(Box::new(substituter))
+        }
+        "local" => {
+            let store_dir = local_store_dir(url)?;
+            let substituter = LocalStoreSubstituter::with_store_dir(store_dir)
+                .with_index_file(cache_path.join(local::INDEX_FILE));
+            if copy_into_cache {
+                Ok(Box::new(
+                    substituter
+                        .with_copies(cache_path, expiration, &settings.cache)
+                        .await
+                        .context("creating a local store substituter")?,
+                ))
+            } else {
+                Ok(Box::new(substituter))
+            }
+        }
         other => {
             anyhow::bail!(
                 "I don't know how to handle this kind of Substituter: {}",
@@ -161,3 +425,115 @@ pub async fn substituter_from_url(
         }
     }
 }
+
+/// Returns the local directory designated by a `file://`, `debuginfod-cache://` or `unpacked://`
+/// url.
+///
+/// The host must be empty or `localhost`, and the path is percent-decoded.
+pub fn file_url_to_path(url: &Url) -> anyhow::Result<PathBuf> {
+    if let Some(host) = url.host_str().filter(|host| *host != "localhost") {
+        anyhow::bail!(
+            "substituter {url} is on host {host:?}, only local directories are supported"
+        );
+    }
+    url.to_file_path()
+        .map_err(|()| anyhow::anyhow!("{url} does not designate a local directory"))
+}
+
+#[test]
+fn test_file_url_to_path() {
+    for (url, expected) in [
+        ("file:///srv/cache", "/srv/cache"),
+        ("file://localhost/srv/cache", "/srv/cache"),
+        ("file:///srv/my%20cache", "/srv/my cache"),
+        ("file:///srv/cache?priority=10", "/srv/cache"),
+        (
+            "debuginfod-cache:///root/.cache/debuginfod_client",
+            "/root/.cache/debuginfod_client",
+        ),
+    ] {
+        assert_eq!(
+            file_url_to_path(&Url::parse(url).unwrap()).unwrap(),
+            std::path::Path::new(expected),
+            "{url}"
+        );
+    }
+    let err = file_url_to_path(&Url::parse("file://example.com/srv/cache").unwrap()).unwrap_err();
+    assert!(format!("{err:#}").contains("example.com"), "{err:#}");
+}
+
+/// Returns the store directory designated by a `local:` url.
+///
+/// This is `/nix/store` for `local:`, and the path of the url for urls like
+/// `local:/mnt/image/nix/store`, for example a store image mounted somewhere else.
+pub fn local_store_dir(url: &Url) -> anyhow::Result<PathBuf> {
+    if url.path().is_empty() {
+        return Ok(PathBuf::from(NIX_STORE));
+    }
+    let path: Vec<u8> = percent_encoding::percent_decode_str(url.path()).collect();
+    let path = PathBuf::from(std::ffi::OsStr::from_bytes(&path));
+    anyhow::ensure!(
+        path.is_absolute(),
+        "the store directory of {url} must be an absolute path"
+    );
+    Ok(path)
+}
+
+#[test]
+fn test_local_store_dir() {
+    for (url, expected) in [
+        ("local:", "/nix/store"),
+        ("local:?priority=10", "/nix/store"),
+        ("local:/mnt/image/nix/store", "/mnt/image/nix/store"),
+        ("local:/mnt/my%20image/nix/store", "/mnt/my image/nix/store"),
+    ] {
+        assert_eq!(
+            local_store_dir(&Url::parse(url).unwrap()).unwrap(),
+            std::path::Path::new(expected),
+            "{url}"
+        );
+    }
+    local_store_dir(&Url::parse("local:nix/store").unwrap()).unwrap_err();
+}
+
+/// Parses the content of a file listing substituter urls, one per line.
+///
+/// Blank lines and lines starting with `#` are ignored. Leading and trailing whitespace is
+/// stripped.
+///
+/// Errors mention the line number of the offending line.
+pub fn parse_substituter_list(content: &str) -> anyhow::Result<Vec<Url>> {
+    let mut result = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let url = Url::parse(line)
+            .with_context(|| format!("line {}: invalid substituter url {line:?}", i + 1))?;
+        result.push(url);
+    }
+    Ok(result)
+}
+
+#[test]
+fn parse_substituter_list_nominal() {
+    let content = "# mirrors\nlocal:\n\n  https://cache.nixos.org  \n\t# disabled\nfile:///some/dir?priority=10\n";
+    let urls = parse_substituter_list(content).unwrap();
+    let urls: Vec<&str> = urls.iter().map(Url::as_str).collect();
+    assert_eq!(
+        urls,
+        [
+            "local:",
+            "https://cache.nixos.org/",
+            "file:///some/dir?priority=10"
+        ]
+    );
+}
+
+#[test]
+fn parse_substituter_list_error_line_number() {
+    let content = "local:\n# comment\nnot an url\n";
+    let err = parse_substituter_list(content).unwrap_err();
+    assert!(dbg!(format!("{err:#}")).contains("line 3"));
+}
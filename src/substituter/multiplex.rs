@@ -6,11 +6,15 @@ use reqwest::Url;
 use tracing::Instrument;
 
 use crate::{
-    build_id::BuildId, store_path::StorePath, utils::percent_encode_to_filename,
+    build_id::BuildId,
+    cache::EntryInfo,
+    settings::Settings,
+    store_path::StorePath,
+    utils::{percent_encode_to_filename, Presence},
     vfs::RestrictedPath,
 };
 
-use super::{substituter_from_url, BoxedSubstituter, Priority, Substituter};
+use super::{substituter_from_url, BoxedSubstituter, PathInfo, Priority, Substituter};
 
 #[derive(Debug)]
 /// A substituter which tries its constituent substituters in succession until one succeeds
@@ -51,6 +55,57 @@ impl Substituter for MultiplexingSubstituter {
         result
     }
 
+    /// Asks each substituter in turn about the build ids that previous ones do not have.
+    async fn batch_exists(&self, build_ids: &[BuildId]) -> Vec<anyhow::Result<Presence>> {
+        let mut result: Vec<anyhow::Result<Presence>> =
+            build_ids.iter().map(|_| Ok(Presence::NotFound)).collect();
+        for substituter in self.substituters.iter() {
+            let pending: Vec<usize> = (0..build_ids.len())
+                .filter(|&i| !matches!(result[i], Ok(Presence::Found)))
+                .collect();
+            if pending.is_empty() {
+                break;
+            }
+            let ids: Vec<BuildId> = pending.iter().map(|&i| build_ids[i].clone()).collect();
+            let answers = substituter.batch_exists(&ids).await;
+            for (i, answer) in pending.into_iter().zip(answers) {
+                match answer {
+                    Ok(Presence::Found) => result[i] = Ok(Presence::Found),
+                    Ok(Presence::NotFound) => (),
+                    Err(e) => {
+                        tracing::trace!("substituter {substituter:?} failed: {e:#}");
+                        result[i] = Err(e);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    async fn build_ids_with_prefix(&self, prefix: &str) -> anyhow::Result<Vec<BuildId>> {
+        let mut result: Vec<BuildId> = Vec::new();
+        let mut error = None;
+        for substituter in self.substituters.iter() {
+            match substituter.build_ids_with_prefix(prefix).await {
+                Ok(build_ids) => {
+                    for build_id in build_ids {
+                        if !result.contains(&build_id) {
+                            result.push(build_id);
+                        }
+                    }
+                }
+                Err(e) => {
+                    tracing::trace!("substituter {substituter:?} failed: {e:#}");
+                    error = Some(e);
+                }
+            }
+        }
+        match error {
+            Some(e) if result.is_empty() => Err(e),
+            _ => Ok(result),
+        }
+    }
+
     #[tracing::instrument]
     async fn fetch_store_path(
         &self,
@@ -81,6 +136,58 @@ impl Substituter for MultiplexingSubstituter {
         result
     }
 
+    #[tracing::instrument]
+    async fn path_info(&self, store_path: &StorePath) -> anyhow::Result<Option<PathInfo>> {
+        let mut result = Ok(None);
+        for substituter in self.substituters.iter() {
+            match substituter.path_info(store_path).await {
+                Ok(Some(info)) => return Ok(Some(info)),
+                Ok(None) => (),
+                Err(e) => {
+                    tracing::trace!("substituter {substituter:?} failed: {e:#}");
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    async fn fetch_narinfo_raw(&self, hash: &str) -> anyhow::Result<Option<String>> {
+        let mut result = Ok(None);
+        for substituter in self.substituters.iter() {
+            match substituter.fetch_narinfo_raw(hash).await {
+                Ok(Some(narinfo)) => return Ok(Some(narinfo)),
+                Ok(None) => (),
+                Err(e) => {
+                    tracing::trace!("substituter {substituter:?} failed: {e:#}");
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    async fn inspect_debug_output(&self, build_id: &BuildId) -> anyhow::Result<Option<EntryInfo>> {
+        for substituter in self.substituters.iter() {
+            if let Some(info) = substituter.inspect_debug_output(build_id).await? {
+                return Ok(Some(info));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn inspect_store_path(
+        &self,
+        store_path: &StorePath,
+    ) -> anyhow::Result<Option<EntryInfo>> {
+        for substituter in self.substituters.iter() {
+            if let Some(info) = substituter.inspect_store_path(store_path).await? {
+                return Ok(Some(info));
+            }
+        }
+        Ok(None)
+    }
+
     fn priority(&self) -> Priority {
         Priority::Unknown
     }
@@ -106,6 +213,23 @@ impl Substituter for MultiplexingSubstituter {
             .find(anyhow::Result::is_err)
             .unwrap_or(Ok(()))
     }
+
+    /// Checks all substituters, logging a warning for each unreachable one.
+    async fn check(&self) -> anyhow::Result<()> {
+        let mut unreachable = 0;
+        for substituter in self.substituters.iter() {
+            if let Err(e) = substituter.check().await {
+                tracing::warn!("substituter {substituter:?} is unreachable: {e:#}");
+                unreachable += 1;
+            }
+        }
+        anyhow::ensure!(
+            unreachable == 0,
+            "{unreachable} out of {} substituters are unreachable",
+            self.substituters.len()
+        );
+        Ok(())
+    }
 }
 
 impl MultiplexingSubstituter {
@@ -120,27 +244,37 @@ impl MultiplexingSubstituter {
         result.substituters.sort_by_key(|s| s.priority());
         result
     }
+}
 
-    /// Same as [MultiplexingSubstituter::new] but constructs substituters from Urls instead.
-    ///
-    /// See [substituter_from_url] for details.
-    pub async fn new_from_urls<'a, I: Iterator<Item = &'a Url>>(
-        urls: I,
-        cache_dir: &Path,
-        expiration: std::time::Duration,
-    ) -> anyhow::Result<Self> {
-        let mut substituters = vec![];
-        for url in urls {
-            let dirname = percent_encode_to_filename(url.as_str());
-            let d = cache_dir.join(dirname);
-            tokio::fs::create_dir_all(&d)
-                .await
-                .with_context(|| format!("mkdir({d:?})"))?;
-            let substituter = substituter_from_url(url, d, expiration).await?;
-            substituters.push(substituter);
-        }
-        Ok(Self::new(substituters.into_iter()))
-    }
+/// Same as [substituter_from_url], but stores the cache of the substituter in a subdirectory of
+/// `cache_dir` named after the url.
+#[allow(clippy::too_many_arguments)]
+pub async fn substituter_in_cache_dir(
+    url: &Url,
+    cache_dir: &Path,
+    expiration: std::time::Duration,
+    offline: bool,
+    user_agent_suffix: Option<&str>,
+    copy_into_cache: bool,
+    ipfs_gateway: &Url,
+    settings: &Settings,
+) -> anyhow::Result<BoxedSubstituter> {
+    let dirname = percent_encode_to_filename(url.as_str());
+    let d = cache_dir.join(dirname);
+    tokio::fs::create_dir_all(&d)
+        .await
+        .with_context(|| format!("mkdir({d:?})"))?;
+    substituter_from_url(
+        url,
+        d,
+        expiration,
+        offline,
+        user_agent_suffix,
+        copy_into_cache,
+        ipfs_gateway,
+        settings,
+    )
+    .await
 }
 
 #[cfg(test)]
@@ -218,6 +352,28 @@ mod tests {
             }
         }
 
+        async fn path_info(&self, _store_path: &StorePath) -> anyhow::Result<Option<PathInfo>> {
+            self.call_count.fetch_add(1, Ordering::SeqCst);
+            match self.answer {
+                Err(ref e) => Err(anyhow::anyhow!("MockSubstituter failed in path_info: {e}")),
+                Ok(_) => Ok(None),
+            }
+        }
+
+        async fn inspect_debug_output(
+            &self,
+            _build_id: &BuildId,
+        ) -> anyhow::Result<Option<EntryInfo>> {
+            Ok(None)
+        }
+
+        async fn inspect_store_path(
+            &self,
+            _store_path: &StorePath,
+        ) -> anyhow::Result<Option<EntryInfo>> {
+            Ok(None)
+        }
+
         fn priority(&self) -> Priority {
             self.priority
         }
@@ -227,6 +383,54 @@ mod tests {
         async fn shrink_disk_cache(&self) -> anyhow::Result<()> {
             Ok(())
         }
+
+        async fn check(&self) -> anyhow::Result<()> {
+            match self.answer {
+                Err(ref e) => Err(anyhow::anyhow!("MockSubstituter failed in check: {e}")),
+                Ok(_) => Ok(()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_exists() {
+        let build_ids = [
+            BuildId::new("b91c254ef8c76310683ce217f6269bc2f3e84d65").unwrap(),
+            BuildId::new("0e20481820d3b92468102b35a5e4a29a8695c1af").unwrap(),
+        ];
+        let batch = async |local, remote| {
+            let sub1 = Arc::new(MockSubstituter::new(local, Priority::Local));
+            let sub2 = Arc::new(MockSubstituter::new(remote, Priority::Remote));
+            let subs: [BoxedSubstituter; 2] = [Box::new(sub1.clone()), Box::new(sub2.clone())];
+            let sub = MultiplexingSubstituter::new(subs.into_iter());
+            let result: Vec<Result<Presence, String>> = sub
+                .batch_exists(&build_ids)
+                .await
+                .into_iter()
+                .map(|r| r.map_err(|e| e.to_string()))
+                .collect();
+            (result, sub1.call_count(), sub2.call_count())
+        };
+        let found = Ok(Presence::Found);
+        let not_found = Ok(Presence::NotFound);
+        let failed = Err("failed".to_owned());
+        // found build ids are not asked to the next substituter
+        assert_eq!(
+            batch(found.clone(), found.clone()).await,
+            (vec![found.clone(), found.clone()], 2, 0)
+        );
+        // a substituter failing does not prevent others from having the build id
+        assert_eq!(
+            batch(failed.clone(), found.clone()).await,
+            (vec![found.clone(), found.clone()], 2, 2)
+        );
+        // but its error is reported when none has it
+        let (result, _, _) = batch(failed.clone(), not_found.clone()).await;
+        assert!(result.iter().all(Result::is_err));
+        assert_eq!(
+            batch(not_found.clone(), not_found.clone()).await,
+            (vec![not_found.clone(), not_found.clone()], 2, 2)
+        );
     }
 
     #[tokio::test]
@@ -432,4 +636,23 @@ mod tests {
         assert_eq!(sub2.call_count(), 2);
         assert_eq!(sub1.call_count(), 2);
     }
+
+    #[tokio::test]
+    async fn check() {
+        let ok = || Box::new(MockSubstituter::new(Ok(Presence::Found), Priority::Local));
+        let subs: [BoxedSubstituter; 2] = [ok(), ok()];
+        MultiplexingSubstituter::new(subs.into_iter())
+            .check()
+            .await
+            .unwrap();
+        let subs: [BoxedSubstituter; 2] = [
+            ok(),
+            Box::new(MockSubstituter::new(Err("down".into()), Priority::Remote)),
+        ];
+        let err = MultiplexingSubstituter::new(subs.into_iter())
+            .check()
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("1 out of 2"));
+    }
 }
@@ -0,0 +1,178 @@
+//! Writing tar archives, to serve a whole source tree in one response.
+
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Tar archives are made of blocks of this size
+const BLOCK_SIZE: usize = 512;
+
+/// Largest value of the 12 bytes octal size field of a ustar header
+const MAX_USTAR_SIZE: u64 = 0o77777777777;
+
+/// Writes a tar archive, one file at a time, without buffering file contents.
+///
+/// Headers are in the ustar format, with pax extended headers for paths longer than 100 bytes
+/// and files larger than 8GiB.
+pub struct TarWriter<W> {
+    inner: W,
+}
+
+impl<W: AsyncWrite + Unpin> TarWriter<W> {
+    /// Writes the archive to `inner`
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    /// Appends a regular file named `path` to the archive, with the first `size` bytes of
+    /// `content`.
+    ///
+    /// Fails if `content` is shorter than `size`, as the archive is then corrupt.
+    pub async fn append_file(
+        &mut self,
+        path: &Path,
+        mode: u32,
+        mtime: u64,
+        size: u64,
+        content: impl AsyncRead + Unpin,
+    ) -> anyhow::Result<()> {
+        let name = path.as_os_str().as_bytes();
+        let mut records = Vec::new();
+        if name.len() > 100 {
+            records.extend(pax_record("path", name));
+        }
+        if size > MAX_USTAR_SIZE {
+            records.extend(pax_record("size", size.to_string().as_bytes()));
+        }
+        if !records.is_empty() {
+            let pax = header(b"pax_header", 0o644, mtime, records.len() as u64, b'x');
+            self.inner.write_all(&pax).await?;
+            self.inner.write_all(&records).await?;
+            self.pad(records.len() as u64).await?;
+        }
+        let header = header(name, mode, mtime, size.min(MAX_USTAR_SIZE), b'0');
+        self.inner.write_all(&header).await?;
+        let copied = tokio::io::copy(&mut content.take(size), &mut self.inner)
+            .await
+            .with_context(|| format!("copying {} into the archive", path.display()))?;
+        anyhow::ensure!(
+            copied == size,
+            "{} is {copied} bytes instead of {size}",
+            path.display()
+        );
+        self.pad(size).await?;
+        Ok(())
+    }
+
+    /// Writes the end of the archive, and returns the underlying writer.
+    pub async fn finish(mut self) -> anyhow::Result<W> {
+        self.inner.write_all(&[0; 2 * BLOCK_SIZE]).await?;
+        self.inner.flush().await?;
+        Ok(self.inner)
+    }
+
+    /// Pads content of `size` bytes to a whole number of blocks
+    async fn pad(&mut self, size: u64) -> std::io::Result<()> {
+        let rest = (size % BLOCK_SIZE as u64) as usize;
+        if rest != 0 {
+            self.inner.write_all(&[0; BLOCK_SIZE][rest..]).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A ustar header. `name` is truncated to 100 bytes, and `size` must fit in 11 octal digits.
+fn header(name: &[u8], mode: u32, mtime: u64, size: u64, kind: u8) -> [u8; BLOCK_SIZE] {
+    let mut header = [0; BLOCK_SIZE];
+    let name = &name[..name.len().min(100)];
+    header[..name.len()].copy_from_slice(name);
+    octal(&mut header[100..108], mode as u64);
+    // uid and gid
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size);
+    octal(&mut header[136..148], mtime.min(MAX_USTAR_SIZE));
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // the checksum is computed with its own field filled with spaces
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    octal(&mut header[148..155], checksum as u64);
+    header
+}
+
+/// Writes `value` in octal to `field`, zero padded and NUL terminated.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{value:0width$o}", width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+/// A pax extended header record `<length> <key>=<value>\n`, where length counts itself.
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut length = rest + 1;
+    while (rest + length.to_string().len()) != length {
+        length = rest + length.to_string().len();
+    }
+    let mut record = format!("{length} {key}=").into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+#[test]
+fn test_pax_record() {
+    assert_eq!(pax_record("path", b"a"), b"9 path=a\n");
+    let long = vec![b'a'; 95];
+    let record = pax_record("path", &long);
+    assert_eq!(record.len(), 105);
+    assert!(record.starts_with(b"105 path=aaa"));
+}
+
+#[tokio::test]
+async fn test_tar_writer() {
+    let long_name = format!("{}/main.c", "subdirectory".repeat(10));
+    let files = [
+        ("README", "hello\n".to_owned()),
+        ("src/empty.c", String::new()),
+        (long_name.as_str(), "int main() {}\n".repeat(100)),
+    ];
+    // a file shorter than announced is an error
+    TarWriter::new(Vec::new())
+        .append_file(Path::new("short"), 0o644, 1, 10, &b"short"[..])
+        .await
+        .unwrap_err();
+    let mut writer = TarWriter::new(Vec::new());
+    for (name, content) in &files {
+        writer
+            .append_file(
+                Path::new(name),
+                0o644,
+                1,
+                content.len() as u64,
+                content.as_bytes(),
+            )
+            .await
+            .unwrap();
+    }
+    let archive = writer.finish().await.unwrap();
+    assert_eq!(archive.len() % BLOCK_SIZE, 0);
+    let t = tempfile::tempdir().unwrap();
+    compress_tools::uncompress_archive(
+        std::io::Cursor::new(archive),
+        t.path(),
+        compress_tools::Ownership::Ignore,
+    )
+    .unwrap();
+    for (name, content) in &files {
+        assert_eq!(
+            &std::fs::read_to_string(t.path().join(name)).unwrap(),
+            content,
+            "{name}"
+        );
+    }
+}
@@ -1,7 +1,6 @@
 //! Functions used in tests only
 
 use reqwest::Url;
-use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::sync::{LazyLock, Once};
 use tracing::Level;
@@ -11,27 +10,11 @@ use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::Layer;
 
-use crate::vfs::AsFile;
+use crate::vfs::{content_sha256, AsFile};
 
 /// Returns the sha256sum of this file in a lowecase hex string
-pub async fn file_sha256<F: AsFile>(file: F) -> String {
-    let mut std_file = file.open().await.unwrap().into_std().await;
-    let mut buf = [0; 4096];
-    let mut hash = hmac_sha256::Hash::new();
-    loop {
-        let n = std_file.read(&mut buf).unwrap();
-        if n == 0 {
-            break;
-        } else {
-            hash.update(&buf[..n]);
-        }
-    }
-    let digest = hash.finalize();
-    let mut result = String::new();
-    for &byte in digest.iter() {
-        result.push_str(&format!("{:0>2x}", byte))
-    }
-    result
+pub async fn file_sha256<F: AsFile + Sync>(file: F) -> String {
+    content_sha256(&file).await.unwrap()
 }
 
 static SETUP_LOGGING: Once = Once::new();
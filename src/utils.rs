@@ -1,9 +1,11 @@
 //! Misc utils
-use std::path::Path;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use std::{fmt::Debug, time::Duration};
 
 use anyhow::Context;
-use async_compression::tokio::bufread::{XzDecoder, ZstdDecoder};
+use async_compression::tokio::bufread::{GzipDecoder, XzDecoder, ZstdDecoder};
+use async_compression::zstd::DParameter;
 use nix::fcntl::AT_FDCWD;
 use nix::sys::time::TimeSpec;
 use pin_project::pin_project;
@@ -90,7 +92,16 @@ pub async fn remove_recursively_if_exists(path: &Path) -> std::io::Result<()> {
     };
     tracing::trace!(?path, "removing");
     let result = if meta?.is_dir() {
-        tokio::fs::remove_dir_all(path).await
+        match tokio::fs::remove_dir_all(path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                tracing::debug!(?path, "remove_dir_all failed, retrying entry by entry: {e}");
+                let path = path.to_owned();
+                tokio::task::spawn_blocking(move || remove_dir_all_forcibly(&path))
+                    .await
+                    .map_err(std::io::Error::other)?
+            }
+            other => other,
+        }
     } else {
         tokio::fs::remove_file(path).await
     };
@@ -100,6 +111,88 @@ pub async fn remove_recursively_if_exists(path: &Path) -> std::io::Result<()> {
     }
 }
 
+/// Removes the directory `path` and its content, making directories writable when needed, and
+/// going on after failures.
+///
+/// Fails with an error listing the paths which could not be removed.
+fn remove_dir_all_forcibly(path: &Path) -> std::io::Result<()> {
+    let mut failures = Vec::new();
+    remove_tree(path, &mut failures);
+    if failures.is_empty() {
+        return Ok(());
+    }
+    const SHOWN: usize = 10;
+    let mut message = format!("failed to remove {} paths:", failures.len());
+    for (path, e) in failures.iter().take(SHOWN) {
+        message.push_str(&format!(" {path:?} ({e})"));
+    }
+    if failures.len() > SHOWN {
+        message.push_str(" ...");
+    }
+    Err(std::io::Error::other(message))
+}
+
+/// Implementation of [remove_dir_all_forcibly], recording failures in `failures`.
+fn remove_tree(path: &Path, failures: &mut Vec<(PathBuf, std::io::Error)>) {
+    fn record(failures: &mut Vec<(PathBuf, std::io::Error)>, path: &Path, e: std::io::Error) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            failures.push((path.to_owned(), e));
+        }
+    }
+    // listing and removing entries requires read, write and execute permission
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.permissions().mode() & 0o700 != 0o700 => {
+            let mode = meta.permissions().mode() | 0o700;
+            if let Err(e) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)) {
+                record(failures, path, e);
+            }
+        }
+        Ok(_) => (),
+        Err(e) => return record(failures, path, e),
+    }
+    match std::fs::read_dir(path) {
+        Ok(entries) => {
+            for entry in entries {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        record(failures, path, e);
+                        continue;
+                    }
+                };
+                let child = entry.path();
+                match entry.file_type() {
+                    Ok(file_type) if file_type.is_dir() => remove_tree(&child, failures),
+                    _ => {
+                        if let Err(e) = std::fs::remove_file(&child) {
+                            record(failures, &child, e);
+                        }
+                    }
+                }
+            }
+        }
+        Err(e) => record(failures, path, e),
+    }
+    if let Err(e) = std::fs::remove_dir(path) {
+        record(failures, path, e);
+    }
+}
+
+#[tokio::test]
+async fn test_remove_recursively_if_exists_read_only_dir() {
+    let t = tempfile::tempdir().unwrap();
+    let dir = t.path().join("test");
+    // like a nar unpacked with the permissions of the store
+    std::fs::create_dir_all(dir.join("lib/debug")).unwrap();
+    std::fs::write(dir.join("lib/debug/file"), "hello").unwrap();
+    for read_only in ["lib/debug/file", "lib/debug", "lib"] {
+        std::fs::set_permissions(dir.join(read_only), std::fs::Permissions::from_mode(0o555))
+            .unwrap();
+    }
+    remove_recursively_if_exists(&dir).await.unwrap();
+    assert!(!dir.exists());
+}
+
 #[tokio::test]
 async fn test_remove_recursively_if_exists_nonempty_dir() {
     let t = tempfile::tempdir().unwrap();
@@ -138,6 +231,48 @@ async fn test_remove_recursively_if_exists_symlink() {
     assert!(!symlink.exists());
 }
 
+/// Copies the file, directory or symlink `from` to `to`, which must not exist.
+///
+/// Symlinks are copied as symlinks. Directories are created writable even if the original is
+/// not, so that the copy can be removed later.
+pub fn copy_recursively(from: &Path, to: &Path) -> std::io::Result<()> {
+    let meta = std::fs::symlink_metadata(from)?;
+    if meta.is_symlink() {
+        std::os::unix::fs::symlink(std::fs::read_link(from)?, to)
+    } else if meta.is_dir() {
+        std::fs::create_dir(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            copy_recursively(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        Ok(())
+    } else {
+        std::fs::copy(from, to).map(|_| ())
+    }
+}
+
+#[test]
+fn test_copy_recursively() {
+    let t = tempfile::tempdir().unwrap();
+    let from = t.path().join("from");
+    std::fs::create_dir_all(from.join("dir")).unwrap();
+    std::fs::write(from.join("dir/file"), "hello").unwrap();
+    std::os::unix::fs::symlink("dir/file", from.join("symlink")).unwrap();
+    std::fs::set_permissions(from.join("dir"), std::fs::Permissions::from_mode(0o555)).unwrap();
+    let to = t.path().join("to");
+    copy_recursively(&from, &to).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(to.join("dir/file")).unwrap(),
+        "hello"
+    );
+    assert_eq!(
+        std::fs::read_link(to.join("symlink")).unwrap(),
+        Path::new("dir/file")
+    );
+    std::fs::remove_dir_all(&to).unwrap();
+    std::fs::set_permissions(from.join("dir"), std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
 /// Removes elements older than `expiration` in this cache directory.
 ///
 /// Does not remove the directory itself, which must exist.
@@ -265,8 +400,184 @@ pub fn percent_encode_to_filename(s: &str) -> String {
     percent_encoding::utf8_percent_encode(s, &CONTROLS_AND_SLASH_AND_PERCENT).to_string()
 }
 
+/// Parses a size in bytes like `4096`, `512K`, `512KiB`, `10 MiB` or `4G`.
+///
+/// Suffixes are powers of 1024.
+pub fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("no number in size {s:?}"))?;
+    let shift = match unit
+        .trim_start()
+        .trim_end_matches("iB")
+        .trim_end_matches('B')
+    {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        other => anyhow::bail!("unknown unit {other:?} in size {s:?}"),
+    };
+    number
+        .checked_mul(1 << shift)
+        .with_context(|| format!("size {s:?} is too large"))
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("4096").unwrap(), 4096);
+    assert_eq!(parse_size("512K").unwrap(), 512 * 1024);
+    assert_eq!(parse_size("10 MiB").unwrap(), 10 * 1024 * 1024);
+    assert_eq!(parse_size("4G").unwrap(), 4 << 30);
+    assert_eq!(parse_size("1B").unwrap(), 1);
+    parse_size("G").unwrap_err();
+    parse_size("4 parsecs").unwrap_err();
+    parse_size("100000000T").unwrap_err();
+}
+
+/// Log2 of the largest window accepted when decoding zstd.
+///
+/// The default of the zstd library is 27 (128MiB); streams compressed with `zstd --long=31` need
+/// up to 2GiB. The window is only allocated as large as the stream declares.
+const ZSTD_WINDOW_LOG_MAX: u32 = 31;
+
+/// A compression format that [DecompressingReader] can decompress
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// `.gz`
+    Gzip,
+    /// `.xz`
+    Xz,
+    /// `.zst`
+    Zstd,
+}
+
+impl Compression {
+    /// The name of this format, as accepted by [CompressionSet::parse]
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+        }
+    }
+}
+
+/// A set of compression formats, possibly including the absence of compression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionSet {
+    none: bool,
+    gzip: bool,
+    xz: bool,
+    zstd: bool,
+}
+
+impl CompressionSet {
+    /// All formats [DecompressingReader] supports, and no compression
+    pub const ALL: Self = Self {
+        none: true,
+        gzip: true,
+        xz: true,
+        zstd: true,
+    };
+
+    /// Parses a comma separated list of formats among `none`, `gzip`, `xz` and `zstd`.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut result = Self {
+            none: false,
+            gzip: false,
+            xz: false,
+            zstd: false,
+        };
+        for name in s.split(',').map(str::trim) {
+            let allowed = match name {
+                "none" => &mut result.none,
+                "gzip" => &mut result.gzip,
+                "xz" => &mut result.xz,
+                "zstd" => &mut result.zstd,
+                other => {
+                    anyhow::bail!("unknown compression {other:?}, expected none, gzip, xz or zstd")
+                }
+            };
+            *allowed = true;
+        }
+        Ok(result)
+    }
+
+    /// Whether `compression`, or no compression if None, is in this set
+    pub fn contains(self, compression: Option<Compression>) -> bool {
+        match compression {
+            None => self.none,
+            Some(Compression::Gzip) => self.gzip,
+            Some(Compression::Xz) => self.xz,
+            Some(Compression::Zstd) => self.zstd,
+        }
+    }
+}
+
+#[test]
+fn test_compression_set() {
+    let set = CompressionSet::parse("zstd, none").unwrap();
+    assert!(set.contains(None));
+    assert!(set.contains(Some(Compression::Zstd)));
+    assert!(!set.contains(Some(Compression::Xz)));
+    assert!(!set.contains(Some(Compression::Gzip)));
+    CompressionSet::parse("zstd,lzip").unwrap_err();
+    CompressionSet::parse("").unwrap_err();
+}
+
+/// The compression of the nar at `path_or_url`, guessed from its extension.
+///
+/// Fails if the extension is unknown, or if the compression is not in `allowed`.
+fn nar_compression(
+    path_or_url: &[u8],
+    allowed: CompressionSet,
+) -> anyhow::Result<Option<Compression>> {
+    let compression = if path_or_url.ends_with(b".nar") {
+        None
+    } else if path_or_url.ends_with(b".nar.xz") {
+        Some(Compression::Xz)
+    } else if path_or_url.ends_with(b".nar.zst") || path_or_url.ends_with(b".nar.zstd") {
+        Some(Compression::Zstd)
+    } else {
+        anyhow::bail!(
+            "don't support compression for extension of {}",
+            &String::from_utf8_lossy(path_or_url)
+        );
+    };
+    anyhow::ensure!(
+        allowed.contains(compression),
+        "compression {} of {} is not allowed by --allowed-compression",
+        compression.map_or("none", Compression::name),
+        String::from_utf8_lossy(path_or_url),
+    );
+    Ok(compression)
+}
+
+#[test]
+fn test_nar_compression() {
+    let zstd_only = CompressionSet::parse("zstd").unwrap();
+    assert_eq!(
+        nar_compression(b"nar/foo.nar.zst", zstd_only).unwrap(),
+        Some(Compression::Zstd)
+    );
+    // the extension is supported, but not allowed
+    nar_compression(b"nar/foo.nar.xz", zstd_only).unwrap_err();
+    nar_compression(b"nar/foo.nar", zstd_only).unwrap_err();
+    assert_eq!(
+        nar_compression(b"nar/foo.nar.xz", CompressionSet::ALL).unwrap(),
+        Some(Compression::Xz)
+    );
+    nar_compression(b"nar/foo.nar.bz2", CompressionSet::ALL).unwrap_err();
+}
+
 #[pin_project(project = DecompressingReaderInnerProjected)]
 enum DecompressingReaderInner<R: AsyncBufRead> {
+    Gzip(#[pin] GzipDecoder<R>),
     XZ(#[pin] XzDecoder<R>),
     Zstd(#[pin] ZstdDecoder<R>),
     NoCompression(#[pin] R),
@@ -285,21 +596,40 @@ impl<R: AsyncBufRead> DecompressingReader<R> {
     /// Reading from the [`DecompressingReader`] will yield the decompressed bytes.
     ///
     /// The format of the compression is guessed from the extension of `path_or_url`.
-    pub fn new(reader: R, path_or_url: &[u8]) -> anyhow::Result<Self> {
-        let reader = if path_or_url.ends_with(b".nar") {
-            DecompressingReaderInner::NoCompression(reader)
-        } else if path_or_url.ends_with(b".nar.xz") {
-            DecompressingReaderInner::XZ(XzDecoder::new(reader))
-        } else if path_or_url.ends_with(b".nar.zst") || path_or_url.ends_with(b".nar.zstd") {
-            DecompressingReaderInner::Zstd(ZstdDecoder::new(reader))
-        } else {
-            anyhow::bail!(
-                "don't support compression for extension of {}",
-                &String::from_utf8_lossy(path_or_url)
-            );
+    ///
+    /// Zstd streams compressed with long distance matching (`zstd --long`) are supported, and so
+    /// are xz files made of several concatenated streams.
+    ///
+    /// Fails if the compression is not in `allowed`.
+    pub fn new(reader: R, path_or_url: &[u8], allowed: CompressionSet) -> anyhow::Result<Self> {
+        let compression = nar_compression(path_or_url, allowed)?;
+        Ok(Self::with_compression(reader, compression, path_or_url))
+    }
+
+    /// Wraps an [`AsyncBufRead`] compressed with `compression`, or not compressed if None.
+    ///
+    /// `name` is only used for debugging.
+    pub fn with_compression(reader: R, compression: Option<Compression>, name: &[u8]) -> Self {
+        let reader = match compression {
+            None => DecompressingReaderInner::NoCompression(reader),
+            Some(Compression::Gzip) => {
+                let mut decoder = GzipDecoder::new(reader);
+                decoder.multiple_members(true);
+                DecompressingReaderInner::Gzip(decoder)
+            }
+            Some(Compression::Xz) => {
+                let mut decoder = XzDecoder::new(reader);
+                // parallel compressors like pixz concatenate several streams
+                decoder.multiple_members(true);
+                DecompressingReaderInner::XZ(decoder)
+            }
+            Some(Compression::Zstd) => DecompressingReaderInner::Zstd(ZstdDecoder::with_params(
+                reader,
+                &[DParameter::window_log_max(ZSTD_WINDOW_LOG_MAX)],
+            )),
         };
-        let name = path_or_url.to_owned();
-        Ok(DecompressingReader { name, reader })
+        let name = name.to_owned();
+        DecompressingReader { name, reader }
     }
 }
 
@@ -320,9 +650,52 @@ impl<R: AsyncBufRead> AsyncRead for DecompressingReader<R> {
         let inner = self.project();
         let inner2 = inner.reader.project();
         match inner2 {
+            DecompressingReaderInnerProjected::Gzip(reader) => reader.poll_read(cx, buf),
             DecompressingReaderInnerProjected::XZ(reader) => reader.poll_read(cx, buf),
             DecompressingReaderInnerProjected::Zstd(reader) => reader.poll_read(cx, buf),
             DecompressingReaderInnerProjected::NoCompression(reader) => reader.poll_read(cx, buf),
         }
     }
 }
+
+#[tokio::test]
+async fn test_decompress_zstd_long_window() {
+    use crate::test_utils::fixture;
+    use tokio::io::AsyncReadExt;
+    // compressed with `zstd --long=31` from stdin, so that the frame declares a 2GiB window
+    let compressed = tokio::fs::read(fixture("long-window.nar.zst"))
+        .await
+        .unwrap();
+    let expected = tokio::fs::read(fixture(
+        "file_binary_cache/nar/0v50llkb6v8s5nxwl0vkkj6h4cqb365vzx023j8lgxp3pi8bivi4.nar",
+    ))
+    .await
+    .unwrap();
+    let mut reader =
+        DecompressingReader::new(&compressed[..], b"long-window.nar.zst", CompressionSet::ALL)
+            .unwrap();
+    let mut decompressed = Vec::new();
+    reader.read_to_end(&mut decompressed).await.unwrap();
+    assert_eq!(decompressed, expected);
+}
+
+#[tokio::test]
+async fn test_decompress_xz_multistream() {
+    use crate::test_utils::fixture;
+    use tokio::io::AsyncReadExt;
+    // two xz streams, each containing half of the nar
+    let compressed = tokio::fs::read(fixture("multistream.nar.xz"))
+        .await
+        .unwrap();
+    let expected = tokio::fs::read(fixture(
+        "file_binary_cache/nar/0v50llkb6v8s5nxwl0vkkj6h4cqb365vzx023j8lgxp3pi8bivi4.nar",
+    ))
+    .await
+    .unwrap();
+    let mut reader =
+        DecompressingReader::new(&compressed[..], b"multistream.nar.xz", CompressionSet::ALL)
+            .unwrap();
+    let mut decompressed = Vec::new();
+    reader.read_to_end(&mut decompressed).await.unwrap();
+    assert_eq!(decompressed, expected);
+}
@@ -1,6 +1,8 @@
 //! Manipulation of paths with untrusted symlinks
 
 use std::fmt::Debug;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::Arc;
 use std::{
     future::Future,
     path::{Component, Path, PathBuf},
@@ -11,8 +13,9 @@ use tracing::Instrument;
 use tracing::Level;
 
 use crate::{
-    cache::CachedPathLock,
+    cache::{is_compressible, CachedPathLock, DecompressedCopies, COMPRESSED_SUFFIX},
     store_path::{StorePath, NIX_STORE},
+    utils::{Compression, DecompressingReader},
 };
 
 /// A path with untrusted symlinks.
@@ -35,6 +38,11 @@ pub struct RestrictedPath {
     inner: PathBuf,
     /// keep the cached path from being gc-ed. None if there is no risk of gc
     lock: Option<CachedPathLock>,
+    /// the store path whose content is at `root`, if known
+    store_path: Option<StorePath>,
+    /// Some when debug files below `root` may be stored compressed, see
+    /// [crate::cache::CacheSettings::compress]
+    decompressed_copies: Option<Arc<DecompressedCopies>>,
 }
 
 impl Debug for RestrictedPath {
@@ -58,6 +66,14 @@ pub struct ResolvedPath {
     path: PathBuf,
     /// keep the cached path from being gc-ed. None if there is no risk of gc
     lock: Option<CachedPathLock>,
+    /// whether `path` is the zstd compressed version of the requested file, see
+    /// [crate::cache::CacheSettings::compress]
+    compressed: bool,
+    /// the store path whose content is at `path`, if known
+    store_path: Option<StorePath>,
+    /// Some when debug files at or below `path` may be stored compressed, see
+    /// [crate::cache::CacheSettings::compress]
+    decompressed_copies: Option<Arc<DecompressedCopies>>,
 }
 
 impl Debug for ResolvedPath {
@@ -98,6 +114,47 @@ impl ResolvedPath {
         }
     }
 
+    /// Returns the last component of the path, for example to guess the type of a file from its
+    /// extension.
+    pub fn file_name(&self) -> Option<&std::ffi::OsStr> {
+        if self.compressed {
+            self.path.file_stem()
+        } else {
+            self.path.file_name()
+        }
+    }
+
+    /// Returns the store path this file or directory was found in, like
+    /// `/nix/store/...-source/src/main.c`.
+    ///
+    /// None when it is not known to come from a store path, for example when it was unpacked
+    /// from an archive.
+    pub fn store_path(&self) -> Option<&StorePath> {
+        self.store_path.as_ref()
+    }
+
+    /// Returns the names of the direct children of this directory
+    pub async fn list_directory(&self) -> anyhow::Result<Vec<std::ffi::OsString>> {
+        let mut entries = tokio::fs::read_dir(&self.path)
+            .await
+            .with_context(|| format!("opendir({self:?})"))?;
+        let mut result = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .with_context(|| format!("readdir({self:?})"))?
+        {
+            result.push(entry.file_name());
+        }
+        Ok(result)
+    }
+
+    /// Returns the sha256 of the content of this file as a lowercase hex string, for example to
+    /// check that the expected bytes were fetched.
+    pub async fn sha256(&self) -> anyhow::Result<String> {
+        content_sha256(self).await
+    }
+
     /// Appends a relative path to this path to access a transitive child file.
     ///
     /// Makes only sense if self is a directory.
@@ -106,7 +163,13 @@ impl ResolvedPath {
     ///
     /// Not expected to error in practice.
     pub async fn join(self, rest: impl AsRef<Path>) -> anyhow::Result<RestrictedPath> {
-        Ok(RestrictedPath::new(self.path, self.lock).await?.join(rest))
+        let root = RestrictedPath::new(self.path, self.lock).await?;
+        Ok(RestrictedPath {
+            store_path: self.store_path,
+            decompressed_copies: self.decompressed_copies,
+            ..root
+        }
+        .join(rest))
     }
 }
 
@@ -117,12 +180,62 @@ pub trait AsFile {
     ///
     /// without revealing the path
     async fn open(&self) -> std::io::Result<tokio::fs::File>;
+
+    /// For files stored compressed, returns their decompressed size and a stream of their
+    /// decompressed content, which is cheaper than [AsFile::open] when the file is only read
+    /// once from start to end.
+    ///
+    /// Returns None for files stored as they are.
+    async fn open_decompressing(&self) -> std::io::Result<Option<(u64, DecompressedStream)>> {
+        Ok(None)
+    }
 }
 
+/// The content of a file stored compressed, see [AsFile::open_decompressing]
+pub type DecompressedStream = std::pin::Pin<Box<dyn tokio::io::AsyncRead + Send>>;
+
+/// Upper bound of the size of the header of a zstd frame
+pub(crate) const ZSTD_MAX_HEADER_SIZE: u64 = 18;
+
 #[async_trait::async_trait]
 impl AsFile for ResolvedPath {
+    /// Files stored compressed are opened from their [DecompressedCopies], so that they can be
+    /// read at random offsets like other files. Reading a file from start to end is cheaper with
+    /// [AsFile::open_decompressing], which does not write a copy.
     async fn open(&self) -> std::io::Result<tokio::fs::File> {
-        tokio::fs::File::open(&self.path).await
+        match self.decompressed_copies {
+            Some(ref copies) if self.compressed => copies.open(&self.path).await,
+            _ => tokio::fs::File::open(&self.path).await,
+        }
+    }
+
+    async fn open_decompressing(&self) -> std::io::Result<Option<(u64, DecompressedStream)>> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+        if !self.compressed {
+            return Ok(None);
+        }
+        let mut file = tokio::fs::File::open(&self.path).await?;
+        let mut header = Vec::new();
+        (&mut file)
+            .take(ZSTD_MAX_HEADER_SIZE)
+            .read_to_end(&mut header)
+            .await?;
+        let size = zstd::zstd_safe::get_frame_content_size(&header)
+            .ok()
+            .flatten()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{self:?} does not record its decompressed size"),
+                )
+            })?;
+        file.rewind().await?;
+        let reader = DecompressingReader::with_compression(
+            tokio::io::BufReader::new(file),
+            Some(Compression::Zstd),
+            self.path.as_os_str().as_bytes(),
+        );
+        Ok(Some((size, Box::pin(reader))))
     }
 }
 
@@ -133,6 +246,37 @@ impl<T: AsRef<Path> + Sync> AsFile for T {
     }
 }
 
+/// Returns the sha256 of the content of `file` as a lowercase hex string.
+///
+/// The file is hashed as it is read, without loading it in memory. Files stored compressed are
+/// hashed as they are decompressed.
+pub async fn content_sha256<F: AsFile + Sync>(file: &F) -> anyhow::Result<String> {
+    use tokio::io::AsyncReadExt as _;
+
+    let mut file: DecompressedStream = match file
+        .open_decompressing()
+        .await
+        .context("opening file to hash")?
+    {
+        Some((_, stream)) => stream,
+        None => Box::pin(file.open().await.context("opening file to hash")?),
+    };
+    let mut buf = vec![0; 64 * 1024];
+    let mut hash = hmac_sha256::Hash::new();
+    loop {
+        let n = file.read(&mut buf).await.context("reading file to hash")?;
+        if n == 0 {
+            break;
+        }
+        hash.update(&buf[..n]);
+    }
+    Ok(hash
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
 /// One can iterate the files in a directory.
 pub trait WalkableDirectory: Sized + Debug {
     /// Returns an iterator of the relative paths of all files contained in this directory
@@ -172,8 +316,119 @@ impl WalkableDirectory for ResolvedPath {
     }
 }
 
+impl ResolvedPath {
+    /// Returns the symlinks to store paths contained in this directory, relative to it.
+    ///
+    /// Symlinks are not followed.
+    pub async fn store_symlinks(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut result = Vec::new();
+            for entry in walkdir::WalkDir::new(&path)
+                .follow_links(false)
+                .follow_root_links(false)
+            {
+                let entry = entry.with_context(|| format!("walking {}", path.display()))?;
+                if !entry.path_is_symlink() {
+                    continue;
+                }
+                let target = std::fs::read_link(entry.path())
+                    .with_context(|| format!("readlink({})", entry.path().display()))?;
+                if target.starts_with(NIX_STORE) {
+                    result.push(entry.path().strip_prefix(&path)?.to_path_buf());
+                }
+            }
+            Ok(result)
+        })
+        .await?
+    }
+}
+
+/// A directory and the other store paths that symlinks inside it point to, walked as if these
+/// symlinks were followed.
+#[derive(Clone, Debug)]
+pub struct LinkedDirectory {
+    root: ResolvedPath,
+    /// location of each symlink relative to `root`, and its target
+    links: Vec<(PathBuf, ResolvedPath)>,
+}
+
+impl LinkedDirectory {
+    /// A directory whose symlinks are not followed yet
+    pub fn new(root: ResolvedPath) -> Self {
+        Self {
+            root,
+            links: Vec::new(),
+        }
+    }
+
+    /// Walks `target` as if it was at `location`, relative to the root.
+    pub fn add_link(&mut self, location: PathBuf, target: ResolvedPath) {
+        self.links.push((location, target));
+    }
+
+    /// How many symlinks were added with [Self::add_link]
+    pub fn link_count(&self) -> usize {
+        self.links.len()
+    }
+}
+
+impl WalkableDirectory for LinkedDirectory {
+    fn list_files_recursively(&self) -> impl Iterator<Item = anyhow::Result<PathBuf>> {
+        let links = self.links.iter().flat_map(|(location, target)| {
+            target.list_files_recursively().map(move |file| {
+                // a symlink to a file is listed as the file itself
+                file.map(|file| match file.as_os_str().is_empty() {
+                    true => location.clone(),
+                    false => location.join(file),
+                })
+            })
+        });
+        self.root.list_files_recursively().chain(links)
+    }
+}
+
 const MAX_SYMLINK_DEPTH: u32 = 20;
 
+/// When the file `missing` does not exist, returns its compressed version written by
+/// [crate::cache::compress_debug_files] if there is one.
+///
+/// `last` tells whether `missing` is the path being resolved, rather than one of its parent
+/// directories. Only files of cache entries written compressed, with `decompressed_copies`,
+/// have a compressed version.
+async fn compressed_version(
+    missing: PathBuf,
+    lock: Option<CachedPathLock>,
+    decompressed_copies: Option<Arc<DecompressedCopies>>,
+    last: bool,
+) -> Option<ResolvedPath> {
+    if !last || decompressed_copies.is_none() || !is_compressible(&missing) {
+        return None;
+    }
+    let mut path = missing.into_os_string();
+    path.push(COMPRESSED_SUFFIX);
+    let path = PathBuf::from(path);
+    // we created it, so it is not a symlink
+    let metadata = tokio::fs::symlink_metadata(&path).await.ok()?;
+    metadata.is_file().then_some(ResolvedPath {
+        path,
+        lock,
+        compressed: true,
+        store_path: None,
+        decompressed_copies,
+    })
+}
+
+/// Returns the store path of `path`, knowing that `root` is the content of `store_path`.
+fn store_path_inside(store_path: &StorePath, root: &Path, path: &Path) -> Option<StorePath> {
+    let relative = path.strip_prefix(root).ok()?;
+    if relative == Path::new("") {
+        // do not add a trailing slash
+        return Some(store_path.clone());
+    }
+    StorePath::new(&store_path.as_ref().join(relative)).ok()
+}
+
 impl RestrictedPath {
     /// Creates a `RestrictedPath` with itself as root
     ///
@@ -199,9 +454,30 @@ impl RestrictedPath {
             inner: root.clone(),
             root,
             lock,
+            store_path: None,
+            decompressed_copies: None,
         })
     }
 
+    /// Records that the root of this path is the content of `store_path`, so that
+    /// [ResolvedPath::store_path] is known after resolution.
+    pub fn with_store_path(self, store_path: StorePath) -> Self {
+        Self {
+            store_path: Some(store_path),
+            ..self
+        }
+    }
+
+    /// Records that debug files below the root of this path may be stored compressed, and are
+    /// decompressed to `copies` to be read at random offsets, see
+    /// [crate::cache::CacheSettings::compress].
+    pub fn with_decompressed_copies(self, copies: Arc<DecompressedCopies>) -> Self {
+        Self {
+            decompressed_copies: Some(copies),
+            ..self
+        }
+    }
+
     /// Like `[Path.join]`
     ///
     /// Keeps the same root
@@ -235,6 +511,8 @@ impl RestrictedPath {
     ) -> anyhow::Result<Option<ResolvedPath>> {
         // can change when the symlink resolves to a different store path
         let mut current_root = &self.root;
+        // the store path whose content is at current_root
+        let mut current_store_path = self.store_path.clone();
         // absolute path of a potential symlink inside current_root
         let mut to_be_resolved = self.inner.clone();
         // how many symlinks we have resolved until now
@@ -242,7 +520,7 @@ impl RestrictedPath {
         // if we resolve a symlink to a different store path, we will start
         // exploring a different restricted path. This variable contains Some
         // of this restricted path in this case
-        let mut current_restricted_path = None;
+        let mut current_restricted_path: Option<RestrictedPath> = None;
         'symlinks: loop {
             anyhow::ensure!(
                 depth <= MAX_SYMLINK_DEPTH,
@@ -292,7 +570,16 @@ impl RestrictedPath {
                 match tokio::fs::read_link(&resolved_path).await {
                     Err(e) => {
                         match e.kind() {
-                            std::io::ErrorKind::NotFound => return Ok(None),
+                            std::io::ErrorKind::NotFound => {
+                                let (lock, copies) = match current_restricted_path {
+                                    Some(x) => (x.lock, x.decompressed_copies),
+                                    None => (self.lock, self.decompressed_copies),
+                                };
+                                let last = remaining_components.next().is_none();
+                                return Ok(
+                                    compressed_version(resolved_path, lock, copies, last).await
+                                );
+                            }
                             // not a symlink
                             std::io::ErrorKind::InvalidInput => (),
                             _ => {
@@ -332,6 +619,7 @@ impl RestrictedPath {
                                 Ok(Some(x)) => x,
                             };
                             to_be_resolved = fetched_store_path.root.join(store_path.relative());
+                            current_store_path = Some(store_path.root());
                             current_restricted_path = Some(fetched_store_path);
                             current_root = &current_restricted_path.as_ref().unwrap().root;
                         }
@@ -340,16 +628,36 @@ impl RestrictedPath {
                 }
             }
             // we iterated on all components, so the target is now resolved_path
+            let store_path = current_store_path
+                .as_ref()
+                .and_then(|store_path| store_path_inside(store_path, current_root, &resolved_path));
+            let (lock, decompressed_copies) = match current_restricted_path {
+                Some(x) => (x.lock, x.decompressed_copies),
+                None => (self.lock, self.decompressed_copies),
+            };
             return Ok(Some(ResolvedPath {
+                store_path,
                 path: resolved_path,
-                lock: match current_restricted_path {
-                    Some(x) => x.lock,
-                    None => self.lock,
-                },
+                lock,
+                compressed: false,
+                decompressed_copies,
             }));
         }
     }
 
+    /// Returns the store path this path points to through symlinks, without fetching it.
+    ///
+    /// Returns None if the path does not lead to another store path.
+    pub async fn store_path_target(self) -> anyhow::Result<Option<StorePath>> {
+        let target = std::sync::Mutex::new(None);
+        self.resolve(|store_path| {
+            *target.lock().unwrap() = Some(store_path);
+            async { Ok(None) }
+        })
+        .await?;
+        Ok(target.into_inner().unwrap())
+    }
+
     /// Like `[RestrictedPath::resolve]` except that symlinks to the store result in an error
     pub async fn resolve_inside_root(self) -> anyhow::Result<Option<ResolvedPath>> {
         self.resolve(|path| async move {
@@ -397,6 +705,25 @@ mod test {
         assert_eq!(&buf, contents);
     }
 
+    #[tokio::test]
+    async fn test_sha256() {
+        let d = make_test_dir(vec!["abc"], vec![("link", "abc")]);
+        let root = RestrictedPath::new(d.path().to_path_buf(), None)
+            .await
+            .unwrap();
+        let resolved = root
+            .join("link")
+            .resolve_inside_root()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            resolved.sha256().await.unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        content_sha256(&d.path().join("missing")).await.unwrap_err();
+    }
+
     #[tokio::test]
     async fn test_resolve_dotdot_no_symlink() {
         let d = make_test_dir(vec!["a/b/c/d", "e"], vec![]);
@@ -663,6 +990,45 @@ mod test {
         };
         let resolved = subject.resolve(resolver).await.unwrap().unwrap();
         assert_contains(dbg!(&resolved), "bin/sl").await;
+        assert_eq!(
+            resolved.store_path().unwrap().as_ref().as_os_str(),
+            "/nix/store/hawy0gnlpv0j6h8a3szfgxfjvn84890h-sl-5.05/bin/sl"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_with_store_path() {
+        let d = make_test_dir(vec!["bin/sl"], vec![]);
+        let store_path = StorePath::new(Path::new(
+            "/nix/store/hawy0gnlpv0j6h8a3szfgxfjvn84890h-sl-5.05",
+        ))
+        .unwrap();
+        let root = RestrictedPath::new(d.path().to_path_buf(), None)
+            .await
+            .unwrap();
+        let unknown = root
+            .clone()
+            .join("bin")
+            .resolve_inside_root()
+            .await
+            .unwrap();
+        assert!(unknown.unwrap().store_path().is_none());
+        let root = root.with_store_path(store_path.clone());
+        let resolved = root.clone().resolve_inside_root().await.unwrap().unwrap();
+        assert_eq!(resolved.store_path(), Some(&store_path));
+        // kept when joining
+        let bin = root
+            .join("bin")
+            .resolve_inside_root()
+            .await
+            .unwrap()
+            .unwrap();
+        let sl = bin.join("sl").await.unwrap();
+        let resolved = sl.resolve_inside_root().await.unwrap().unwrap();
+        assert_eq!(
+            resolved.store_path().unwrap().as_ref().as_os_str(),
+            "/nix/store/hawy0gnlpv0j6h8a3szfgxfjvn84890h-sl-5.05/bin/sl"
+        );
     }
 
     #[tokio::test]
@@ -0,0 +1,66 @@
+//! integration tests for the `check-cache` subcommand
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use assert_cmd::assert::OutputAssertExt;
+use assert_cmd::cargo_bin;
+
+fn check_cache_command(cache: &Path, binary_cache: &Path) -> Command {
+    let mut command = Command::new(cargo_bin!("nixseparatedebuginfod2"));
+    command
+        .env("RUST_LOG", "nixseparatedebuginfod2=trace")
+        .arg("--cache-dir")
+        .arg(cache)
+        .arg("--expiration")
+        .arg("1h")
+        .arg("check-cache")
+        .arg(format!("file://{}", binary_cache.to_str().unwrap()));
+    command
+}
+
+#[test]
+fn check_cache_fixture() {
+    let cache = tempfile::tempdir().unwrap();
+    let fixture =
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/file_binary_cache");
+    let result = check_cache_command(cache.path(), &fixture)
+        .arg("--build-id")
+        .arg("b87e34547e94f167f4b737f3a25955477a485cc7")
+        .assert()
+        .success();
+    let stdout = String::from_utf8_lossy(&result.get_output().stdout).into_owned();
+    assert!(
+        dbg!(&stdout).contains("b87e34547e94f167f4b737f3a25955477a485cc7: debuginfo ok"),
+        "{stdout}"
+    );
+}
+
+#[test]
+fn check_cache_without_index() {
+    let cache = tempfile::tempdir().unwrap();
+    let binary_cache = tempfile::tempdir().unwrap();
+    let result = check_cache_command(cache.path(), binary_cache.path())
+        .assert()
+        .failure();
+    let stdout = String::from_utf8_lossy(&result.get_output().stdout).into_owned();
+    assert!(dbg!(&stdout).contains("index-debug-info"), "{stdout}");
+}
+
+#[test]
+fn check_cache_invalid_redirect() {
+    let cache = tempfile::tempdir().unwrap();
+    let binary_cache = tempfile::tempdir().unwrap();
+    let debuginfo = binary_cache.path().join("debuginfo");
+    std::fs::create_dir(&debuginfo).unwrap();
+    std::fs::write(
+        debuginfo.join("0e20481820d3b92468102b35a5e4a29a8695c1af.debug"),
+        "not json",
+    )
+    .unwrap();
+    let result = check_cache_command(cache.path(), binary_cache.path())
+        .assert()
+        .failure();
+    let stdout = String::from_utf8_lossy(&result.get_output().stdout).into_owned();
+    assert!(dbg!(&stdout).contains("invalid redirect"), "{stdout}");
+}
@@ -0,0 +1,33 @@
+//! integration tests for `--check-substituters`
+
+use std::time::Duration;
+
+use assert_cmd::cargo_bin;
+use assert_cmd::Command;
+
+#[test]
+fn unreachable_substituter_prevents_startup() {
+    let cache = tempfile::tempdir().unwrap();
+    let result = Command::new(cargo_bin!("nixseparatedebuginfod2"))
+        .env("RUST_LOG", "nixseparatedebuginfod2=info")
+        .arg("--substituter")
+        .arg(format!(
+            "file://{}",
+            cache.path().join("missing").to_str().unwrap()
+        ))
+        .arg("--cache-dir")
+        .arg(cache.path())
+        .arg("--expiration")
+        .arg("1h")
+        .arg("--listen-address")
+        .arg("127.0.0.1:0")
+        .arg("--check-substituters")
+        // if the check does not fail, the server runs forever
+        .timeout(Duration::from_secs(60))
+        .assert()
+        .failure();
+    let output = result.get_output();
+    let logs = String::from_utf8_lossy(&output.stdout) + String::from_utf8_lossy(&output.stderr);
+    assert!(dbg!(&logs).contains("missing"));
+    assert!(logs.contains("--check-substituters"));
+}
@@ -0,0 +1,66 @@
+//! integration tests for the library, as a program embedding it would use it
+
+use std::path::Path;
+use std::time::Duration;
+
+use nixseparatedebuginfod2::substituter::memory::{MemoryEntry, MemorySubstituter};
+use nixseparatedebuginfod2::{BuildId, Debuginfod, Settings, StorePath};
+
+#[tokio::test]
+async fn lookups_in_memory_substituter() {
+    let t = tempfile::tempdir().unwrap();
+    let build_id = BuildId::new("0123456789abcdef0123456789abcdef01234567").unwrap();
+    let source = StorePath::new(Path::new(
+        "/nix/store/2qw62845796lyx649ck67zbk04pv8xhf-source",
+    ))
+    .unwrap();
+    let substituter = MemorySubstituter::new(t.path().join("substituter"))
+        .with_debug_output(
+            build_id.clone(),
+            [
+                (
+                    "lib/debug/.build-id/01/23456789abcdef0123456789abcdef01234567.debug",
+                    MemoryEntry::File(b"debug symbols".to_vec()),
+                ),
+                (
+                    "lib/debug/.build-id/01/23456789abcdef0123456789abcdef01234567.source",
+                    MemoryEntry::Symlink(source.as_ref().to_owned()),
+                ),
+            ],
+        )
+        .with_store_path(
+            &source,
+            [("src/main.c", MemoryEntry::File(b"int main() {}".to_vec()))],
+        );
+    let debuginfod = Debuginfod::new(
+        t.path().join("cache"),
+        Box::new(substituter),
+        Duration::from_secs(1000),
+        &Settings::default(),
+    )
+    .await
+    .unwrap();
+
+    let debuginfo = debuginfod.debuginfo(&build_id).await.unwrap().unwrap();
+    // sha256 of "debug symbols"
+    assert_eq!(
+        debuginfo.sha256().await.unwrap(),
+        "4afb94c5d780af68a55ac3a32f084e9e4a5afd84a204978d3a533f9ecc023998"
+    );
+    let main = debuginfod
+        .source(&build_id, "/build/source/src/main.c")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        main.store_path().unwrap().relative(),
+        Path::new("src/main.c")
+    );
+    // sha256 of "int main() {}"
+    assert_eq!(
+        main.sha256().await.unwrap(),
+        "00096d96da5299e65479678a8e79b07ab36e6185120e892a1360e1be25e84fbb"
+    );
+    let missing = BuildId::new("1123456789abcdef0123456789abcdef01234567").unwrap();
+    assert!(debuginfod.debuginfo(&missing).await.unwrap().is_none());
+}
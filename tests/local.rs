@@ -100,7 +100,7 @@ impl Server {
         let cache = tempfile::tempdir().unwrap();
         std::fs::create_dir(cache.path().join("server")).unwrap();
         std::fs::create_dir(cache.path().join("client")).unwrap();
-        let mut check_command = fake_store(&store);
+        let mut check_command = fake_store(store);
         check_command.arg("true");
         // bwrap will fail if /nix/store is composed of several mountpoints.
         // which is the case inside the nix sandbox.
@@ -119,7 +119,7 @@ impl Server {
             .unwrap();
             std::process::exit(0);
         };
-        let mut command = fake_store(&store);
+        let mut command = fake_store(store);
         command.env("RUST_LOG", "nixseparatedebuginfod2=trace,tower_http=debug");
         command
             .arg(cargo_bin!("nixseparatedebuginfod2"))
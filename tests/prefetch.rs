@@ -0,0 +1,68 @@
+//! integration tests for the `prefetch` subcommand
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use assert_cmd::assert::OutputAssertExt;
+use assert_cmd::cargo_bin;
+
+/// Path to the `tests/fixture` folder of the repo.
+fn fixture(path: &str) -> PathBuf {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(path);
+    assert!(path.exists());
+    path
+}
+
+fn prefetch_command(cache: &std::path::Path) -> Command {
+    let mut command = Command::new(cargo_bin!("nixseparatedebuginfod2"));
+    command
+        .env("RUST_LOG", "nixseparatedebuginfod2=trace")
+        .arg("--substituter")
+        .arg(format!(
+            "file://{}",
+            fixture("file_binary_cache").to_str().unwrap()
+        ))
+        .arg("--cache-dir")
+        .arg(cache)
+        .arg("--expiration")
+        .arg("1h")
+        .arg("prefetch");
+    command
+}
+
+#[test]
+fn prefetch_nominal() {
+    let cache = tempfile::tempdir().unwrap();
+    // /nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make
+    let result = prefetch_command(cache.path())
+        .arg("--build-id")
+        .arg("0e20481820d3b92468102b35a5e4a29a8695c1af")
+        .assert()
+        .success();
+    let stdout = String::from_utf8_lossy(&result.get_output().stdout).into_owned();
+    assert!(dbg!(&stdout).contains("debuginfo ok, executable ok, source ok"));
+    // the source archive was unpacked in the cache
+    assert!(cache
+        .path()
+        .join("other/sources/cache")
+        .read_dir()
+        .unwrap()
+        .next()
+        .is_some());
+}
+
+#[test]
+fn prefetch_missing() {
+    let cache = tempfile::tempdir().unwrap();
+    let result = prefetch_command(cache.path())
+        .arg("--build-id")
+        .arg("0e20481820d3b92468102b35a5e4a29a8695c1af")
+        .arg("--build-id")
+        .arg("483bd7f7229bdb00000000000000e4f37e15c293")
+        .assert()
+        .failure();
+    let stdout = String::from_utf8_lossy(&result.get_output().stdout).into_owned();
+    assert!(dbg!(&stdout).contains("483bd7f7229bdb00000000000000e4f37e15c293: debuginfo not found"));
+}
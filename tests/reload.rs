@@ -0,0 +1,83 @@
+//! integration tests for reloading substituters on SIGHUP
+
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::time::Duration;
+
+use assert_cmd::cargo_bin;
+
+/// Path to the `tests/fixture` folder of the repo.
+fn fixture(path: &str) -> PathBuf {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(path);
+    assert!(path.exists());
+    path
+}
+
+/// Kills the server when dropped
+struct KillOnDrop(Child);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Polls `url` until it answers something else than `previous`, and returns the status.
+fn wait_for_status_change(url: &str, previous: Option<u16>) -> u16 {
+    for _ in 0..100 {
+        if let Ok(response) = reqwest::blocking::get(url) {
+            let status = response.status().as_u16();
+            if Some(status) != previous {
+                return status;
+            }
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    panic!("status of {url} stayed {previous:?}");
+}
+
+#[test]
+fn sighup_reloads_substituters_file() {
+    let cache = tempfile::tempdir().unwrap();
+    let substituters_file = cache.path().join("substituters");
+    let missing = format!("file://{}", cache.path().join("missing").to_str().unwrap());
+    std::fs::write(&substituters_file, format!("{missing}\n")).unwrap();
+    let port = port_check::free_local_ipv4_port().unwrap();
+    let server = KillOnDrop(
+        Command::new(cargo_bin!("nixseparatedebuginfod2"))
+            .env("RUST_LOG", "nixseparatedebuginfod2=trace")
+            .arg("--substituters-file")
+            .arg(&substituters_file)
+            .arg("--cache-dir")
+            .arg(cache.path().join("server"))
+            .arg("--expiration")
+            .arg("1h")
+            .arg("--listen-address")
+            .arg(format!("127.0.0.1:{port}"))
+            .spawn()
+            .unwrap(),
+    );
+    // /nix/store/34j18r2rpi7js1whmvzm9wliad55rilr-gnumake-4.4.1/bin/make
+    let url = format!(
+        "http://127.0.0.1:{port}/buildid/0e20481820d3b92468102b35a5e4a29a8695c1af/debuginfo"
+    );
+    assert_eq!(wait_for_status_change(&url, None), 404);
+
+    std::fs::write(
+        &substituters_file,
+        format!(
+            "{missing}\nfile://{}\n",
+            fixture("file_binary_cache").to_str().unwrap()
+        ),
+    )
+    .unwrap();
+    nix::sys::signal::kill(
+        nix::unistd::Pid::from_raw(server.0.id() as i32),
+        nix::sys::signal::Signal::SIGHUP,
+    )
+    .unwrap();
+    assert_eq!(wait_for_status_change(&url, Some(404)), 200);
+}
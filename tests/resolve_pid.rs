@@ -0,0 +1,49 @@
+//! integration tests for the `resolve-pid` subcommand
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use assert_cmd::assert::OutputAssertExt;
+use assert_cmd::cargo_bin;
+
+fn resolve_pid_command(cache: &std::path::Path, pid: u32) -> Command {
+    let fixture =
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/file_binary_cache");
+    let mut command = Command::new(cargo_bin!("nixseparatedebuginfod2"));
+    command
+        .env("RUST_LOG", "nixseparatedebuginfod2=trace")
+        .arg("--substituter")
+        .arg(format!("file://{}", fixture.to_str().unwrap()))
+        .arg("--cache-dir")
+        .arg(cache)
+        .arg("--expiration")
+        .arg("1h")
+        .arg("resolve-pid")
+        .arg(pid.to_string());
+    command
+}
+
+#[test]
+fn resolve_pid_of_self() {
+    let cache = tempfile::tempdir().unwrap();
+    let result = resolve_pid_command(cache.path(), std::process::id())
+        .assert()
+        .success();
+    let stdout = String::from_utf8_lossy(&result.get_output().stdout).into_owned();
+    // this test executable is not in the binary cache
+    let exe = std::env::current_exe().unwrap();
+    assert!(
+        dbg!(&stdout).contains(&format!("{}: ", exe.display())),
+        "{exe:?} missing"
+    );
+    assert!(stdout.contains("debuginfo not found") || stdout.contains("no build id"));
+}
+
+#[test]
+fn resolve_pid_missing() {
+    let cache = tempfile::tempdir().unwrap();
+    // pids are at most 2^22
+    resolve_pid_command(cache.path(), u32::MAX)
+        .assert()
+        .failure();
+}